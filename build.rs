@@ -0,0 +1,24 @@
+//! Tells the build whether `keys/release.gpg` holds the key that
+//! hermit's releases are signed with. It is only exported into it when
+//! releases are built, and without it `hermit upgrade` can't check, and
+//! so won't install, anything.
+
+use std::{env, fs};
+
+const RELEASE_KEYRING: &str = "keys/release.gpg";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", RELEASE_KEYRING);
+    println!("cargo:rustc-check-cfg=cfg(release_key)");
+    let has_key = fs::metadata(RELEASE_KEYRING).is_ok_and(|metadata| metadata.len() > 0);
+    if has_key {
+        println!("cargo:rustc-cfg=release_key");
+    } else if env::var_os("CARGO_FEATURE_UPGRADE").is_some()
+        && env::var("PROFILE").as_deref() == Ok("release")
+    {
+        println!(
+            "cargo:warning={} is empty, so this hermit won't be able to upgrade itself",
+            RELEASE_KEYRING
+        );
+    }
+}
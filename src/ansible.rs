@@ -0,0 +1,367 @@
+//! The queued operations as a list of Ansible tasks, for teams whose
+//! machines are set up by configuration management rather than by
+//! running hermit on them. The tasks assume that the shells are checked
+//! out on the managed machines where they are on this one, since links
+//! point into them.
+
+use crate::{
+    common::*,
+    file_operations::{ConflictPolicy, Op},
+};
+
+/// A task: its name, the module it uses, the module's arguments, and
+/// what Ansible should do with it beyond running the module.
+struct Task {
+    name: String,
+    module: &'static str,
+    args: Vec<(&'static str, Value)>,
+    vars: Vec<(&'static str, Value)>,
+    register: Option<String>,
+    when: Option<String>,
+}
+
+/// The value of an argument to a module.
+enum Value {
+    /// Text from the plan, which Ansible is told not to template, so
+    /// that a `{{` in a path or a file is left as it is.
+    Text(String),
+    /// One of the module's own words, like `state: absent`.
+    Word(&'static str),
+    Flag(bool),
+    List(Vec<String>),
+}
+
+fn text(value: impl Into<String>) -> Value {
+    Value::Text(value.into())
+}
+
+fn path_text(path: &Path) -> Result<String> {
+    path.to_str()
+        .map(str::to_owned)
+        .ok_or_else(|| Error::NotAnsibleTask {
+            path: path.to_path_buf(),
+            reason: "its path isn't valid UTF-8",
+        })
+}
+
+fn file(path: &Path) -> Result<Value> {
+    path_text(path).map(Value::Text)
+}
+
+/// JSON strings are YAML strings too, with the same escapes.
+fn quoted(value: &str) -> String {
+    serde_json::to_string(value).expect("strings are always valid JSON")
+}
+
+/// A string that Ansible leaves as it is, rather than treating as a
+/// Jinja template.
+fn unsafe_quoted(value: &str) -> String {
+    format!("!unsafe {}", quoted(value))
+}
+
+impl Task {
+    fn new(name: String, module: &'static str, args: Vec<(&'static str, Value)>) -> Task {
+        Task {
+            name,
+            module,
+            args,
+            vars: Vec::new(),
+            register: None,
+            when: None,
+        }
+    }
+
+    /// A task that runs `argv`, which Ansible skips when `guard` says
+    /// there is nothing to do.
+    fn command(
+        name: String,
+        argv: Vec<String>,
+        guard: Option<(&'static str, &Path)>,
+    ) -> Result<Task> {
+        let mut args = vec![("argv", Value::List(argv))];
+        if let Some((key, guarded)) = guard {
+            args.push((key, file(guarded)?));
+        }
+        Ok(Task::new(name, "ansible.builtin.command", args))
+    }
+
+    /// The tasks that carry out `op`, the `index`th operation, which
+    /// names the variables that its tasks register.
+    fn of(op: &Op, index: usize, forced: bool) -> Result<Vec<Task>> {
+        let name = op.describe();
+        let task = match op {
+            Op::MkDir(path) => Task::new(
+                name,
+                "ansible.builtin.file",
+                vec![("path", file(path)?), ("state", Value::Word("directory"))],
+            ),
+            Op::GitInit(path) => Task::command(
+                name,
+                vec!["git".to_owned(), "init".to_owned(), path_text(path)?],
+                Some(("creates", &path.join(".git"))),
+            )?,
+            Op::GitClone { url, path, options } => {
+                let mut args = vec![("repo", text(url.as_str())), ("dest", file(path)?)];
+                if let Some(branch) = &options.branch {
+                    args.push(("version", text(branch.as_str())));
+                }
+                if let Some(depth) = options.depth {
+                    args.push(("depth", text(depth.to_string())));
+                }
+                Task::new(name, "ansible.builtin.git", args)
+            }
+            Op::GitRemote {
+                repo,
+                name: remote,
+                url,
+            } => Task::command(
+                name,
+                vec![
+                    "git".to_owned(),
+                    "-C".to_owned(),
+                    path_text(repo)?,
+                    "remote".to_owned(),
+                    "add".to_owned(),
+                    remote.clone(),
+                    url.clone(),
+                ],
+                None,
+            )?,
+            Op::Link { path, target } | Op::Relink { path, target, .. } => Task::new(
+                name,
+                "ansible.builtin.file",
+                vec![
+                    ("src", file(target)?),
+                    ("dest", file(path)?),
+                    ("state", Value::Word("link")),
+                    (
+                        "force",
+                        Value::Flag(forced || matches!(op, Op::Relink { .. })),
+                    ),
+                ],
+            ),
+            Op::Copy { path, source } => Task::new(
+                name,
+                "ansible.builtin.copy",
+                vec![
+                    ("src", file(source)?),
+                    ("dest", file(path)?),
+                    ("remote_src", Value::Flag(true)),
+                ],
+            ),
+            Op::Move { path, destination } => Task::command(
+                name,
+                vec!["mv".to_owned(), path_text(path)?, path_text(destination)?],
+                Some(("removes", path)),
+            )?,
+            Op::Remove(path) => Task::new(
+                name,
+                "ansible.builtin.file",
+                vec![("path", file(path)?), ("state", Value::Word("absent"))],
+            ),
+            Op::Unlink { path, source } => return Task::unlink(name, index, path, source),
+            Op::WriteFile { path, contents } | Op::CreateFile { path, contents } => {
+                let contents =
+                    String::from_utf8(contents.clone()).map_err(|_| Error::NotAnsibleTask {
+                        path: path.clone(),
+                        reason: "its contents aren't valid UTF-8",
+                    })?;
+                Task::new(
+                    name,
+                    "ansible.builtin.copy",
+                    vec![
+                        ("dest", file(path)?),
+                        ("content", Value::Text(contents)),
+                        ("force", Value::Flag(matches!(op, Op::WriteFile { .. }))),
+                    ],
+                )
+            }
+        };
+        Ok(vec![task])
+    }
+
+    /// Removing `path` only if it is still a link to `source` or a copy
+    /// of it, as hermit does, takes looking at both of them first.
+    fn unlink(name: String, index: usize, path: &Path, source: &Path) -> Result<Vec<Task>> {
+        let found = format!("hermit_unlink_{}", index);
+        let original = format!("{}_source", found);
+        let look = |looked_at: &Path, register: &str| -> Result<Task> {
+            let mut task = Task::new(
+                format!("look at {}", message::path(looked_at)),
+                "ansible.builtin.stat",
+                vec![("path", file(looked_at)?)],
+            );
+            task.register = Some(register.to_owned());
+            Ok(task)
+        };
+        let stats = vec![look(path, &found)?, look(source, &original)?];
+
+        let mut remove = Task::new(
+            name,
+            "ansible.builtin.file",
+            vec![("path", file(path)?), ("state", Value::Word("absent"))],
+        );
+        remove.vars = vec![("hermit_source", file(source)?)];
+        remove.when = Some(format!(
+            "({found}.stat.islnk | default(false) and {found}.stat.lnk_target == hermit_source) \
+             or ({found}.stat.isreg | default(false) and {original}.stat.isreg | default(false) \
+             and {found}.stat.checksum == {original}.stat.checksum)",
+            found = found,
+            original = original,
+        ));
+        Ok(stats.into_iter().chain(Some(remove)).collect())
+    }
+
+    fn render(&self) -> String {
+        let mut shown = format!(
+            "- name: {}\n  {}:\n",
+            unsafe_quoted(&self.name),
+            self.module
+        );
+        render_values(&mut shown, &self.args);
+        if !self.vars.is_empty() {
+            shown.push_str("  vars:\n");
+            render_values(&mut shown, &self.vars);
+        }
+        if let Some(register) = &self.register {
+            shown.push_str(&format!("  register: {}\n", register));
+        }
+        if let Some(when) = &self.when {
+            shown.push_str(&format!("  when: {}\n", quoted(when)));
+        }
+        shown
+    }
+}
+
+fn render_values(shown: &mut String, values: &[(&'static str, Value)]) {
+    for (key, value) in values {
+        match value {
+            Value::Text(value) => {
+                shown.push_str(&format!("    {}: {}\n", key, unsafe_quoted(value)))
+            }
+            Value::Word(value) => shown.push_str(&format!("    {}: {}\n", key, quoted(value))),
+            Value::Flag(value) => shown.push_str(&format!("    {}: {}\n", key, value)),
+            Value::List(values) => {
+                shown.push_str(&format!("    {}:\n", key));
+                for value in values {
+                    shown.push_str(&format!("      - {}\n", unsafe_quoted(value)));
+                }
+            }
+        }
+    }
+}
+
+/// The operations queued in `file_ops` that have something left to do,
+/// as Ansible tasks in the order hermit would carry them out in, in a
+/// YAML file for `ansible.builtin.include_tasks`. Nothing in the tasks
+/// is templated by Ansible. Plans with paths or written files that
+/// aren't valid UTF-8 can't be written as YAML, and are refused.
+pub fn tasks(file_ops: &FileOperations) -> Result<String> {
+    let fs = file_ops.backend();
+    let forced = file_ops.conflict_policy() != ConflictPolicy::Error;
+    let mut tasks = Vec::new();
+    for (index, op) in file_ops
+        .ordered_operations()
+        .into_iter()
+        .filter(|op| !op.is_satisfied(fs))
+        .enumerate()
+    {
+        tasks.extend(Task::of(op, index, forced)?.iter().map(Task::render));
+    }
+    if tasks.is_empty() {
+        return Ok("--- []\n".to_owned());
+    }
+    Ok(format!("---\n{}", tasks.join("\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fs_backend::{FsBackend, MemoryFs};
+
+    #[test]
+    fn links_become_file_tasks() {
+        let fs = Rc::new(MemoryFs::new());
+        fs.add_file("/hermit/shells/work/.bashrc", "work");
+        fs.add_file("/hermit/shells/work/.gitconfig", "[user]");
+        fs.create_dir_all(Path::new("/home/geoff")).unwrap();
+        fs.symlink(
+            Path::new("/hermit/shells/default/.profile"),
+            Path::new("/home/geoff/.profile"),
+        )
+        .unwrap();
+        let mut file_ops = FileOperations::with_backend("/home/geoff", fs.clone());
+        file_ops.unlink(".profile", "/hermit/shells/default/.profile");
+        file_ops.link(".bashrc", "/hermit/shells/work/.bashrc");
+        file_ops.copy(".gitconfig", "/hermit/shells/work/.gitconfig");
+        file_ops.unlink(".inputrc", "/hermit/shells/default/.inputrc");
+
+        assert_eq!(
+            tasks(&file_ops).unwrap(),
+            "---\n\
+             - name: !unsafe \"look at /home/geoff/.profile\"\n\
+             \x20 ansible.builtin.stat:\n\
+             \x20   path: !unsafe \"/home/geoff/.profile\"\n\
+             \x20 register: hermit_unlink_0\n\
+             \n\
+             - name: !unsafe \"look at /hermit/shells/default/.profile\"\n\
+             \x20 ansible.builtin.stat:\n\
+             \x20   path: !unsafe \"/hermit/shells/default/.profile\"\n\
+             \x20 register: hermit_unlink_0_source\n\
+             \n\
+             - name: !unsafe \"remove /home/geoff/.profile\"\n\
+             \x20 ansible.builtin.file:\n\
+             \x20   path: !unsafe \"/home/geoff/.profile\"\n\
+             \x20   state: \"absent\"\n\
+             \x20 vars:\n\
+             \x20   hermit_source: !unsafe \"/hermit/shells/default/.profile\"\n\
+             \x20 when: \"(hermit_unlink_0.stat.islnk | default(false) \
+             and hermit_unlink_0.stat.lnk_target == hermit_source) \
+             or (hermit_unlink_0.stat.isreg | default(false) \
+             and hermit_unlink_0_source.stat.isreg | default(false) \
+             and hermit_unlink_0.stat.checksum == hermit_unlink_0_source.stat.checksum)\"\n\
+             \n\
+             - name: !unsafe \"link /home/geoff/.bashrc\"\n\
+             \x20 ansible.builtin.file:\n\
+             \x20   src: !unsafe \"/hermit/shells/work/.bashrc\"\n\
+             \x20   dest: !unsafe \"/home/geoff/.bashrc\"\n\
+             \x20   state: \"link\"\n\
+             \x20   force: false\n\
+             \n\
+             - name: !unsafe \"copy /home/geoff/.gitconfig\"\n\
+             \x20 ansible.builtin.copy:\n\
+             \x20   src: !unsafe \"/hermit/shells/work/.gitconfig\"\n\
+             \x20   dest: !unsafe \"/home/geoff/.gitconfig\"\n\
+             \x20   remote_src: true\n"
+        );
+        file_ops.clear();
+        assert_eq!(tasks(&file_ops).unwrap(), "--- []\n");
+    }
+
+    #[test]
+    fn nothing_is_left_for_jinja_to_template() {
+        let fs = Rc::new(MemoryFs::new());
+        let mut file_ops = FileOperations::with_backend("/home/geoff", fs);
+        file_ops.write_file(".vimrc", "let g:x = \"{{ lookup('pipe', 'id') }}\"\n");
+
+        let tasks = tasks(&file_ops).unwrap();
+        assert!(tasks.contains(
+            "    content: !unsafe \"let g:x = \\\"{{ lookup('pipe', 'id') }}\\\"\\n\"\n"
+        ));
+    }
+
+    #[test]
+    fn paths_that_arent_utf8_are_refused() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let fs = Rc::new(MemoryFs::new());
+        let mut file_ops = FileOperations::with_backend("/home/geoff", fs);
+        file_ops.create_dir(OsStr::from_bytes(b"caf\xe9"));
+
+        assert!(matches!(
+            tasks(&file_ops),
+            Err(Error::NotAnsibleTask { reason, .. }) if reason.contains("path")
+        ));
+    }
+}
@@ -0,0 +1,383 @@
+//! The audit log: a line for every change hermit makes, or fails to
+//! make, with when it was made, by whom and with which command. Unlike
+//! the journal, it is never rewritten by `hermit undo`, so that on a
+//! machine that several people look after it shows everything that
+//! happened to it.
+
+use crate::{
+    common::*,
+    file_operations::Outcome as OpOutcome,
+    host::Host,
+    observer::{Event, Observer},
+    shell_info, state,
+};
+
+use std::{
+    cell::RefCell,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The name of the file in the hermit root's state directory that
+/// holds the audit log.
+pub const AUDIT_FILE_NAME: &str = "audit";
+
+/// How an operation in the log turned out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Changed,
+    Failed,
+}
+
+impl Outcome {
+    pub const NAMES: &'static [&'static str] = &["changed", "failed"];
+
+    pub fn from_name(name: &str) -> Option<Outcome> {
+        match name {
+            "changed" => Some(Outcome::Changed),
+            "failed" => Some(Outcome::Failed),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Changed => "changed",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+/// One line of the log.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Entry {
+    /// When the operation was carried out, in seconds since the epoch.
+    pub time: u64,
+    /// The hermit command that carried it out, like `inhabit`.
+    pub command: String,
+    pub user: String,
+    /// Who ran hermit with sudo, since `user` is then root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sudo_user: Option<String>,
+    /// What the operation set out to do, like `link /home/geoff/.bashrc`.
+    pub op: String,
+    pub outcome: Outcome,
+    /// Why the operation failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Entry {
+    /// Who made the change, along with who ran hermit with sudo.
+    pub fn who(&self) -> String {
+        match &self.sudo_user {
+            Some(sudo_user) => format!("{} (sudo by {})", self.user, sudo_user),
+            None => self.user.clone(),
+        }
+    }
+
+    /// When the operation was carried out, as `YYYY-MM-DD HH:MM:SS` in
+    /// UTC.
+    pub fn when(&self) -> String {
+        let seconds = self.time % 86400;
+        format!(
+            "{} {:02}:{:02}:{:02}",
+            shell_info::date_from_timestamp(self.time),
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60
+        )
+    }
+}
+
+/// Which entries of the log to show. Everything is shown when nothing
+/// is set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub command: Option<String>,
+    /// Matches the user that ran hermit, or ran it with sudo.
+    pub user: Option<String>,
+    pub outcome: Option<Outcome>,
+}
+
+impl Filter {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        self.command
+            .as_ref()
+            .is_none_or(|command| *command == entry.command)
+            && self
+                .user
+                .as_ref()
+                .is_none_or(|user| *user == entry.user || Some(user) == entry.sudo_user.as_ref())
+            && self.outcome.is_none_or(|outcome| outcome == entry.outcome)
+    }
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn in_root(root: impl AsRef<Path>) -> AuditLog {
+        AuditLog {
+            path: state::dir(root).join(AUDIT_FILE_NAME),
+        }
+    }
+
+    /// The entries that `filter` matches, oldest first.
+    pub fn entries(&self, filter: &Filter) -> Result<Vec<Entry>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(Error::InvalidAuditLog(err.to_string())),
+        };
+        let mut entries = vec![];
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let entry: Entry = serde_json::from_str(line)
+                .map_err(|err| Error::InvalidAuditLog(err.to_string()))?;
+            if filter.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Adds `entries` to the end of the log.
+    pub fn append(&self, entries: &[Entry]) -> Result<()> {
+        let invalid = |err: io::Error| Error::InvalidAuditLog(err.to_string());
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(
+                &serde_json::to_string(entry).expect("audit entries are always valid JSON"),
+            );
+            lines.push('\n');
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(invalid)?;
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()))
+            .map_err(invalid)
+    }
+}
+
+/// The user running hermit, by the name the host goes by, or the
+/// name of the user's ID if the environment doesn't say.
+fn user(host: &Host) -> String {
+    if !host.username.is_empty() {
+        return host.username.clone();
+    }
+    process::Command::new("id")
+        .arg("-un")
+        .stdin(process::Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(output.stdout.trim_ascii()).into_owned())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// An observer that adds the operations that `command` carries out on
+/// `host` to an audit log, a batch at a time, and the changes it makes
+/// right away as they are made. It is handed to both `Hermit` and
+/// `FileOperations`.
+pub struct AuditTrail {
+    log: AuditLog,
+    command: String,
+    user: String,
+    sudo_user: Option<String>,
+    pending: RefCell<Vec<Entry>>,
+}
+
+impl AuditTrail {
+    pub fn new(log: AuditLog, command: &str, host: &Host) -> AuditTrail {
+        let user = user(host);
+        let sudo_user = host
+            .env
+            .get("SUDO_USER")
+            .filter(|sudo_user| !sudo_user.is_empty() && **sudo_user != user)
+            .cloned();
+        AuditTrail {
+            log,
+            command: command.to_owned(),
+            user,
+            sudo_user,
+            pending: RefCell::new(vec![]),
+        }
+    }
+
+    fn add(&self, op: &str, outcome: Outcome, error: Option<String>) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        self.pending.borrow_mut().push(Entry {
+            time,
+            command: self.command.clone(),
+            user: self.user.clone(),
+            sudo_user: self.sudo_user.clone(),
+            op: op.to_owned(),
+            outcome,
+            error,
+        });
+    }
+
+    /// Adds the entries that are waiting to the log.
+    fn write(&self) {
+        let entries = self.pending.take();
+        if entries.is_empty() {
+            return;
+        }
+        if let Err(err) = self.log.append(&entries) {
+            message::report(err);
+        }
+    }
+}
+
+impl Observer for AuditTrail {
+    fn notify(&self, event: &Event) {
+        match event {
+            Event::OpDone {
+                description,
+                outcome: OpOutcome::Changed,
+            } => self.add(description, Outcome::Changed, None),
+            Event::OpFailed { description, error } => {
+                self.add(description, Outcome::Failed, Some(error.clone()))
+            }
+            Event::ChangeMade { description, error } => {
+                let outcome = match error {
+                    Some(_) => Outcome::Failed,
+                    None => Outcome::Changed,
+                };
+                self.add(description, outcome, error.clone());
+                self.write();
+            }
+            Event::OperationsDone { .. } => self.write(),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn changes_and_failures_are_logged_with_who_made_them() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let home = test_root.join("home");
+        fs::create_dir(&home).unwrap();
+        fs::write(home.join(".vimrc"), "mine").unwrap();
+        fs::write(test_root.join(".bashrc"), "work").unwrap();
+        let log = AuditLog::in_root(test_root);
+        let host = Host {
+            username: "root".to_owned(),
+            env: vec![("SUDO_USER".to_owned(), "geoff".to_owned())]
+                .into_iter()
+                .collect(),
+            ..Host::default()
+        };
+
+        let mut file_ops = FileOperations::rooted_at(&home);
+        file_ops.add_observer(Rc::new(AuditTrail::new(
+            AuditLog::in_root(test_root),
+            "inhabit",
+            &host,
+        )));
+        file_ops.link(".bashrc", test_root.join(".bashrc"));
+        file_ops.link(".vimrc", test_root.join(".vimrc"));
+        file_ops.commit();
+
+        let entries = log.entries(&Filter::default()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            (entries[0].user.as_str(), entries[0].command.as_str()),
+            ("root", "inhabit")
+        );
+        assert_eq!(entries[0].sudo_user.as_deref(), Some("geoff"));
+        assert_eq!(
+            entries[0].op,
+            format!("link {}", home.join(".bashrc").display())
+        );
+        assert_eq!(
+            (entries[0].outcome, entries[0].error.as_ref()),
+            (Outcome::Changed, None)
+        );
+        assert_eq!(entries[1].outcome, Outcome::Failed);
+        assert!(entries[1].error.is_some());
+
+        let failed = Filter {
+            outcome: Some(Outcome::Failed),
+            ..Filter::default()
+        };
+        assert_eq!(log.entries(&failed).unwrap(), entries[1..]);
+        let sudoer = Filter {
+            user: Some("geoff".to_owned()),
+            ..Filter::default()
+        };
+        assert_eq!(log.entries(&sudoer).unwrap(), entries);
+        let someone_else = Filter {
+            user: Some("alice".to_owned()),
+            ..Filter::default()
+        };
+        assert_eq!(log.entries(&someone_else).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn changes_made_right_away_are_logged_as_they_are_made() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let log = AuditLog::in_root(test_root);
+        let host = Host {
+            username: "geoff".to_owned(),
+            ..Host::default()
+        };
+        let trail = AuditTrail::new(AuditLog::in_root(test_root), "remote", &host);
+
+        trail.notify(&Event::ChangeMade {
+            description: "add the remote backup at /srv/backup.git".to_owned(),
+            error: None,
+        });
+        trail.notify(&Event::ChangeMade {
+            description: "remove the remote origin".to_owned(),
+            error: Some("remote 'origin' does not exist".to_owned()),
+        });
+
+        let entries = log.entries(&Filter::default()).unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.op.as_str(), entry.outcome))
+                .collect::<Vec<_>>(),
+            vec![
+                ("add the remote backup at /srv/backup.git", Outcome::Changed),
+                ("remove the remote origin", Outcome::Failed),
+            ]
+        );
+        assert_eq!(
+            entries[1].error.as_deref(),
+            Some("remote 'origin' does not exist")
+        );
+    }
+
+    #[test]
+    fn times_are_shown_in_utc() {
+        let entry = Entry {
+            time: 1_000_000_000,
+            command: "apply".to_owned(),
+            user: "geoff".to_owned(),
+            sudo_user: None,
+            op: "create /home/geoff/.config".to_owned(),
+            outcome: Outcome::Changed,
+            error: None,
+        };
+        assert_eq!(entry.when(), "2001-09-09 01:46:40");
+    }
+}
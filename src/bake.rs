@@ -0,0 +1,200 @@
+//! Baking a shell into a container image, so that CI jobs and dev
+//! containers pick up the same dotfiles as the machines the shell is
+//! used on.
+//!
+//! A bake is a directory with a `Dockerfile` in it, and the shell's
+//! files in `home/` as they are put in the home directory. Building it
+//! makes an image with nothing but those files in it, for other images
+//! to copy in with `COPY --from`.
+
+use crate::{common::*, manifest::ShellFile};
+
+/// The directory in a bake that holds the shell's files.
+pub const FILES_DIR: &str = "home";
+
+/// The file that describes the image.
+pub const DOCKERFILE: &str = "Dockerfile";
+
+/// How every Dockerfile that hermit bakes starts, so that baking again
+/// can tell its own output apart from anything else.
+const HEADER: &str = "# Baked by hermit";
+
+/// The kinds of image a shell can be baked into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Docker,
+}
+
+impl Format {
+    pub const NAMES: &'static [&'static str] = &["docker"];
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "docker" => Some(Format::Docker),
+            _ => None,
+        }
+    }
+}
+
+/// Where the files go in the image, and who owns them there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recipe {
+    pub format: Format,
+    /// The home directory in the image.
+    pub home: PathBuf,
+    /// The user, or `user:group`, that owns the files in the image.
+    pub owner: Option<String>,
+}
+
+/// What went into a bake.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Baked {
+    /// The number of files in the image.
+    pub files: usize,
+    /// The secret files that were left out, by their target, since an
+    /// image is easily shared.
+    pub secrets: Vec<PathBuf>,
+}
+
+impl Recipe {
+    /// The Dockerfile for the shell named `name`.
+    pub fn dockerfile(&self, name: &str) -> String {
+        let chown = self
+            .owner
+            .as_ref()
+            .map(|owner| format!("--chown={} ", owner))
+            .unwrap_or_default();
+        format!(
+            "{header} from the shell {name}. Build it with\n\
+             #     docker build -t {name}-dotfiles .\n\
+             # and copy its files into another image with\n\
+             #     COPY --from={name}-dotfiles / /\n\
+             FROM scratch\n\
+             COPY {chown}{dir}/ {home}/\n",
+            header = HEADER,
+            name = name,
+            chown = chown,
+            dir = FILES_DIR,
+            home = self.home.display().to_string().trim_end_matches('/'),
+        )
+    }
+
+    /// Bakes the `files` of the shell named `name` at `shell_root` into
+    /// the directory `out`, which must be empty or an earlier bake.
+    pub fn bake(
+        &self,
+        name: &str,
+        shell_root: &Path,
+        files: &[ShellFile],
+        out: &Path,
+    ) -> Result<Baked> {
+        let failed = |err: io::Error| Error::BakeFailed(out.to_path_buf(), err.to_string());
+        let files_dir = out.join(FILES_DIR);
+        if is_earlier_bake(out) {
+            if files_dir.exists() {
+                fs::remove_dir_all(&files_dir).map_err(failed)?;
+            }
+        } else if fs::read_dir(out).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(Error::BakeFailed(
+                out.to_path_buf(),
+                "it isn't empty".to_owned(),
+            ));
+        }
+        fs::create_dir_all(&files_dir).map_err(failed)?;
+
+        let mut baked = Baked::default();
+        for file in files {
+            if file.secret {
+                baked.secrets.push(file.target.clone());
+                continue;
+            }
+            let source = shell_root.join(&file.source);
+            for entry in WalkDir::new(&source).follow_links(true) {
+                let entry = entry.map_err(|err| failed(err.into()))?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                let mut destination = files_dir.join(&file.target);
+                match entry.path().strip_prefix(&source) {
+                    Ok(below) if below != Path::new("") => destination.push(below),
+                    _ => (),
+                }
+                if let Some(parent) = destination.parent() {
+                    fs::create_dir_all(parent).map_err(failed)?;
+                }
+                fs::copy(entry.path(), &destination).map_err(failed)?;
+                baked.files += 1;
+            }
+        }
+
+        let description = match self.format {
+            Format::Docker => self.dockerfile(name),
+        };
+        fs::write(out.join(DOCKERFILE), description).map_err(failed)?;
+        Ok(baked)
+    }
+}
+
+fn is_earlier_bake(out: &Path) -> bool {
+    fs::read_to_string(out.join(DOCKERFILE)).is_ok_and(|contents| contents.starts_with(HEADER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{manifest::Granularity, test_helpers::filesystem::set_up};
+
+    #[test]
+    fn shells_are_baked_without_their_secrets() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path().join("shell");
+        let out = test_root_dir.path().join("bake");
+        fs::create_dir_all(shell_root.join("vim/plugin")).unwrap();
+        fs::write(shell_root.join("bashrc"), "home").unwrap();
+        fs::write(shell_root.join("vim/plugin/a.vim"), "a").unwrap();
+        fs::write(shell_root.join(".netrc"), "password").unwrap();
+        let files = vec![
+            ShellFile {
+                source: PathBuf::from("bashrc"),
+                ..ShellFile::mirrored(".bashrc")
+            },
+            ShellFile {
+                source: PathBuf::from("vim"),
+                ..ShellFile::mirrored(".vim").with_granularity(Granularity::Directory)
+            },
+            ShellFile::mirrored(".netrc").with_secret(true),
+        ];
+        let recipe = Recipe {
+            format: Format::Docker,
+            home: PathBuf::from("/home/dev/"),
+            owner: Some("dev".to_owned()),
+        };
+
+        let baked = recipe.bake("work", &shell_root, &files, &out).unwrap();
+
+        assert_eq!(
+            baked,
+            Baked {
+                files: 2,
+                secrets: vec![PathBuf::from(".netrc")],
+            }
+        );
+        assert_eq!(fs::read(out.join("home/.bashrc")).unwrap(), b"home");
+        assert_eq!(fs::read(out.join("home/.vim/plugin/a.vim")).unwrap(), b"a");
+        assert!(!out.join("home/.netrc").exists());
+        let dockerfile = fs::read_to_string(out.join(DOCKERFILE)).unwrap();
+        assert!(dockerfile.ends_with("FROM scratch\nCOPY --chown=dev home/ /home/dev/\n"));
+
+        fs::remove_file(shell_root.join("vim/plugin/a.vim")).unwrap();
+        let baked = recipe.bake("work", &shell_root, &files, &out).unwrap();
+        assert_eq!(baked.files, 1);
+        assert!(!out.join("home/.vim/plugin/a.vim").exists());
+
+        fs::write(out.join(DOCKERFILE), "FROM debian").unwrap();
+        assert!(matches!(
+            recipe.bake("work", &shell_root, &files, &out),
+            Err(Error::BakeFailed(..))
+        ));
+    }
+}
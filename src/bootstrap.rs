@@ -0,0 +1,258 @@
+use crate::{common::*, host::Host, manifest::When};
+
+use serde::Deserialize;
+
+/// A single setup step declared in a shell's manifest.
+///
+/// Each step either gives a command line to `run` through `sh -c`, or
+/// names a `script` inside the shell to execute. Steps always run
+/// from the root of the shell.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct BootstrapStep {
+    pub name: String,
+    #[serde(default)]
+    pub run: Option<String>,
+    #[serde(default)]
+    pub script: Option<PathBuf>,
+    #[serde(default)]
+    pub when: Option<When>,
+}
+
+impl BootstrapStep {
+    pub fn applies_to(&self, host: &Host) -> bool {
+        self.when.as_ref().is_none_or(|when| when.matches(host))
+    }
+
+    fn command(&self, shell_root: &Path) -> Result<process::Command> {
+        let mut command = match (&self.run, &self.script) {
+            (Some(run), None) => {
+                let mut command = process::Command::new("sh");
+                command.arg("-c").arg(run);
+                command
+            }
+            (None, Some(script)) => process::Command::new(shell_root.join(script)),
+            _ => {
+                return Err(Error::InvalidManifest(format!(
+                    "bootstrap step '{}' needs exactly one of `run` or `script`",
+                    self.name
+                )))
+            }
+        };
+        command.current_dir(shell_root);
+        Ok(command)
+    }
+}
+
+/// The names of the bootstrap steps that have completed for one shell
+/// on this machine, stored one per line.
+#[derive(Clone, Debug)]
+pub struct BootstrapLog {
+    path: PathBuf,
+    completed: Vec<String>,
+}
+
+impl BootstrapLog {
+    pub fn load(path: impl AsRef<Path>) -> BootstrapLog {
+        let path = PathBuf::from(path.as_ref());
+        let completed = fs::read_to_string(&path)
+            .map(|contents| contents.lines().map(ToOwned::to_owned).collect())
+            .unwrap_or_default();
+        BootstrapLog { path, completed }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.is_file()
+    }
+
+    pub fn is_complete(&self, name: &str) -> bool {
+        self.completed.iter().any(|step| step == name)
+    }
+
+    pub fn record(&mut self, name: &str) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", name)?;
+        self.completed.push(name.to_owned());
+        Ok(())
+    }
+}
+
+/// The bootstrap steps of a shell that apply to this machine, along
+/// with the record of which of them have already run.
+pub struct Bootstrap {
+    shell_root: PathBuf,
+    steps: Vec<BootstrapStep>,
+    log: BootstrapLog,
+    env: Vec<(&'static str, PathBuf)>,
+    input: bool,
+}
+
+impl Bootstrap {
+    pub fn new(
+        shell_root: impl AsRef<Path>,
+        steps: Vec<BootstrapStep>,
+        log: BootstrapLog,
+    ) -> Bootstrap {
+        Bootstrap {
+            shell_root: PathBuf::from(shell_root.as_ref()),
+            steps,
+            log,
+            env: vec![],
+            input: true,
+        }
+    }
+
+    /// Sets environment variables for the steps, on top of hermit's
+    /// own environment.
+    pub fn with_env(self, env: Vec<(&'static str, PathBuf)>) -> Bootstrap {
+        Bootstrap { env, ..self }
+    }
+
+    /// Closes the standard input of the steps, so that a step that
+    /// asks a question fails instead of waiting for an answer. What
+    /// the steps print goes to standard error, out of the way of
+    /// output that is meant for another program.
+    pub fn without_input(self) -> Bootstrap {
+        Bootstrap {
+            input: false,
+            ..self
+        }
+    }
+
+    /// Whether any step of this shell has ever completed on this
+    /// machine.
+    pub fn has_run(&self) -> bool {
+        self.log.exists()
+    }
+
+    pub fn pending_steps(&self) -> Vec<BootstrapStep> {
+        self.steps
+            .iter()
+            .filter(|step| !self.log.is_complete(&step.name))
+            .cloned()
+            .collect()
+    }
+
+    /// Runs one step and records it as complete if it succeeded.
+    pub fn run_step(&mut self, step: &BootstrapStep) -> Result<()> {
+        let failed = |reason: String| Error::BootstrapStepFailed(step.name.clone(), reason);
+
+        let mut command = step.command(&self.shell_root)?;
+        command.envs(self.env.iter().cloned());
+        if !self.input {
+            command.stdin(process::Stdio::null()).stdout(io::stderr());
+        }
+        let status = command.status().map_err(|err| failed(err.to_string()))?;
+        if !status.success() {
+            return Err(failed(status.to_string()));
+        }
+
+        self.log
+            .record(&step.name)
+            .map_err(|err| failed(format!("could not record completion: {}", err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    fn step(name: &str, run: &str) -> BootstrapStep {
+        BootstrapStep {
+            name: name.to_owned(),
+            run: Some(run.to_owned()),
+            script: None,
+            when: None,
+        }
+    }
+
+    #[test]
+    fn all_steps_are_pending_at_first() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let log = BootstrapLog::load(test_root.join("log"));
+        let bootstrap = Bootstrap::new(test_root, vec![step("a", "true")], log);
+
+        assert!(!bootstrap.has_run());
+        assert_eq!(bootstrap.pending_steps(), vec![step("a", "true")]);
+    }
+
+    #[test]
+    fn completed_steps_are_skipped_on_later_runs() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let log_path = test_root.join("bootstrap").join("default");
+        let steps = vec![step("a", "true"), step("b", "true")];
+
+        let mut bootstrap = Bootstrap::new(test_root, steps.clone(), BootstrapLog::load(&log_path));
+        bootstrap.run_step(&steps[0]).expect("Step failed");
+
+        let bootstrap = Bootstrap::new(test_root, steps, BootstrapLog::load(&log_path));
+        assert!(bootstrap.has_run());
+        assert_eq!(bootstrap.pending_steps(), vec![step("b", "true")]);
+    }
+
+    #[test]
+    fn failed_steps_stay_pending() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let log = BootstrapLog::load(test_root.join("log"));
+        let failing = step("fails", "exit 3");
+        let mut bootstrap = Bootstrap::new(test_root, vec![failing.clone()], log);
+
+        let result = bootstrap.run_step(&failing);
+
+        assert!(matches!(result, Err(Error::BootstrapStepFailed(ref name, _)) if name == "fails"));
+        assert_eq!(bootstrap.pending_steps(), vec![failing]);
+    }
+
+    #[test]
+    fn steps_run_from_the_shell_root() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let log = BootstrapLog::load(test_root.join("log"));
+        let touch = step("touch", "touch ran");
+        let mut bootstrap = Bootstrap::new(test_root, vec![touch.clone()], log);
+
+        bootstrap.run_step(&touch).expect("Step failed");
+
+        assert!(test_root.join("ran").is_file());
+    }
+
+    #[test]
+    fn steps_get_the_environment_they_are_given() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let log = BootstrapLog::load(test_root.join("log"));
+        let echo = step("echo", "echo \"$HERMIT_OS\" > os");
+        let mut bootstrap = Bootstrap::new(test_root, vec![echo.clone()], log)
+            .with_env(vec![("HERMIT_OS", PathBuf::from("plan9"))]);
+
+        bootstrap.run_step(&echo).expect("Step failed");
+
+        assert_eq!(fs::read_to_string(test_root.join("os")).unwrap(), "plan9\n");
+    }
+
+    #[test]
+    fn steps_need_exactly_one_action() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let log = BootstrapLog::load(test_root.join("log"));
+        let confused = BootstrapStep {
+            script: Some(PathBuf::from("setup.sh")),
+            ..step("confused", "true")
+        };
+        let mut bootstrap = Bootstrap::new(test_root, vec![confused.clone()], log);
+
+        let result = bootstrap.run_step(&confused);
+
+        assert!(matches!(result, Err(Error::InvalidManifest(_))));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::common::*;
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::METADATA_DIR;
+
+/// The name of the file inside a shell's metadata directory that
+/// records which branch each machine uses.
+pub const BRANCHES_FILE_NAME: &str = "branches.toml";
+
+/// The branches that machines use for a shell, keyed by hostname.
+///
+/// This lives in the shell repository itself, so every clone of the
+/// shell knows which branch belongs to which machine.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MachineBranches {
+    #[serde(default)]
+    branches: BTreeMap<String, String>,
+}
+
+impl MachineBranches {
+    pub fn path_in(shell_root: impl AsRef<Path>) -> PathBuf {
+        shell_root
+            .as_ref()
+            .join(METADATA_DIR)
+            .join(BRANCHES_FILE_NAME)
+    }
+
+    /// Reads the machine branches of a shell. A shell without any
+    /// gets an empty set.
+    pub fn load(shell_root: impl AsRef<Path>) -> Result<MachineBranches> {
+        let path = MachineBranches::path_in(shell_root);
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                toml::from_str(&contents).map_err(|err| Error::InvalidMetadata(err.to_string()))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(MachineBranches::default()),
+            Err(err) => Err(Error::InvalidMetadata(err.to_string())),
+        }
+    }
+
+    pub fn save(&self, shell_root: impl AsRef<Path>) -> Result<()> {
+        let path = MachineBranches::path_in(shell_root);
+        let contents =
+            toml::to_string(self).map_err(|err| Error::InvalidMetadata(err.to_string()))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| Error::InvalidMetadata(err.to_string()))?;
+        }
+        fs::write(&path, contents).map_err(|err| Error::InvalidMetadata(err.to_string()))
+    }
+
+    pub fn branch_for(&self, hostname: &str) -> Option<&str> {
+        self.branches.get(hostname).map(String::as_str)
+    }
+
+    pub fn set_branch(&mut self, hostname: &str, branch: &str) {
+        self.branches.insert(hostname.to_owned(), branch.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn a_shell_without_branches_has_none() {
+        let test_root_dir = set_up();
+        let branches = MachineBranches::load(test_root_dir.path()).unwrap();
+
+        assert_eq!(branches.branch_for("laptop"), None);
+    }
+
+    #[test]
+    fn branches_survive_a_round_trip() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+
+        let mut branches = MachineBranches::default();
+        branches.set_branch("laptop", "laptop");
+        branches.set_branch("build-01.example.com", "servers");
+        branches.save(test_root).unwrap();
+
+        let loaded = MachineBranches::load(test_root).unwrap();
+        assert_eq!(loaded, branches);
+        assert_eq!(loaded.branch_for("build-01.example.com"), Some("servers"));
+        assert!(test_root.join(".hermit/branches.toml").is_file());
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{
+    common::*,
+    journal::hex,
+    manifest::{Strategy, METADATA_DIR},
+    status::content_hash,
+};
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// The name of the file inside a shell's metadata directory that
+/// holds the checksums of its files as they were last committed.
+pub const CHECKSUMS_FILE_NAME: &str = "checksums.toml";
+
+/// The SHA-256 of the contents of the copied and secret files of a
+/// shell, by their path in the shell, recorded whenever the shell is
+/// committed. Those are the files that hermit can't tell have been
+/// changed just by looking at a link.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Checksums {
+    #[serde(default)]
+    files: BTreeMap<String, String>,
+}
+
+/// What `hermit verify` found out about a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The file is just as it was committed.
+    Intact,
+    /// The file in the shell has been changed, and the change hasn't
+    /// been committed yet.
+    Changed,
+    /// The copy is of an earlier version of the file in the shell,
+    /// which has been changed since.
+    OutOfDate,
+    /// The copy has been changed into something that was never in the
+    /// shell.
+    Tampered,
+    /// The file in the shell differs from what was committed, though
+    /// git doesn't see a change.
+    Corrupted,
+    /// No checksum has been recorded for the file.
+    Unrecorded,
+}
+
+/// What `hermit verify` found out about one file of a shell.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Verification {
+    pub file: ShellFile,
+    pub verdict: Verdict,
+}
+
+impl Verdict {
+    /// Whether the file has been changed by something other than the
+    /// user editing the shell, rather than just drifting.
+    pub fn is_problem(self) -> bool {
+        matches!(self, Verdict::Tampered | Verdict::Corrupted)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Verdict::Intact => "intact",
+            Verdict::Changed => "changed",
+            Verdict::OutOfDate => "out of date",
+            Verdict::Tampered => "tampered",
+            Verdict::Corrupted => "corrupted",
+            Verdict::Unrecorded => "unrecorded",
+        }
+    }
+}
+
+/// Whether hermit keeps a checksum of `file`.
+pub fn is_checked(file: &ShellFile) -> bool {
+    file.strategy == Strategy::Copy || file.secret
+}
+
+/// The SHA-256 of the contents of the file at `path`, in hex.
+pub fn checksum(path: impl AsRef<Path>) -> io::Result<String> {
+    content_hash(path).map(|hash| hex(&hash))
+}
+
+impl Checksums {
+    /// Where the file is, relative to the root of the shell.
+    pub fn relative_path() -> PathBuf {
+        Path::new(METADATA_DIR).join(CHECKSUMS_FILE_NAME)
+    }
+
+    /// Reads the checksums recorded in a shell. A shell without any
+    /// has none.
+    pub fn load(shell_root: impl AsRef<Path>) -> Result<Checksums> {
+        let path = shell_root.as_ref().join(Checksums::relative_path());
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                toml::from_str(&contents).map_err(|err| Error::InvalidMetadata(err.to_string()))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Checksums::default()),
+            Err(err) => Err(Error::InvalidMetadata(err.to_string())),
+        }
+    }
+
+    /// The checksums of those of `files` that hermit keeps checksums
+    /// of, as they are in the shell now. Files whose names aren't
+    /// valid UTF-8 can't be written to TOML, and are left out.
+    pub fn of_files<'a>(
+        shell_root: &Path,
+        files: impl IntoIterator<Item = &'a ShellFile>,
+    ) -> Result<Checksums> {
+        let mut files_by_source = BTreeMap::new();
+        for file in files.into_iter().filter(|file| is_checked(file)) {
+            if let Some(source) = file.source.to_str() {
+                let checksum = checksum(shell_root.join(source))
+                    .map_err(|err| Error::InvalidMetadata(err.to_string()))?;
+                files_by_source.insert(source.to_owned(), checksum);
+            }
+        }
+        Ok(Checksums {
+            files: files_by_source,
+        })
+    }
+
+    pub fn save(&self, shell_root: impl AsRef<Path>) -> Result<()> {
+        let path = shell_root.as_ref().join(Checksums::relative_path());
+        let contents =
+            toml::to_string(self).map_err(|err| Error::InvalidMetadata(err.to_string()))?;
+        fs::create_dir_all(path.parent().unwrap_or(&path))
+            .map_err(|err| Error::InvalidMetadata(err.to_string()))?;
+        fs::write(&path, contents).map_err(|err| Error::InvalidMetadata(err.to_string()))
+    }
+
+    /// The recorded checksum of the file at `source` in the shell.
+    pub fn get(&self, source: &Path) -> Option<&str> {
+        self.files.get(source.to_str()?).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Checks a file against its recorded checksum. `source` is the
+    /// file in the shell, `copy` where it is copied to, for files that
+    /// are copied, and `has_changes` whether git sees a change to the
+    /// file in the shell that hasn't been committed.
+    pub fn verify(
+        &self,
+        file: &ShellFile,
+        source: &Path,
+        copy: Option<&Path>,
+        has_changes: impl FnOnce() -> bool,
+    ) -> Verdict {
+        let recorded = match self.get(&file.source) {
+            Some(recorded) => recorded,
+            None => return Verdict::Unrecorded,
+        };
+        let current = checksum(source).ok();
+        if let Some(copy) = copy {
+            let copied = checksum(copy).ok();
+            if copied.is_none() || copied == current {
+                // A missing copy is just waiting to be made.
+            } else if copied.as_deref() == Some(recorded) {
+                return Verdict::OutOfDate;
+            } else {
+                return Verdict::Tampered;
+            }
+        }
+        if current.as_deref() == Some(recorded) {
+            Verdict::Intact
+        } else if has_changes() {
+            Verdict::Changed
+        } else {
+            Verdict::Corrupted
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn only_copies_and_secrets_are_checked() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        for name in [".bashrc", ".netrc", "gpg.conf"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        let files = vec![
+            ShellFile::mirrored(".bashrc"),
+            ShellFile::mirrored(".netrc").with_secret(true),
+            ShellFile::mirrored("gpg.conf").with_strategy(Strategy::Copy),
+        ];
+
+        let checksums = Checksums::of_files(root, &files).unwrap();
+        checksums.save(root).unwrap();
+
+        assert_eq!(Checksums::load(root).unwrap(), checksums);
+        assert_eq!(checksums.len(), 2);
+        assert_eq!(checksums.get(Path::new(".bashrc")), None);
+        assert_eq!(
+            checksums.get(Path::new(".netrc")),
+            Some(checksum(root.join(".netrc")).unwrap().as_str())
+        );
+    }
+
+    #[test]
+    fn unreadable_files_are_reported_as_such() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let files = vec![ShellFile::mirrored("gpg.conf").with_strategy(Strategy::Copy)];
+
+        assert!(matches!(
+            Checksums::of_files(root, &files),
+            Err(Error::InvalidMetadata(_))
+        ));
+        fs::write(root.join(".hermit"), "in the way").unwrap();
+        assert!(matches!(
+            Checksums::default().save(root),
+            Err(Error::InvalidMetadata(_))
+        ));
+    }
+
+    #[test]
+    fn tampering_is_told_apart_from_drift() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let source = root.join("gpg.conf");
+        let copy = root.join("copy");
+        let file = ShellFile::mirrored("gpg.conf").with_strategy(Strategy::Copy);
+        fs::write(&source, "old").unwrap();
+        fs::write(&copy, "old").unwrap();
+        let checksums = Checksums::of_files(root, std::slice::from_ref(&file)).unwrap();
+        let verify = || checksums.verify(&file, &source, Some(&copy), || true);
+
+        assert_eq!(verify(), Verdict::Intact);
+
+        fs::write(&source, "new").unwrap();
+        assert_eq!(verify(), Verdict::OutOfDate);
+
+        fs::write(&copy, "evil").unwrap();
+        assert_eq!(verify(), Verdict::Tampered);
+
+        fs::write(&copy, "new").unwrap();
+        assert_eq!(verify(), Verdict::Changed);
+        assert_eq!(
+            checksums.verify(&file, &source, Some(&copy), || false),
+            Verdict::Corrupted
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! What hermit's commands need in order to run, so that whatever runs
+//! them, like the command line tool, can tell up front whether one can
+//! run at all, instead of finding out halfway through it.
+
+use crate::{common::*, Hermit};
+
+/// A command that hermit is asked to run, with as much of what it was
+/// given as it takes to tell what it needs.
+pub struct Invocation<'a> {
+    /// The name of the command, like `sync`.
+    pub command: &'a str,
+    /// The subcommand it was given, like `add` for `packages add`.
+    pub subcommand: Option<&'a str>,
+    /// Whether the command was given the flag with this name.
+    pub flag: &'a dyn Fn(&str) -> bool,
+}
+
+/// What becomes of a command in read-only mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadOnly {
+    /// It only looks at things, so it runs as usual.
+    Runs,
+    /// It shows what it would change instead, as with `--dry-run`.
+    Previews,
+    /// It can't show what it would change without changing it, so it
+    /// doesn't run at all.
+    Refused,
+}
+
+impl Invocation<'_> {
+    /// Whether the command can't do what it is for without the
+    /// network. For `sync` that depends on the current shell, since one
+    /// that is kept in a cloud folder is synced with a directory on this
+    /// machine, with links made in `target_root`.
+    pub fn needs_network<C: Config>(&self, hermit: &Hermit<C>, target_root: &Path) -> bool {
+        match self.command {
+            "clone" | "provision" | "push" | "upgrade" => true,
+            "sync" => matches!(hermit.sync_dir(target_root), Ok(None)),
+            "init" => (self.flag)("push"),
+            _ => false,
+        }
+    }
+
+    /// What the command does in read-only mode.
+    pub fn read_only(&self) -> ReadOnly {
+        let flag = self.flag;
+        match (self.command, self.subcommand) {
+            ("cd", _)
+            | ("doctor", _)
+            | ("env", _)
+            | ("files", _)
+            | ("history", _)
+            | ("list", _)
+            | ("plan", _)
+            | ("prompt-info", _)
+            | ("shell", _)
+            | ("shell-init", _)
+            | ("stats", _)
+            | ("status", _)
+            | ("version", _) => ReadOnly::Runs,
+            ("branch", None) | ("packages", Some("diff")) | ("remote", Some("list")) => {
+                ReadOnly::Runs
+            }
+            // Git runs these filters on its own, to show the shell's files.
+            ("secret", Some("clean")) | ("secret", Some("smudge")) => ReadOnly::Runs,
+            ("snapshot", _) if flag("list") => ReadOnly::Runs,
+            ("upgrade", _) if flag("check") => ReadOnly::Runs,
+            ("verify", _) if !flag("record") => ReadOnly::Runs,
+            ("add", _)
+            | ("apply", _)
+            | ("init", _)
+            | ("inhabit", _)
+            | ("nuke", _)
+            | ("push", _)
+            | ("sync", _)
+            | ("unlink", _)
+            | ("packages", Some("add")) => ReadOnly::Previews,
+            _ => ReadOnly::Refused,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::mock::MockConfig;
+
+    fn invocation<'a>(command: &'a str, flag: &'a dyn Fn(&str) -> bool) -> Invocation<'a> {
+        Invocation {
+            command,
+            subcommand: None,
+            flag,
+        }
+    }
+
+    #[test]
+    fn only_commands_that_talk_to_remotes_need_the_network() {
+        let hermit = Hermit::new(MockConfig::new());
+        let home = Path::new("/home/geoff");
+        let needs_network = |command, flag: &dyn Fn(&str) -> bool| {
+            invocation(command, flag).needs_network(&hermit, home)
+        };
+
+        assert!(needs_network("clone", &|_| false));
+        assert!(needs_network("push", &|_| false));
+        assert!(needs_network("init", &|flag| flag == "push"));
+        assert!(!needs_network("init", &|_| false));
+        assert!(!needs_network("status", &|_| false));
+    }
+
+    #[test]
+    fn read_only_mode_previews_what_it_can_and_refuses_the_rest() {
+        let read_only = |command, subcommand, flag: &dyn Fn(&str) -> bool| {
+            Invocation {
+                command,
+                subcommand,
+                flag,
+            }
+            .read_only()
+        };
+
+        assert_eq!(read_only("status", None, &|_| false), ReadOnly::Runs);
+        assert_eq!(
+            read_only("packages", Some("diff"), &|_| false),
+            ReadOnly::Runs
+        );
+        assert_eq!(
+            read_only("upgrade", None, &|flag| flag == "check"),
+            ReadOnly::Runs
+        );
+
+        assert_eq!(read_only("add", None, &|_| false), ReadOnly::Previews);
+        assert_eq!(read_only("sync", None, &|_| false), ReadOnly::Previews);
+        assert_eq!(read_only("push", None, &|_| false), ReadOnly::Previews);
+        assert_eq!(
+            read_only("packages", Some("add"), &|_| false),
+            ReadOnly::Previews
+        );
+
+        assert_eq!(read_only("upgrade", None, &|_| false), ReadOnly::Refused);
+        assert_eq!(
+            read_only("packages", Some("install"), &|_| false),
+            ReadOnly::Refused
+        );
+        assert_eq!(
+            read_only("secret", Some("unlock"), &|_| false),
+            ReadOnly::Refused
+        );
+        assert_eq!(
+            read_only("branch", Some("create"), &|_| false),
+            ReadOnly::Refused
+        );
+    }
+}
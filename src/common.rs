@@ -19,8 +19,6 @@ pub use std::{
 // External crate imports
 // ##################################################
 
-pub use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
-
 pub use thiserror::Error;
 
 pub use walkdir::{self, WalkDir};
@@ -30,10 +28,11 @@ pub use walkdir::{self, WalkDir};
 // ##################################################
 
 pub use crate::{
-    config::{Config, FsConfig},
+    config::Config,
     env,
     file_operations::FileOperations,
-    hermit::{Error, Hermit, Result},
+    hermit::{Error, Result},
+    manifest::{Manifest, ShellFile},
     message,
     shell::Shell,
 };
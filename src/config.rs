@@ -1,12 +1,70 @@
-use crate::common::*;
-
+use std::{collections::BTreeSet, ffi::OsString};
+
+use crate::{
+    common::*,
+    fs_backend::{FileKind, FsBackend, RealFs},
+    host::Host,
+    ignore_rules::IgnoreRules,
+    index, manifest,
+    settings::Settings,
+    shell_info::ShellInfo,
+    state,
+};
+
+/// The name of the directory in the hermit root that holds the shells.
+pub const SHELLS_DIR_NAME: &str = "shells";
+
+/// Everything hermit needs to know about where shells live and which
+/// one is in use.
 pub trait Config {
-    type IntoIterator: IntoIterator<Item = PathBuf>;
+    type IntoIterator: IntoIterator<Item = ShellFile>;
 
+    /// The hermit root directory, which holds all of the shells.
     fn root_path(&self) -> &PathBuf;
 
+    /// The machine that hermit is running on.
+    fn host(&self) -> &Host;
+
+    /// The settings from the hermit root's `config.toml`.
+    fn settings(&self) -> &Settings;
+
     fn shell_root_path(&self) -> PathBuf {
-        self.root_path().join("shells")
+        self.root_path().join(SHELLS_DIR_NAME)
+    }
+
+    /// The directory in the hermit root that holds what hermit keeps
+    /// track of on this machine, like its journal, apart from the
+    /// shells.
+    fn state_path(&self) -> PathBuf {
+        state::dir(self.root_path())
+    }
+
+    /// The contents of the file `name` in the state directory, or
+    /// `None` if there isn't one yet.
+    fn read_state(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        state::read(&RealFs, &self.state_path().join(name))
+    }
+
+    /// Replaces the file `name` in the state directory all at once, so
+    /// that it is never left half written.
+    fn write_state(&self, name: &str, contents: &[u8]) -> Result<()> {
+        Ok(state::write_atomic(
+            &RealFs,
+            &self.state_path().join(name),
+            contents,
+        )?)
+    }
+
+    /// Whether `hermit init` (or `clone`) has set up the hermit root.
+    fn is_initialized(&self) -> bool {
+        self.shell_root_path().is_dir()
+    }
+
+    /// Whether the shells all live in one shared git repository, with
+    /// each shell a subdirectory of it, instead of every shell being
+    /// a repository of its own.
+    fn is_shared_repo(&self) -> bool {
+        self.shell_root_path().join(".git").exists()
     }
 
     fn current_shell_name(&self) -> Option<&str>;
@@ -16,153 +74,461 @@ pub trait Config {
             .map(|name| self.shell_root_path().join(name))
     }
 
-    fn set_current_shell_name(&mut self, name: &str) -> io::Result<()>;
+    /// Changes the current shell. The shell that was current before,
+    /// if it was a different one, becomes the previous shell. Names
+    /// that `validate_shell_name` rejects are an error.
+    fn set_current_shell_name(&mut self, name: &str) -> Result<()>;
+
+    /// The shell that was current before the current one.
+    fn previous_shell_name(&self) -> Option<&str>;
 
     fn shell_exists(&self, name: &str) -> bool;
 
-    fn shell_files(&self, name: &str) -> Self::IntoIterator;
+    /// The names of all of the shells, in alphabetical order.
+    fn shell_names(&self) -> Vec<String>;
+
+    /// The files tracked by a shell, each paired with where it should
+    /// be linked. They come in a stable order: sorted by path in the
+    /// shell, one component at a time, so everything inside a
+    /// directory comes right after the files that sort before it.
+    fn shell_files(&self, name: &str) -> Result<Self::IntoIterator>;
+
+    /// Walks the files of a shell afresh, instead of trusting anything
+    /// remembered about them from earlier walks, and returns how many
+    /// there are.
+    fn refresh_index(&self, name: &str) -> Result<usize> {
+        Ok(self.shell_files(name)?.into_iter().count())
+    }
+}
+
+/// Checks that `name` can safely be used as the name of a directory
+/// in the shells directory: it must not be empty, contain a path
+/// separator or control characters, or start with a dot (which also
+/// rules out `.` and `..`).
+pub fn validate_shell_name(name: &str) -> Result<()> {
+    let reason = if name.is_empty() {
+        "it is empty"
+    } else if name.contains(['/', '\\']) {
+        "it contains a path separator"
+    } else if name.starts_with('.') {
+        "it starts with a dot"
+    } else if name.chars().any(char::is_control) {
+        "it contains control characters"
+    } else {
+        return Ok(());
+    };
+    Err(Error::InvalidShellName {
+        name: name.escape_default().to_string(),
+        reason,
+    })
 }
 
+/// A `Config` backed by a hermit root directory, on disk unless
+/// another `FsBackend` is given.
 #[derive(Clone)]
 pub struct FsConfig {
     root_path: PathBuf,
+    fs: Rc<dyn FsBackend>,
     current_shell: Option<String>,
+    previous_shell: Option<String>,
+    shell_override: Option<String>,
+    host: Host,
+    settings: Settings,
 }
 
-fn read_shell_from_path(path: &PathBuf) -> io::Result<String> {
-    let mut file = File::open(path)?;
-    let mut current_shell = String::new();
-
-    file.read_to_string(&mut current_shell)?;
-
-    Ok(current_shell)
+fn config_path(root_path: &Path) -> PathBuf {
+    root_path.join("current_shell")
 }
 
-fn config_path(root_path: &PathBuf) -> PathBuf {
-    root_path.join("current_shell")
+fn previous_shell_path(root_path: &Path) -> PathBuf {
+    root_path.join("previous_shell")
 }
 
 impl FsConfig {
     pub fn new(root_path: impl AsRef<Path>) -> anyhow::Result<FsConfig> {
+        FsConfig::with_backend(root_path, Rc::new(RealFs))
+    }
+
+    /// Like `new`, but reads and changes the hermit root in `fs`.
+    pub fn with_backend(
+        root_path: impl AsRef<Path>,
+        fs: Rc<dyn FsBackend>,
+    ) -> anyhow::Result<FsConfig> {
         let root_path = PathBuf::from(root_path.as_ref());
-        fs::create_dir_all(&root_path)?; // TODO: what do I do with this error?
-        let config_path = config_path(&root_path);
-        let current_shell = read_shell_from_path(&config_path).ok();
+        state::migrate(fs.as_ref(), &root_path)?;
+        let current_shell = fs.read_to_string(&config_path(&root_path)).ok();
+        let previous_shell = fs.read_to_string(&previous_shell_path(&root_path)).ok();
+        let settings = Settings::load_with(fs.as_ref(), &root_path)?;
 
         Ok(FsConfig {
             root_path,
+            fs,
             current_shell,
+            previous_shell,
+            shell_override: None,
+            host: Host::current(),
+            settings,
         })
     }
 
+    /// Acts on the shell `name` instead of the current one, until the
+    /// current shell is changed. The current shell stays as it is on
+    /// disk.
+    pub fn with_shell_override(self, name: Option<String>) -> FsConfig {
+        FsConfig {
+            shell_override: name,
+            ..self
+        }
+    }
+
     fn config_path(&self) -> PathBuf {
-        config_path(&self.root_path())
+        config_path(self.root_path())
     }
 }
 
 impl Config for FsConfig {
-    type IntoIterator = Files;
+    type IntoIterator = ShellFiles;
 
     fn root_path(&self) -> &PathBuf {
         &self.root_path
     }
 
-    fn current_shell_name(&self) -> Option<&str> {
-        self.current_shell.as_ref().map(Borrow::borrow)
+    fn host(&self) -> &Host {
+        &self.host
     }
 
-    fn set_current_shell_name(&mut self, name: &str) -> io::Result<()> {
-        let mut file = File::create(&self.config_path())?;
+    fn settings(&self) -> &Settings {
+        &self.settings
+    }
 
-        file.write_all(name.as_bytes())?;
+    fn is_initialized(&self) -> bool {
+        self.fs.is_dir(&self.shell_root_path())
+    }
 
-        self.current_shell = Some(name.to_string());
+    fn read_state(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        state::read(self.fs.as_ref(), &self.state_path().join(name))
+    }
+
+    fn write_state(&self, name: &str, contents: &[u8]) -> Result<()> {
+        Ok(state::write_atomic(
+            self.fs.as_ref(),
+            &self.state_path().join(name),
+            contents,
+        )?)
+    }
+
+    fn is_shared_repo(&self) -> bool {
+        self.fs.exists(&self.shell_root_path().join(".git"))
+    }
+
+    fn current_shell_name(&self) -> Option<&str> {
+        self.shell_override
+            .as_ref()
+            .or(self.current_shell.as_ref())
+            .map(Borrow::borrow)
+    }
+
+    fn set_current_shell_name(&mut self, name: &str) -> Result<()> {
+        validate_shell_name(name)?;
+        self.shell_override = None;
+        state::write_atomic(self.fs.as_ref(), &self.config_path(), name.as_bytes())?;
+
+        let previous_shell = self.current_shell.replace(name.to_string());
+        if let Some(previous_shell) = previous_shell.filter(|previous| previous != name) {
+            state::write_atomic(
+                self.fs.as_ref(),
+                &previous_shell_path(&self.root_path),
+                previous_shell.as_bytes(),
+            )?;
+            self.previous_shell = Some(previous_shell);
+        }
 
         Ok(())
     }
 
+    fn previous_shell_name(&self) -> Option<&str> {
+        self.previous_shell.as_ref().map(Borrow::borrow)
+    }
+
     fn shell_exists(&self, name: &str) -> bool {
         let shell_path = self.shell_root_path().join(name);
-        shell_path.is_dir()
+        validate_shell_name(name).is_ok() && self.fs.is_dir(&shell_path)
     }
 
-    fn shell_files(&self, _name: &str) -> Self::IntoIterator {
-        Files::new(self.current_shell_path())
+    fn shell_names(&self) -> Vec<String> {
+        let shell_root = self.shell_root_path();
+        self.fs
+            .read_dir(&shell_root)
+            .into_iter()
+            .flatten()
+            .filter(|name| self.fs.is_dir(&shell_root.join(name)))
+            .filter_map(|name| name.into_string().ok())
+            .filter(|name| !name.starts_with('.'))
+            .collect()
+    }
+
+    fn shell_files(&self, name: &str) -> Result<Self::IntoIterator> {
+        let shell_path = self.shell_root_path().join(name);
+        let mut manifest = Manifest::load_with(self.fs.as_ref(), &shell_path)?;
+        let info = ShellInfo::load_with(self.fs.as_ref(), &shell_path)?;
+        manifest.add_dots = info.add_dots;
+        let whole_dirs = manifest.whole_directories(&self.host);
+        let max_depth = self.settings.walk.max_depth;
+        let key = format!("{:?} {:?} {:?}", whole_dirs, max_depth, info.follow_links);
+        let files = Files::with_backend(Some(shell_path), self.fs.clone())
+            .with_whole_dirs(whole_dirs)
+            .with_max_depth(max_depth)
+            .with_follow_links(info.follow_links);
+        Ok(ShellFiles {
+            files: index::files(
+                self.fs.as_ref(),
+                &index::path(&self.state_path(), name),
+                &key,
+                files,
+            ),
+            manifest,
+            host: self.host.clone(),
+        })
+    }
+
+    fn refresh_index(&self, name: &str) -> Result<usize> {
+        let path = index::path(&self.state_path(), name);
+        if self.fs.exists(&path) {
+            self.fs.remove_file(&path)?;
+        }
+        Ok(self.shell_files(name)?.into_iter().count())
+    }
+}
+
+/// The files of a shell paired with the manifest that says where each
+/// of them should be linked on this host.
+pub struct ShellFiles {
+    files: Vec<PathBuf>,
+    manifest: Manifest,
+    host: Host,
+}
+
+impl IntoIterator for ShellFiles {
+    type Item = ShellFile;
+    type IntoIter = ShellFilesIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ShellFilesIter {
+            files: self.files.into_iter(),
+            manifest: self.manifest,
+            host: self.host,
+        }
+    }
+}
+
+/// An iterator resolving each walked file through the shell manifest,
+/// skipping files that the manifest excludes from this host.
+pub struct ShellFilesIter {
+    files: std::vec::IntoIter<PathBuf>,
+    manifest: Manifest,
+    host: Host,
+}
+
+impl Iterator for ShellFilesIter {
+    type Item = ShellFile;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let path = self.files.next()?;
+            if let Some(file) = self.manifest.resolve(path, &self.host) {
+                return Some(file);
+            }
+        }
     }
 }
 
-/// A wrapper on a DirEntry iterator.
+/// The files below a directory, relative to it, in the order `Files`
+/// walks them.
 ///
-/// This type can only be constructed by the `Files` wrapper, and it
-/// handles cleaning up the iterator of `DirEntry`s into an iterator
-/// of `PathBuf` to the files in that stream, and stripping them of
-/// the walk root path prefix.
-pub struct FilesIter<T>(Option<(T, PathBuf)>);
-
-impl<T> Iterator for FilesIter<T>
-where
-    T: Iterator<Item = StdResult<walkdir::DirEntry, walkdir::Error>>,
-{
+/// This type can only be constructed by the `Files` wrapper.
+pub struct FilesIter {
+    fs: Rc<dyn FsBackend>,
+    root: PathBuf,
+    whole_dirs: BTreeSet<PathBuf>,
+    max_depth: Option<usize>,
+    follow_links: bool,
+    /// Where the links that have been followed lead, so that none of
+    /// them is walked twice.
+    followed: BTreeSet<PathBuf>,
+    /// The directories that have been read so far.
+    walked: Vec<PathBuf>,
+    ignore_rules: IgnoreRules,
+    /// The entries still to visit, the next one last, each with its
+    /// depth below the root and what is there.
+    pending: Vec<(PathBuf, usize, io::Result<FileKind>)>,
+}
+
+impl FilesIter {
+    /// The directories that the walk has read so far, which is all of
+    /// the ones that what it found depends on once it is over.
+    pub fn walked_dirs(&self) -> &[PathBuf] {
+        &self.walked
+    }
+
+    /// Queues up the entries of `dir` to be visited next.
+    fn push_entries(&mut self, dir: &Path, depth: usize) {
+        self.walked.push(dir.to_path_buf());
+        let paths = self
+            .fs
+            .read_dir(dir)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|name| dir.join(name))
+            .collect::<Vec<_>>();
+        let kinds = self.fs.kinds(&paths);
+        self.pending.extend(
+            paths
+                .into_iter()
+                .zip(kinds)
+                .rev()
+                .map(|(path, kind)| (path, depth, kind)),
+        );
+    }
+
+    /// Whether to walk into the directory that the link at `path` leads
+    /// to. Directories that the walk is already inside, or that another
+    /// link already led to, aren't walked again, so that links going in
+    /// circles can't make the walk go on forever.
+    fn follow(&mut self, path: &Path) -> bool {
+        let target = match self.fs.canonicalize(path) {
+            Ok(target) => target,
+            Err(_) => return false,
+        };
+        let is_inside = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root))
+            .any(|dir| {
+                self.fs
+                    .canonicalize(dir)
+                    .is_ok_and(|dir| dir.starts_with(&target))
+            });
+        !is_inside && self.followed.insert(target)
+    }
+}
+
+impl Iterator for FilesIter {
     type Item = PathBuf;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some((ref mut iter, ref prefix_path)) = self.0 {
-            loop {
-                match iter.next() {
-                    Some(Ok(entry)) => {
-                        let file_type = entry.file_type();
-                        if file_type.is_dir() {
-                            continue;
-                        }
-
-                        let file_path = entry.path().to_path_buf();
-                        let shell_relative_path = file_path
-                            .strip_prefix(prefix_path)
-                            .unwrap() // this unwrap is safe because
-                            .to_path_buf(); // of the Files::new constructor
-                        return Some(shell_relative_path);
-                    }
-                    Some(Err(_)) => continue,
-                    None => return None,
-                };
+        loop {
+            let (path, depth, kind) = self.pending.pop()?;
+            let kind = match kind {
+                Ok(kind) => kind,
+                Err(_) => continue,
+            };
+            let is_dir = kind == FileKind::Dir
+                || (kind == FileKind::Symlink
+                    && self.follow_links
+                    && self
+                        .fs
+                        .canonicalize(&path)
+                        .is_ok_and(|target| self.fs.is_dir(&target)));
+            let name = path.file_name().map(OsString::from).unwrap_or_default();
+            if manifest::is_internal(&name, depth) || self.ignore_rules.is_ignored(&path, is_dir) {
+                continue;
+            }
+
+            let relative = path.strip_prefix(&self.root).ok().map(Path::to_path_buf);
+            if is_dir
+                && !relative
+                    .as_ref()
+                    .is_some_and(|dir| self.whole_dirs.contains(dir))
+            {
+                if self.max_depth.is_none_or(|max_depth| depth < max_depth)
+                    && (kind == FileKind::Dir || self.follow(&path))
+                {
+                    self.push_entries(&path, depth + 1);
+                }
+                continue;
             }
-        } else {
-            None
+            return relative;
         }
     }
 }
 
-/// A wrapper on WalkDir that handles nullability and bundles the walk
-/// root path.
+/// A walk of the files below a directory, if there is one. Entries
+/// are visited in order of their file names, so walks don't depend on
+/// the order the filesystem lists them in, and symlinks aren't
+/// followed unless asked to.
 ///
-/// In particular, this pair of values is used to generate `PathBuf`s
-/// relative to the specified root directory with
-/// `PathBuf::strip_prefix`, and since the `WalkDir` was created with
-/// the same path as `FilesIter` will use to strip the prefix, it is
-/// always safe to just unwrap the result returned by `strip_prefix`.
-pub struct Files(Option<(WalkDir, PathBuf)>);
+/// The walk never descends into `.git` or hermit's own metadata (see
+/// `manifest::is_internal`), so those are never linked, and neither
+/// is anything that a `.gitignore` or `.hermitignore` in the shell
+/// ignores.
+pub struct Files {
+    fs: Rc<dyn FsBackend>,
+    root: Option<PathBuf>,
+    whole_dirs: BTreeSet<PathBuf>,
+    max_depth: Option<usize>,
+    follow_links: bool,
+}
 
 impl Files {
     /// Constructs a new `Files` from a directory path.
     pub fn new(shell_path: Option<impl AsRef<Path>>) -> Files {
-        let walker = shell_path.map(|path| {
-            (
-                WalkDir::new(&path).min_depth(1).follow_links(false),
-                PathBuf::from(path.as_ref()),
-            )
-        });
-        Files(walker)
+        Files::with_backend(shell_path, Rc::new(RealFs))
+    }
+
+    /// Like `new`, but walks the directory in `fs`.
+    pub fn with_backend(shell_path: Option<impl AsRef<Path>>, fs: Rc<dyn FsBackend>) -> Files {
+        Files {
+            fs,
+            root: shell_path.map(|path| path.as_ref().to_path_buf()),
+            whole_dirs: BTreeSet::new(),
+            max_depth: None,
+            follow_links: false,
+        }
+    }
+
+    /// Makes the walk yield the directories at these paths, relative
+    /// to the root, instead of the files below them.
+    pub fn with_whole_dirs(self, whole_dirs: BTreeSet<PathBuf>) -> Files {
+        Files { whole_dirs, ..self }
+    }
+
+    /// Stops the walk from looking at anything more than `max_depth`
+    /// directories deep, if there is a limit.
+    pub fn with_max_depth(self, max_depth: Option<usize>) -> Files {
+        Files { max_depth, ..self }
+    }
+
+    /// Makes the walk go into the directories that symlinks lead to,
+    /// as if they were in the directory being walked.
+    pub fn with_follow_links(self, follow_links: bool) -> Files {
+        Files {
+            follow_links,
+            ..self
+        }
     }
 }
 
 impl IntoIterator for Files {
     type Item = PathBuf;
-    type IntoIter = FilesIter<walkdir::IntoIter>;
+    type IntoIter = FilesIter;
 
     fn into_iter(self) -> Self::IntoIter {
-        let Files(opt) = self;
-        let iter_opt = opt.map(|(walker, path)| (walker.into_iter(), path));
-        FilesIter(iter_opt)
+        let root = self.root.clone().unwrap_or_default();
+        let mut iter = FilesIter {
+            ignore_rules: IgnoreRules::with_backend(&root, self.fs.clone()),
+            fs: self.fs,
+            pending: vec![],
+            whole_dirs: self.whole_dirs,
+            max_depth: self.max_depth,
+            follow_links: self.follow_links,
+            followed: BTreeSet::new(),
+            walked: vec![],
+            root,
+        };
+        if let Some(root) = self.root {
+            iter.push_entries(&root, 1);
+        }
+        iter
     }
 }
 
@@ -170,16 +536,28 @@ impl IntoIterator for Files {
 pub mod mock {
     use super::Config;
 
+    use crate::{hermit::Result, host::Host, manifest::ShellFile, settings::Settings};
+
     use std::borrow::Borrow;
-    use std::io;
     use std::path::{Path, PathBuf};
 
     #[derive(Clone, Debug, Eq, PartialEq)]
     pub struct MockConfig {
         root_path: PathBuf,
         current_shell: String,
+        previous_shell: Option<String>,
         allowed_shell_names: Vec<String>,
-        files: Vec<PathBuf>,
+        files: Vec<ShellFile>,
+        host: Host,
+        settings: Settings,
+        shared_repo: bool,
+        initialized: bool,
+    }
+
+    impl Default for MockConfig {
+        fn default() -> Self {
+            Self::new()
+        }
     }
 
     impl MockConfig {
@@ -188,7 +566,12 @@ pub mod mock {
                 root_path: PathBuf::from("/"),
                 allowed_shell_names: vec!["default".to_owned()],
                 current_shell: "default".to_owned(),
+                previous_shell: None,
                 files: vec![],
+                host: Host::default(),
+                settings: Settings::default(),
+                shared_repo: false,
+                initialized: true,
             }
         }
 
@@ -197,51 +580,105 @@ pub mod mock {
                 root_path: PathBuf::from(root.as_ref()),
                 allowed_shell_names: vec!["default".to_owned()],
                 current_shell: "default".to_owned(),
+                previous_shell: None,
                 files: vec![],
+                host: Host::default(),
+                settings: Settings::default(),
+                shared_repo: false,
+                initialized: true,
             }
         }
 
         pub fn set_paths(&mut self, paths: Vec<impl AsRef<Path>>) {
-            self.files = paths
-                .into_iter()
-                .map(|p| PathBuf::from(p.as_ref()))
-                .collect();
+            self.files = paths.into_iter().map(ShellFile::mirrored).collect();
+        }
+
+        pub fn add_shell(&mut self, name: &str) {
+            self.allowed_shell_names.push(name.to_owned());
+        }
+
+        pub fn set_files(&mut self, files: Vec<ShellFile>) {
+            self.files = files;
+        }
+
+        pub fn set_shared_repo(&mut self, shared_repo: bool) {
+            self.shared_repo = shared_repo;
+        }
+
+        pub fn set_initialized(&mut self, initialized: bool) {
+            self.initialized = initialized;
         }
     }
 
     impl Config for MockConfig {
-        type IntoIterator = Vec<PathBuf>;
+        type IntoIterator = Vec<ShellFile>;
 
         fn root_path(&self) -> &PathBuf {
             &self.root_path
         }
 
+        fn host(&self) -> &Host {
+            &self.host
+        }
+
+        fn settings(&self) -> &Settings {
+            &self.settings
+        }
+
         fn current_shell_name(&self) -> Option<&str> {
             Some(&self.current_shell).map(|shell_name| shell_name.borrow())
         }
 
-        fn set_current_shell_name(&mut self, name: &str) -> io::Result<()> {
-            self.current_shell = name.to_owned();
+        fn set_current_shell_name(&mut self, name: &str) -> Result<()> {
+            super::validate_shell_name(name)?;
+            if self.current_shell != name {
+                let previous = std::mem::replace(&mut self.current_shell, name.to_owned());
+                self.previous_shell = Some(previous);
+            }
             Ok(())
         }
 
+        fn previous_shell_name(&self) -> Option<&str> {
+            self.previous_shell.as_deref()
+        }
+
         fn shell_exists(&self, name: &str) -> bool {
             self.allowed_shell_names.contains(&name.to_owned())
         }
 
-        fn shell_files(&self, _name: &str) -> Self::IntoIterator {
-            self.files.clone()
+        fn shell_names(&self) -> Vec<String> {
+            self.allowed_shell_names.clone()
+        }
+
+        fn is_shared_repo(&self) -> bool {
+            self.shared_repo
+        }
+
+        fn is_initialized(&self) -> bool {
+            self.initialized
+        }
+
+        fn shell_files(&self, _name: &str) -> Result<Self::IntoIterator> {
+            Ok(self.files.clone())
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Config, FsConfig};
+    use super::{validate_shell_name, Config, FsConfig};
+
+    use crate::{
+        file_operations::Op,
+        manifest::{Granularity, ShellFile},
+        settings::SETTINGS_FILE_NAME,
+        Error, FileOperations, Shell,
+    };
 
     use std::fs::{self, File};
     use std::io::prelude::*;
     use std::path::{Path, PathBuf};
+    use std::rc::Rc;
 
     use tempfile::{tempdir, TempDir};
 
@@ -267,19 +704,29 @@ mod test {
     fn has_a_root_path() {
         let test_root_dir = set_up("default", vec!["default"]);
         let test_root = test_root_dir.path();
-        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let config = FsConfig::new(test_root).expect("failed to create FSConfig");
         assert_eq!(config.root_path(), &test_root);
     }
 
     #[test]
-    fn creating_a_config_creates_its_root_dir() {
+    fn creating_a_config_leaves_a_missing_root_alone() {
         let test_root_dir = set_up("default", vec!["default"]);
         let test_root = test_root_dir.path();
         let config_root = test_root.join(".hermit");
 
+        let config = FsConfig::new(&config_root).expect("failed to create FSConfig");
         assert!(!config_root.exists());
-        FsConfig::new(&config_root).expect("failed to create FSConfig");
-        assert!(config_root.exists());
+        assert!(!config.is_initialized());
+    }
+
+    #[test]
+    fn setting_the_current_shell_creates_the_root_dir() {
+        let test_root_dir = set_up("default", vec!["default"]);
+        let config_root = test_root_dir.path().join(".hermit");
+
+        let mut config = FsConfig::new(&config_root).expect("failed to create FSConfig");
+        config.set_current_shell_name("default").unwrap();
+        assert!(config_root.join("current_shell").is_file());
     }
 
     #[test]
@@ -294,10 +741,10 @@ mod test {
     fn can_set_the_current_shell_name() {
         let test_root_dir = set_up("default", vec!["default"]);
         let test_root = test_root_dir.path();
-        let mut config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let mut config = FsConfig::new(test_root).expect("failed to create FSConfig");
         config.set_current_shell_name("current").unwrap();
 
-        let mut config_file = File::open(&test_root.join("current_shell")).unwrap();
+        let mut config_file = File::open(test_root.join("current_shell")).unwrap();
         let mut name_on_disk = String::new();
         config_file.read_to_string(&mut name_on_disk).unwrap();
 
@@ -306,6 +753,90 @@ mod test {
         assert_eq!(name_on_disk, current);
     }
 
+    #[test]
+    fn a_shell_override_takes_the_place_of_the_current_shell() {
+        let test_root = set_up("default", vec!["default", "work"]);
+        let mut config = FsConfig::new(&test_root)
+            .expect("failed to create FSConfig")
+            .with_shell_override(Some("work".to_owned()));
+
+        assert_eq!(config.current_shell_name(), Some("work"));
+        let on_disk = fs::read_to_string(test_root.path().join("current_shell")).unwrap();
+        assert_eq!(on_disk, "default");
+
+        config.set_current_shell_name("default").unwrap();
+        assert_eq!(config.current_shell_name(), Some("default"));
+    }
+
+    #[test]
+    fn remembers_the_previous_shell() {
+        let test_root_dir = set_up("default", vec!["default", "work"]);
+        let test_root = test_root_dir.path();
+        let mut config = FsConfig::new(test_root).expect("failed to create FSConfig");
+        assert_eq!(config.previous_shell_name(), None);
+
+        config.set_current_shell_name("work").unwrap();
+        assert_eq!(config.previous_shell_name(), Some("default"));
+
+        let config = FsConfig::new(test_root).expect("failed to create FSConfig");
+        assert_eq!(config.previous_shell_name(), Some("default"));
+    }
+
+    #[test]
+    fn setting_the_same_shell_keeps_the_previous_shell() {
+        let test_root_dir = set_up("default", vec!["default", "work"]);
+        let test_root = test_root_dir.path();
+        let mut config = FsConfig::new(test_root).expect("failed to create FSConfig");
+
+        config.set_current_shell_name("work").unwrap();
+        config.set_current_shell_name("work").unwrap();
+        assert_eq!(config.previous_shell_name(), Some("default"));
+    }
+
+    #[test]
+    fn state_is_kept_apart_from_the_shells() {
+        let test_root_dir = set_up("default", vec!["default"]);
+        let test_root = test_root_dir.path();
+        let config = FsConfig::new(test_root).expect("failed to create FSConfig");
+        assert_eq!(config.read_state("plan.json").unwrap(), None);
+
+        config.write_state("plan.json", b"{}").unwrap();
+
+        assert_eq!(config.state_path(), test_root.join("state"));
+        assert_eq!(fs::read(test_root.join("state/plan.json")).unwrap(), b"{}");
+        assert_eq!(
+            config.read_state("plan.json").unwrap(),
+            Some(b"{}".to_vec())
+        );
+    }
+
+    #[test]
+    fn rejects_shell_names_that_are_not_plain_directory_names() {
+        for name in &["", "a/b", "..", ".hidden", "tab\there", "back\\slash"] {
+            assert!(
+                validate_shell_name(name).is_err(),
+                "'{}' should be invalid",
+                name
+            );
+        }
+        assert!(validate_shell_name("work-laptop_2.0").is_ok());
+    }
+
+    #[test]
+    fn cannot_switch_to_an_invalid_shell_name() {
+        let test_root = set_up("default", vec!["default"]);
+        let mut config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+
+        assert_eq!(
+            config.set_current_shell_name("../escape"),
+            Err(Error::InvalidShellName {
+                name: "../escape".to_owned(),
+                reason: "it contains a path separator",
+            })
+        );
+        assert_eq!(config.current_shell_name(), Some("default"));
+    }
+
     #[test]
     fn can_confirm_a_shell_exists() {
         let test_root = set_up("default", vec!["default", "other"]);
@@ -320,6 +851,27 @@ mod test {
         let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
 
         assert!(!config.shell_exists("another"));
+        assert!(!config.shell_exists(".."));
+        assert!(!config.shell_exists("."));
+    }
+
+    #[test]
+    fn lists_the_names_of_all_shells() {
+        let test_root = set_up("default", vec!["work", "default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+
+        assert_eq!(config.shell_names(), vec!["default", "work"]);
+    }
+
+    #[test]
+    fn a_shells_directory_with_a_repository_is_shared() {
+        let test_root = set_up("default", vec!["work", "default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        assert!(!config.is_shared_repo());
+
+        fs::create_dir(config.shell_root_path().join(".git")).unwrap();
+        assert!(config.is_shared_repo());
+        assert_eq!(config.shell_names(), vec!["default", "work"]);
     }
 
     #[test]
@@ -327,12 +879,13 @@ mod test {
         let test_root = set_up("default", vec!["default"]);
         let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
         let shell_root = config.shell_root_path().join("default");
-        File::create(&shell_root.join("file1")).expect("Failed to create test file");
+        File::create(shell_root.join("file1")).expect("Failed to create test file");
 
         let files = config
             .shell_files("default")
+            .expect("Failed to list shell files")
             .into_iter()
-            .map(|f| f.to_string_lossy().to_string())
+            .map(|f| f.source.to_string_lossy().to_string())
             .collect::<Vec<_>>();
         assert_eq!(files, vec!["file1"]);
     }
@@ -345,7 +898,7 @@ mod test {
         for path in paths {
             let full_path = root_path.join(path.as_ref());
             let dir_path = full_path.parent().expect("Path had no parent");
-            fs::create_dir_all(&dir_path).expect("Failed to create dir path");
+            fs::create_dir_all(dir_path).expect("Failed to create dir path");
             File::create(&full_path).expect("Could not create file");
         }
     }
@@ -359,11 +912,260 @@ mod test {
 
         let files = config
             .shell_files("default")
+            .expect("Failed to list shell files")
             .into_iter()
-            .map(|f| f.to_string_lossy().to_string())
+            .map(|f| f.source.to_string_lossy().to_string())
             .collect::<Vec<_>>();
         assert!(files.contains(&"file1".into()));
         assert!(files.contains(&"subdir/file2".into()));
         assert!(!files.contains(&"subdir".into()));
     }
+
+    #[test]
+    fn shell_files_come_in_a_stable_sorted_order() {
+        let test_root = set_up("default", vec!["default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let shell_root = config.shell_root_path().join("default");
+        create_paths(
+            &shell_root,
+            vec!["zshrc", ".config/b", "a/z", ".config/a", "a/b/c", ".bashrc"],
+        );
+
+        let files = config
+            .shell_files("default")
+            .expect("Failed to list shell files")
+            .into_iter()
+            .map(|f| f.source.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![".bashrc", ".config/a", ".config/b", "a/b/c", "a/z", "zshrc"]
+        );
+    }
+
+    #[test]
+    fn shell_files_leave_out_ignored_files() {
+        let test_root = set_up("default", vec!["default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let shell_root = config.shell_root_path().join("default");
+        create_paths(
+            &shell_root,
+            vec![
+                ".bashrc",
+                ".cache/zsh/compdump",
+                ".config/app/debug.log",
+                "README.md",
+            ],
+        );
+        fs::write(shell_root.join(".gitignore"), ".cache/\n*.log\n").unwrap();
+        fs::write(shell_root.join(".hermitignore"), "/README.md\n").unwrap();
+
+        let files = config
+            .shell_files("default")
+            .expect("Failed to list shell files")
+            .into_iter()
+            .map(|f| f.source.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(files, vec![".bashrc", ".gitignore"]);
+    }
+
+    #[test]
+    fn shell_files_follow_the_shell_manifest() {
+        let test_root = set_up("default", vec!["default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let shell_root = config.shell_root_path().join("default");
+        create_paths(&shell_root, vec!["kitty.conf", ".bashrc"]);
+        fs::write(
+            shell_root.join("hermit.toml"),
+            "[[file]]\nsource = \"kitty.conf\"\ntarget = \"~/.config/kitty/kitty.conf\"\n",
+        )
+        .expect("Failed to write manifest");
+
+        let mut files = config
+            .shell_files("default")
+            .expect("Failed to list shell files")
+            .into_iter()
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.source.cmp(&b.source));
+        assert_eq!(
+            files,
+            vec![
+                ShellFile::mirrored(".bashrc"),
+                ShellFile::mapped("kitty.conf", ".config/kitty/kitty.conf"),
+            ]
+        );
+    }
+
+    #[test]
+    fn directories_linked_as_a_whole_are_one_shell_file() {
+        let test_root = set_up("default", vec!["default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let shell_root = config.shell_root_path().join("default");
+        create_paths(
+            &shell_root,
+            vec!["nvim/init.vim", "nvim/lua/plugins.lua", ".bashrc"],
+        );
+        fs::write(
+            shell_root.join("hermit.toml"),
+            "[[file]]\nsource = \"nvim\"\ntarget = \"~/.config/nvim\"\n\
+             granularity = \"directory\"\n",
+        )
+        .expect("Failed to write manifest");
+
+        let files = config
+            .shell_files("default")
+            .expect("Failed to list shell files")
+            .into_iter()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![
+                ShellFile::mirrored(".bashrc"),
+                ShellFile::mapped("nvim", ".config/nvim").with_granularity(Granularity::Directory),
+            ]
+        );
+    }
+
+    #[test]
+    fn shell_files_stop_at_the_walk_depth_limit() {
+        let test_root = set_up("default", vec!["default"]);
+        fs::write(
+            test_root.path().join(SETTINGS_FILE_NAME),
+            "[walk]\nmax_depth = 2\n",
+        )
+        .expect("Failed to write settings");
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let shell_root = config.shell_root_path().join("default");
+        create_paths(
+            &shell_root,
+            vec![".bashrc", ".config/starship.toml", ".config/app/cache/blob"],
+        );
+
+        let files = config
+            .shell_files("default")
+            .expect("Failed to list shell files")
+            .into_iter()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![
+                ShellFile::mirrored(".bashrc"),
+                ShellFile::mirrored(".config/starship.toml"),
+            ]
+        );
+    }
+
+    #[test]
+    fn shell_files_can_follow_links_without_going_in_circles() {
+        let test_root = set_up("default", vec!["default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let shell_root = config.shell_root_path().join("default");
+        let shared = test_root.path().join("shared");
+        create_paths(&shared, vec!["vim/colors.vim"]);
+        create_paths(&shell_root, vec![".bashrc"]);
+        std::os::unix::fs::symlink(&shared, shell_root.join("shared")).unwrap();
+        std::os::unix::fs::symlink("..", shared.join("vim/up")).unwrap();
+        std::os::unix::fs::symlink(&shell_root, shared.join("shell")).unwrap();
+        let shell_files = |config: &FsConfig| {
+            config
+                .shell_files("default")
+                .expect("Failed to list shell files")
+                .into_iter()
+                .map(|file| file.target)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            shell_files(&config),
+            vec![PathBuf::from(".bashrc"), PathBuf::from("shared")]
+        );
+
+        fs::create_dir_all(shell_root.join(".hermit")).unwrap();
+        fs::write(
+            shell_root.join(".hermit/shell.toml"),
+            "follow_links = true\n",
+        )
+        .unwrap();
+        assert_eq!(
+            shell_files(&config),
+            vec![
+                PathBuf::from(".bashrc"),
+                PathBuf::from("shared/vim/colors.vim"),
+            ]
+        );
+    }
+
+    #[test]
+    fn shell_files_never_include_git_or_hermit_internals() {
+        let test_root = set_up("default", vec!["default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let shell_root = config.shell_root_path().join("default");
+        create_paths(
+            &shell_root,
+            vec![
+                ".bashrc",
+                ".git/config",
+                ".git/hooks/pre-commit",
+                ".hermit/branches.toml",
+                "hermit.toml",
+                ".vim/pack/plugin/.git",
+                ".vim/pack/plugin/plugin.vim",
+                "notes/hermit.toml",
+            ],
+        );
+
+        let mut files = config
+            .shell_files("default")
+            .expect("Failed to list shell files")
+            .into_iter()
+            .map(|f| f.source)
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from(".bashrc"),
+                PathBuf::from(".vim/pack/plugin/plugin.vim"),
+                PathBuf::from("notes/hermit.toml"),
+            ]
+        );
+    }
+
+    #[test]
+    fn link_plans_never_include_git_or_hermit_internals() {
+        let test_root = set_up("default", vec!["default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let shell_root = config.shell_root_path().join("default");
+        create_paths(
+            &shell_root,
+            vec![
+                ".bashrc",
+                ".git/HEAD",
+                ".hermit/bootstrap.log",
+                "hermit.toml",
+            ],
+        );
+        let shell = Shell::new("default", Rc::new(config));
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        shell.link(&mut file_ops).expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::Link {
+                path: PathBuf::from("/home/geoff/.bashrc"),
+                target: shell_root.join(".bashrc"),
+            }]
+        );
+    }
+
+    #[test]
+    fn shell_files_reports_a_broken_manifest() {
+        let test_root = set_up("default", vec!["default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let shell_root = config.shell_root_path().join("default");
+        fs::write(shell_root.join("hermit.toml"), "[[file]").expect("Failed to write manifest");
+
+        assert!(config.shell_files("default").is_err());
+    }
 }
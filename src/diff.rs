@@ -0,0 +1,91 @@
+/// The most pairs of lines to compare, to keep the table of common
+/// subsequences from using up all of the memory for huge files.
+const MAX_COMPARISONS: usize = 4_000_000;
+
+/// A line that is only in one of two texts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Line {
+    Removed(String),
+    Added(String),
+}
+
+impl Line {
+    /// The line as a unified diff shows it.
+    pub fn unified(&self) -> String {
+        match self {
+            Line::Removed(line) => format!("-{}", line),
+            Line::Added(line) => format!("+{}", line),
+        }
+    }
+}
+
+/// The lines that would have to be removed from `old` and added to it
+/// to get `new`, in order, keeping as many lines as possible. Texts
+/// that are too long to compare quickly give `None`.
+pub fn lines(old: &str, new: &str) -> Option<Vec<Line>> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    if old.len().saturating_mul(new.len()) > MAX_COMPARISONS {
+        return None;
+    }
+
+    // common[i][j] is the length of the longest common subsequence of
+    // old[i..] and new[j..].
+    let mut common = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            changes.push(Line::Removed(old[i].to_owned()));
+            i += 1;
+        } else {
+            changes.push(Line::Added(new[j].to_owned()));
+            j += 1;
+        }
+    }
+    Some(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_texts_have_no_changes() {
+        assert_eq!(lines("a\nb\n", "a\nb\n"), Some(vec![]));
+    }
+
+    #[test]
+    fn finds_removed_and_added_lines() {
+        assert_eq!(
+            lines("a\nb\nc\n", "a\nc\nd\n"),
+            Some(vec![
+                Line::Removed("b".to_owned()),
+                Line::Added("d".to_owned())
+            ])
+        );
+    }
+
+    #[test]
+    fn changed_lines_are_removed_then_added() {
+        let changes = lines("export EDITOR=vi\n", "export EDITOR=nvim\n").unwrap();
+
+        assert_eq!(
+            changes.iter().map(Line::unified).collect::<Vec<_>>(),
+            vec!["-export EDITOR=vi", "+export EDITOR=nvim"]
+        );
+    }
+}
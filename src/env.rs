@@ -2,7 +2,35 @@ use crate::common::*;
 
 use std::env;
 
-pub use dirs::home_dir;
+/// The hermit root directory.
+pub const ROOT_VAR: &str = "HERMIT_ROOT";
+
+/// The directory that shells are linked into.
+pub const HOME_VAR: &str = "HERMIT_HOME";
+
+/// The name of the current shell. Hermit sets it for the commands it
+/// runs, and setting it yourself makes hermit act on that shell
+/// instead of the current one, without switching to it.
+pub const SHELL_VAR: &str = "HERMIT_SHELL";
+
+/// The directory of the current shell.
+pub const SHELL_PATH_VAR: &str = "HERMIT_SHELL_PATH";
+
+/// The machine's hostname.
+pub const HOSTNAME_VAR: &str = "HERMIT_HOSTNAME";
+
+/// The machine's operating system, like `linux` or `macos`.
+pub const OS_VAR: &str = "HERMIT_OS";
+
+/// The machine's processor architecture, like `x86_64` or `aarch64`.
+pub const ARCH_VAR: &str = "HERMIT_ARCH";
+
+/// The name of the user running hermit.
+pub const USERNAME_VAR: &str = "HERMIT_USERNAME";
+
+/// Set to anything but `0` to keep hermit from changing anything, like
+/// `--read-only` does.
+pub const READ_ONLY_VAR: &str = "HERMIT_READONLY";
 
 pub fn get_program_name() -> String {
     env::args()
@@ -13,8 +41,35 @@ pub fn get_program_name() -> String {
         .unwrap_or_else(|| "hermit".to_owned())
 }
 
+/// The user's preferred editor, following the usual `$VISUAL` then
+/// `$EDITOR` convention.
+pub fn editor() -> String {
+    env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned())
+}
+
+/// The shell named by `HERMIT_SHELL`, if it is set to anything.
+pub fn shell_override() -> Option<String> {
+    env::var(SHELL_VAR).ok().filter(|name| !name.is_empty())
+}
+
+/// Whether `HERMIT_READONLY` asks for read-only mode.
+pub fn read_only() -> bool {
+    env::var_os(READ_ONLY_VAR).is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+/// The directory that shells are linked into: `HERMIT_HOME` if it is
+/// set, and the user's home directory otherwise.
+pub fn home_dir() -> Option<PathBuf> {
+    env::var_os(HOME_VAR)
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+        .or_else(dirs::home_dir)
+}
+
 pub fn get_hermit_dir() -> Option<PathBuf> {
-    env::var("HERMIT_ROOT")
+    env::var(ROOT_VAR)
         .map(PathBuf::from)
         .ok()
         .or_else(default_hermit_dir)
@@ -24,6 +79,92 @@ pub fn default_hermit_dir() -> Option<PathBuf> {
     dirs::config_dir().map(|home| home.join("hermit"))
 }
 
+/// A command line for `sh` that runs hermit on `hermit_root`, for git
+/// to run from hooks and filters, which don't get hermit's environment.
+pub fn hermit_command(hermit_root: &Path, args: &[&str]) -> Result<String> {
+    let root = hermit_root
+        .to_str()
+        .ok_or_else(|| Error::PathNotUtf8(hermit_root.to_path_buf()))?;
+    let mut command = format!("{}={} hermit", ROOT_VAR, quote(root));
+    for arg in args {
+        command.push(' ');
+        command.push_str(&quote(arg));
+    }
+    Ok(command)
+}
+
+/// The ways that `hermit env` can print variables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Sh,
+    Fish,
+    Json,
+}
+
+impl ExportFormat {
+    pub const NAMES: [&'static str; 3] = ["sh", "fish", "json"];
+
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        match name {
+            "sh" => Some(ExportFormat::Sh),
+            "fish" => Some(ExportFormat::Fish),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// Commands that set `vars` when they are evaluated, or a JSON
+    /// object of them.
+    pub fn export(self, vars: &[(String, String)]) -> String {
+        match self {
+            ExportFormat::Sh => vars
+                .iter()
+                .map(|(name, value)| format!("export {}={}\n", name, quote(value)))
+                .collect(),
+            ExportFormat::Fish => vars
+                .iter()
+                .map(|(name, value)| format!("set -gx {} {}\n", name, quote_for_fish(value)))
+                .collect(),
+            ExportFormat::Json => {
+                let object = vars
+                    .iter()
+                    .map(|(name, value)| (name.clone(), serde_json::Value::from(value.as_str())))
+                    .collect::<serde_json::Map<_, _>>();
+                format!("{}\n", serde_json::Value::Object(object))
+            }
+        }
+    }
+}
+
+/// Whether `name` can be the name of an environment variable in every
+/// shell.
+pub fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Quotes `word` for `sh`, leaving plain words alone.
+fn quote(word: &str) -> String {
+    if !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c))
+    {
+        word.to_owned()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// Quotes `word` for fish, where backslashes are special even in
+/// single quotes.
+fn quote_for_fish(word: &str) -> String {
+    format!("'{}'", word.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -32,7 +173,7 @@ mod tests {
 
     use once_cell::sync::Lazy;
 
-    // This mutex is solely for preventing these two tests from
+    // This mutex is solely for preventing these tests from
     // stomping on each other. While it doesn't happen often, it's
     // still an issue we want to avoid for CI builds (spurious
     // build failures are the worst).
@@ -49,6 +190,60 @@ mod tests {
         assert_eq!(default_hermit_dir(), hermit_dir);
     }
 
+    #[test]
+    fn an_empty_shell_override_is_ignored() {
+        let shell_override: Option<String>;
+        {
+            let _lock = ROOT_ENV_LOCK.lock().unwrap();
+            env::set_var(SHELL_VAR, "");
+            shell_override = super::shell_override();
+            env::remove_var(SHELL_VAR);
+        }
+        assert_eq!(shell_override, None);
+    }
+
+    #[test]
+    fn hermit_commands_are_quoted_for_sh() {
+        assert_eq!(
+            hermit_command(
+                Path::new("/home/o'neil/hermit"),
+                &["doctor", "--shell", "my shell"]
+            )
+            .unwrap(),
+            "HERMIT_ROOT='/home/o'\\''neil/hermit' hermit doctor --shell 'my shell'"
+        );
+    }
+
+    #[test]
+    fn exports_are_quoted_for_each_format() {
+        let vars = vec![
+            ("EDITOR".to_owned(), "nvim".to_owned()),
+            ("GREETING".to_owned(), "it's a \\ day".to_owned()),
+        ];
+
+        assert_eq!(
+            ExportFormat::Sh.export(&vars),
+            "export EDITOR=nvim\nexport GREETING='it'\\''s a \\ day'\n"
+        );
+        assert_eq!(
+            ExportFormat::Fish.export(&vars),
+            "set -gx EDITOR 'nvim'\nset -gx GREETING 'it\\'s a \\\\ day'\n"
+        );
+        assert_eq!(
+            ExportFormat::Json.export(&vars),
+            "{\"EDITOR\":\"nvim\",\"GREETING\":\"it's a \\\\ day\"}\n"
+        );
+    }
+
+    #[test]
+    fn only_portable_names_are_variable_names() {
+        assert!(is_var_name("GOPATH"));
+        assert!(is_var_name("_private2"));
+        assert!(!is_var_name("2FAST"));
+        assert!(!is_var_name("MY-VAR"));
+        assert!(!is_var_name(""));
+    }
+
     #[test]
     fn hermit_dir_can_be_set_by_environment_variable() {
         let hermit_dir: Option<PathBuf>;
@@ -0,0 +1,86 @@
+//! The codes that hermit exits with, so that scripts can tell what
+//! kind of thing went wrong. `doc/exit-codes.md` lists them for users.
+
+use crate::common::*;
+
+pub const SUCCESS: i32 = 0;
+/// The command ran, but some of the changes it set out to make failed
+/// or were skipped.
+pub const PARTIAL_FAILURE: i32 = 1;
+/// The command line asked for something that doesn't exist or doesn't
+/// make sense, like an unknown shell, file or flag.
+pub const USAGE: i32 = 2;
+/// The hermit root, or one of the files that configure it or its
+/// shells, is missing or can't be read.
+pub const CONFIG: i32 = 3;
+/// Anything else that stopped the command, like git or another
+/// program failing.
+pub const FAILURE: i32 = 4;
+
+/// The code to exit with after `err`.
+pub fn of(err: &anyhow::Error) -> i32 {
+    err.downcast_ref::<Error>()
+        .map_or(FAILURE, Error::exit_code)
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::ChangesFailed(_) => PARTIAL_FAILURE,
+
+            Error::UnknownShell { .. }
+            | Error::UnknownSubcommand(_)
+            | Error::UnknownSnapshot(_)
+            | Error::InvalidShellName { .. }
+            | Error::ShellAlreadyExists(_)
+            | Error::MergeIntoItself(_)
+            | Error::ShellInUse(_)
+            | Error::NoSuchFile(_)
+            | Error::NoMatches(_)
+            | Error::InvalidPattern(..)
+            | Error::NotInTargetRoot(_)
+            | Error::SystemShell(_)
+            | Error::NotASystemShell(_)
+            | Error::ReadOnly(_)
+            | Error::Offline(_)
+            | Error::AlreadyInShell(_)
+            | Error::FileNotTracked(_)
+            | Error::NoShellNameInUrl(_)
+            | Error::NoPreviousShell
+            | Error::NothingToUndo
+            | Error::NothingStashed
+            | Error::NotATerminal(_)
+            | Error::TooManyFiles { .. }
+            | Error::TooDeep { .. }
+            | Error::SubcommandNotImplemented(_) => USAGE,
+
+            Error::NotInitialized(_)
+            | Error::NoActiveShell
+            | Error::InvalidManifest(_)
+            | Error::InvalidSettings(_)
+            | Error::InvalidMetadata(_)
+            | Error::OutsideSystemPrefixes(_)
+            | Error::InvalidJournal(_)
+            | Error::InvalidAuditLog(_)
+            | Error::InvalidPlan(_)
+            | Error::NoGitIdentity
+            | Error::CannotSign(_) => CONFIG,
+
+            _ => FAILURE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_exit_with_the_code_for_their_kind() {
+        assert_eq!(of(&Error::ChangesFailed(2).into()), PARTIAL_FAILURE);
+        assert_eq!(of(&Error::NoSuchFile(".bashrc".into()).into()), USAGE);
+        assert_eq!(of(&Error::InvalidSettings(String::new()).into()), CONFIG);
+        assert_eq!(of(&Error::Git(String::new()).into()), FAILURE);
+        assert_eq!(of(&anyhow::anyhow!("something else")), FAILURE);
+    }
+}
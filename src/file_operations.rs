@@ -1,27 +1,386 @@
-use crate::common::*;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, BinaryHeap},
+    ffi::OsString,
+};
 
-#[derive(Debug, PartialEq, Eq)]
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    common::*,
+    fs_backend::{self, FileKind, FsBackend, RealFs},
+    git::{self, CloneOptions},
+    journal::{hex, os_path, Change},
+    lfs,
+    observer::{Event, Observer, Observers},
+};
+
+/// A single change to the filesystem. Operations can be saved, as part
+/// of a plan, and carried out later.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Op {
-    MkDir(PathBuf),
-    GitInit(PathBuf),
-    Link { path: PathBuf, target: PathBuf },
-    Remove(PathBuf),
+    MkDir(#[serde(with = "os_path")] PathBuf),
+    GitInit(#[serde(with = "os_path")] PathBuf),
+    GitClone {
+        url: String,
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        options: CloneOptions,
+    },
+    GitRemote {
+        #[serde(with = "os_path")]
+        repo: PathBuf,
+        name: String,
+        url: String,
+    },
+    Link {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        #[serde(with = "os_path")]
+        target: PathBuf,
+    },
+    Copy {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        #[serde(with = "os_path")]
+        source: PathBuf,
+    },
+    /// Moves the file at `path` to `destination`, creating the
+    /// directories it goes in.
+    Move {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        #[serde(with = "os_path")]
+        destination: PathBuf,
+    },
+    Remove(#[serde(with = "os_path")] PathBuf),
+    /// Removes `path` if it is a link to `source` or a copy of it, and
+    /// leaves it alone otherwise.
+    Unlink {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        #[serde(with = "os_path")]
+        source: PathBuf,
+    },
+    /// Replaces the link or copy of `source` at `path` with a link to
+    /// `target`, by making the new link next to it and renaming it over
+    /// it, so that there is never a moment when nothing is at `path`.
+    /// Anything else at `path` is in the way, as it is for `Link`.
+    Relink {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        #[serde(with = "os_path")]
+        source: PathBuf,
+        #[serde(with = "os_path")]
+        target: PathBuf,
+    },
+    WriteFile {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        contents: Vec<u8>,
+    },
+    /// Like `WriteFile`, but leaves a file that already exists alone.
+    CreateFile {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        contents: Vec<u8>,
+    },
+}
+
+/// What to do when a file is in the way of a link or copy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Leave the file alone and fail the operation.
+    Error,
+    /// Delete the file.
+    Overwrite,
+    /// Move the file aside to `<name>.hermit-backup`.
+    Backup,
+}
+
+/// What the permissions and owner of a copied file are taken from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PermissionPolicy {
+    /// The file it is a copy of: its mode bits, and its owner and group
+    /// as well when hermit runs as root.
+    #[default]
+    Preserve,
+    /// The umask, as for any new file. A file that is replaced keeps
+    /// the permissions it had.
+    Umask,
+}
+
+impl PermissionPolicy {
+    pub fn from_name(name: &str) -> Option<PermissionPolicy> {
+        match name {
+            "preserve" => Some(PermissionPolicy::Preserve),
+            "umask" => Some(PermissionPolicy::Umask),
+            _ => None,
+        }
+    }
+}
+
+/// What carrying out an operation did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Changed,
+    /// The filesystem already looked the way the operation would have
+    /// left it, so nothing was done.
+    Unchanged,
+    /// The operation wasn't carried out, because an earlier one failed
+    /// and operations fail fast.
+    Skipped,
+}
+
+pub type Result = anyhow::Result<Outcome>;
+
+/// What carrying out a batch of queued operations did, with the result
+/// of each of them in the order they were queued in.
+#[derive(Debug, Default)]
+pub struct CommitReport {
+    results: Vec<Result>,
+}
+
+impl CommitReport {
+    pub fn new(results: Vec<Result>) -> CommitReport {
+        CommitReport { results }
+    }
+
+    pub fn results(&self) -> &[Result] {
+        &self.results
+    }
+
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Result> {
+        self.results.iter()
+    }
+
+    pub fn changed(&self) -> usize {
+        self.count(Outcome::Changed)
+    }
+
+    pub fn unchanged(&self) -> usize {
+        self.count(Outcome::Unchanged)
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.count(Outcome::Skipped)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.errors().count()
+    }
+
+    /// Whether every operation was carried out, or had nothing to do.
+    pub fn is_success(&self) -> bool {
+        self.failed() == 0 && self.skipped() == 0
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &anyhow::Error> {
+        self.results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+    }
+
+    fn count(&self, outcome: Outcome) -> usize {
+        self.results
+            .iter()
+            .filter(|result| matches!(result, Ok(counted) if *counted == outcome))
+            .count()
+    }
+}
+
+/// Adds the results of another batch, so that one report covers every
+/// batch that a command carried out.
+impl Extend<Result> for CommitReport {
+    fn extend<I: IntoIterator<Item = Result>>(&mut self, results: I) {
+        self.results.extend(results);
+    }
+}
+
+/// Lists what failed, one error per line, followed by how many
+/// operations ended up each way, as in "2 changed, 1 failed".
+impl Display for CommitReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for err in self.errors() {
+            writeln!(f, "{}", err)?;
+        }
+        let counts = [
+            (self.changed(), "changed"),
+            (self.unchanged(), "unchanged"),
+            (self.failed(), "failed"),
+            (self.skipped(), "skipped"),
+        ]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, outcome)| format!("{} {}", count, outcome))
+        .collect::<Vec<_>>();
+        if counts.is_empty() {
+            write!(f, "nothing to do")
+        } else {
+            write!(f, "{}", counts.join(", "))
+        }
+    }
+}
+
+impl std::ops::Index<usize> for CommitReport {
+    type Output = Result;
+
+    fn index(&self, index: usize) -> &Result {
+        &self.results[index]
+    }
+}
+
+impl IntoIterator for CommitReport {
+    type Item = Result;
+    type IntoIter = std::vec::IntoIter<Result>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.results.into_iter()
+    }
 }
 
-pub type Result = anyhow::Result<()>;
+impl<'a> IntoIterator for &'a CommitReport {
+    type Item = &'a Result;
+    type IntoIter = std::slice::Iter<'a, Result>;
 
+    fn into_iter(self) -> Self::IntoIter {
+        self.results.iter()
+    }
+}
+
+impl Op {
+    /// The paths that the operation puts something at.
+    fn creates(&self) -> Option<&Path> {
+        match self {
+            Op::MkDir(path) | Op::GitInit(path) => Some(path),
+            Op::GitClone { path, .. }
+            | Op::Link { path, .. }
+            | Op::Relink { path, .. }
+            | Op::Copy { path, .. }
+            | Op::WriteFile { path, .. }
+            | Op::CreateFile { path, .. } => Some(path),
+            Op::Move { destination, .. } => Some(destination),
+            Op::GitRemote { .. } | Op::Remove(_) | Op::Unlink { .. } => None,
+        }
+    }
+
+    /// The path that the operation takes something away from.
+    fn removes(&self) -> Option<&Path> {
+        match self {
+            Op::Remove(path)
+            | Op::Unlink { path, .. }
+            | Op::Relink { path, .. }
+            | Op::Move { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The path that the operation needs to find something at, other
+    /// than what it removes, which is whatever is there already.
+    fn reads(&self) -> Option<&Path> {
+        match self {
+            Op::Copy { source, .. } | Op::Unlink { source, .. } | Op::Relink { source, .. } => {
+                Some(source)
+            }
+            Op::GitRemote { repo, .. } => Some(repo),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        let (verb, path) = match self {
+            Op::MkDir(path) => ("create", path),
+            Op::GitInit(path) | Op::GitClone { path, .. } => ("create a repository at", path),
+            Op::GitRemote { repo, .. } => ("add a remote to", repo),
+            Op::Link { path, .. } | Op::Relink { path, .. } => ("link", path),
+            Op::Copy { path, .. } => ("copy", path),
+            Op::Move { path, .. } => ("move", path),
+            Op::Remove(path) | Op::Unlink { path, .. } => ("remove", path),
+            Op::WriteFile { path, .. } | Op::CreateFile { path, .. } => ("write", path),
+        };
+        format!("{} {}", verb, message::path(path))
+    }
+
+    /// Whether the filesystem already looks the way this operation
+    /// would leave it. Creating a git repository is never considered
+    /// done already, so that initializing a shell twice is an error.
+    pub fn is_satisfied(&self, fs: &dyn FsBackend) -> bool {
+        match self {
+            Op::MkDir(dir) => fs.is_dir(dir),
+            Op::GitInit(_) | Op::GitClone { .. } => false,
+            Op::GitRemote { repo, name, url } => git::open(repo)
+                .and_then(|repo| Ok(repo.find_remote(name)?.url() == Some(url.as_str())))
+                .unwrap_or(false),
+            Op::Link { path, target } | Op::Relink { path, target, .. } => {
+                fs.read_link(path).is_ok_and(|link| link == *target)
+            }
+            Op::Copy { path, source } => is_copy_of(fs, path, source),
+            Op::Move { path, destination } => !fs.exists(path) && fs.exists(destination),
+            Op::Unlink { path, source } => !is_put_in_place(fs, path, source),
+            Op::Remove(file) => fs
+                .kind(file)
+                .is_err_and(|err| err.kind() == io::ErrorKind::NotFound),
+            Op::WriteFile { path, contents } => {
+                fs.read(path).is_ok_and(|existing| existing == *contents)
+            }
+            Op::CreateFile { path, .. } => fs.exists(path),
+        }
+    }
+}
+
+/// A queue of filesystem changes, rooted at a directory that relative
+/// paths are resolved against.
+///
+/// Operations are only carried out by `commit` (or `flush`), which
+/// returns the result of each of them in order. They are carried out
+/// on the real filesystem unless another `FsBackend` is given.
 pub struct FileOperations {
     root: PathBuf,
+    fs: Rc<dyn FsBackend>,
     operations: Vec<Op>,
     git_init_opts: git2::RepositoryInitOptions,
+    conflict_policy: ConflictPolicy,
+    permission_policy: PermissionPolicy,
+    preserve_xattrs: bool,
+    permanent_removal: bool,
+    fail_fast: bool,
+    failures: usize,
+    changes: Vec<Change>,
+    observers: Observers,
 }
 
 impl FileOperations {
     pub fn rooted_at(path: impl AsRef<Path>) -> FileOperations {
+        FileOperations::with_backend(path, Rc::new(RealFs))
+    }
+
+    /// Like `rooted_at`, but carries out the operations on `fs`.
+    pub fn with_backend(path: impl AsRef<Path>, fs: Rc<dyn FsBackend>) -> FileOperations {
         FileOperations {
             root: PathBuf::from(path.as_ref()),
+            fs,
             operations: vec![],
             git_init_opts: FileOperations::default_git_opts(),
+            conflict_policy: ConflictPolicy::Error,
+            permission_policy: PermissionPolicy::Preserve,
+            preserve_xattrs: false,
+            permanent_removal: false,
+            fail_fast: false,
+            failures: 0,
+            changes: vec![],
+            observers: Observers::default(),
         }
     }
 
@@ -32,12 +391,95 @@ impl FileOperations {
         opts
     }
 
-    #[allow(dead_code)]
+    /// Names the branch that the repositories made by
+    /// `create_git_repo` start out on.
+    pub fn set_initial_branch(&mut self, branch: &str) {
+        self.git_init_opts.initial_head(branch);
+    }
+
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        self.conflict_policy
+    }
+
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.conflict_policy = policy;
+    }
+
+    pub fn permission_policy(&self) -> PermissionPolicy {
+        self.permission_policy
+    }
+
+    pub fn set_permission_policy(&mut self, policy: PermissionPolicy) {
+        self.permission_policy = policy;
+    }
+
+    /// Whether copies get the extended attributes, and so on Linux the
+    /// ACLs, of the files they are copies of.
+    pub fn preserve_xattrs(&self) -> bool {
+        self.preserve_xattrs
+    }
+
+    pub fn set_preserve_xattrs(&mut self, preserve: bool) {
+        self.preserve_xattrs = preserve;
+    }
+
+    /// Whether removed files are deleted outright instead of being
+    /// sent to the trash. They always are when hermit is built without
+    /// the `trash` feature.
+    pub fn permanent_removal(&self) -> bool {
+        self.permanent_removal || cfg!(not(feature = "trash"))
+    }
+
+    pub fn set_permanent_removal(&mut self, permanent: bool) {
+        self.permanent_removal = permanent;
+    }
+
+    /// Whether the operations left after one fails are skipped, instead
+    /// of being carried out anyway.
+    pub fn fail_fast(&self) -> bool {
+        self.fail_fast
+    }
+
+    pub fn set_fail_fast(&mut self, fail_fast: bool) {
+        self.fail_fast = fail_fast;
+    }
+
+    /// Tells `observer` about the operations as they are carried out.
+    pub fn add_observer(&mut self, observer: Rc<dyn Observer>) {
+        self.observers.add(observer);
+    }
+
+    /// The filesystem that the operations are carried out on.
+    pub fn backend(&self) -> &dyn FsBackend {
+        self.fs.as_ref()
+    }
+
+    /// The directory that relative paths are resolved against.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolves the paths of the operations queued from now on against
+    /// `root` instead.
+    pub fn set_root(&mut self, root: impl AsRef<Path>) {
+        self.root = root.as_ref().to_path_buf();
+    }
+
     pub fn operations(&self) -> &Vec<Op> {
         &self.operations
     }
 
-    #[allow(dead_code)]
+    /// What the operations carried out so far have changed, oldest
+    /// first.
+    pub fn changes(&self) -> &Vec<Change> {
+        &self.changes
+    }
+
+    /// Returns the changes made so far and forgets about them.
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        mem::take(&mut self.changes)
+    }
+
     pub fn create_dir(&mut self, name: impl AsRef<Path>) {
         self.operations.push(Op::MkDir(self.root.join(name)))
     }
@@ -49,6 +491,87 @@ impl FileOperations {
         });
     }
 
+    /// Copies `source` to `path`, replacing whatever file is there.
+    pub fn copy(&mut self, path: impl AsRef<Path>, source: impl AsRef<Path>) {
+        self.operations.push(Op::Copy {
+            path: self.root.join(path),
+            source: source.as_ref().to_path_buf(),
+        });
+    }
+
+    /// Removes the link or copy of `source` at `path`, if that is what
+    /// is there.
+    pub fn unlink(&mut self, path: impl AsRef<Path>, source: impl AsRef<Path>) {
+        self.operations.push(Op::Unlink {
+            path: self.root.join(path),
+            source: source.as_ref().to_path_buf(),
+        });
+    }
+
+    /// Turns each link that is queued at a path that a link or copy is
+    /// also queued to be removed from into a `Relink`, which replaces
+    /// the one with the other in one go.
+    pub fn merge_relinks(&mut self) {
+        let mut unlinks = self
+            .operations
+            .iter()
+            .enumerate()
+            .filter_map(|(index, op)| match op {
+                Op::Unlink { path, source } => Some((path.clone(), (index, source.clone()))),
+                _ => None,
+            })
+            .collect::<BTreeMap<_, _>>();
+        let mut merged = BTreeSet::new();
+        for op in &mut self.operations {
+            if let Op::Link { path, target } = op {
+                if let Some((index, source)) = unlinks.remove(path.as_path()) {
+                    merged.insert(index);
+                    *op = Op::Relink {
+                        path: mem::take(path),
+                        source,
+                        target: mem::take(target),
+                    };
+                }
+            }
+        }
+        let mut index = 0;
+        self.operations.retain(|_| {
+            index += 1;
+            !merged.contains(&(index - 1))
+        });
+    }
+
+    /// Removes the directory at `path` if it holds nothing but links to
+    /// the files in `source`, made when they were linked one by one, so
+    /// that a link to the whole of `source` can take its place.
+    pub fn unlink_file_by_file(&mut self, path: impl AsRef<Path>, source: impl AsRef<Path>) {
+        let dir = self.root.join(path);
+        let fs = Rc::clone(&self.fs);
+        if fs_backend::is_linked_file_by_file(fs.as_ref(), &dir, source.as_ref()) {
+            self.unlink_below(fs.as_ref(), &dir, source.as_ref());
+            self.remove(dir);
+        }
+    }
+
+    fn unlink_below(&mut self, fs: &dyn FsBackend, dir: &Path, source: &Path) {
+        for name in fs.read_dir(dir).unwrap_or_default() {
+            let (path, source) = (dir.join(&name), source.join(&name));
+            if fs.kind(&path).ok() == Some(FileKind::Symlink) {
+                self.unlink(path, source);
+            } else {
+                self.unlink_below(fs, &path, &source);
+                self.remove(path);
+            }
+        }
+    }
+
+    pub fn move_file(&mut self, path: impl AsRef<Path>, destination: impl AsRef<Path>) {
+        self.operations.push(Op::Move {
+            path: self.root.join(path),
+            destination: destination.as_ref().to_path_buf(),
+        });
+    }
+
     pub fn remove(&mut self, file: impl AsRef<Path>) {
         self.operations.push(Op::Remove(self.root.join(file)));
     }
@@ -57,41 +580,539 @@ impl FileOperations {
         self.operations.push(Op::GitInit(self.root.join(name)))
     }
 
-    pub fn commit(mut self) -> Vec<Result> {
-        mem::replace(&mut self.operations, vec![])
+    /// Clones the repository at `url` into a new directory.
+    pub fn clone_git_repo(&mut self, url: &str, path: impl AsRef<Path>, options: &CloneOptions) {
+        self.operations.push(Op::GitClone {
+            url: url.to_owned(),
+            path: self.root.join(path),
+            options: options.clone(),
+        });
+    }
+
+    /// Creates a file with the given contents, replacing any file
+    /// that is already there.
+    pub fn write_file(&mut self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        self.operations.push(Op::WriteFile {
+            path: self.root.join(path),
+            contents: contents.into(),
+        });
+    }
+
+    /// Creates a file with the given contents, unless there is already
+    /// a file there.
+    pub fn create_file(&mut self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        self.operations.push(Op::CreateFile {
+            path: self.root.join(path),
+            contents: contents.into(),
+        });
+    }
+
+    /// Adds a remote to the git repository at `repo`, which may be one
+    /// that an earlier operation creates.
+    pub fn add_git_remote(&mut self, repo: impl AsRef<Path>, name: &str, url: &str) {
+        self.operations.push(Op::GitRemote {
+            repo: self.root.join(repo),
+            name: name.to_owned(),
+            url: url.to_owned(),
+        });
+    }
+
+    /// Queues an operation that was worked out earlier, like one from a
+    /// saved plan.
+    pub fn queue(&mut self, op: Op) {
+        self.operations.push(op);
+    }
+
+    /// Forgets the operations queued so far without carrying them out.
+    pub fn clear(&mut self) {
+        self.operations.clear();
+    }
+
+    pub fn commit(mut self) -> CommitReport {
+        self.flush()
+    }
+
+    /// Executes the operations queued so far, leaving this set empty
+    /// and ready to queue more. The results are in the order the
+    /// operations were queued in, whatever order they were carried out
+    /// in (see `execution_order`). When failing fast, the operations
+    /// after the first one that fails are `Skipped`.
+    pub fn flush(&mut self) -> CommitReport {
+        let operations = mem::take(&mut self.operations);
+        let (order, cyclic) = execution_order(&operations);
+        let mut operations = operations.into_iter().map(Some).collect::<Vec<_>>();
+        let mut results = operations.iter().map(|_| None).collect::<Vec<_>>();
+        let mut failed = false;
+        for index in order.into_iter().chain(cyclic.iter().copied()) {
+            let op = operations[index]
+                .take()
+                .expect("operations are only carried out once");
+            let description = op.describe();
+            let result = if failed && self.fail_fast {
+                Ok(Outcome::Skipped)
+            } else if cyclic.contains(&index) {
+                Err(anyhow::anyhow!(
+                    "Can't {}, since it depends on operations that depend on it in turn",
+                    description
+                ))
+            } else {
+                self.do_op(op)
+            };
+            match &result {
+                Ok(outcome) => self.observers.notify(Event::OpDone {
+                    description,
+                    outcome: *outcome,
+                }),
+                Err(err) => self.observers.notify(Event::OpFailed {
+                    description,
+                    error: err.to_string(),
+                }),
+            }
+            failed |= result.is_err();
+            results[index] = Some(result);
+        }
+        let results = results
+            .into_iter()
+            .map(|result| result.expect("every operation has a result"))
+            .collect::<Vec<_>>();
+        let report = CommitReport::new(results);
+        self.failures += report.failed() + report.skipped();
+        self.observers.notify(Event::OperationsDone {
+            changed: report.changed(),
+            failed: report.failed(),
+        });
+        report
+    }
+
+    /// How many operations have failed or been skipped since these
+    /// operations were set up, over every flush.
+    pub fn failures(&self) -> usize {
+        self.failures
+    }
+
+    /// The queued operations in the order they would be carried out
+    /// in, with any that depend on each other in a cycle, and so can't
+    /// be carried out, at the end.
+    pub fn ordered_operations(&self) -> Vec<&Op> {
+        let (order, cyclic) = execution_order(&self.operations);
+        order
             .into_iter()
-            .map(|op| self.do_op(op))
-            .collect::<Vec<_>>()
+            .chain(cyclic)
+            .map(|index| &self.operations[index])
+            .collect()
     }
 
-    /// Private Methods
+    // Private Methods
 
     fn do_op(&mut self, op: Op) -> Result {
+        if op.is_satisfied(self.fs.as_ref()) {
+            return Ok(Outcome::Unchanged);
+        }
+
         match op {
-            Op::MkDir(dir) => fs::create_dir_all(dir)?,
-            Op::GitInit(dir) => git_init(dir, &self.git_init_opts)?,
-            Op::Link { path, target } => unix::fs::symlink(target, path)?,
-            Op::Remove(file) => fs::remove_file(file)?,
+            Op::MkDir(dir) => self.create_dir_all(&dir)?,
+            Op::GitInit(dir) => {
+                git_init(&dir, &self.git_init_opts)?;
+                self.record(Change::irreversible(format!(
+                    "created a git repository in {}",
+                    message::path(dir)
+                )));
+            }
+            Op::GitClone { url, path, options } => {
+                git::clone(&url, &path, &options)?;
+                self.record(Change::irreversible(format!(
+                    "cloned {} into {}",
+                    url,
+                    message::path(&path)
+                )));
+                if lfs::set_up(&path)? {
+                    self.record(Change::irreversible(format!(
+                        "fetched the Git LFS files of {}",
+                        message::path(path)
+                    )));
+                }
+            }
+            Op::GitRemote { repo, name, url } => {
+                git::open(&repo)?.remote(&name, &url)?;
+                self.record(Change::irreversible(format!(
+                    "added remote {} to {}",
+                    name,
+                    message::path(repo)
+                )));
+            }
+            Op::Link { path, target } => {
+                self.clear_the_way(&path, |_| false)?;
+                self.create_parent_dirs(&path)?;
+                self.fs.symlink(&target, &path)?;
+                self.observers.notify(Event::FileLinked {
+                    path: path.clone(),
+                    target: target.clone(),
+                });
+                self.record(Change::CreatedLink { path, target });
+            }
+            Op::Relink {
+                path,
+                source,
+                target,
+            } => {
+                let replaced = if is_put_in_place(self.fs.as_ref(), &path, &source) {
+                    Some(match self.fs.read_link(&path) {
+                        Ok(old_target) => Change::RemovedLink {
+                            path: path.clone(),
+                            target: old_target,
+                        },
+                        Err(_) => Change::RemovedCopy {
+                            path: path.clone(),
+                            source,
+                        },
+                    })
+                } else {
+                    self.clear_the_way(&path, |_| false)?;
+                    None
+                };
+                self.create_parent_dirs(&path)?;
+                self.replace_with_link(&path, &target)?;
+                self.observers.notify(Event::FileLinked {
+                    path: path.clone(),
+                    target: target.clone(),
+                });
+                self.changes.extend(replaced);
+                self.record(Change::CreatedLink { path, target });
+            }
+            Op::Copy { path, source } => {
+                self.clear_the_way(&path, |kind| kind == FileKind::File)?;
+                self.create_parent_dirs(&path)?;
+                let replacing = self.fs.exists(&path);
+                self.copy_file(&source, &path)?;
+                let change = if replacing {
+                    Change::irreversible(format!("replaced {}", message::path(path)))
+                } else {
+                    self.created_file(path)?
+                };
+                self.record(change);
+            }
+            Op::Move { path, destination } => {
+                self.create_parent_dirs(&destination)?;
+                if self.fs.rename(&path, &destination).is_err() {
+                    // Most likely a different filesystem, which rename
+                    // can't move across.
+                    self.copy_file(&path, &destination)?;
+                    self.fs.remove_file(&path)?;
+                }
+                self.record(Change::Moved { path, destination });
+            }
+            Op::Remove(file) | Op::Unlink { path: file, .. }
+                if self
+                    .fs
+                    .kind(&file)
+                    .is_ok_and(|kind| kind == FileKind::Symlink) =>
+            {
+                let target = self.fs.read_link(&file)?;
+                self.fs.remove_file(&file)?;
+                self.record(Change::RemovedLink { path: file, target });
+            }
+            Op::Unlink { path, source } => {
+                self.fs.remove_file(&path)?;
+                self.record(Change::RemovedCopy { path, source });
+            }
+            Op::Remove(file) => {
+                let change = self.discard(&file)?;
+                self.record(change);
+            }
+            Op::WriteFile { path, contents } => {
+                let replacing = self.fs.exists(&path);
+                self.create_parent_dirs(&path)?;
+                self.fs.write(&path, &contents)?;
+                let change = if replacing {
+                    Change::irreversible(format!("replaced {}", message::path(path)))
+                } else {
+                    self.created_file(path)?
+                };
+                self.record(change);
+            }
+            Op::CreateFile { path, contents } => {
+                self.create_parent_dirs(&path)?;
+                self.fs.write(&path, &contents)?;
+                let change = self.created_file(path)?;
+                self.record(change);
+            }
         };
+        Ok(Outcome::Changed)
+    }
+
+    fn record(&mut self, change: Change) {
+        self.changes.push(change);
+    }
+
+    /// Copies `source` to `path`, with the permissions the permission
+    /// policy asks for, and its extended attributes if they are kept.
+    fn copy_file(&self, source: &Path, path: &Path) -> io::Result<()> {
+        match self.permission_policy {
+            PermissionPolicy::Preserve => {
+                self.fs.copy(source, path)?;
+                self.fs.copy_owner(source, path)?;
+            }
+            PermissionPolicy::Umask => self.fs.write(path, &self.fs.read(source)?)?,
+        }
+        if self.preserve_xattrs {
+            let names = self.fs.copy_xattrs(source, path)?;
+            if !names.is_empty() {
+                self.observers.notify(Event::AttributesDropped {
+                    path: path.to_path_buf(),
+                    names,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts a link to `target` at `path`, in place of whatever is
+    /// there, by making it next to `path` and renaming it over it.
+    fn replace_with_link(&self, path: &Path, target: &Path) -> io::Result<()> {
+        let mut temp_name = OsString::from(".");
+        temp_name.push(path.file_name().unwrap_or_default());
+        temp_name.push(".hermit-link");
+        let temp = path.with_file_name(temp_name);
+        let _ = self.fs.remove_file(&temp);
+        self.fs.symlink(target, &temp)?;
+        self.fs.rename(&temp, path).inspect_err(|_| {
+            let _ = self.fs.remove_file(&temp);
+        })
+    }
+
+    /// The change for a file that was just created, remembering its
+    /// contents so that undoing it leaves the file alone if it has
+    /// changed since.
+    fn created_file(&self, path: PathBuf) -> io::Result<Change> {
+        let hash = hex(&Sha256::digest(&self.fs.read(&path)?));
+        Ok(Change::CreatedFile { path, hash })
+    }
+
+    fn create_parent_dirs(&mut self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) => self.create_dir_all(parent),
+            None => Ok(()),
+        }
+    }
+
+    fn create_dir_all(&mut self, dir: &Path) -> io::Result<()> {
+        let mut created = dir
+            .ancestors()
+            .take_while(|dir| !self.fs.exists(dir))
+            .map(|dir| Change::CreatedDir {
+                path: dir.to_path_buf(),
+            })
+            .collect::<Vec<_>>();
+        self.fs.create_dir_all(dir)?;
+        created.reverse();
+        self.changes.extend(created);
+        Ok(())
+    }
+
+    /// Sends the file at `path` to the trash, or deletes it if
+    /// removals are permanent.
+    fn discard(&self, path: &Path) -> anyhow::Result<Change> {
+        if !self.permanent_removal() {
+            self.fs.trash(path)?;
+            return Ok(Change::irreversible(format!(
+                "moved {} to the trash",
+                message::path(path)
+            )));
+        }
+        self.fs.remove(path)?;
+        Ok(Change::irreversible(format!(
+            "removed {}",
+            message::path(path)
+        )))
+    }
+
+    /// Deals with whatever is at `path` according to the conflict
+    /// policy, unless it is something that can simply be replaced.
+    fn clear_the_way(
+        &mut self,
+        path: &Path,
+        replaceable: impl Fn(FileKind) -> bool,
+    ) -> anyhow::Result<()> {
+        match self.fs.kind(path) {
+            Ok(kind) if !replaceable(kind) => (),
+            _ => return Ok(()),
+        }
+
+        self.observers.notify(Event::ConflictDetected {
+            path: path.to_path_buf(),
+        });
+        match self.conflict_policy {
+            ConflictPolicy::Error => {
+                anyhow::bail!(
+                    "{} is in the way, use --force to replace it",
+                    message::path(path)
+                )
+            }
+            ConflictPolicy::Overwrite => {
+                let change = self.discard(path)?;
+                self.record(change);
+            }
+            ConflictPolicy::Backup => {
+                let backup = backup_path_in(self.fs.as_ref(), path);
+                self.fs.rename(path, &backup)?;
+                self.record(Change::MovedAside {
+                    path: path.to_path_buf(),
+                    backup,
+                });
+            }
+        }
         Ok(())
     }
 }
 
-fn git_init(dir: PathBuf, options: &git2::RepositoryInitOptions) -> Result {
-    git2::Repository::init_opts(dir, options)
-        .map(|_| ())
-        .map_err(anyhow::Error::from)
+/// Works out an order to carry out `operations` in, so that each one
+/// comes after the ones it depends on:
+///
+/// - directories are created before what goes in them,
+/// - whatever is at a path is removed before something new is put
+///   there,
+/// - a file is created before it is read, and read before it, or the
+///   directory it is in, is removed, and
+/// - what is in a directory is moved out or removed before the
+///   directory is.
+///
+/// Operations that don't depend on each other are carried out in the
+/// order they were queued in. Returns the indexes of the operations in
+/// that order, and separately those that depend on each other in a
+/// cycle.
+fn execution_order(operations: &[Op]) -> (Vec<usize>, Vec<usize>) {
+    let index = |paths: fn(&Op) -> Option<&Path>| {
+        let mut by_path = BTreeMap::<&Path, Vec<usize>>::new();
+        for (i, op) in operations.iter().enumerate() {
+            if let Some(path) = paths(op) {
+                by_path.entry(path).or_default().push(i);
+            }
+        }
+        by_path
+    };
+    let creators = index(Op::creates);
+    let removers = index(Op::removes);
+    let readers = index(Op::reads);
+    let at = |by_path: &BTreeMap<&Path, Vec<usize>>, path: &Path| {
+        by_path.get(path).cloned().unwrap_or_default()
+    };
+
+    let mut dependents = vec![BTreeSet::new(); operations.len()];
+    for (j, op) in operations.iter().enumerate() {
+        let mut depends_on = vec![];
+        if let Some(path) = op.creates() {
+            for ancestor in path.ancestors() {
+                if ancestor != path {
+                    depends_on.extend(at(&creators, ancestor));
+                }
+                depends_on.extend(at(&removers, ancestor));
+            }
+        }
+        if let Some(path) = op.reads() {
+            for ancestor in path.ancestors() {
+                depends_on.extend(at(&creators, ancestor));
+            }
+        }
+        if let Some(path) = op.removes() {
+            let below = |by_path: &BTreeMap<&Path, Vec<usize>>, inclusive: bool| {
+                by_path
+                    .range(path..)
+                    .take_while(|(below, _)| below.starts_with(path))
+                    .filter(|(below, _)| inclusive || **below != path)
+                    .flat_map(|(_, ops)| ops.clone())
+                    .collect::<Vec<_>>()
+            };
+            depends_on.extend(below(&readers, true));
+            depends_on.extend(below(&removers, false));
+        }
+        for i in depends_on {
+            if i != j {
+                dependents[i].insert(j);
+            }
+        }
+    }
+
+    let mut waiting_on = vec![0; operations.len()];
+    for j in dependents.iter().flatten() {
+        waiting_on[*j] += 1;
+    }
+    let mut ready = (0..operations.len())
+        .filter(|&i| waiting_on[i] == 0)
+        .map(Reverse)
+        .collect::<BinaryHeap<_>>();
+    let mut order = vec![];
+    while let Some(Reverse(i)) = ready.pop() {
+        order.push(i);
+        for &j in &dependents[i] {
+            waiting_on[j] -= 1;
+            if waiting_on[j] == 0 {
+                ready.push(Reverse(j));
+            }
+        }
+    }
+    let cyclic = (0..operations.len())
+        .filter(|&i| waiting_on[i] > 0)
+        .collect();
+    (order, cyclic)
+}
+
+/// Whether `path` is a link to `source` or an identical copy of it.
+pub(crate) fn is_put_in_place(fs: &dyn FsBackend, path: &Path, source: &Path) -> bool {
+    // Through a link to its directory, `path` is the shell's own file,
+    // which mustn't be removed.
+    !fs_backend::is_reached_through_link(fs, path, source)
+        && (fs.read_link(path).is_ok_and(|link| link == source) || is_copy_of(fs, path, source))
+}
+
+/// Whether `path` is a file with the same contents as `source`.
+fn is_copy_of(fs: &dyn FsBackend, path: &Path, source: &Path) -> bool {
+    fs.kind(path).is_ok_and(|kind| kind == FileKind::File)
+        && matches!((fs.read(path), fs.read(source)), (Ok(copy), Ok(original)) if copy == original)
+}
+
+/// A path next to `path` that nothing exists at, to move a file that
+/// is in the way to.
+pub fn backup_path(path: &Path) -> PathBuf {
+    backup_path_in(&RealFs, path)
+}
+
+fn backup_path_in(fs: &dyn FsBackend, path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".hermit-backup");
+    let mut backup = path.with_file_name(&name);
+    let mut count = 1;
+    while fs.exists(&backup) {
+        let mut numbered = name.clone();
+        numbered.push(format!(".{}", count));
+        backup = path.with_file_name(numbered);
+        count += 1;
+    }
+    backup
+}
+
+fn git_init(dir: &Path, options: &git2::RepositoryInitOptions) -> anyhow::Result<()> {
+    let repo = git2::Repository::init_opts(dir, options)?;
+    git::set_dotfile_defaults(&repo)?;
+    Ok(())
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrows_for_generic_args)]
 mod tests {
     use std::{
         fs,
+        os::unix::fs::PermissionsExt,
         path::{Path, PathBuf},
     };
 
-    use super::FileOperations;
+    use super::{CommitReport, ConflictPolicy, FileOperations, Op, Outcome, PermissionPolicy};
     use crate::test_helpers::filesystem::set_up;
+    use crate::{
+        common::Rc,
+        fs_backend::{FsBackend, MemoryFs},
+        journal::Change,
+        observer::{Event, Recorder},
+    };
 
     #[test]
     fn can_link_file() {
@@ -119,6 +1140,30 @@ mod tests {
         };
     }
 
+    #[test]
+    fn links_and_copies_create_the_directories_they_go_in() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let target_path = test_root.join("target_file");
+        fs::write(&target_path, "contents").unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.link(".config/kitty/kitty.conf", &target_path);
+        file_set.copy(".gnupg/gpg.conf", &target_path);
+        for result in file_set.commit() {
+            result.expect("Op failed");
+        }
+
+        assert_eq!(
+            fs::read_link(test_root.join(".config/kitty/kitty.conf")).unwrap(),
+            target_path
+        );
+        assert_eq!(
+            fs::read_to_string(test_root.join(".gnupg/gpg.conf")).unwrap(),
+            "contents"
+        );
+    }
+
     #[test]
     fn does_not_link_file_without_commit() {
         let test_root = PathBuf::from("no-link");
@@ -131,11 +1176,460 @@ mod tests {
         assert!(!link_path.exists());
     }
 
+    #[test]
+    fn flushing_executes_queued_operations_and_empties_the_queue() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+
+        file_set.create_dir("test");
+        let results = file_set.flush();
+
+        assert_eq!(results.len(), 1);
+        results[0].as_ref().expect("Op failed");
+        assert!(test_root.join("test").is_dir());
+        assert!(file_set.operations().is_empty());
+    }
+
+    #[test]
+    fn can_copy_a_file_over_another() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+        fs::write(test_root.join("source"), "new").unwrap();
+        fs::write(test_root.join("copy"), "old").unwrap();
+
+        file_set.copy("copy", test_root.join("source"));
+        assert_eq!(fs::read_to_string(test_root.join("copy")).unwrap(), "old");
+        let results = file_set.commit();
+
+        assert_eq!(results.len(), 1);
+        results[0].as_ref().expect("Op failed");
+        assert_eq!(fs::read_to_string(test_root.join("copy")).unwrap(), "new");
+    }
+
+    #[test]
+    fn copies_take_their_permissions_from_the_policy() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let source = test_root.join("netrc");
+        fs::write(&source, "secret").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o600)).unwrap();
+        let mode = |name: &str| {
+            fs::metadata(test_root.join(name))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        };
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.copy("preserved", &source);
+        file_set.flush()[0].as_ref().expect("Op failed");
+        file_set.set_permission_policy(PermissionPolicy::Umask);
+        file_set.copy("fresh", &source);
+        file_set.commit()[0].as_ref().expect("Op failed");
+
+        assert_eq!(mode("preserved"), 0o600);
+        assert_ne!(mode("fresh"), 0o600);
+        assert_eq!(
+            fs::read_to_string(test_root.join("fresh")).unwrap(),
+            "secret"
+        );
+    }
+
+    #[cfg(feature = "xattrs")]
+    #[test]
+    fn copies_can_keep_extended_attributes() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let source = test_root.join("source");
+        fs::write(&source, "contents").unwrap();
+        if xattr::set(&source, "user.hermit", b"kept").is_err() {
+            // The temporary directory's filesystem can't store them.
+            return;
+        }
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.copy("plain", &source);
+        file_set.flush()[0].as_ref().expect("Op failed");
+        file_set.set_preserve_xattrs(true);
+        file_set.copy("kept", &source);
+        file_set.flush()[0].as_ref().expect("Op failed");
+
+        let attribute = |name: &str| xattr::get(test_root.join(name), "user.hermit").unwrap();
+        assert_eq!(attribute("plain"), None);
+        assert_eq!(attribute("kept"), Some(b"kept".to_vec()));
+    }
+
+    #[test]
+    fn relinking_an_existing_link_is_unchanged() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let target_path = test_root.join("target_file");
+        fs::File::create(&target_path).unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.link("link", &target_path);
+        file_set.link("link", &target_path);
+        let results = file_set.commit();
+
+        assert_eq!(results[0].as_ref().unwrap(), &Outcome::Changed);
+        assert_eq!(results[1].as_ref().unwrap(), &Outcome::Unchanged);
+    }
+
+    #[test]
+    fn linking_over_a_different_link_is_an_error() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        std::os::unix::fs::symlink(test_root.join("other"), test_root.join("link")).unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.link("link", test_root.join("target_file"));
+        let results = file_set.commit();
+
+        assert!(results[0].is_err());
+    }
+
+    #[test]
+    fn observers_hear_about_operations_as_they_happen() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let target = test_root.join("target_file");
+        fs::write(test_root.join("taken"), "in the way").unwrap();
+        let recorder = Rc::new(Recorder::default());
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.add_observer(recorder.clone());
+        file_set.link("link", &target);
+        file_set.link("taken", &target);
+        file_set.commit();
+
+        let events = recorder.events();
+        assert_eq!(
+            events[..3],
+            [
+                Event::FileLinked {
+                    path: test_root.join("link"),
+                    target: target.clone(),
+                },
+                Event::OpDone {
+                    description: format!("link {}", test_root.join("link").display()),
+                    outcome: Outcome::Changed,
+                },
+                Event::ConflictDetected {
+                    path: test_root.join("taken"),
+                },
+            ]
+        );
+        assert!(matches!(
+            &events[3],
+            Event::OpFailed { description, .. } if description.starts_with("link ")
+        ));
+        assert_eq!(
+            events[4],
+            Event::OperationsDone {
+                changed: 1,
+                failed: 1
+            }
+        );
+    }
+
+    #[test]
+    fn forcing_replaces_what_is_in_the_way() {
+        let fs = Rc::new(MemoryFs::new());
+        fs.add_file("/home/geoff/link", "in the way");
+        fs.add_file("/home/geoff/dir/notes", "mine");
+        let target_path = Path::new("/hermit/shells/work/target_file");
+
+        let mut file_set = FileOperations::with_backend("/home/geoff", fs.clone());
+        file_set.set_conflict_policy(ConflictPolicy::Overwrite);
+        file_set.link("link", target_path);
+        file_set.link("dir", target_path);
+        let permanent = file_set.permanent_removal();
+        let results = file_set.commit();
+
+        for result in results {
+            result.expect("Op failed");
+        }
+        assert_eq!(
+            fs.read_link(Path::new("/home/geoff/link")).unwrap(),
+            target_path
+        );
+        assert_eq!(
+            fs.read_link(Path::new("/home/geoff/dir")).unwrap(),
+            target_path
+        );
+        // What was in the way can be got back, unless removals are
+        // permanent in this build.
+        let trashed = if permanent {
+            vec![]
+        } else {
+            vec![
+                PathBuf::from("/home/geoff/link"),
+                PathBuf::from("/home/geoff/dir"),
+            ]
+        };
+        assert_eq!(fs.trashed(), trashed);
+    }
+
+    #[test]
+    fn backing_up_moves_what_is_in_the_way_aside() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let target_path = test_root.join("target_file");
+        fs::write(test_root.join(".bashrc"), "mine").unwrap();
+        fs::write(test_root.join(".bashrc.hermit-backup"), "older").unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.set_conflict_policy(ConflictPolicy::Backup);
+        file_set.link(".bashrc", &target_path);
+        file_set.commit()[0].as_ref().expect("Op failed");
+
+        assert_eq!(
+            fs::read_link(test_root.join(".bashrc")).unwrap(),
+            target_path
+        );
+        assert_eq!(
+            fs::read_to_string(test_root.join(".bashrc.hermit-backup.1")).unwrap(),
+            "mine"
+        );
+    }
+
+    #[test]
+    fn records_what_it_changed() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let target_path = test_root.join("target_file");
+        fs::write(test_root.join(".bashrc"), "mine").unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.set_conflict_policy(ConflictPolicy::Backup);
+        file_set.create_dir(".config/kitty");
+        file_set.link(".bashrc", &target_path);
+        for result in file_set.flush() {
+            result.expect("Op failed");
+        }
+
+        assert_eq!(
+            file_set.take_changes(),
+            vec![
+                Change::CreatedDir {
+                    path: test_root.join(".config")
+                },
+                Change::CreatedDir {
+                    path: test_root.join(".config/kitty")
+                },
+                Change::MovedAside {
+                    path: test_root.join(".bashrc"),
+                    backup: test_root.join(".bashrc.hermit-backup"),
+                },
+                Change::CreatedLink {
+                    path: test_root.join(".bashrc"),
+                    target: target_path,
+                },
+            ]
+        );
+        assert_eq!(file_set.changes(), &vec![]);
+    }
+
+    #[test]
+    fn links_are_replaced_in_one_go() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let old = test_root.join("old");
+        let new = test_root.join("new");
+        fs::write(&old, "old").unwrap();
+        fs::write(&new, "new").unwrap();
+        std::os::unix::fs::symlink(&old, test_root.join(".bashrc")).unwrap();
+        fs::write(test_root.join(".inputrc"), "old").unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.unlink(".bashrc", &old);
+        file_set.unlink(".inputrc", &old);
+        file_set.unlink(".vimrc", &old);
+        file_set.link(".bashrc", &new);
+        file_set.link(".inputrc", &new);
+        file_set.merge_relinks();
+        assert_eq!(
+            file_set.operations(),
+            &vec![
+                Op::Unlink {
+                    path: test_root.join(".vimrc"),
+                    source: old.clone(),
+                },
+                Op::Relink {
+                    path: test_root.join(".bashrc"),
+                    source: old.clone(),
+                    target: new.clone(),
+                },
+                Op::Relink {
+                    path: test_root.join(".inputrc"),
+                    source: old.clone(),
+                    target: new.clone(),
+                },
+            ]
+        );
+        for result in file_set.flush() {
+            result.expect("Op failed");
+        }
+
+        for name in &[".bashrc", ".inputrc"] {
+            assert_eq!(fs::read_link(test_root.join(name)).unwrap(), new);
+            assert!(!test_root.join(format!(".{}.hermit-link", name)).exists());
+        }
+        assert_eq!(
+            file_set.take_changes(),
+            vec![
+                Change::RemovedLink {
+                    path: test_root.join(".bashrc"),
+                    target: old.clone(),
+                },
+                Change::CreatedLink {
+                    path: test_root.join(".bashrc"),
+                    target: new.clone(),
+                },
+                Change::RemovedCopy {
+                    path: test_root.join(".inputrc"),
+                    source: old,
+                },
+                Change::CreatedLink {
+                    path: test_root.join(".inputrc"),
+                    target: new,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn copies_only_replace_regular_files_by_default() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::write(test_root.join("source"), "new").unwrap();
+        fs::create_dir(test_root.join("copy")).unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.copy("copy", test_root.join("source"));
+        let results = file_set.commit();
+
+        assert!(results[0].is_err());
+        assert!(test_root.join("copy").is_dir());
+    }
+
+    #[test]
+    fn satisfied_operations_are_unchanged() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::create_dir(test_root.join("dir")).unwrap();
+        fs::write(test_root.join("source"), "contents").unwrap();
+        fs::write(test_root.join("copy"), "contents").unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.create_dir("dir");
+        file_set.remove("already-gone");
+        file_set.copy("copy", test_root.join("source"));
+        file_set.write_file("source", "contents");
+        let results = file_set.commit();
+
+        assert_eq!(results.len(), 4);
+        for result in results {
+            assert_eq!(result.expect("Op failed"), Outcome::Unchanged);
+        }
+    }
+
+    #[test]
+    fn unlinking_only_removes_links_and_copies() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let source = test_root.join("source");
+        fs::write(&source, "contents").unwrap();
+        std::os::unix::fs::symlink(&source, test_root.join("link")).unwrap();
+        fs::write(test_root.join("copy"), "contents").unwrap();
+        fs::write(test_root.join("mine"), "other contents").unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.unlink("link", &source);
+        file_set.unlink("copy", &source);
+        file_set.unlink("mine", &source);
+        let results = file_set
+            .commit()
+            .into_iter()
+            .map(|result| result.expect("Op failed"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            results,
+            vec![Outcome::Changed, Outcome::Changed, Outcome::Unchanged]
+        );
+        assert!(!test_root.join("link").exists());
+        assert!(!test_root.join("copy").exists());
+        assert!(test_root.join("mine").exists());
+    }
+
+    #[test]
+    fn can_move_a_file_into_a_new_directory() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::write(test_root.join(".bashrc"), "mine").unwrap();
+        let destination = test_root.join("shell/.bashrc");
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.move_file(".bashrc", &destination);
+        file_set.commit()[0].as_ref().expect("Op failed");
+
+        assert!(!test_root.join(".bashrc").exists());
+        assert_eq!(fs::read_to_string(destination).unwrap(), "mine");
+    }
+
+    #[test]
+    fn can_move_and_link_unusual_paths() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let deep = (0..64).map(|n| format!("level {}", n)).collect::<PathBuf>();
+        let names = [
+            PathBuf::from(OsStr::from_bytes(b"caf\xe9")),
+            PathBuf::from("My Documents/notes.txt"),
+            deep.join("file"),
+        ];
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        for name in &names {
+            let path = test_root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "mine").unwrap();
+            let destination = test_root.join("shell").join(name);
+            file_set.move_file(name, &destination);
+            file_set.link(name, &destination);
+        }
+        for result in file_set.commit() {
+            result.expect("Op failed");
+        }
+
+        for name in &names {
+            assert_eq!(
+                fs::read_link(test_root.join(name)).unwrap(),
+                test_root.join("shell").join(name)
+            );
+            assert_eq!(fs::read_to_string(test_root.join(name)).unwrap(), "mine");
+        }
+    }
+
+    #[test]
+    fn removals_go_to_the_trash_by_default() {
+        let file_set = FileOperations::rooted_at("/");
+
+        assert_eq!(file_set.permanent_removal(), !cfg!(feature = "trash"));
+    }
+
     #[test]
     fn can_remove_file() {
         let test_root_dir = set_up();
         let test_root = test_root_dir.path();
         let mut file_set = FileOperations::rooted_at(&test_root);
+        // Keep the tests from filling up the trash.
+        file_set.set_permanent_removal(true);
 
         // Create file to remove
         fs::File::create(test_root.join("file_a")).unwrap();
@@ -161,6 +1655,47 @@ mod tests {
         assert!(file_path.exists());
     }
 
+    #[test]
+    fn can_write_a_file() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+
+        file_set.write_file("file_a", "contents");
+        assert!(!test_root.join("file_a").exists());
+        let results = file_set.commit();
+
+        assert_eq!(results.len(), 1);
+        results[0].as_ref().expect("Op failed");
+        assert_eq!(
+            fs::read_to_string(test_root.join("file_a")).unwrap(),
+            "contents"
+        );
+    }
+
+    #[test]
+    fn creating_a_file_leaves_an_existing_one_alone() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::write(test_root.join("existing"), "mine").unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.create_file("existing", "theirs");
+        file_set.create_file("new/file", "theirs");
+        let results = file_set.commit();
+
+        assert_eq!(results[0].as_ref().unwrap(), &Outcome::Unchanged);
+        assert_eq!(results[1].as_ref().unwrap(), &Outcome::Changed);
+        assert_eq!(
+            fs::read_to_string(test_root.join("existing")).unwrap(),
+            "mine"
+        );
+        assert_eq!(
+            fs::read_to_string(test_root.join("new/file")).unwrap(),
+            "theirs"
+        );
+    }
+
     #[test]
     fn can_create_a_directory() {
         let test_root_dir = set_up();
@@ -216,6 +1751,26 @@ mod tests {
         assert!(test_root.join(".git").is_dir());
     }
 
+    #[test]
+    fn new_git_repos_get_dotfile_defaults() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+
+        file_set.set_initial_branch("trunk");
+        file_set.create_git_repo("repo");
+        file_set.commit();
+
+        let repo = git2::Repository::open(test_root.join("repo")).unwrap();
+        let config = repo.config().unwrap();
+        assert_eq!(
+            repo.find_reference("HEAD").unwrap().symbolic_target(),
+            Some("refs/heads/trunk")
+        );
+        assert_eq!(config.get_bool("core.autocrlf").ok(), Some(false));
+        assert_eq!(config.get_string("core.eol").ok().as_deref(), Some("lf"));
+    }
+
     #[test]
     fn does_not_init_without_commit() {
         let test_root_dir = set_up();
@@ -244,6 +1799,40 @@ mod tests {
         assert!(test_root.join(&path).join(".git").is_dir());
     }
 
+    #[test]
+    fn can_add_a_remote_to_a_new_repo() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+
+        file_set.create_git_repo("repo");
+        file_set.add_git_remote("repo", "origin", "https://example.com/dotfiles.git");
+        let results = file_set.commit();
+
+        assert_eq!(results.len(), 2);
+        results[1].as_ref().expect("Op failed");
+        let repo = git2::Repository::open(test_root.join("repo")).unwrap();
+        let remote = repo.find_remote("origin").unwrap();
+        assert_eq!(remote.url(), Some("https://example.com/dotfiles.git"));
+    }
+
+    #[test]
+    fn can_clone_a_git_repo() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        git2::Repository::init(test_root.join("origin")).unwrap();
+        let mut file_set = FileOperations::rooted_at(test_root);
+
+        let url = test_root.join("origin");
+        file_set.clone_git_repo(url.to_str().unwrap(), "copy", &Default::default());
+        assert!(!test_root.join("copy").exists());
+        let results = file_set.commit();
+
+        assert_eq!(results.len(), 1);
+        results[0].as_ref().expect("Op failed");
+        assert!(git2::Repository::open(test_root.join("copy")).is_ok());
+    }
+
     #[test]
     fn wont_re_init_an_already_existing_repository() {
         let test_root_dir = set_up();
@@ -258,4 +1847,174 @@ mod tests {
         results[0].as_ref().expect("Op failed");
         results[1].as_ref().expect_err("Op unexpectedly succeeded");
     }
+
+    #[test]
+    fn operations_can_be_carried_out_in_memory() {
+        let fs = Rc::new(MemoryFs::new());
+        fs.add_file("/hermit/shells/default/.gitconfig", "[user]");
+        fs.add_file("/home/geoff/.gitconfig", "mine");
+        let mut file_set = FileOperations::with_backend("/home/geoff", fs.clone());
+        file_set.set_conflict_policy(ConflictPolicy::Backup);
+
+        file_set.link(".gitconfig", "/hermit/shells/default/.gitconfig");
+        file_set.copy(".gnupg/gpg.conf", "/hermit/shells/default/.gitconfig");
+        for result in file_set.commit() {
+            result.expect("Op failed");
+        }
+
+        assert_eq!(
+            fs.read(Path::new("/home/geoff/.gitconfig")).unwrap(),
+            b"[user]"
+        );
+        assert_eq!(
+            fs.read(Path::new("/home/geoff/.gitconfig.hermit-backup"))
+                .unwrap(),
+            b"mine"
+        );
+        assert_eq!(
+            fs.read(Path::new("/home/geoff/.gnupg/gpg.conf")).unwrap(),
+            b"[user]"
+        );
+        assert!(!Path::new("/home/geoff/.gnupg/gpg.conf").exists());
+    }
+
+    #[test]
+    fn unlinking_never_removes_the_shells_own_file_through_a_directory_link() {
+        let fs = Rc::new(MemoryFs::new());
+        fs.add_file("/hermit/shells/default/.config/nvim/init.vim", "set number");
+        fs.create_dir_all(Path::new("/home/geoff/.config")).unwrap();
+        fs.symlink(
+            Path::new("/hermit/shells/default/.config/nvim"),
+            Path::new("/home/geoff/.config/nvim"),
+        )
+        .unwrap();
+        let mut file_set = FileOperations::with_backend("/home/geoff", fs.clone());
+
+        file_set.unlink(
+            ".config/nvim/init.vim",
+            "/hermit/shells/default/.config/nvim/init.vim",
+        );
+        let results = file_set.commit();
+
+        assert_eq!(results[0].as_ref().ok(), Some(&Outcome::Unchanged));
+        assert!(fs.exists(Path::new("/hermit/shells/default/.config/nvim/init.vim")));
+    }
+
+    #[test]
+    fn operations_are_carried_out_after_those_they_depend_on() {
+        let fs = Rc::new(MemoryFs::new());
+        fs.add_file("/hermit/shells/work/.bashrc", "work");
+        fs.add_file("/hermit/shells/old/.notes", "notes");
+        fs.create_dir_all(Path::new("/home/geoff")).unwrap();
+        fs.symlink(
+            Path::new("/hermit/shells/default/.bashrc"),
+            Path::new("/home/geoff/.bashrc"),
+        )
+        .unwrap();
+        let mut file_set = FileOperations::with_backend("/home/geoff", fs.clone());
+
+        file_set.remove("/hermit/shells/old");
+        file_set.link(".bashrc", "/hermit/shells/work/.bashrc");
+        file_set.copy(".inputrc", "/hermit/shells/work/.inputrc");
+        file_set.move_file("/hermit/shells/old/.notes", "/hermit/shells/work/.notes");
+        file_set.unlink(".bashrc", "/hermit/shells/default/.bashrc");
+        file_set.write_file("/hermit/shells/work/.inputrc", "set editing-mode vi");
+
+        assert_eq!(
+            file_set
+                .ordered_operations()
+                .into_iter()
+                .map(Op::describe)
+                .collect::<Vec<_>>(),
+            vec![
+                "move /hermit/shells/old/.notes",
+                "remove /hermit/shells/old",
+                "remove /home/geoff/.bashrc",
+                "link /home/geoff/.bashrc",
+                "write /hermit/shells/work/.inputrc",
+                "copy /home/geoff/.inputrc",
+            ]
+        );
+        file_set.set_permanent_removal(true);
+        for result in file_set.commit() {
+            result.expect("Op failed");
+        }
+        assert_eq!(
+            fs.read_link(Path::new("/home/geoff/.bashrc")).unwrap(),
+            Path::new("/hermit/shells/work/.bashrc")
+        );
+        assert_eq!(
+            fs.read(Path::new("/hermit/shells/work/.notes")).unwrap(),
+            b"notes"
+        );
+        assert!(!fs.exists(Path::new("/hermit/shells/old")));
+    }
+
+    #[test]
+    fn operations_that_depend_on_each_other_in_a_cycle_fail() {
+        let fs = Rc::new(MemoryFs::new());
+        fs.add_file("/home/geoff/a", "a");
+        fs.add_file("/home/geoff/b", "b");
+        let mut file_set = FileOperations::with_backend("/home/geoff", fs.clone());
+
+        file_set.move_file("a", "/home/geoff/b");
+        file_set.create_dir(".config");
+        file_set.move_file("b", "/home/geoff/a");
+        let results = file_set.commit();
+
+        assert_eq!(
+            results[0].as_ref().unwrap_err().to_string(),
+            "Can't move /home/geoff/a, since it depends on operations that depend on it in turn"
+        );
+        assert!(results[1].is_ok());
+        assert!(results[2].is_err());
+        assert_eq!(fs.read(Path::new("/home/geoff/a")).unwrap(), b"a");
+        assert!(fs.is_dir(Path::new("/home/geoff/.config")));
+    }
+
+    #[test]
+    fn failing_fast_skips_what_is_left_after_a_failure() {
+        let fs = Rc::new(MemoryFs::new());
+        fs.add_file("/hermit/shells/default/.bashrc", "");
+        fs.add_file("/home/geoff/.bashrc", "mine");
+        let queue = |file_set: &mut FileOperations| {
+            file_set.link(".bashrc", "/hermit/shells/default/.bashrc");
+            file_set.link(".vimrc", "/hermit/shells/default/.vimrc");
+        };
+
+        let mut file_set = FileOperations::with_backend("/home/geoff", fs.clone());
+        queue(&mut file_set);
+        let results = file_set.flush();
+        assert!(results[0].is_err());
+        assert_eq!(results[1].as_ref().unwrap(), &Outcome::Changed);
+
+        fs.remove_file(Path::new("/home/geoff/.vimrc")).unwrap();
+        file_set.set_fail_fast(true);
+        queue(&mut file_set);
+        let results = file_set.flush();
+        assert!(results[0].is_err());
+        assert_eq!(results[1].as_ref().unwrap(), &Outcome::Skipped);
+        assert!(!fs.exists(Path::new("/home/geoff/.vimrc")));
+    }
+
+    #[test]
+    fn commit_reports_count_what_happened() {
+        let fs = Rc::new(MemoryFs::new());
+        fs.add_file("/home/geoff/.bashrc", "mine");
+        let mut file_set = FileOperations::with_backend("/home/geoff", fs.clone());
+        file_set.create_dir(".");
+        file_set.create_dir(".config");
+        file_set.link(".bashrc", "/hermit/shells/default/.bashrc");
+
+        let report = file_set.commit();
+
+        assert_eq!((report.len(), report.changed(), report.failed()), (3, 1, 1));
+        assert!(!report.is_success());
+        assert_eq!(
+            report.to_string(),
+            "/home/geoff/.bashrc is in the way, use --force to replace it\n\
+             1 changed, 1 unchanged, 1 failed"
+        );
+        assert_eq!(CommitReport::default().to_string(), "nothing to do");
+    }
 }
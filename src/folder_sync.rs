@@ -0,0 +1,389 @@
+use crate::common::*;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fs_backend::{FsBackend, RealFs},
+    journal::{hex, os_path},
+    manifest::{LOCAL_DIR, METADATA_DIR},
+    status,
+};
+
+/// The file in a shell's local directory that remembers what its files
+/// were like the last time they were synced with a folder.
+pub const STATE_FILE_NAME: &str = "folder-sync.json";
+
+/// Where the state of a shell's syncs is kept.
+pub fn state_path(shell_root: &Path) -> PathBuf {
+    shell_root
+        .join(METADATA_DIR)
+        .join(LOCAL_DIR)
+        .join(STATE_FILE_NAME)
+}
+
+/// The size and modification time of a file, so that files that
+/// haven't changed since the last sync don't have to be hashed again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+struct Stamp {
+    size: u64,
+    /// Nanoseconds since the Unix epoch.
+    modified: u64,
+}
+
+impl Stamp {
+    fn of(metadata: &fs::Metadata) -> Stamp {
+        Stamp {
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_nanos() as u64),
+        }
+    }
+}
+
+/// A file as it was the last time both copies of it were the same.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Record {
+    #[serde(with = "os_path")]
+    path: PathBuf,
+    hash: String,
+    here: Stamp,
+    there: Stamp,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct State {
+    #[serde(default)]
+    files: Vec<Record>,
+}
+
+/// A file as it is now on one side of a sync.
+#[derive(Clone)]
+struct Seen {
+    hash: String,
+    stamp: Stamp,
+}
+
+/// What syncing a shell with a folder did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Files copied from the folder into the shell.
+    pub pulled: Vec<PathBuf>,
+    /// Files copied from the shell into the folder.
+    pub pushed: Vec<PathBuf>,
+    /// Files removed from the shell because they were removed from the
+    /// folder.
+    pub removed_here: Vec<PathBuf>,
+    /// Files removed from the folder because they were removed from the
+    /// shell.
+    pub removed_there: Vec<PathBuf>,
+    /// Files that changed on both sides since the last sync. They are
+    /// left alone until both sides are made the same again.
+    pub conflicts: Vec<PathBuf>,
+}
+
+impl SyncReport {
+    pub fn is_empty(&self) -> bool {
+        self.pulled.is_empty()
+            && self.pushed.is_empty()
+            && self.removed_here.is_empty()
+            && self.removed_there.is_empty()
+            && self.conflicts.is_empty()
+    }
+}
+
+/// Brings the files of the shell at `shell_root` and those in `folder`
+/// in step with each other, copying each file that changed on one side
+/// since the last sync to the other, and removing files that were
+/// removed. `state_path` is where what the files were like after the
+/// last sync is kept.
+///
+/// Git's own files and the shell's local directory are never synced.
+pub fn sync(shell_root: &Path, folder: &Path, state_path: &Path) -> io::Result<SyncReport> {
+    run(shell_root, folder, state_path, true)
+}
+
+/// What [`sync`] would do, without copying or removing anything, or
+/// keeping what the files are like now.
+pub fn preview(shell_root: &Path, folder: &Path, state_path: &Path) -> io::Result<SyncReport> {
+    run(shell_root, folder, state_path, false)
+}
+
+fn run(shell_root: &Path, folder: &Path, state_path: &Path, apply: bool) -> io::Result<SyncReport> {
+    let state = match fs::read_to_string(state_path) {
+        Ok(contents) => serde_json::from_str::<State>(&contents)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => State::default(),
+        Err(err) => return Err(err),
+    };
+    let mut last = state
+        .files
+        .into_iter()
+        .map(|record| (record.path.clone(), record))
+        .collect::<BTreeMap<_, _>>();
+
+    let paths = files(shell_root)?
+        .into_iter()
+        .chain(files(folder)?)
+        .chain(last.keys().cloned())
+        .collect::<BTreeSet<_>>();
+
+    let mut report = SyncReport::default();
+    let mut records = vec![];
+    for path in paths {
+        let base = last.remove(&path);
+        let here = look(
+            shell_root,
+            &path,
+            base.as_ref().map(|base| (&base.hash, base.here)),
+        )?;
+        let there = look(
+            folder,
+            &path,
+            base.as_ref().map(|base| (&base.hash, base.there)),
+        )?;
+        let base_hash = base.as_ref().map(|base| base.hash.as_str());
+        // A preview takes it that a copy would turn out like the file
+        // it was copied from.
+        let carry = |from: &Path, to: &Path, seen: &Seen| {
+            if apply {
+                copy(from, to, &path)
+            } else {
+                Ok(seen.clone())
+            }
+        };
+
+        let record = match (here, there) {
+            (Some(here), Some(there)) if here.hash == there.hash => Some((here, there)),
+            (Some(here), Some(there)) if base_hash == Some(&here.hash) => {
+                let here = carry(folder, shell_root, &there)?;
+                report.pulled.push(path.clone());
+                Some((here, there))
+            }
+            (Some(here), Some(there)) if base_hash == Some(&there.hash) => {
+                let there = carry(shell_root, folder, &here)?;
+                report.pushed.push(path.clone());
+                Some((here, there))
+            }
+            (Some(here), None) if base_hash.is_none() => {
+                let there = carry(shell_root, folder, &here)?;
+                report.pushed.push(path.clone());
+                Some((here, there))
+            }
+            (None, Some(there)) if base_hash.is_none() => {
+                let here = carry(folder, shell_root, &there)?;
+                report.pulled.push(path.clone());
+                Some((here, there))
+            }
+            (Some(here), None) if base_hash == Some(&here.hash) => {
+                if apply {
+                    fs::remove_file(shell_root.join(&path))?;
+                }
+                report.removed_here.push(path.clone());
+                None
+            }
+            (None, Some(there)) if base_hash == Some(&there.hash) => {
+                if apply {
+                    fs::remove_file(folder.join(&path))?;
+                }
+                report.removed_there.push(path.clone());
+                None
+            }
+            (None, None) => None,
+            _ => {
+                report.conflicts.push(path.clone());
+                if let Some(base) = base {
+                    records.push(base);
+                }
+                None
+            }
+        };
+
+        if let Some((here, there)) = record {
+            records.push(Record {
+                path,
+                hash: here.hash,
+                here: here.stamp,
+                there: there.stamp,
+            });
+        }
+    }
+
+    if !apply {
+        return Ok(report);
+    }
+    if let Some(parent) = state_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let state = serde_json::to_string_pretty(&State { files: records })
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    fs::write(state_path, state)?;
+    Ok(report)
+}
+
+/// The files below `root` that are synced, relative to it.
+fn files(root: &Path) -> io::Result<BTreeSet<PathBuf>> {
+    let local_dir = root.join(METADATA_DIR).join(LOCAL_DIR);
+    let mut files = BTreeSet::new();
+    for entry in WalkDir::new(root)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != ".git" && entry.path() != local_dir)
+    {
+        let entry = entry.map_err(io::Error::from)?;
+        if entry.file_type().is_file() {
+            if let Ok(relative) = entry.path().strip_prefix(root) {
+                files.insert(relative.to_path_buf());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Looks at a file on one side of a sync, reusing the hash from the
+/// last sync if the file's size and modification time haven't changed.
+fn look(root: &Path, path: &Path, last: Option<(&String, Stamp)>) -> io::Result<Option<Seen>> {
+    let full_path = root.join(path);
+    let metadata = match fs::symlink_metadata(&full_path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(None),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let stamp = Stamp::of(&metadata);
+    let hash = match last {
+        Some((hash, last_stamp)) if last_stamp == stamp => hash.clone(),
+        _ => hex(&status::content_hash(&full_path)?),
+    };
+    Ok(Some(Seen { hash, stamp }))
+}
+
+/// Copies a file from one side of a sync to the other, returning what
+/// the copy looks like.
+fn copy(from: &Path, to: &Path, path: &Path) -> io::Result<Seen> {
+    let destination = to.join(path);
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    RealFs.copy(&from.join(path), &destination)?;
+    Ok(Seen {
+        hash: hex(&status::content_hash(&destination)?),
+        stamp: Stamp::of(&fs::metadata(&destination)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    struct Sides {
+        _dir: tempfile::TempDir,
+        here: PathBuf,
+        there: PathBuf,
+        state: PathBuf,
+    }
+
+    impl Sides {
+        fn new() -> Sides {
+            let dir = set_up();
+            let here = dir.path().join("shell");
+            let there = dir.path().join("Dropbox/dotfiles");
+            fs::create_dir_all(&here).unwrap();
+            fs::create_dir_all(&there).unwrap();
+            Sides {
+                state: state_path(&here),
+                here,
+                there,
+                _dir: dir,
+            }
+        }
+
+        fn sync(&self) -> SyncReport {
+            sync(&self.here, &self.there, &self.state).expect("Sync failed")
+        }
+    }
+
+    #[test]
+    fn new_files_are_copied_both_ways() {
+        let sides = Sides::new();
+        fs::write(sides.here.join(".bashrc"), "here").unwrap();
+        fs::create_dir_all(sides.there.join(".config/git")).unwrap();
+        fs::write(sides.there.join(".config/git/config"), "there").unwrap();
+        fs::create_dir_all(sides.here.join(".git")).unwrap();
+        fs::write(sides.here.join(".git/HEAD"), "ref").unwrap();
+
+        let report = sides.sync();
+
+        assert_eq!(report.pushed, vec![PathBuf::from(".bashrc")]);
+        assert_eq!(report.pulled, vec![PathBuf::from(".config/git/config")]);
+        assert_eq!(
+            fs::read_to_string(sides.there.join(".bashrc")).unwrap(),
+            "here"
+        );
+        assert!(!sides.there.join(".git").exists());
+        assert!(!sides.there.join(".hermit/local").exists());
+        assert!(sides.sync().is_empty());
+    }
+
+    #[test]
+    fn changes_and_removals_follow_the_side_that_made_them() {
+        let sides = Sides::new();
+        fs::write(sides.here.join(".bashrc"), "one").unwrap();
+        fs::write(sides.here.join(".vimrc"), "set nu").unwrap();
+        sides.sync();
+
+        fs::write(sides.there.join(".bashrc"), "two, longer").unwrap();
+        fs::remove_file(sides.here.join(".vimrc")).unwrap();
+        let report = sides.sync();
+
+        assert_eq!(report.pulled, vec![PathBuf::from(".bashrc")]);
+        assert_eq!(report.removed_there, vec![PathBuf::from(".vimrc")]);
+        assert_eq!(
+            fs::read_to_string(sides.here.join(".bashrc")).unwrap(),
+            "two, longer"
+        );
+        assert!(!sides.there.join(".vimrc").exists());
+    }
+
+    #[test]
+    fn files_changed_on_both_sides_are_conflicts() {
+        let sides = Sides::new();
+        fs::write(sides.here.join(".bashrc"), "one").unwrap();
+        sides.sync();
+        fs::write(sides.here.join(".bashrc"), "two here").unwrap();
+        fs::write(sides.there.join(".bashrc"), "two there").unwrap();
+
+        assert_eq!(sides.sync().conflicts, vec![PathBuf::from(".bashrc")]);
+        assert_eq!(sides.sync().conflicts, vec![PathBuf::from(".bashrc")]);
+        assert_eq!(
+            fs::read_to_string(sides.there.join(".bashrc")).unwrap(),
+            "two there"
+        );
+
+        fs::write(sides.there.join(".bashrc"), "two here").unwrap();
+        assert!(sides.sync().is_empty());
+    }
+
+    #[test]
+    fn a_preview_reports_what_a_sync_would_do_without_doing_it() {
+        let sides = Sides::new();
+        fs::write(sides.here.join(".bashrc"), "here").unwrap();
+
+        let preview = preview(&sides.here, &sides.there, &sides.state).unwrap();
+
+        assert_eq!(preview.pushed, vec![PathBuf::from(".bashrc")]);
+        assert!(!sides.there.join(".bashrc").exists());
+        assert!(!sides.state.exists());
+        assert_eq!(sides.sync(), preview);
+    }
+}
@@ -0,0 +1,755 @@
+use crate::{common::*, parallel};
+
+use std::{
+    cell::RefCell, collections::BTreeMap, ffi::OsString, os::unix::fs::MetadataExt,
+    path::Component, time::SystemTime,
+};
+
+/// What is at a path, without following symlinks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// The filesystem that `FileOperations` and `FsConfig` read and
+/// change. `RealFs` is the one on disk, and `MemoryFs` keeps
+/// everything in memory so that hermit, or a plugin built on it, can be
+/// tested without touching the disk.
+///
+/// Git repositories can't live in memory, so the operations that create
+/// or change them always use the real filesystem.
+pub trait FsBackend {
+    /// What is at `path`, like `fs::symlink_metadata`.
+    fn kind(&self, path: &Path) -> io::Result<FileKind>;
+
+    /// The contents of the file at `path`, following symlinks.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// The names of the entries of the directory at `path`, sorted.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates or replaces the file at `path`. The directory it goes in
+    /// must exist.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    /// Creates a symlink at `path` pointing to `target`.
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let contents = self.read(from)?;
+        self.write(to, &contents)
+    }
+
+    /// Gives `to` the extended attributes of `from`, which on Linux
+    /// include its ACLs, and returns the names of those that `to`
+    /// couldn't be given, usually because its filesystem can't store
+    /// them. Filesystems without extended attributes have none to
+    /// give.
+    fn copy_xattrs(&self, _from: &Path, _to: &Path) -> io::Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// Gives `to` the owner and group of `from`, as far as hermit is
+    /// allowed to. Only root can give a file to someone else, so when
+    /// hermit runs as anyone else, `to` stays theirs. Filesystems that
+    /// don't keep track of owners leave it alone.
+    fn copy_owner(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Gets rid of whatever is at `path` in a way that the user can
+    /// take back, if the filesystem has a way to. Otherwise it is
+    /// removed for good.
+    fn trash(&self, path: &Path) -> io::Result<()> {
+        self.remove(path)
+    }
+
+    /// Removes whatever is at `path`, directory or not.
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        match self.kind(path)? {
+            FileKind::Dir => self.remove_dir_all(path),
+            FileKind::File | FileKind::Symlink => self.remove_file(path),
+        }
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.kind(path).is_ok()
+    }
+
+    /// Whether `path` is a directory, following symlinks.
+    fn is_dir(&self, path: &Path) -> bool {
+        let resolved = self.canonicalize(path);
+        matches!(
+            resolved.and_then(|path| self.kind(&path)),
+            Ok(FileKind::Dir)
+        )
+    }
+
+    /// What is at each of `paths`, in the same order.
+    fn kinds(&self, paths: &[PathBuf]) -> Vec<io::Result<FileKind>> {
+        paths.iter().map(|path| self.kind(path)).collect()
+    }
+
+    /// When whatever is at `path` last changed, following symlinks.
+    /// Filesystems that don't keep track of that say it is
+    /// unsupported.
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("can't tell when {} changed", path.display()),
+        ))
+    }
+
+    /// Where `path` really is, with every symlink along the way
+    /// followed and `.` and `..` resolved, like `fs::canonicalize`.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let mut resolved = PathBuf::new();
+        let mut rest = path
+            .components()
+            .rev()
+            .map(|part| part.as_os_str().to_owned())
+            .collect::<Vec<_>>();
+        let mut hops = 0;
+        while let Some(component) = rest.pop() {
+            match Path::new(&component).components().next() {
+                Some(Component::RootDir) => resolved = PathBuf::from("/"),
+                Some(Component::ParentDir) => {
+                    resolved.pop();
+                }
+                Some(Component::Normal(name)) => {
+                    let candidate = resolved.join(name);
+                    if self.kind(&candidate)? == FileKind::Symlink {
+                        hops += 1;
+                        if hops > MAX_SYMLINK_DEPTH {
+                            return Err(io::Error::other(format!(
+                                "too many levels of symlinks at {}",
+                                path.display()
+                            )));
+                        }
+                        let target = self.read_link(&candidate)?;
+                        rest.extend(
+                            target
+                                .components()
+                                .rev()
+                                .map(|part| part.as_os_str().to_owned()),
+                        );
+                    } else {
+                        resolved = candidate;
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+/// Whether `path` is `source` itself, reached through a link to a
+/// directory that `source` is in, rather than a link or copy of it.
+pub fn is_reached_through_link(fs: &dyn FsBackend, path: &Path, source: &Path) -> bool {
+    path.ancestors().skip(1).any(|ancestor| {
+        match (fs.read_link(ancestor), path.strip_prefix(ancestor)) {
+            (Ok(target), Ok(rest)) => target.join(rest) == source,
+            _ => false,
+        }
+    })
+}
+
+/// Whether `dir` is a directory with nothing in it but links to the
+/// files in `source`, and directories of the same, the way a directory
+/// looks when its files are linked one by one.
+pub fn is_linked_file_by_file(fs: &dyn FsBackend, dir: &Path, source: &Path) -> bool {
+    if fs.kind(dir).ok() != Some(FileKind::Dir) {
+        return false;
+    }
+    fs.read_dir(dir).is_ok_and(|names| {
+        names.iter().all(|name| {
+            let (path, source) = (dir.join(name), source.join(name));
+            match fs.kind(&path) {
+                Ok(FileKind::Symlink) => fs.read_link(&path).is_ok_and(|link| link == source),
+                Ok(FileKind::Dir) => is_linked_file_by_file(fs, &path, &source),
+                _ => false,
+            }
+        })
+    })
+}
+
+/// The filesystem on disk.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFs;
+
+impl FsBackend for RealFs {
+    fn kind(&self, path: &Path) -> io::Result<FileKind> {
+        let file_type = fs::symlink_metadata(path)?.file_type();
+        Ok(if file_type.is_symlink() {
+            FileKind::Symlink
+        } else if file_type.is_dir() {
+            FileKind::Dir
+        } else {
+            FileKind::File
+        })
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let mut names = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        names.sort();
+        Ok(names)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        unix::fs::symlink(target, path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    /// Copies permissions along with the contents. Where the
+    /// filesystem can (Btrfs, XFS and APFS can), the copy shares its
+    /// blocks with `from` until one of them changes, so that copies of
+    /// large files don't take up room twice. It is made next to `to`
+    /// and renamed over it, since a reflink can't replace a file.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut temp_name = OsString::from(".");
+        temp_name.push(to.file_name().unwrap_or_default());
+        temp_name.push(".hermit-copy");
+        let temp = to.with_file_name(temp_name);
+        if reflink_copy::reflink(from, &temp).is_err() {
+            let _ = fs::remove_file(&temp);
+            return fs::copy(from, to).map(|_| ());
+        }
+        fs::set_permissions(&temp, fs::metadata(from)?.permissions())
+            .and_then(|()| fs::rename(&temp, to))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&temp);
+            })
+    }
+
+    #[cfg(feature = "xattrs")]
+    fn copy_xattrs(&self, from: &Path, to: &Path) -> io::Result<Vec<String>> {
+        let names = match xattr::list(from) {
+            Ok(names) => names,
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut dropped = vec![];
+        for name in names {
+            let kept = match xattr::get(from, &name)? {
+                Some(value) => xattr::set(to, &name, &value).is_ok(),
+                None => true,
+            };
+            if !kept {
+                dropped.push(name.to_string_lossy().into_owned());
+            }
+        }
+        Ok(dropped)
+    }
+
+    fn copy_owner(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let metadata = fs::metadata(from)?;
+        match unix::fs::chown(to, Some(metadata.uid()), Some(metadata.gid())) {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+            result => result,
+        }
+    }
+
+    #[cfg(feature = "trash")]
+    fn trash(&self, path: &Path) -> io::Result<()> {
+        trash::delete(path).map_err(|err| io::Error::other(err.to_string()))
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    /// Looks at large directories from several threads at once.
+    fn kinds(&self, paths: &[PathBuf]) -> Vec<io::Result<FileKind>> {
+        parallel::map(paths, |path| self.kind(path))
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        fs::metadata(path)?.modified()
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+}
+
+/// How many symlinks in a row are followed before giving up, like the
+/// limit the kernel has.
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    File(Vec<u8>),
+    Dir,
+    Symlink(PathBuf),
+}
+
+/// A filesystem that only exists in memory. The root directory, and
+/// the empty path that relative paths start from, always exist.
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    nodes: RefCell<BTreeMap<PathBuf, Node>>,
+    trashed: RefCell<Vec<PathBuf>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// Creates a file along with the directories it goes in, for
+    /// setting up a test.
+    pub fn add_file(&self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)
+                .expect("a file is in the way of the directory");
+        }
+        self.nodes
+            .borrow_mut()
+            .insert(path.to_path_buf(), Node::File(contents.into()));
+    }
+
+    /// What has been sent to the trash, in the order it was sent.
+    pub fn trashed(&self) -> Vec<PathBuf> {
+        self.trashed.borrow().clone()
+    }
+
+    fn node(&self, path: &Path) -> io::Result<Node> {
+        if is_top(path) {
+            return Ok(Node::Dir);
+        }
+        self.nodes
+            .borrow()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+
+    /// The node at `path`, following symlinks.
+    fn resolve(&self, path: &Path) -> io::Result<(PathBuf, Node)> {
+        let mut path = path.to_path_buf();
+        for _ in 0..MAX_SYMLINK_DEPTH {
+            match self.node(&path)? {
+                Node::Symlink(target) => {
+                    path = path.parent().unwrap_or(Path::new("")).join(target);
+                }
+                node => return Ok((path, node)),
+            }
+        }
+        Err(io::Error::other(format!(
+            "too many levels of symlinks at {}",
+            path.display()
+        )))
+    }
+
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !self.is_dir(parent) => Err(not_found(parent)),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_vacant(&self, path: &Path) -> io::Result<()> {
+        if self.exists(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        self.check_parent(path)
+    }
+}
+
+fn is_top(path: &Path) -> bool {
+    path == Path::new("") || path == Path::new("/")
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} doesn't exist", path.display()),
+    )
+}
+
+impl FsBackend for MemoryFs {
+    fn kind(&self, path: &Path) -> io::Result<FileKind> {
+        Ok(match self.node(path)? {
+            Node::File(_) => FileKind::File,
+            Node::Dir => FileKind::Dir,
+            Node::Symlink(_) => FileKind::Symlink,
+        })
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.resolve(path)? {
+            (_, Node::File(contents)) => Ok(contents),
+            (path, _) => Err(io::Error::other(format!(
+                "{} is a directory",
+                path.display()
+            ))),
+        }
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.node(path)? {
+            Node::Symlink(target) => Ok(target),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a symlink", path.display()),
+            )),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let (path, node) = self.resolve(path)?;
+        if node != Node::Dir {
+            return Err(io::Error::other(format!(
+                "{} isn't a directory",
+                path.display()
+            )));
+        }
+        Ok(self
+            .nodes
+            .borrow()
+            .keys()
+            .filter(|entry| entry.parent() == Some(&path))
+            .filter_map(|entry| entry.file_name().map(ToOwned::to_owned))
+            .collect())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        for dir in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            match self.node(dir) {
+                Ok(Node::Dir) => (),
+                Ok(_) if self.is_dir(dir) => (),
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} isn't a directory", dir.display()),
+                    ))
+                }
+                Err(_) => {
+                    self.nodes.borrow_mut().insert(dir.to_path_buf(), Node::Dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let path = match self.resolve(path) {
+            Ok((_, Node::Dir)) => {
+                return Err(io::Error::other(format!(
+                    "{} is a directory",
+                    path.display()
+                )))
+            }
+            Ok((path, _)) => path,
+            Err(_) => path.to_path_buf(),
+        };
+        self.check_parent(&path)?;
+        self.nodes
+            .borrow_mut()
+            .insert(path, Node::File(contents.to_vec()));
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        self.check_vacant(path)?;
+        self.nodes
+            .borrow_mut()
+            .insert(path.to_path_buf(), Node::Symlink(target.to_path_buf()));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.node(from)?;
+        self.check_parent(to)?;
+        // Like a real rename, one over a link replaces the link rather
+        // than following it.
+        if self.kind(to).is_ok_and(|kind| kind == FileKind::Dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is a directory", to.display()),
+            ));
+        }
+
+        let mut nodes = self.nodes.borrow_mut();
+        let moved = nodes
+            .keys()
+            .filter(|path| path.starts_with(from))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in moved {
+            let node = nodes.remove(&path).expect("the path was just listed");
+            let relative = path.strip_prefix(from).expect("the path is below from");
+            let destination = if relative == Path::new("") {
+                to.to_path_buf()
+            } else {
+                to.join(relative)
+            };
+            nodes.insert(destination, node);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        if self.node(path)? == Node::Dir {
+            return Err(io::Error::other(format!(
+                "{} is a directory",
+                path.display()
+            )));
+        }
+        self.nodes.borrow_mut().remove(path);
+        Ok(())
+    }
+
+    /// Removes whatever is at `path`, remembering that it was trashed
+    /// rather than removed.
+    fn trash(&self, path: &Path) -> io::Result<()> {
+        self.remove(path)?;
+        self.trashed.borrow_mut().push(path.to_path_buf());
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.node(path)? != Node::Dir {
+            return Err(io::Error::other(format!(
+                "{} isn't a directory",
+                path.display()
+            )));
+        }
+        self.nodes
+            .borrow_mut()
+            .retain(|entry, _| !entry.starts_with(path));
+        Ok(())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        matches!(self.resolve(path), Ok((_, Node::Dir)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn copies_replace_files_without_leaving_anything_behind() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        fs::write(root.join("source"), "new").unwrap();
+        fs::write(root.join("copy"), "old").unwrap();
+
+        RealFs
+            .copy(&root.join("source"), &root.join("copy"))
+            .unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("copy")).unwrap(), "new");
+        assert_eq!(
+            RealFs.read_dir(root).unwrap(),
+            vec![OsString::from("copy"), OsString::from("source")]
+        );
+    }
+
+    #[test]
+    fn memory_files_can_be_written_and_read() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("/home/geoff/.config")).unwrap();
+        fs.write(Path::new("/home/geoff/.bashrc"), b"alias ll='ls -l'")
+            .unwrap();
+
+        assert_eq!(
+            fs.read_to_string(Path::new("/home/geoff/.bashrc")).unwrap(),
+            "alias ll='ls -l'"
+        );
+        assert_eq!(
+            fs.read_dir(Path::new("/home/geoff")).unwrap(),
+            vec![OsString::from(".bashrc"), OsString::from(".config")]
+        );
+        assert_eq!(
+            fs.write(Path::new("/home/sarah/.bashrc"), b"")
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn memory_paths_can_be_canonicalized() {
+        let fs = MemoryFs::new();
+        fs.add_file("/hermit/shells/default/.vimrc", "set nu");
+        fs.create_dir_all(Path::new("/home")).unwrap();
+        fs.symlink(Path::new("/hermit/shells"), Path::new("/home/shells"))
+            .unwrap();
+        fs.symlink(Path::new("../home/loop"), Path::new("/home/loop"))
+            .unwrap();
+
+        assert_eq!(
+            fs.canonicalize(Path::new("/home/shells/default/../default/.vimrc"))
+                .unwrap(),
+            PathBuf::from("/hermit/shells/default/.vimrc")
+        );
+        assert!(fs.canonicalize(Path::new("/home/loop")).is_err());
+    }
+
+    #[test]
+    fn memory_symlinks_are_followed_when_reading() {
+        let fs = MemoryFs::new();
+        fs.add_file("/hermit/shells/default/.vimrc", "set nu");
+        fs.create_dir_all(Path::new("/home")).unwrap();
+        fs.symlink(
+            Path::new("/hermit/shells/default/.vimrc"),
+            Path::new("/home/.vimrc"),
+        )
+        .unwrap();
+        fs.symlink(Path::new(".vimrc"), Path::new("/home/.exrc"))
+            .unwrap();
+
+        assert_eq!(
+            fs.kind(Path::new("/home/.exrc")).unwrap(),
+            FileKind::Symlink
+        );
+        assert_eq!(fs.read(Path::new("/home/.exrc")).unwrap(), b"set nu");
+        assert_eq!(
+            fs.symlink(Path::new("/elsewhere"), Path::new("/home/.vimrc"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::AlreadyExists
+        );
+    }
+
+    #[test]
+    fn memory_directories_move_and_go_away_with_their_contents() {
+        let fs = MemoryFs::new();
+        fs.add_file("/hermit/shells/work/.bashrc", "work");
+        fs.add_file("/hermit/shells/work/.config/git/config", "");
+
+        fs.rename(
+            Path::new("/hermit/shells/work"),
+            Path::new("/hermit/shells/old"),
+        )
+        .unwrap();
+        assert!(!fs.exists(Path::new("/hermit/shells/work/.bashrc")));
+        assert_eq!(
+            fs.read(Path::new("/hermit/shells/old/.bashrc")).unwrap(),
+            b"work"
+        );
+
+        fs.remove(Path::new("/hermit/shells/old")).unwrap();
+        assert_eq!(
+            fs.read_dir(Path::new("/hermit/shells")).unwrap(),
+            Vec::<OsString>::new()
+        );
+    }
+
+    /// A filesystem with only the methods that every backend has to
+    /// have, to try out the rest.
+    struct Minimal(MemoryFs);
+
+    impl FsBackend for Minimal {
+        fn kind(&self, path: &Path) -> io::Result<FileKind> {
+            self.0.kind(path)
+        }
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            self.0.read(path)
+        }
+        fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+            self.0.read_link(path)
+        }
+        fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+            self.0.read_dir(path)
+        }
+        fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+            self.0.create_dir_all(path)
+        }
+        fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+            self.0.write(path, contents)
+        }
+        fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+            self.0.symlink(target, path)
+        }
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.0.rename(from, to)
+        }
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            self.0.remove_file(path)
+        }
+        fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+            self.0.remove_dir_all(path)
+        }
+    }
+
+    #[test]
+    fn links_to_directories_are_directories() {
+        let fs = Minimal(MemoryFs::new());
+        fs.0.add_file("/hermit/shells/work/.config/nvim/init.vim", "set nu");
+        fs.0.create_dir_all(Path::new("/home/geoff/.config"))
+            .unwrap();
+        fs.symlink(
+            Path::new("/hermit/shells/work/.config/nvim"),
+            Path::new("/home/geoff/.config/nvim"),
+        )
+        .unwrap();
+        fs.symlink(Path::new("nvim"), Path::new("/home/geoff/.config/vim"))
+            .unwrap();
+
+        assert!(fs.is_dir(Path::new("/home/geoff/.config/nvim")));
+        assert!(fs.is_dir(Path::new("/home/geoff/.config/vim")));
+        assert!(!fs.is_dir(Path::new("/home/geoff/.config/nvim/init.vim")));
+        assert!(!fs.is_dir(Path::new("/home/geoff/.bashrc")));
+    }
+}
@@ -0,0 +1,1881 @@
+use crate::common::*;
+
+use std::{ffi::OsStr, io::IsTerminal, os::unix::ffi::OsStrExt};
+
+use git2::{Oid, Repository};
+use serde::{Deserialize, Serialize};
+
+impl From<git2::Error> for Error {
+    fn from(err: git2::Error) -> Error {
+        Error::Git(err.message().to_owned())
+    }
+}
+
+/// Opens the repository that a shell lives in. That is usually the
+/// shell directory itself, but it can also be a repository that the
+/// shell is a subdirectory of.
+pub fn open(repo_root: impl AsRef<Path>) -> Result<Repository> {
+    Ok(Repository::discover(repo_root)?)
+}
+
+/// Opens the repository that `root` is in, along with the path of
+/// `root` inside of it, so that paths relative to `root` can be
+/// turned into paths relative to the repository.
+fn open_scoped(root: &Path) -> Result<(Repository, PathBuf)> {
+    let repo = open(root)?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| Error::Git("bare repositories can't hold shells".to_owned()))?
+        .canonicalize()?;
+    let prefix = root
+        .canonicalize()?
+        .strip_prefix(&workdir)
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    Ok((repo, prefix))
+}
+
+/// How much of a repository to fetch when cloning it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CloneOptions {
+    /// Only fetch this branch, and check it out.
+    pub branch: Option<String>,
+    /// Only fetch this many commits of history.
+    pub depth: Option<u32>,
+}
+
+/// Clones `url` into `path`. libgit2 can't make shallow clones, so
+/// those are handed to the git command line tool instead.
+pub fn clone(url: &str, path: impl AsRef<Path>, options: &CloneOptions) -> Result<()> {
+    if let Some(depth) = options.depth {
+        return clone_shallow(url, path.as_ref(), depth, options.branch.as_deref());
+    }
+
+    let mut builder = git2::build::RepoBuilder::new();
+    if let Some(branch) = &options.branch {
+        builder.branch(branch);
+        builder.remote_create(move |repo, name, url| {
+            let refspec = format!("+refs/heads/{0}:refs/remotes/{1}/{0}", branch, name);
+            repo.remote_with_fetch(name, url, &refspec)
+        });
+    }
+    builder.clone(url, path.as_ref())?;
+    Ok(())
+}
+
+fn clone_shallow(url: &str, path: &Path, depth: u32, branch: Option<&str>) -> Result<()> {
+    let mut command = process::Command::new("git");
+    command.args(["clone", "--depth", &depth.to_string()]);
+    if let Some(branch) = branch {
+        command.args(["--branch", branch]);
+    }
+    command.arg(url).arg(path);
+    run_showing_errors(command, "git clone")
+}
+
+/// Runs a git command that talks to a remote, showing what it writes
+/// to stderr as it goes, and keeping the last of it as the reason it
+/// failed, if it does, since that is where git says what went wrong
+/// with the connection.
+fn run_showing_errors(mut command: process::Command, name: &str) -> Result<()> {
+    let failed = |reason: String| Error::CommandFailed(name.to_owned(), reason);
+    let mut child = command
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .map_err(|err| failed(err.to_string()))?;
+    let mut said = vec![];
+    if let Some(mut stderr) = child.stderr.take() {
+        let mut buffer = [0; 4096];
+        loop {
+            match stderr.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => {
+                    let _ = io::stderr().write_all(&buffer[..read]);
+                    said.extend_from_slice(&buffer[..read]);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(failed(err.to_string())),
+            }
+        }
+    }
+    let status = child.wait().map_err(|err| failed(err.to_string()))?;
+    if status.success() {
+        return Ok(());
+    }
+    let said = String::from_utf8_lossy(&said);
+    let lines = said
+        .split(['\n', '\r'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    let errors = lines
+        .iter()
+        .filter_map(|line| {
+            ["fatal: ", "error: ", "ssh: "]
+                .iter()
+                .find_map(|prefix| line.strip_prefix(prefix))
+        })
+        .collect::<Vec<_>>();
+    let reason = match (errors.is_empty(), lines.last()) {
+        (false, _) => errors.join("; "),
+        (true, Some(line)) => (*line).to_owned(),
+        (true, None) => status.to_string(),
+    };
+    Err(failed(reason))
+}
+
+/// Whether a message from git says that the network couldn't be used,
+/// rather than that something else went wrong.
+pub fn is_network_failure(message: &str) -> bool {
+    const SIGNS: &[&str] = &[
+        "could not resolve host",
+        "failed to resolve address",
+        "temporary failure in name resolution",
+        "name or service not known",
+        "network is unreachable",
+        "no route to host",
+        "connection timed out",
+        "operation timed out",
+        "connection refused",
+        "failed to connect to",
+    ];
+    let message = message.to_lowercase();
+    SIGNS.iter().any(|sign| message.contains(sign))
+}
+
+/// Who a commit is made by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    pub email: String,
+}
+
+/// Set to override who commits are made by, for hermit as for git.
+pub const AUTHOR_NAME_VAR: &str = "GIT_AUTHOR_NAME";
+pub const AUTHOR_EMAIL_VAR: &str = "GIT_AUTHOR_EMAIL";
+
+impl Identity {
+    /// Reads an identity written the way git writes them, as
+    /// `Name <email>`.
+    pub fn parse(identity: &str) -> Option<Identity> {
+        let (name, rest) = identity.split_once('<')?;
+        let email = rest.strip_suffix('>')?;
+        let name = name.trim();
+        if name.is_empty() || email.is_empty() || email.contains(['<', '>']) {
+            return None;
+        }
+        Some(Identity {
+            name: name.to_owned(),
+            email: email.to_owned(),
+        })
+    }
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} <{}>", self.name, self.email)
+    }
+}
+
+/// Who the commits that hermit makes in the repository at `repo_root`
+/// are made by. Like git, `GIT_AUTHOR_NAME` and `GIT_AUTHOR_EMAIL`
+/// take precedence over `user.name` and `user.email` in the git
+/// configuration.
+pub fn identity(repo_root: impl AsRef<Path>) -> Result<Identity> {
+    identity_of(&open(repo_root)?)
+}
+
+fn identity_of(repo: &Repository) -> Result<Identity> {
+    let config = repo.config()?;
+    let setting = |var: &str, key: &str| {
+        std::env::var(var)
+            .ok()
+            .or_else(|| config.get_string(key).ok())
+            .filter(|value| !value.is_empty())
+    };
+    match (
+        setting(AUTHOR_NAME_VAR, "user.name"),
+        setting(AUTHOR_EMAIL_VAR, "user.email"),
+    ) {
+        (Some(name), Some(email)) => Ok(Identity { name, email }),
+        _ => Err(Error::NoGitIdentity),
+    }
+}
+
+fn signature(repo: &Repository) -> Result<git2::Signature<'static>> {
+    let identity = identity_of(repo)?;
+    Ok(git2::Signature::now(&identity.name, &identity.email)?)
+}
+
+/// The kinds of keys that git signs commits with, as named by its
+/// `gpg.format` setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigningFormat {
+    OpenPgp,
+    Ssh,
+}
+
+/// The key that commits in a repository are signed with, and the
+/// program that signs with it, from git's `gpg.format`,
+/// `user.signingKey`, `gpg.program` and `gpg.ssh.program` settings.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SigningKey {
+    pub format: SigningFormat,
+    /// The key. For OpenPGP it defaults to the committer, the way git
+    /// does it.
+    pub key: String,
+    pub program: String,
+}
+
+/// The key that commits in the repository at `repo_root` are signed
+/// with.
+pub fn signing_key(repo_root: impl AsRef<Path>) -> Result<SigningKey> {
+    signing_key_of(&open(repo_root)?)
+}
+
+fn signing_key_of(repo: &Repository) -> Result<SigningKey> {
+    let config = repo.config()?;
+    let setting = |key: &str| {
+        config
+            .get_string(key)
+            .ok()
+            .filter(|value| !value.is_empty())
+    };
+    let key = setting("user.signingkey");
+    match setting("gpg.format").as_deref() {
+        None | Some("openpgp") => Ok(SigningKey {
+            format: SigningFormat::OpenPgp,
+            key: match key {
+                Some(key) => key,
+                None => identity_of(repo)?.to_string(),
+            },
+            program: setting("gpg.openpgp.program")
+                .or_else(|| setting("gpg.program"))
+                .unwrap_or_else(|| "gpg".to_owned()),
+        }),
+        Some("ssh") => Ok(SigningKey {
+            format: SigningFormat::Ssh,
+            key: key.ok_or_else(|| {
+                Error::CannotSign("user.signingKey isn't set to an SSH key".to_owned())
+            })?,
+            program: setting("gpg.ssh.program").unwrap_or_else(|| "ssh-keygen".to_owned()),
+        }),
+        Some(format) => Err(Error::CannotSign(format!(
+            "hermit can't sign with {} keys",
+            format
+        ))),
+    }
+}
+
+impl SigningKey {
+    /// The file an SSH key is kept in. Keys given literally, the way
+    /// git also allows, aren't supported.
+    fn ssh_key_file(&self) -> Result<PathBuf> {
+        if self.key.starts_with("key::") || self.key.starts_with("ssh-") {
+            return Err(Error::CannotSign(
+                "hermit can only sign with SSH keys kept in files".to_owned(),
+            ));
+        }
+        Ok(match self.key.strip_prefix("~/") {
+            Some(rest) => dirs::home_dir().unwrap_or_default().join(rest),
+            None => PathBuf::from(&self.key),
+        })
+    }
+
+    fn command(&self) -> Result<process::Command> {
+        let mut command = process::Command::new(&self.program);
+        match self.format {
+            SigningFormat::OpenPgp => {
+                command.args(["--detach-sign", "--armor", "--local-user", &self.key]);
+            }
+            SigningFormat::Ssh => {
+                command.args(["-Y", "sign", "-n", "git", "-f"]);
+                command.arg(self.ssh_key_file()?);
+            }
+        }
+        Ok(command)
+    }
+
+    /// Signs `contents`, and returns the signature.
+    pub fn sign(&self, contents: &str) -> Result<String> {
+        let failed = |reason: String| Error::CannotSign(format!("{}: {}", self.program, reason));
+        let mut child = self
+            .command()?
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .map_err(|err| failed(err.to_string()))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(contents.as_bytes())
+                .map_err(|err| failed(err.to_string()))?;
+        }
+        let output = child
+            .wait_with_output()
+            .map_err(|err| failed(err.to_string()))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(failed(match stderr.trim() {
+                "" => output.status.to_string(),
+                stderr => stderr.to_owned(),
+            }));
+        }
+        String::from_utf8(output.stdout).map_err(|_| failed("the signature isn't text".to_owned()))
+    }
+
+    /// Checks that the key can be signed with, short of signing
+    /// anything, which might ask for a passphrase.
+    pub fn check(&self) -> Result<()> {
+        let unavailable = |reason: String| Error::CannotSign(reason);
+        match self.format {
+            SigningFormat::OpenPgp => {
+                let output = process::Command::new(&self.program)
+                    .args(["--list-secret-keys", &self.key])
+                    .stdin(process::Stdio::null())
+                    .output()
+                    .map_err(|err| unavailable(format!("{}: {}", self.program, err)))?;
+                if !output.status.success() {
+                    return Err(unavailable(format!(
+                        "{} has no secret key for {}",
+                        self.program, self.key
+                    )));
+                }
+            }
+            SigningFormat::Ssh => {
+                let file = self.ssh_key_file()?;
+                if !file.is_file() {
+                    return Err(unavailable(format!(
+                        "there is no SSH key at {}",
+                        message::path(&file)
+                    )));
+                }
+                process::Command::new(&self.program)
+                    .arg("-?")
+                    .stdin(process::Stdio::null())
+                    .output()
+                    .map_err(|err| unavailable(format!("{}: {}", self.program, err)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether git's `commit.gpgSign` setting says to sign commits in
+/// `repo`.
+fn signs_commits(repo: &Repository) -> bool {
+    repo.config()
+        .and_then(|config| config.get_bool("commit.gpgsign"))
+        .unwrap_or(false)
+}
+
+/// Whether commits in the repository at `repo_root` are signed
+/// whether or not hermit is asked to.
+pub fn signs_commits_by_default(repo_root: impl AsRef<Path>) -> bool {
+    open(repo_root).is_ok_and(|repo| signs_commits(&repo))
+}
+
+/// The setting in a repository's own git configuration that has hermit
+/// check the signatures of the commits it links and syncs with. It is
+/// kept out of the shell's files, where the remote could turn it off.
+pub const VERIFY_SIGNATURES_KEY: &str = "hermit.verifySignatures";
+
+/// Has hermit check the signatures of the commits in the repository at
+/// `repo_root` from now on.
+pub fn require_signatures(repo_root: impl AsRef<Path>) -> Result<()> {
+    open(repo_root)?
+        .config()?
+        .open_level(git2::ConfigLevel::Local)?
+        .set_bool(VERIFY_SIGNATURES_KEY, true)?;
+    Ok(())
+}
+
+/// Whether hermit checks the signatures of the commits in the
+/// repository at `repo_root`.
+pub fn requires_signatures(repo_root: impl AsRef<Path>) -> bool {
+    open(repo_root)
+        .and_then(|repo| Ok(repo.config()?.get_bool(VERIFY_SIGNATURES_KEY)?))
+        .unwrap_or(false)
+}
+
+/// Checks that the commit `rev` names is signed by a key that git
+/// trusts, either itself or by a signed tag that points at it. git
+/// does the checking, so its settings, like
+/// `gpg.ssh.allowedSignersFile`, decide which keys it trusts.
+pub fn verify_signature(repo_root: impl AsRef<Path>, rev: &str) -> Result<()> {
+    let repo_root = repo_root.as_ref();
+    let repo = open(repo_root)?;
+    let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+    let id = commit.id().to_string();
+    let reason = match verify(repo_root, &["verify-commit", &id]) {
+        Ok(()) => return Ok(()),
+        Err(reason) => reason,
+    };
+    for name in repo.tag_names(None)?.iter().flatten() {
+        let tag = format!("refs/tags/{}", name);
+        let points_at_commit = repo
+            .revparse_single(&tag)
+            .and_then(|tagged| tagged.peel_to_commit())
+            .is_ok_and(|tagged| tagged.id() == commit.id());
+        if points_at_commit && verify(repo_root, &["verify-tag", &tag]).is_ok() {
+            return Ok(());
+        }
+    }
+    Err(Error::UnverifiedCommit {
+        commit: id[..7].to_owned(),
+        reason,
+    })
+}
+
+/// The full id of the commit that `rev` names, so that a commit that
+/// was checked is the one that gets used even if the ref moves.
+pub fn commit_id(repo_root: impl AsRef<Path>, rev: &str) -> Result<String> {
+    let repo = open(repo_root)?;
+    let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+    Ok(commit.id().to_string())
+}
+
+/// Runs one of git's `verify-*` commands, and returns why it failed if
+/// it did.
+fn verify(repo_root: &Path, args: &[&str]) -> StdResult<(), String> {
+    let output = process::Command::new("git")
+        .arg("-C")
+        .arg(repo_root)
+        .args(args)
+        .stdin(process::Stdio::null())
+        .output()
+        .map_err(|err| err.to_string())?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(stderr
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or("it isn't signed")
+        .trim()
+        .to_owned())
+}
+
+/// The name git would give the directory it clones `url` into, like
+/// `dotfiles` for `git@github.com:me/dotfiles.git`.
+pub fn repo_name_from_url(url: &str) -> Option<&str> {
+    let path = url.trim_end_matches('/');
+    let name = path.rsplit(['/', ':']).next()?;
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// When the files below `root` were last committed to, as a Unix
+/// timestamp, following the first parent of each commit back from
+/// HEAD. `None` if nothing below it has been committed yet.
+pub fn last_commit_time(root: impl AsRef<Path>) -> Result<Option<i64>> {
+    let (repo, prefix) = open_scoped(root.as_ref())?;
+    let head = match repo.head().and_then(|head| head.peel_to_commit()) {
+        Ok(head) => head,
+        Err(_) => return Ok(None),
+    };
+    if prefix.as_os_str().is_empty() {
+        return Ok(Some(head.time().seconds()));
+    }
+
+    let tree_of = |commit: &git2::Commit<'_>| {
+        commit
+            .tree()
+            .ok()?
+            .get_path(&prefix)
+            .ok()
+            .map(|entry| entry.id())
+    };
+    let mut walk = repo.revwalk()?;
+    walk.push(head.id())?;
+    walk.simplify_first_parent()?;
+    for id in walk {
+        let commit = repo.find_commit(id?)?;
+        let tree = tree_of(&commit);
+        let parent_tree = commit.parent(0).ok().and_then(|parent| tree_of(&parent));
+        if tree.is_some() && tree != parent_tree {
+            return Ok(Some(commit.time().seconds()));
+        }
+    }
+    Ok(None)
+}
+
+/// The version of libgit2 that hermit was built with.
+pub fn libgit2_version() -> String {
+    let (major, minor, patch) = git2::Version::get().libgit2_version();
+    format!("{}.{}.{}", major, minor, patch)
+}
+
+/// What `git --version` says, or `None` if git can't be run.
+pub fn cli_version() -> Option<String> {
+    let output = process::Command::new("git")
+        .arg("--version")
+        .stdin(process::Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let version = String::from_utf8_lossy(&output.stdout);
+    Some(version.trim().trim_start_matches("git version ").to_owned())
+}
+
+/// The branch new repositories start out on, from `init.defaultBranch`
+/// in the user's git configuration, or `main`.
+pub fn default_branch() -> String {
+    git2::Config::open_default()
+        .and_then(|config| config.get_string("init.defaultBranch"))
+        .unwrap_or_else(|_| "main".to_owned())
+}
+
+/// Configures a new repository for holding dotfiles. Their line
+/// endings are left alone when they are checked out, since a shell
+/// started on one system is often used on another, and a script with
+/// `\r\n` line endings won't run.
+pub fn set_dotfile_defaults(repo: &Repository) -> Result<()> {
+    let mut config = repo.config()?.open_level(git2::ConfigLevel::Local)?;
+    config.set_bool("core.autocrlf", false)?;
+    config.set_str("core.eol", "lf")?;
+    Ok(())
+}
+
+/// Whether a file in the repository differs from what was last
+/// committed, including files that git isn't tracking yet.
+pub fn has_changes(repo_root: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<bool> {
+    let (repo, prefix) = open_scoped(repo_root.as_ref())?;
+    let status = repo.status_file(&prefix.join(path))?;
+    Ok(!status.is_empty() && !status.contains(git2::Status::IGNORED))
+}
+
+/// Stages the given paths and commits them on top of `HEAD`, made by
+/// the user's `identity`. The commit is signed when `sign` is set, or
+/// git's `commit.gpgSign` setting says to sign it.
+pub fn commit_paths(
+    repo_root: impl AsRef<Path>,
+    paths: &[impl AsRef<Path>],
+    message: &str,
+    sign: bool,
+) -> Result<Oid> {
+    let (repo, prefix) = open_scoped(repo_root.as_ref())?;
+
+    let mut index = repo.index()?;
+    for path in paths {
+        index.add_path(&prefix.join(path))?;
+    }
+    index.write()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+
+    let signature = signature(&repo)?;
+    let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    let parents = parent.iter().collect::<Vec<_>>();
+
+    if !sign && !signs_commits(&repo) {
+        return Ok(repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )?);
+    }
+
+    // libgit2 leaves signing to the caller: the commit is written out,
+    // signed, and only then stored, after which HEAD has to be moved
+    // on to it by hand.
+    let contents = repo.commit_create_buffer(&signature, &signature, message, &tree, &parents)?;
+    let contents = contents
+        .as_str()
+        .ok_or_else(|| Error::Git("commit isn't valid UTF-8".to_owned()))?;
+    let gpgsig = signing_key_of(&repo)?.sign(contents)?;
+    let oid = repo.commit_signed(contents, &gpgsig, None)?;
+    let log_message = format!("commit: {}", message.lines().next().unwrap_or_default());
+    let head = repo.find_reference("HEAD")?;
+    match head.symbolic_target() {
+        Some(branch) => {
+            repo.reference(branch, oid, true, &log_message)?;
+        }
+        None => repo.set_head_detached(oid)?,
+    }
+    Ok(oid)
+}
+
+/// Whether files that git tracks under `repo_root` have changes that
+/// haven't been committed. Untracked files don't count.
+pub fn has_uncommitted_changes(repo_root: impl AsRef<Path>) -> Result<bool> {
+    let (repo, prefix) = open_scoped(repo_root.as_ref())?;
+    let mut options = git2::StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    if !prefix.as_os_str().is_empty() {
+        options.pathspec(&prefix);
+    }
+    let changed = !repo.statuses(Some(&mut options))?.is_empty();
+    Ok(changed)
+}
+
+/// Sets aside the uncommitted changes to the files that git tracks in
+/// the repository at `repo_root`, the way `git stash` does, and leaves
+/// the files as they were last committed. Returns whether there were
+/// any changes to set aside.
+pub fn stash(repo_root: impl AsRef<Path>, message: Option<&str>) -> Result<bool> {
+    let mut repo = open(repo_root)?;
+    let signature = signature(&repo)?;
+    match repo.stash_save2(&signature, message, None) {
+        Ok(_) => Ok(true),
+        Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Puts back the changes that were set aside most recently, and drops
+/// them from the stash. If they don't apply cleanly, they stay in the
+/// stash.
+pub fn stash_pop(repo_root: impl AsRef<Path>) -> Result<()> {
+    let mut repo = open(repo_root)?;
+    match repo.stash_pop(0, None) {
+        Ok(()) => Ok(()),
+        Err(err) if err.code() == git2::ErrorCode::NotFound => Err(Error::NothingStashed),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// How to settle a file that was changed both on this machine and in
+/// the remote.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictChoice {
+    /// Keep the file as this machine has it.
+    Local,
+    /// Take the file as the remote has it.
+    Remote,
+    /// Merge the two by hand, with `git mergetool`.
+    MergeTool,
+    /// Give up, and put everything back the way it was.
+    Abort,
+}
+
+/// Whether a rebase in the repository at `repo_root` has stopped part
+/// of the way through.
+pub fn rebase_in_progress(repo_root: impl AsRef<Path>) -> Result<bool> {
+    use git2::RepositoryState::*;
+    Ok(matches!(
+        open(repo_root)?.state(),
+        Rebase | RebaseInteractive | RebaseMerge
+    ))
+}
+
+/// The files with conflicts that haven't been settled yet, relative to
+/// the root of the repository.
+pub fn conflicted_paths(repo_root: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let repo = open(repo_root)?;
+    let mut paths = vec![];
+    for conflict in repo.index()?.conflicts()? {
+        let conflict = conflict?;
+        let entry = conflict.our.or(conflict.their).or(conflict.ancestor);
+        if let Some(entry) = entry {
+            paths.push(PathBuf::from(OsStr::from_bytes(&entry.path)));
+        }
+    }
+    Ok(paths)
+}
+
+/// Carries on with a rebase that stopped because of conflicts, asking
+/// `choose` how to settle each file, until the rebase is done. Files
+/// that still have conflicts after the merge tool are asked about
+/// again. If `choose` gives up, or fails, the rebase is aborted.
+pub fn resolve_rebase(
+    repo_root: impl AsRef<Path>,
+    mut choose: impl FnMut(&Path) -> Result<ConflictChoice>,
+) -> Result<()> {
+    let repo_root = repo_root.as_ref();
+    let workdir = open(repo_root)?
+        .workdir()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| Error::Git("bare repositories can't hold shells".to_owned()))?;
+    while rebase_in_progress(repo_root)? {
+        let conflicts = conflicted_paths(repo_root)?;
+        if conflicts.is_empty() {
+            abort_rebase(&workdir);
+            return Err(Error::CommandFailed(
+                "git rebase".to_owned(),
+                "it stopped without a conflict to settle".to_owned(),
+            ));
+        }
+        for path in &conflicts {
+            loop {
+                let choice = match choose(path) {
+                    Ok(choice) => choice,
+                    Err(err) => {
+                        abort_rebase(&workdir);
+                        return Err(err);
+                    }
+                };
+                // While rebasing, "ours" is what is being rebased onto,
+                // which came from the remote, and "theirs" is the
+                // commit of this machine being replayed on top of it.
+                match choice {
+                    ConflictChoice::Local => break take_side(&workdir, path, "--theirs")?,
+                    ConflictChoice::Remote => break take_side(&workdir, path, "--ours")?,
+                    ConflictChoice::MergeTool => {
+                        let _ = rebase_command(&workdir, &["mergetool", "--"], Some(path));
+                        if !conflicted_paths(repo_root)?.contains(path) {
+                            break;
+                        }
+                    }
+                    ConflictChoice::Abort => {
+                        abort_rebase(&workdir);
+                        return Err(Error::SyncConflicts(conflicts));
+                    }
+                }
+            }
+        }
+        if rebase_command(&workdir, &["rebase", "--continue"], None).is_err()
+            && rebase_in_progress(repo_root)?
+            && conflicted_paths(repo_root)?.is_empty()
+        {
+            // Settling the conflicts left the commit with no changes of
+            // its own, which git won't continue with.
+            let _ = rebase_command(&workdir, &["rebase", "--skip"], None);
+        }
+    }
+    Ok(())
+}
+
+/// Settles the conflict in `path` with one side's version of it, or by
+/// removing it if that side removed it.
+fn take_side(workdir: &Path, path: &Path, side: &str) -> Result<()> {
+    if rebase_command(workdir, &["checkout", side, "--"], Some(path)).is_ok() {
+        rebase_command(workdir, &["add", "--"], Some(path))
+    } else {
+        rebase_command(workdir, &["rm", "--quiet", "--"], Some(path))
+    }
+}
+
+fn abort_rebase(workdir: &Path) {
+    let _ = rebase_command(workdir, &["rebase", "--abort"], None);
+}
+
+/// Runs a git command that helps a rebase along, without letting it
+/// open an editor for commit messages.
+fn rebase_command(workdir: &Path, args: &[&str], path: Option<&Path>) -> Result<()> {
+    let command = format!("git {}", args.join(" "));
+    let status = process::Command::new("git")
+        .arg("-C")
+        .arg(workdir)
+        .args(args)
+        .args(path)
+        .env("GIT_EDITOR", "true")
+        .status()
+        .map_err(|err| Error::CommandFailed(command.clone(), err.to_string()))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::CommandFailed(command, status.to_string()))
+    }
+}
+
+/// An annotated tag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tag {
+    pub name: String,
+    pub message: String,
+    /// When the tag was made, in seconds since the Unix epoch.
+    pub time: i64,
+}
+
+/// Tags the commit at `HEAD` with an annotated tag, made by the user's
+/// `identity`.
+pub fn create_tag(repo_root: impl AsRef<Path>, name: &str, message: &str) -> Result<()> {
+    let repo = open(repo_root)?;
+    let head = repo.head()?.peel_to_commit()?;
+    let signature = signature(&repo)?;
+    repo.tag(name, head.as_object(), &signature, message, false)?;
+    Ok(())
+}
+
+/// The annotated tags of a repository whose names start with `prefix`,
+/// oldest first.
+pub fn tags(repo_root: impl AsRef<Path>, prefix: &str) -> Result<Vec<Tag>> {
+    let repo = open(repo_root)?;
+    let names = repo.tag_names(Some(&format!("{}*", prefix)))?;
+    let mut tags = vec![];
+    for name in names.iter().flatten() {
+        let tag = match repo
+            .revparse_single(&format!("refs/tags/{}", name))?
+            .into_tag()
+        {
+            Ok(tag) => tag,
+            Err(_) => continue,
+        };
+        tags.push(Tag {
+            name: name.to_owned(),
+            message: tag.message().unwrap_or_default().to_owned(),
+            time: tag.tagger().map_or(0, |tagger| tagger.when().seconds()),
+        });
+    }
+    tags.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.name.cmp(&b.name)));
+    Ok(tags)
+}
+
+/// Makes the files under `repo_root` what they were in the commit that
+/// `tag` points to, staging the changes on the current branch rather
+/// than moving it. Changes that haven't been committed are lost.
+pub fn restore_tag(repo_root: impl AsRef<Path>, tag: &str) -> Result<()> {
+    let (repo, prefix) = open_scoped(repo_root.as_ref())?;
+    let tree = repo
+        .revparse_single(&format!("refs/tags/{}", tag))?
+        .peel_to_tree()?;
+    let mut checkout = git2::build::CheckoutBuilder::new();
+    checkout.force();
+    if !prefix.as_os_str().is_empty() {
+        checkout.path(&prefix);
+    }
+    repo.checkout_tree(tree.as_object(), Some(&mut checkout))?;
+    Ok(())
+}
+
+/// The files in a repository that are staged to be committed, relative
+/// to `repo_root`, along with their staged contents. Deleted files and
+/// files outside of `repo_root` are left out.
+pub fn staged_files(repo_root: impl AsRef<Path>) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+    let (repo, prefix) = open_scoped(repo_root.as_ref())?;
+    let head = repo.head().ok().and_then(|head| head.peel_to_tree().ok());
+    let diff = repo.diff_tree_to_index(head.as_ref(), None, None)?;
+
+    let mut staged = vec![];
+    for delta in diff.deltas() {
+        let new_file = delta.new_file();
+        if delta.status() == git2::Delta::Deleted {
+            continue;
+        }
+        let path = match new_file
+            .path()
+            .and_then(|path| path.strip_prefix(&prefix).ok())
+        {
+            Some(path) => path.to_path_buf(),
+            None => continue,
+        };
+        let blob = repo.find_blob(new_file.id())?;
+        staged.push((path, blob.content().to_vec()));
+    }
+    Ok(staged)
+}
+
+/// The directory that git looks for a repository's hooks in.
+pub fn hooks_dir(repo_root: impl AsRef<Path>) -> Result<PathBuf> {
+    let repo = open(repo_root)?;
+    let hooks = repo.config()?.get_path("core.hooksPath").ok();
+    Ok(match hooks {
+        Some(hooks) if hooks.is_absolute() => hooks,
+        Some(hooks) => repo.workdir().unwrap_or_else(|| repo.path()).join(hooks),
+        None => repo.path().join("hooks"),
+    })
+}
+
+/// A remote configured in a repository.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Remote {
+    pub name: String,
+    pub url: Option<String>,
+    /// Whether `hermit push` pushes to the remote along with the one
+    /// it pushes to anyway.
+    pub mirror: bool,
+}
+
+/// The setting in a remote's section of the git configuration that
+/// makes it a mirror. It isn't git's own `mirror` setting, which
+/// changes what `git push` pushes.
+fn mirror_key(name: &str) -> String {
+    format!("remote.{}.hermitMirror", name)
+}
+
+/// The remotes of a repository, in alphabetical order.
+pub fn remotes(repo_root: impl AsRef<Path>) -> Result<Vec<Remote>> {
+    let repo = open(repo_root)?;
+    let config = repo.config()?;
+    let names = repo.remotes()?;
+    let mut remotes = vec![];
+    for name in names.iter().flatten() {
+        let remote = repo.find_remote(name)?;
+        remotes.push(Remote {
+            name: name.to_owned(),
+            url: remote.url().map(ToOwned::to_owned),
+            mirror: config.get_bool(&mirror_key(name)).unwrap_or(false),
+        });
+    }
+    remotes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(remotes)
+}
+
+pub fn add_remote(repo_root: impl AsRef<Path>, name: &str, url: &str) -> Result<()> {
+    open(repo_root)?.remote(name, url)?;
+    Ok(())
+}
+
+pub fn remove_remote(repo_root: impl AsRef<Path>, name: &str) -> Result<()> {
+    open(repo_root)?.remote_delete(name)?;
+    Ok(())
+}
+
+pub fn set_remote_url(repo_root: impl AsRef<Path>, name: &str, url: &str) -> Result<()> {
+    let repo = open(repo_root)?;
+    repo.find_remote(name)?;
+    repo.remote_set_url(name, url)?;
+    Ok(())
+}
+
+/// Makes the remote `name` a mirror, or stops it from being one.
+pub fn set_mirror(repo_root: impl AsRef<Path>, name: &str, mirror: bool) -> Result<()> {
+    let repo = open(repo_root)?;
+    repo.find_remote(name)?;
+    let mut config = repo.config()?.open_level(git2::ConfigLevel::Local)?;
+    if mirror {
+        config.set_bool(&mirror_key(name), true)?;
+    } else if config.get_entry(&mirror_key(name)).is_ok() {
+        config.remove(&mirror_key(name))?;
+    }
+    Ok(())
+}
+
+/// Whether the repository's attributes hand `path`, relative to the
+/// repository, to Git LFS.
+fn is_lfs_tracked(repo: &Repository, path: &Path) -> Result<bool> {
+    let filter = repo.get_attr(path, "filter", git2::AttrCheckFlags::default())?;
+    Ok(filter == Some("lfs"))
+}
+
+/// Those of `paths`, relative to `root`, that are kept in Git LFS.
+pub fn lfs_tracked(root: impl AsRef<Path>, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let (repo, prefix) = open_scoped(root.as_ref())?;
+    let mut tracked = vec![];
+    for path in paths {
+        if is_lfs_tracked(&repo, &prefix.join(path))? {
+            tracked.push(path.clone());
+        }
+    }
+    Ok(tracked)
+}
+
+/// Whether any file committed to the repository is kept in Git LFS.
+pub fn uses_lfs(repo_root: impl AsRef<Path>) -> Result<bool> {
+    let repo = open(repo_root)?;
+    for entry in repo.index()?.iter() {
+        let path = PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned());
+        if is_lfs_tracked(&repo, &path)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Runs a git command in a repository, with the terminal handed over
+/// to it. Used for the things that libgit2 can't do, or where the
+/// user's credential helpers and ssh agent are needed.
+pub fn run(repo_root: impl AsRef<Path>, args: &[&str]) -> Result<()> {
+    let command = format!("git {}", args.first().unwrap_or(&""));
+    let status = process::Command::new("git")
+        .arg("-C")
+        .arg(repo_root.as_ref())
+        .args(args)
+        .status()
+        .map_err(|err| Error::CommandFailed(command.clone(), err.to_string()))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::CommandFailed(command, status.to_string()))
+    }
+}
+
+/// The git commands that `run_remote` asks for progress from.
+const PROGRESS_COMMANDS: &[&str] = &["clone", "fetch", "push"];
+
+/// Runs a git command that talks to a remote in a repository, like
+/// `run`, but with the reason it failed in the error, so that a
+/// network that can't be reached is recognized. The progress of a
+/// fetch, push or clone is still shown on a terminal.
+pub fn run_remote(repo_root: impl AsRef<Path>, args: &[&str]) -> Result<()> {
+    let mut command = process::Command::new("git");
+    command.arg("-C").arg(repo_root.as_ref());
+    if let Some((subcommand, rest)) = args.split_first() {
+        command.arg(subcommand);
+        // Git only shows progress on its own when stderr is a terminal,
+        // and only these commands take `--progress`; others, like
+        // `lfs`, would take it for one of their own arguments.
+        if PROGRESS_COMMANDS.contains(subcommand) && io::stderr().is_terminal() {
+            command.arg("--progress");
+        }
+        command.args(rest);
+    }
+    run_showing_errors(command, &format!("git {}", args.first().unwrap_or(&"")))
+}
+
+/// Pushes the current branch to `remote`. When `remote` is where the
+/// branch's upstream is, it is pushed to the branch it tracks there;
+/// a branch without an upstream gets `remote` as its upstream, and the
+/// upstream is left alone otherwise.
+pub fn push(repo_root: impl AsRef<Path>, remote: &str) -> Result<()> {
+    let repo_root = repo_root.as_ref();
+    match upstream(repo_root)? {
+        Some((upstream, merge)) if upstream == remote => {
+            run_remote(repo_root, &["push", remote, &format!("HEAD:{}", merge)])
+        }
+        Some(_) => run_remote(repo_root, &["push", remote, "HEAD"]),
+        None => run_remote(repo_root, &["push", "--set-upstream", remote, "HEAD"]),
+    }
+}
+
+/// The remote that a plain push of the current branch goes to: the
+/// one its upstream is on, or `origin` if it has no upstream yet.
+pub fn push_remote(repo_root: impl AsRef<Path>) -> Result<String> {
+    Ok(upstream(repo_root)?.map_or_else(|| "origin".to_owned(), |(remote, _)| remote))
+}
+
+/// Pushes the current branch to `remote`, leaving its upstream alone.
+pub fn push_to_mirror(repo_root: impl AsRef<Path>, remote: &str) -> Result<()> {
+    run_remote(repo_root, &["push", remote, "HEAD"])
+}
+
+/// Sets up a clean and smudge filter called `name` in a repository.
+/// The filter is required, so git fails rather than committing a file
+/// that the filter couldn't clean.
+pub fn set_filter(
+    repo_root: impl AsRef<Path>,
+    name: &str,
+    clean: &str,
+    smudge: &str,
+) -> Result<()> {
+    let mut config = open(repo_root)?
+        .config()?
+        .open_level(git2::ConfigLevel::Local)?;
+    config.set_str(&format!("filter.{}.clean", name), clean)?;
+    config.set_str(&format!("filter.{}.smudge", name), smudge)?;
+    config.set_bool(&format!("filter.{}.required", name), true)?;
+    Ok(())
+}
+
+/// The name of the branch checked out in a repository, if any.
+pub fn current_branch(repo_root: impl AsRef<Path>) -> Result<Option<String>> {
+    let repo = open(repo_root)?;
+    let head = match repo.head() {
+        Ok(head) => head,
+        Err(_) => return Ok(None),
+    };
+    Ok(if head.is_branch() {
+        head.shorthand().map(ToOwned::to_owned)
+    } else {
+        None
+    })
+}
+
+/// How many commits the branch checked out in a repository has that
+/// its upstream doesn't, if it has an upstream.
+pub fn commits_ahead(repo_root: impl AsRef<Path>) -> Result<Option<usize>> {
+    let repo = open(repo_root)?;
+    let head = match repo.head() {
+        Ok(head) if head.is_branch() => head,
+        _ => return Ok(None),
+    };
+    let branch = git2::Branch::wrap(head);
+    let upstream = match branch.upstream() {
+        Ok(upstream) => upstream,
+        Err(_) => return Ok(None),
+    };
+    match (branch.get().target(), upstream.get().target()) {
+        (Some(local), Some(upstream)) => Ok(Some(repo.graph_ahead_behind(local, upstream)?.0)),
+        _ => Ok(None),
+    }
+}
+
+/// The remote that the branch checked out in a repository pulls from,
+/// and the branch it pulls, as `refs/heads/<name>`, if it has an
+/// upstream.
+pub fn upstream(repo_root: impl AsRef<Path>) -> Result<Option<(String, String)>> {
+    let repo = open(repo_root)?;
+    let branch = match repo.head() {
+        Ok(head) if head.is_branch() => head.shorthand().map(ToOwned::to_owned),
+        _ => None,
+    };
+    let branch = match branch {
+        Some(branch) => branch,
+        None => return Ok(None),
+    };
+    let config = repo.config()?;
+    let setting = |key: &str| {
+        config
+            .get_string(&format!("branch.{}.{}", branch, key))
+            .ok()
+    };
+    Ok(setting("remote").zip(setting("merge")))
+}
+
+/// The commits that the upstream of the branch checked out in a
+/// repository has and the branch doesn't, as of the last fetch, or that
+/// some other commit has and the branch doesn't.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Behind {
+    pub commits: usize,
+    /// When the oldest of them was committed, as a Unix timestamp.
+    pub since: i64,
+}
+
+/// How far behind its upstream the branch checked out in a repository
+/// is, or `None` if it has no upstream or isn't behind it.
+pub fn behind(repo_root: impl AsRef<Path>) -> Result<Option<Behind>> {
+    let repo = open(repo_root)?;
+    let head = match repo.head() {
+        Ok(head) if head.is_branch() => head,
+        _ => return Ok(None),
+    };
+    let upstream = match git2::Branch::wrap(head).upstream() {
+        Ok(upstream) => upstream.get().target(),
+        Err(_) => None,
+    };
+    match upstream {
+        Some(upstream) => behind_of(&repo, upstream),
+        None => Ok(None),
+    }
+}
+
+/// How far behind `commit`, given by its id, the branch checked out in
+/// a repository is, or `None` if it isn't behind it. An error if the
+/// repository doesn't have the commit, because it hasn't been fetched.
+pub fn behind_commit(repo_root: impl AsRef<Path>, commit: &str) -> Result<Option<Behind>> {
+    let repo = open(repo_root)?;
+    let commit = repo.find_commit(git2::Oid::from_str(commit)?)?.id();
+    behind_of(&repo, commit)
+}
+
+fn behind_of(repo: &git2::Repository, ahead: git2::Oid) -> Result<Option<Behind>> {
+    let local = match repo.head()?.target() {
+        Some(local) => local,
+        None => return Ok(None),
+    };
+    let mut walk = repo.revwalk()?;
+    walk.push(ahead)?;
+    walk.hide(local)?;
+    let mut behind: Option<Behind> = None;
+    for id in walk {
+        let time = repo.find_commit(id?)?.time().seconds();
+        behind = Some(match behind {
+            Some(behind) => Behind {
+                commits: behind.commits + 1,
+                since: behind.since.min(time),
+            },
+            None => Behind {
+                commits: 1,
+                since: time,
+            },
+        });
+    }
+    Ok(behind)
+}
+
+/// When a repository last fetched anything, as a Unix timestamp: when
+/// `FETCH_HEAD` was written, or, for a repository that has only been
+/// cloned, when the upstream of its branch was last updated.
+pub fn last_fetch(repo_root: impl AsRef<Path>) -> Option<i64> {
+    let repo = open(repo_root).ok()?;
+    let fetched = fs::metadata(repo.path().join("FETCH_HEAD"))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|since| since.as_secs() as i64);
+    let updated = || {
+        let head = repo.head().ok()?;
+        let upstream = git2::Branch::wrap(head).upstream().ok()?;
+        let reflog = repo.reflog(upstream.get().name()?).ok()?;
+        let updated = reflog.get(0)?.committer().when().seconds();
+        Some(updated)
+    };
+    fetched.or_else(updated)
+}
+
+/// Asks `remote` which commit its `reference`, like `HEAD` or
+/// `refs/heads/main`, is at, without fetching anything. `None` if the
+/// remote doesn't have it. Nobody is asked for a password, and
+/// connections that go nowhere are given up on quickly.
+pub fn ls_remote(
+    repo_root: impl AsRef<Path>,
+    remote: &str,
+    reference: &str,
+) -> Result<Option<String>> {
+    let mut command = process::Command::new("git");
+    command
+        .arg("-C")
+        .arg(repo_root.as_ref())
+        .args([
+            "-c",
+            "http.lowSpeedLimit=1000",
+            "-c",
+            "http.lowSpeedTime=10",
+        ])
+        .args(["ls-remote", "--quiet", remote, reference])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(process::Stdio::null());
+    if std::env::var_os("GIT_SSH_COMMAND").is_none() {
+        command.env(
+            "GIT_SSH_COMMAND",
+            "ssh -o BatchMode=yes -o ConnectTimeout=10",
+        );
+    }
+    let failed = |reason: String| Error::CommandFailed("git ls-remote".to_owned(), reason);
+    let output = command.output().map_err(|err| failed(err.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .unwrap_or("it exited with an error");
+        return Err(failed(reason.to_owned()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(ToOwned::to_owned))
+}
+
+/// Whether a local branch with this name exists.
+pub fn branch_exists(repo_root: impl AsRef<Path>, name: &str) -> Result<bool> {
+    let repo = open(repo_root)?;
+    let exists = repo.find_branch(name, git2::BranchType::Local).is_ok();
+    Ok(exists)
+}
+
+/// The branch that machine branches are based on: `main` or `master`,
+/// preferring the remote's copy of it.
+pub fn main_branch(repo_root: impl AsRef<Path>) -> Result<Option<String>> {
+    let repo = open(repo_root)?;
+    let candidates = ["origin/main", "origin/master", "main", "master"];
+    Ok(candidates
+        .iter()
+        .find(|name| {
+            let branch_type = if name.contains('/') {
+                git2::BranchType::Remote
+            } else {
+                git2::BranchType::Local
+            };
+            repo.find_branch(name, branch_type).is_ok()
+        })
+        .map(|name| name.to_string()))
+}
+
+#[cfg(test)]
+pub mod test_support {
+    use super::*;
+
+    /// Creates a repository with an identity configured, so commits
+    /// can be made without relying on the user's git configuration.
+    pub fn init_repo(path: impl AsRef<Path>) -> Repository {
+        let repo = Repository::init(path).expect("Failed to init repo");
+        {
+            let mut config = repo.config().expect("Failed to open repo config");
+            config.set_str("user.name", "Geoff").unwrap();
+            config.set_str("user.email", "geoff@example.com").unwrap();
+        }
+        repo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_support::init_repo, *};
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn names_repos_after_the_end_of_their_url() {
+        assert_eq!(
+            repo_name_from_url("git@github.com:me/dotfiles.git"),
+            Some("dotfiles")
+        );
+        assert_eq!(
+            repo_name_from_url("https://example.com/me/work-shell/"),
+            Some("work-shell")
+        );
+        assert_eq!(repo_name_from_url("host:shell"), Some("shell"));
+        assert_eq!(repo_name_from_url("https://example.com/.git"), None);
+    }
+
+    #[test]
+    fn parses_identities_the_way_git_writes_them() {
+        let identity = Identity::parse("Geoff Shannon <geoff@example.com>").unwrap();
+
+        assert_eq!(identity.name, "Geoff Shannon");
+        assert_eq!(identity.email, "geoff@example.com");
+        assert_eq!(identity.to_string(), "Geoff Shannon <geoff@example.com>");
+        assert_eq!(Identity::parse("geoff@example.com"), None);
+        assert_eq!(Identity::parse("<geoff@example.com>"), None);
+    }
+
+    #[test]
+    fn committing_without_an_identity_is_a_clear_error() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let repo = init_repo(test_root);
+        repo.config().unwrap().set_str("user.name", "").unwrap();
+        fs::write(test_root.join(".bashrc"), "").unwrap();
+
+        assert_eq!(
+            commit_paths(test_root, &[".bashrc"], "Add bashrc", false),
+            Err(Error::NoGitIdentity)
+        );
+    }
+
+    #[test]
+    fn signed_commits_carry_the_signature_of_the_signing_program() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let repo = init_repo(test_root);
+        let program = test_root.join("fake-gpg");
+        fs::write(
+            &program,
+            "#!/bin/sh\ncat >/dev/null\n[ \"$4\" = ABCD ] || { echo \"no key $4\" >&2; exit 2; }\necho SIGNED\n",
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut config = repo.config().unwrap();
+        config
+            .set_str("gpg.program", program.to_str().unwrap())
+            .unwrap();
+        fs::write(test_root.join(".bashrc"), "").unwrap();
+
+        assert!(matches!(
+            commit_paths(test_root, &[".bashrc"], "Add bashrc", true),
+            Err(Error::CannotSign(reason)) if reason.ends_with("no key Geoff <geoff@example.com>")
+        ));
+
+        config.set_str("user.signingkey", "ABCD").unwrap();
+        let oid = commit_paths(test_root, &[".bashrc"], "Add bashrc", true).unwrap();
+        let (signature, _) = repo.extract_signature(&oid, None).unwrap();
+        assert_eq!(signature.as_str(), Some("SIGNED\n"));
+        assert_eq!(repo.head().unwrap().target(), Some(oid));
+
+        config.set_bool("commit.gpgsign", true).unwrap();
+        fs::write(test_root.join(".bashrc"), "changed").unwrap();
+        let oid = commit_paths(test_root, &[".bashrc"], "Change bashrc", false).unwrap();
+        assert!(repo.extract_signature(&oid, None).is_ok());
+        assert_eq!(repo.head().unwrap().target(), Some(oid));
+    }
+
+    #[test]
+    fn only_commits_that_git_trusts_pass_verification() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let repo = init_repo(test_root);
+        // Signs anything, and vouches for any signature it made.
+        let program = test_root.join("fake-gpg");
+        fs::write(
+            &program,
+            "#!/bin/sh\ncat >/dev/null\ncase \"$*\" in\n\
+             *--verify*) printf '[GNUPG:] NEWSIG\\n[GNUPG:] GOODSIG ABCD Geoff\\n[GNUPG:] TRUST_FULLY 0 pgp\\n' ;;\n\
+             *) echo '[GNUPG:] SIG_CREATED D' >&2\n\
+             printf -- '-----BEGIN PGP SIGNATURE-----\\n\\nfake\\n-----END PGP SIGNATURE-----\\n' ;;\n\
+             esac\n",
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut config = repo.config().unwrap();
+        config
+            .set_str("gpg.program", program.to_str().unwrap())
+            .unwrap();
+        config.set_str("user.signingkey", "ABCD").unwrap();
+        fs::write(test_root.join(".bashrc"), "").unwrap();
+
+        assert!(!requires_signatures(test_root));
+        require_signatures(test_root).unwrap();
+        assert!(requires_signatures(test_root));
+
+        commit_paths(test_root, &[".bashrc"], "Add bashrc", false).unwrap();
+        assert!(matches!(
+            verify_signature(test_root, "HEAD"),
+            Err(Error::UnverifiedCommit { .. })
+        ));
+
+        run(test_root, &["tag", "--sign", "--message", "Trusted", "v1"]).unwrap();
+        assert_eq!(verify_signature(test_root, "HEAD"), Ok(()));
+
+        fs::write(test_root.join(".bashrc"), "changed").unwrap();
+        commit_paths(test_root, &[".bashrc"], "Change bashrc", true).unwrap();
+        assert_eq!(verify_signature(test_root, "HEAD"), Ok(()));
+    }
+
+    #[test]
+    fn stashed_changes_can_be_put_back() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        repo_with_history(test_root);
+
+        assert_eq!(stash(test_root, None), Ok(false));
+        assert_eq!(stash_pop(test_root), Err(Error::NothingStashed));
+
+        fs::write(test_root.join(".bashrc"), "four").unwrap();
+        assert_eq!(stash(test_root, Some("Trying things")), Ok(true));
+        assert_eq!(
+            fs::read_to_string(test_root.join(".bashrc")).unwrap(),
+            "three"
+        );
+        assert_eq!(has_uncommitted_changes(test_root), Ok(false));
+
+        stash_pop(test_root).unwrap();
+        assert_eq!(
+            fs::read_to_string(test_root.join(".bashrc")).unwrap(),
+            "four"
+        );
+        assert_eq!(stash_pop(test_root), Err(Error::NothingStashed));
+    }
+
+    fn repo_with_history(path: &Path) -> Repository {
+        let repo = init_repo(path);
+        for contents in &["one", "two", "three"] {
+            fs::write(path.join(".bashrc"), contents).unwrap();
+            commit_paths(path, &[".bashrc"], contents, false).unwrap();
+        }
+        repo
+    }
+
+    fn file_url(path: &Path) -> String {
+        format!("file://{}", path.display())
+    }
+
+    #[test]
+    fn clones_a_single_branch() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let origin = repo_with_history(&test_root.join("origin"));
+        let head = origin.head().unwrap().peel_to_commit().unwrap();
+        origin.branch("laptop", &head, false).unwrap();
+        origin.branch("desktop", &head, false).unwrap();
+
+        let options = CloneOptions {
+            branch: Some("laptop".to_owned()),
+            depth: None,
+        };
+        clone(
+            &file_url(&test_root.join("origin")),
+            test_root.join("copy"),
+            &options,
+        )
+        .expect("Clone failed");
+
+        let copy = Repository::open(test_root.join("copy")).unwrap();
+        assert_eq!(copy.head().unwrap().shorthand(), Some("laptop"));
+        assert!(copy.find_reference("refs/remotes/origin/desktop").is_err());
+    }
+
+    /// Changes `.bashrc` both in the remote and in the clone at `local`,
+    /// and pulls, which stops with a conflict.
+    fn pull_conflicting_change(origin: &Path, local: &Path, round: &str) {
+        fs::write(origin.join(".bashrc"), format!("remote {}", round)).unwrap();
+        commit_paths(origin, &[".bashrc"], "Remote change", false).unwrap();
+        fs::write(local.join(".bashrc"), format!("local {}", round)).unwrap();
+        commit_paths(local, &[".bashrc"], "Local change", false).unwrap();
+        assert!(run(local, &["pull", "--rebase", "--quiet"]).is_err());
+    }
+
+    #[test]
+    fn conflicts_in_a_pull_are_settled_file_by_file() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let origin = test_root.join("origin");
+        let local = test_root.join("local");
+        repo_with_history(&origin);
+        clone(&file_url(&origin), &local, &CloneOptions::default()).unwrap();
+        let mut config = Repository::open(&local).unwrap().config().unwrap();
+        config.set_str("user.name", "Geoff").unwrap();
+        config.set_str("user.email", "geoff@example.com").unwrap();
+
+        pull_conflicting_change(&origin, &local, "one");
+        assert_eq!(rebase_in_progress(&local), Ok(true));
+        assert_eq!(conflicted_paths(&local), Ok(vec![PathBuf::from(".bashrc")]));
+
+        let mut asked = vec![];
+        resolve_rebase(&local, |path| {
+            asked.push(path.to_path_buf());
+            Ok(ConflictChoice::Local)
+        })
+        .unwrap();
+        assert_eq!(asked, vec![PathBuf::from(".bashrc")]);
+        assert_eq!(rebase_in_progress(&local), Ok(false));
+        assert_eq!(
+            fs::read_to_string(local.join(".bashrc")).unwrap(),
+            "local one"
+        );
+
+        pull_conflicting_change(&origin, &local, "two");
+        let head = Repository::open(&local)
+            .unwrap()
+            .revparse_single("ORIG_HEAD")
+            .unwrap()
+            .id();
+        assert_eq!(
+            resolve_rebase(&local, |_| Ok(ConflictChoice::Abort)),
+            Err(Error::SyncConflicts(vec![PathBuf::from(".bashrc")]))
+        );
+        let repo = Repository::open(&local).unwrap();
+        assert_eq!(repo.head().unwrap().target(), Some(head));
+        assert_eq!(
+            fs::read_to_string(local.join(".bashrc")).unwrap(),
+            "local two"
+        );
+    }
+
+    #[test]
+    fn clones_shallowly() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        repo_with_history(&test_root.join("origin"));
+
+        let options = CloneOptions {
+            branch: None,
+            depth: Some(1),
+        };
+        clone(
+            &file_url(&test_root.join("origin")),
+            test_root.join("copy"),
+            &options,
+        )
+        .expect("Clone failed");
+
+        let copy = Repository::open(test_root.join("copy")).unwrap();
+        assert!(copy.is_shallow());
+        let head = copy.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message(), Some("three"));
+    }
+
+    #[test]
+    fn manages_remotes() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        init_repo(test_root);
+
+        add_remote(test_root, "origin", "git@github.com:me/dotfiles").unwrap();
+        add_remote(test_root, "mirror", "git@example.com:dotfiles").unwrap();
+        set_remote_url(test_root, "mirror", "git@example.org:dotfiles").unwrap();
+        set_mirror(test_root, "mirror", true).unwrap();
+        assert_eq!(
+            remotes(test_root).unwrap(),
+            vec![
+                Remote {
+                    name: "mirror".to_owned(),
+                    url: Some("git@example.org:dotfiles".to_owned()),
+                    mirror: true,
+                },
+                Remote {
+                    name: "origin".to_owned(),
+                    url: Some("git@github.com:me/dotfiles".to_owned()),
+                    mirror: false,
+                },
+            ]
+        );
+        assert!(set_mirror(test_root, "backup", true).is_err());
+
+        remove_remote(test_root, "mirror").unwrap();
+        assert_eq!(remotes(test_root).unwrap().len(), 1);
+        add_remote(test_root, "mirror", "git@example.com:dotfiles").unwrap();
+        assert!(!remotes(test_root).unwrap()[0].mirror);
+        set_mirror(test_root, "mirror", true).unwrap();
+        set_mirror(test_root, "mirror", false).unwrap();
+        set_mirror(test_root, "mirror", false).unwrap();
+        assert!(!remotes(test_root).unwrap()[0].mirror);
+    }
+
+    #[test]
+    fn cannot_set_the_url_of_a_missing_remote() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        init_repo(test_root);
+
+        assert!(set_remote_url(test_root, "origin", "git@example.com:x").is_err());
+        assert!(remotes(test_root).unwrap().is_empty());
+    }
+
+    #[test]
+    fn knows_the_current_and_main_branches() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        repo_with_history(test_root);
+        run(test_root, &["branch", "-m", "main"]).unwrap();
+        run(test_root, &["checkout", "-q", "-b", "laptop"]).unwrap();
+
+        assert_eq!(
+            current_branch(test_root).unwrap(),
+            Some("laptop".to_owned())
+        );
+        assert_eq!(main_branch(test_root).unwrap(), Some("main".to_owned()));
+        assert!(branch_exists(test_root, "main").unwrap());
+        assert!(!branch_exists(test_root, "desktop").unwrap());
+    }
+
+    #[test]
+    fn reports_failed_git_commands() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        init_repo(test_root);
+
+        assert!(matches!(
+            run(test_root, &["checkout", "-q", "nonexistent"]),
+            Err(Error::CommandFailed(ref command, _)) if command == "git checkout"
+        ));
+    }
+
+    #[test]
+    fn paths_are_scoped_to_shells_in_a_shared_repository() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let repo = init_repo(test_root);
+        let shell_root = test_root.join("work");
+        fs::create_dir(&shell_root).unwrap();
+        fs::write(shell_root.join(".bashrc"), "work").unwrap();
+
+        assert!(has_changes(&shell_root, ".bashrc").unwrap());
+        let oid = commit_paths(&shell_root, &[".bashrc"], "Add bashrc", false).unwrap();
+        assert!(!has_changes(&shell_root, ".bashrc").unwrap());
+
+        let tree = repo.find_commit(oid).unwrap().tree().unwrap();
+        assert!(tree.get_path(Path::new("work/.bashrc")).is_ok());
+    }
+
+    #[test]
+    fn last_commits_are_those_to_the_shell() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let repo = init_repo(test_root);
+        for shell in &["work", "home"] {
+            fs::create_dir(test_root.join(shell)).unwrap();
+            fs::write(test_root.join(shell).join(".bashrc"), shell).unwrap();
+        }
+        assert_eq!(last_commit_time(test_root.join("work")).unwrap(), None);
+
+        let oid = commit_paths(test_root.join("work"), &[".bashrc"], "Add bashrc", false).unwrap();
+        let time = repo.find_commit(oid).unwrap().time().seconds();
+
+        assert_eq!(
+            last_commit_time(test_root.join("work")).unwrap(),
+            Some(time)
+        );
+        assert_eq!(last_commit_time(test_root.join("home")).unwrap(), None);
+        assert_eq!(last_commit_time(test_root).unwrap(), Some(time));
+    }
+
+    #[test]
+    fn new_files_count_as_changes() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        init_repo(test_root);
+        fs::write(test_root.join(".bashrc"), "alias ll='ls -l'").unwrap();
+
+        assert!(has_changes(test_root, ".bashrc").unwrap());
+    }
+
+    #[test]
+    fn committed_files_have_no_changes() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        init_repo(test_root);
+        fs::write(test_root.join(".bashrc"), "alias ll='ls -l'").unwrap();
+
+        commit_paths(test_root, &[".bashrc"], "Add bashrc", false).expect("Commit failed");
+
+        assert!(!has_changes(test_root, ".bashrc").unwrap());
+    }
+
+    #[test]
+    fn lists_the_staged_files_of_a_shell() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let repo = init_repo(test_root);
+        let shell_root = test_root.join("work");
+        fs::create_dir(&shell_root).unwrap();
+        fs::write(shell_root.join(".bashrc"), "work").unwrap();
+        fs::write(test_root.join("README.md"), "shells").unwrap();
+        commit_paths(&shell_root, &[".bashrc"], "Add bashrc", false).unwrap();
+        fs::write(shell_root.join(".vimrc"), "set nu").unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("work/.vimrc")).unwrap();
+        index.add_path(Path::new("README.md")).unwrap();
+        index.write().unwrap();
+
+        assert_eq!(
+            staged_files(&shell_root).unwrap(),
+            vec![(PathBuf::from(".vimrc"), b"set nu".to_vec())]
+        );
+    }
+
+    #[test]
+    fn restores_the_files_of_a_tag() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let repo = init_repo(test_root);
+        fs::write(test_root.join(".bashrc"), "old").unwrap();
+        commit_paths(test_root, &[".bashrc"], "Add bashrc", false).unwrap();
+        create_tag(test_root, "hermit/default/before", "Before").unwrap();
+        fs::write(test_root.join(".bashrc"), "new").unwrap();
+        fs::write(test_root.join(".vimrc"), "set nu").unwrap();
+        assert!(has_uncommitted_changes(test_root).unwrap());
+        commit_paths(test_root, &[".bashrc", ".vimrc"], "Change things", false).unwrap();
+        assert!(!has_uncommitted_changes(test_root).unwrap());
+
+        restore_tag(test_root, "hermit/default/before").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(test_root.join(".bashrc")).unwrap(),
+            "old"
+        );
+        assert!(!test_root.join(".vimrc").exists());
+        assert!(repo.head().unwrap().is_branch());
+        assert_eq!(
+            tags(test_root, "hermit/default/")
+                .unwrap()
+                .iter()
+                .map(|tag| (tag.name.as_str(), tag.message.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("hermit/default/before", "Before")]
+        );
+    }
+
+    #[test]
+    fn commits_can_be_empty() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let repo = init_repo(test_root);
+
+        let oid = commit_paths(test_root, &[] as &[&str], "Initial commit", false).unwrap();
+
+        let commit = repo.find_commit(oid).unwrap();
+        assert_eq!(commit.parent_count(), 0);
+        assert_eq!(commit.tree().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn knows_how_far_behind_the_remote_a_branch_is() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let origin_path = test_root.join("origin");
+        let copy_path = test_root.join("copy");
+        let origin = repo_with_history(&origin_path);
+        clone(
+            &file_url(&origin_path),
+            &copy_path,
+            &CloneOptions::default(),
+        )
+        .unwrap();
+        let branch = current_branch(&copy_path).unwrap().unwrap();
+        let merge = format!("refs/heads/{}", branch);
+
+        assert_eq!(
+            upstream(&copy_path).unwrap(),
+            Some(("origin".to_owned(), merge.clone()))
+        );
+        assert_eq!(behind(&copy_path).unwrap(), None);
+
+        fs::write(origin_path.join(".bashrc"), "four").unwrap();
+        let oid = commit_paths(&origin_path, &[".bashrc"], "four", false).unwrap();
+        assert_eq!(
+            ls_remote(&copy_path, "origin", &merge).unwrap(),
+            Some(oid.to_string())
+        );
+        assert_eq!(
+            ls_remote(&copy_path, "origin", "refs/heads/nope").unwrap(),
+            None
+        );
+        run(&copy_path, &["fetch", "--quiet"]).unwrap();
+        let time = origin.find_commit(oid).unwrap().time().seconds();
+        assert_eq!(
+            behind(&copy_path).unwrap(),
+            Some(Behind {
+                commits: 1,
+                since: time
+            })
+        );
+
+        set_remote_url(&copy_path, "origin", &file_url(&test_root.join("gone"))).unwrap();
+        assert!(ls_remote(&copy_path, "origin", &merge).is_err());
+    }
+
+    #[test]
+    fn recognizes_network_failures() {
+        assert!(is_network_failure(
+            "fatal: unable to access 'https://example.com/x.git/': Could not resolve host: example.com"
+        ));
+        assert!(is_network_failure(
+            "ssh: connect to host example.com port 22: Network is unreachable"
+        ));
+        assert!(!is_network_failure(
+            "fatal: 'origin' does not appear to be a git repository"
+        ));
+
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        init_repo(test_root);
+        let err = run_remote(test_root, &["fetch", "nowhere"]).unwrap_err();
+        assert!(
+            matches!(&err, Error::CommandFailed(command, reason)
+                if command == "git fetch" && reason.contains("nowhere")),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn pushes_to_a_remote() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        git2::Repository::init_bare(test_root.join("remote.git")).unwrap();
+        let repo = init_repo(test_root.join("local"));
+        let remote_url = test_root.join("remote.git");
+        repo.remote("origin", remote_url.to_str().unwrap()).unwrap();
+        let oid = commit_paths(test_root.join("local"), &[] as &[&str], "Initial", false).unwrap();
+
+        push(test_root.join("local"), "origin").expect("Push failed");
+
+        let remote = git2::Repository::open_bare(test_root.join("remote.git")).unwrap();
+        assert_eq!(remote.head().unwrap().target(), Some(oid));
+        assert_eq!(push_remote(test_root.join("local")).unwrap(), "origin");
+    }
+
+    #[test]
+    fn pushing_elsewhere_leaves_the_upstream_alone() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let local = test_root.join("local");
+        let repo = init_repo(&local);
+        for name in &["origin", "backup"] {
+            let url = test_root.join(format!("{}.git", name));
+            git2::Repository::init_bare(&url).unwrap();
+            repo.remote(name, url.to_str().unwrap()).unwrap();
+        }
+        commit_paths(&local, &[] as &[&str], "Initial", false).unwrap();
+        assert_eq!(push_remote(&local).unwrap(), "origin");
+
+        push(&local, "backup").expect("Push failed");
+        push(&local, "origin").expect("Push failed");
+
+        let (remote, _) = upstream(&local).unwrap().expect("No upstream");
+        assert_eq!(remote, "backup");
+        assert_eq!(push_remote(&local).unwrap(), "backup");
+    }
+
+    #[test]
+    fn commits_build_on_each_other() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let repo = init_repo(test_root);
+        fs::write(test_root.join(".bashrc"), "one").unwrap();
+        let first = commit_paths(test_root, &[".bashrc"], "One", false).unwrap();
+        fs::write(test_root.join(".bashrc"), "two").unwrap();
+        let second = commit_paths(test_root, &[".bashrc"], "Two", false).unwrap();
+
+        let commit = repo.find_commit(second).unwrap();
+        assert_eq!(commit.parent_id(0).unwrap(), first);
+        assert_eq!(commit.message(), Some("Two"));
+    }
+}
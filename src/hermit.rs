@@ -1,4 +1,39 @@
-use crate::common::*;
+use std::{
+    collections::BTreeSet,
+    os::unix::fs::PermissionsExt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    audit::AuditLog,
+    bake::{Baked, Recipe},
+    bootstrap::{Bootstrap, BootstrapLog},
+    branches::MachineBranches,
+    checksums::{self, Checksums, Verification},
+    common::*,
+    config,
+    doctor::{self, CommitProblem, ForeignLink, LargeDirectory, RemoteProblem},
+    file_operations::{CommitReport, ConflictPolicy},
+    git::{self, ConflictChoice},
+    hooks::{Event as HookEvent, Hooks},
+    host::Host,
+    journal::Journal,
+    keyring::{Keyring, SystemKeyring},
+    lfs,
+    manifest::{
+        self, Granularity, Strategy, TagFilter, LOCAL_DIR, MANIFEST_FILE_NAME, METADATA_DIR,
+    },
+    observer::{Event, Observer, Observers},
+    parallel, paths,
+    provision::{self, Outcome as Provisioned, Record},
+    secret::{self, Key},
+    settings::{Settings, DEFAULT_SETTINGS, SETTINGS_FILE_NAME},
+    shell_info::{self, ShellInfo},
+    stats::ShellStats,
+    status::{self, Delta, FileState, FileStatus, Status},
+    suggestions,
+    template::{Placeholders, TemplateFile},
+};
 
 #[derive(Clone, Debug, Error, PartialEq, Eq)]
 pub enum Error {
@@ -8,8 +43,251 @@ pub enum Error {
     #[error("That is not the name of a shell")]
     ShellDoesNotExist,
 
+    #[error("There is no shell named '{name}'{}", suggestions::did_you_mean(.suggestions))]
+    UnknownShell {
+        name: String,
+        suggestions: Vec<String>,
+    },
+
+    #[error("There is no hermit root at {}, run `hermit init` first", message::path(.0))]
+    NotInitialized(PathBuf),
+
     #[error("No shell is active right now")]
     NoActiveShell,
+
+    #[error("No shell was active before this one")]
+    NoPreviousShell,
+
+    #[error("Could not read the shell manifest: {0}")]
+    InvalidManifest(String),
+
+    #[error("Bootstrap step '{0}' failed: {1}")]
+    BootstrapStepFailed(String, String),
+
+    #[error("'{0}' is not a hermit command, and no hermit-{0} plugin was found on PATH")]
+    UnknownSubcommand(String),
+
+    #[error("Could not run the hermit-{0} plugin: {1}")]
+    PluginFailed(String, String),
+
+    #[error("No such file: {}", message::path(.0))]
+    NoSuchFile(PathBuf),
+
+    #[error("Nothing matches {0}")]
+    NoMatches(String),
+
+    #[error("Invalid pattern {0}: {1}")]
+    InvalidPattern(String, String),
+
+    #[error("{} is not inside the directory that shells are linked into", message::path(.0))]
+    NotInTargetRoot(PathBuf),
+
+    #[error("The shell '{0}' manages system files; apply it with `sudo hermit apply --system --shell {0}` instead")]
+    SystemShell(String),
+
+    #[error("The shell '{0}' doesn't manage system files; give it a [system] table in its shell.toml first")]
+    NotASystemShell(String),
+
+    #[error(
+        "{} {} outside the directories that the shell's [system] prefixes let it manage",
+        message::paths(.0),
+        if .0.len() == 1 { "is" } else { "are" }
+    )]
+    OutsideSystemPrefixes(Vec<PathBuf>),
+
+    #[error("Only root can change system files; run hermit with sudo")]
+    NeedsRoot,
+
+    #[error(
+        "Read-only mode is on, and `hermit {0}` can't show what it would change without changing \
+         it, so it doesn't run; drop --read-only, unset HERMIT_READONLY and set read_only to \
+         false in {} to run it",
+        SETTINGS_FILE_NAME
+    )]
+    ReadOnly(String),
+
+    #[error("`hermit {0}` needs the network, so it can't run with --offline")]
+    Offline(String),
+
+    #[error("The shell already has a file at {}", message::path(.0))]
+    AlreadyInShell(PathBuf),
+
+    #[error("Refusing to link {}, because {reason}", message::path(.path))]
+    UnsafeLink { path: PathBuf, reason: &'static str },
+
+    #[error("{} can't be written in a manifest, because it isn't valid UTF-8", message::path(.0))]
+    PathNotUtf8(PathBuf),
+
+    #[error("{} can't be written as an Ansible task, because {reason}", message::path(.path))]
+    NotAnsibleTask { path: PathBuf, reason: &'static str },
+
+    #[error("{} is not tracked by the shell", message::path(.0))]
+    FileNotTracked(PathBuf),
+
+    #[error("Could not run {0}: {1}")]
+    CommandFailed(String, String),
+
+    #[error(
+        "Hermit commits as you, but git doesn't know who you are; set user.name and user.email with `git config --global`, or pass --author \"Name <email>\""
+    )]
+    NoGitIdentity,
+
+    #[error("There are no stashed changes to put back")]
+    NothingStashed,
+
+    #[error("Commits can't be signed: {0}")]
+    CannotSign(String),
+
+    #[error(
+        "Commit {commit} isn't signed by a key that git trusts, so hermit won't use it: {reason}"
+    )]
+    UnverifiedCommit { commit: String, reason: String },
+
+    #[error("The {event} hook {} failed: {reason}", message::path(.path))]
+    HookFailed {
+        event: &'static str,
+        path: PathBuf,
+        reason: String,
+    },
+
+    #[error(
+        "{} keeps files in Git LFS, but git-lfs isn't installed; install it, then run `git lfs pull` there",
+        message::path(.0)
+    )]
+    LfsNotInstalled(PathBuf),
+
+    #[error("The editor failed: {0}")]
+    EditorFailed(String),
+
+    #[error("Could not push to {}", .0.join(", "))]
+    PushFailed(Vec<String>),
+
+    #[error("Could not find a main or master branch to rebase onto")]
+    NoMainBranch,
+
+    #[error(
+        "The sync was stopped, because {} changed both here and in the remote; sync from a terminal to settle that",
+        message::paths(.0)
+    )]
+    SyncConflicts(Vec<PathBuf>),
+
+    #[error("Invalid settings: {0}")]
+    InvalidSettings(String),
+
+    #[error("Invalid shell metadata: {0}")]
+    InvalidMetadata(String),
+
+    #[error("Could not read the journal: {0}")]
+    InvalidJournal(String),
+
+    #[error("Could not read the audit log: {0}")]
+    InvalidAuditLog(String),
+
+    #[error("There is nothing to undo")]
+    NothingToUndo,
+
+    #[error("'{name}' is not a valid shell name, because {reason}")]
+    InvalidShellName { name: String, reason: &'static str },
+
+    #[error("There is already a shell named '{0}'")]
+    ShellAlreadyExists(String),
+
+    #[error("Stopped, since a question went unanswered")]
+    Cancelled,
+
+    #[error("Can't merge the shell '{0}' into itself")]
+    MergeIntoItself(String),
+
+    #[error("The shell '{0}' is in use, inhabit another shell before removing it")]
+    ShellInUse(String),
+
+    #[error("Shells can only share a repository if there are no shells yet")]
+    ShellsAlreadyExist,
+
+    #[error("Could not work out a shell name from {0}, please give one")]
+    NoShellNameInUrl(String),
+
+    #[error("Could not use template {0}: {1}")]
+    TemplateFailed(String, String),
+
+    #[error("git: {0}")]
+    Git(String),
+
+    #[error("Could not update the package list: {0}")]
+    PackageListUnwritable(String),
+
+    #[error("{0} failed: {1}")]
+    PackageManagerFailed(&'static str, String),
+
+    #[error("Could not protect secret files: {0}")]
+    Secret(String),
+
+    #[error("Could not sync with {}: {1}", message::path(.0))]
+    FolderSyncFailed(PathBuf, String),
+
+    #[error("'{0}' has changes that haven't been committed; commit or stash them first")]
+    UncommittedChanges(String),
+
+    #[error("There is no snapshot named '{0}'")]
+    UnknownSnapshot(String),
+
+    #[error("Could not install the git hook: {0}")]
+    HookNotInstalled(String),
+
+    #[error("The commit was stopped; fix the problems above, or commit with --no-verify")]
+    CommitProblems,
+
+    #[error("Could not set up the shell's directory for machine-local files: {0}")]
+    LocalDirUnwritable(String),
+
+    #[error("Could not read the plan: {0}")]
+    InvalidPlan(String),
+
+    #[error("{0} of the changes failed or were skipped")]
+    ChangesFailed(usize),
+
+    #[error("Could not save the plan to {}: {1}", message::path(.0))]
+    PlanUnwritable(PathBuf, String),
+
+    #[error("Could not bake into {}: {1}", message::path(.0))]
+    BakeFailed(PathBuf, String),
+
+    #[error("The plan is out of date, since {0}; make a new one")]
+    StalePlan(String),
+
+    #[error("{0} needs a terminal to run in")]
+    NotATerminal(String),
+
+    #[error("Could not draw on the terminal: {0}")]
+    TerminalFailed(String),
+
+    #[error("Could not answer requests: {0}")]
+    RequestsFailed(String),
+
+    #[error(
+        "{} has {files} files in it, more than the {limit} that `large_directory` in {} allows; \
+         add it with --large if you really mean to",
+        message::path(.path),
+        SETTINGS_FILE_NAME
+    )]
+    TooManyFiles {
+        path: PathBuf,
+        files: usize,
+        limit: usize,
+    },
+
+    #[error(
+        "{} is more than {max_depth} directories deep, deeper than `max_depth` in {} lets hermit look",
+        message::path(.path),
+        SETTINGS_FILE_NAME
+    )]
+    TooDeep { path: PathBuf, max_depth: usize },
+
+    #[error("{0} of the shell's files have been tampered with or corrupted")]
+    VerificationFailed(usize),
+
+    #[error("Could not upgrade hermit: {0}")]
+    UpgradeFailed(String),
 }
 
 impl From<io::Error> for Error {
@@ -20,144 +298,3332 @@ impl From<io::Error> for Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// The line that marks git hooks as hermit's own, so that they can be
+/// replaced but other hooks aren't.
+const HOOK_MARKER: &str = "# Installed by `hermit hooks install`, which may replace it.";
+
+/// The tags that mark snapshots of a shell start with this, followed
+/// by the name of the shell and a slash.
+const SNAPSHOT_TAG_PREFIX: &str = "hermit";
+
+/// A saved state of a shell that it can be rolled back to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub name: String,
+    /// When the snapshot was taken, in seconds since the Unix epoch.
+    pub time: u64,
+    /// Where each of the shell's files was linked when the snapshot
+    /// was taken.
+    pub plan: Vec<String>,
+}
+
+fn snapshot_tag(shell_name: &str, name: &str) -> String {
+    format!("{}/{}/{}", SNAPSHOT_TAG_PREFIX, shell_name, name)
+}
+
+/// Carries out the queued changes, adding what that did to `report`,
+/// and fails if any of them did. Returns how many things were changed.
+fn flush_strictly(file_ops: &mut FileOperations, report: &mut CommitReport) -> Result<usize> {
+    let flushed = file_ops.flush();
+    let changed = flushed.changed();
+    let failed = flushed.failed() + flushed.skipped();
+    report.extend(flushed);
+    match failed {
+        0 => Ok(changed),
+        failed => Err(Error::ChangesFailed(failed)),
+    }
+}
+
+/// How to settle a file that two shells being merged both have, with
+/// different contents.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// Keep the file of the shell being merged into.
+    Ours,
+    /// Replace it with the file of the shell being merged.
+    Theirs,
+    /// Keep both, putting the merged file at another path in the shell.
+    Rename(PathBuf),
+}
+
+/// What merging one shell into another does, by path in the shells.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Merge {
+    /// Files that only the merged shell had.
+    pub added: Vec<PathBuf>,
+    /// Files that were replaced by those of the merged shell.
+    pub replaced: Vec<PathBuf>,
+    /// Files of the merged shell put at a new path, and that path.
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+    /// Files that were kept as they were.
+    pub kept: Vec<PathBuf>,
+}
+
+/// The entry point for hermit's commands.
+///
+/// Methods that change the home directory take a `FileOperations`
+/// and only queue up the changes they need; nothing happens until
+/// the caller commits it.
 pub struct Hermit<T: Config> {
     config: Rc<T>,
+    keyring: Rc<dyn Keyring>,
+    observers: Observers,
 }
 
 impl<T: Config> Hermit<T> {
     pub fn new(config: T) -> Hermit<T> {
         Hermit {
             config: Rc::new(config),
+            keyring: Rc::new(SystemKeyring),
+            observers: Observers::default(),
         }
     }
 
+    /// Keeps the key for secret files in `keyring` instead of the
+    /// operating system's keychain.
+    pub fn with_keyring(self, keyring: Rc<dyn Keyring>) -> Hermit<T> {
+        Hermit { keyring, ..self }
+    }
+
+    /// Tells `observer` about what hermit does. To hear about the
+    /// operations hermit queues as they are carried out, hand it to
+    /// `FileOperations::add_observer` as well.
+    pub fn with_observer(mut self, observer: Rc<dyn Observer>) -> Hermit<T> {
+        self.observers.add(observer);
+        self
+    }
+
+    /// The machine that hermit is running on.
+    pub fn host(&self) -> &Host {
+        self.config.host()
+    }
+
+    pub fn settings(&self) -> &Settings {
+        self.config.settings()
+    }
+
     pub fn current_shell(&self) -> Result<Shell<T>> {
-        self.config
+        let name = self
+            .config
             .current_shell_name()
-            .map(|shell_name| Shell::new(shell_name, self.config.clone()))
-            .ok_or(Error::NoActiveShell)
+            .ok_or(Error::NoActiveShell)?;
+        config::validate_shell_name(name)?;
+        Ok(Shell::new(name, self.config.clone()))
     }
 
-    fn set_current_shell(&mut self, name: &str) -> Result<()> {
-        match Rc::get_mut(&mut self.config) {
-            Some(config) => config.set_current_shell_name(name).map_err(Error::from),
-            None => unreachable!(message::error_str(
-                "attempted to modify config while it was being used."
-            )),
+    /// Looks up a shell by name, failing if it doesn't exist.
+    pub fn shell(&self, name: &str) -> Result<Shell<T>> {
+        let name = self.existing_shell_name(name)?;
+        Ok(Shell::new(name, self.config.clone()))
+    }
+
+    /// Resolves `name` to the name of an existing shell. An
+    /// unambiguous prefix of a shell's name is accepted too, and the
+    /// error for an unknown name suggests similarly named shells. Names
+    /// that aren't plain directory names, like `..`, are an error
+    /// wherever they come from.
+    pub fn existing_shell_name(&self, name: &str) -> Result<String> {
+        config::validate_shell_name(name)?;
+        if self.config.shell_exists(name) {
+            return Ok(name.to_owned());
+        }
+
+        let names = self.config.shell_names();
+        suggestions::unique_prefix_match(name, &names).ok_or_else(|| Error::UnknownShell {
+            name: name.to_owned(),
+            suggestions: suggestions::similar_names(name, &names),
+        })
+    }
+
+    /// What bug reports need to say about where hermit is running,
+    /// each with a label, as `hermit version --verbose` prints them.
+    pub fn version_details(&self, target_root: &Path) -> Vec<(&'static str, String)> {
+        let root = self.config.root_path();
+        let mut root_line = message::path(root).to_string();
+        if !self.config.is_initialized() {
+            root_line.push_str(" (not initialized)");
+        }
+        let shell = match self.current_shell() {
+            Ok(shell) if env::shell_override().is_some() => {
+                format!("{} (from ${})", shell.name, env::SHELL_VAR)
+            }
+            Ok(shell) => shell.name,
+            Err(_) => "none".to_owned(),
+        };
+
+        let settings = &self.settings().link;
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        let mut links = format!(
+            "symlinks, force {}, backup {}",
+            on_off(settings.force),
+            on_off(settings.backup)
+        );
+        if let Ok(files) = self.current_shell().and_then(|shell| shell.files()) {
+            let copied = files
+                .iter()
+                .filter(|file| file.strategy == Strategy::Copy)
+                .count();
+            links.push_str(&format!(
+                "; {} files linked, {} copied",
+                files.len() - copied,
+                copied
+            ));
+        }
+
+        let host = self.host();
+        let features = [
+            ("trash", cfg!(feature = "trash")),
+            ("interactive", cfg!(feature = "interactive")),
+            ("upgrade", cfg!(feature = "upgrade")),
+        ]
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+
+        vec![
+            ("root", root_line),
+            ("shell", shell),
+            ("home", message::path(target_root).to_string()),
+            ("links", links),
+            ("libgit2", git::libgit2_version()),
+            (
+                "git",
+                git::cli_version().unwrap_or_else(|| "not found".to_owned()),
+            ),
+            ("platform", format!("{} {}", host.os, host.arch)),
+            (
+                "features",
+                if features.is_empty() {
+                    "none".to_owned()
+                } else {
+                    features.join(", ")
+                },
+            ),
+        ]
+    }
+
+    /// The record of the changes hermit has made in this root.
+    pub fn journal(&self) -> Journal {
+        Journal::in_root(self.config.root_path())
+    }
+
+    /// The log of every change hermit has made or tried to make in this
+    /// root, and who made it.
+    pub fn audit_log(&self) -> AuditLog {
+        AuditLog::in_root(self.config.root_path())
+    }
+
+    /// Makes `name` the current shell without touching any links.
+    pub fn set_current_shell(&mut self, name: &str) -> Result<()> {
+        let set = match Rc::get_mut(&mut self.config) {
+            Some(config) => config.set_current_shell_name(name),
+            None => unreachable!(
+                "{}",
+                message::error_str("attempted to modify config while it was being used.")
+            ),
+        };
+        self.audited(format!("make {} the current shell", name), set)
+    }
+
+    /// Tells the observers about a change that was made right away
+    /// rather than queued as an operation, so that it is audited like
+    /// the operations are, and hands back how it went.
+    pub fn audited<R>(&self, description: impl Into<String>, result: Result<R>) -> Result<R> {
+        self.observers.notify(Event::ChangeMade {
+            description: description.into(),
+            error: result.as_ref().err().map(ToString::to_string),
+        });
+        result
+    }
+
+    /// Fails unless the hermit root has been set up, so that commands
+    /// that need it can say so up front.
+    pub fn check_initialized(&self) -> Result<()> {
+        if self.config.is_initialized() {
+            Ok(())
+        } else {
+            Err(Error::NotInitialized(self.config.root_path().clone()))
         }
     }
 
+    /// Sets up the hermit root, with a settings file spelling out the
+    /// defaults. Whatever is already there is left alone, so this also
+    /// fills in the parts of a root that have gone missing.
+    pub fn scaffold(&self, file_ops: &mut FileOperations) {
+        let root = self.config.root_path();
+        file_ops.create_dir(self.config.shell_root_path());
+        file_ops.create_file(root.join(SETTINGS_FILE_NAME), DEFAULT_SETTINGS);
+    }
+
     pub fn init_shell(&mut self, file_ops: &mut FileOperations, name: &str) -> Result<()> {
         self.set_current_shell(name)?;
-        let new_shell = self.current_shell()?;
-        let path = new_shell.root_path();
-        let parent = path.parent().expect("Shell root path was too short");
-        file_ops.create_dir(parent);
-        file_ops.create_git_repo(&path);
+        self.plan_init_shell(file_ops, name)
+    }
+
+    /// Queues creating a new shell, without making it current.
+    pub fn plan_init_shell(&self, file_ops: &mut FileOperations, name: &str) -> Result<()> {
+        config::validate_shell_name(name)?;
+        let path = Shell::new(name, self.config.clone()).root_path();
+        if self.config.is_shared_repo() {
+            file_ops.create_dir(&path);
+        } else {
+            let parent = path.parent().expect("Shell root path was too short");
+            file_ops.create_dir(parent);
+            file_ops.create_git_repo(&path);
+        }
+        self.shell_created(name);
         Ok(())
     }
 
-    pub fn inhabit(&mut self, file_ops: &mut FileOperations, name: &str) -> Result<()> {
+    fn shell_created(&self, name: &str) {
+        self.observers.notify(Event::ShellCreated {
+            name: name.to_owned(),
+        });
+    }
+
+    /// Creates a new shell without a git repository, for shells that
+    /// are kept in step with a folder instead, and makes it current.
+    pub fn init_folder_shell(&mut self, file_ops: &mut FileOperations, name: &str) -> Result<()> {
+        self.set_current_shell(name)?;
+        self.plan_init_folder_shell(file_ops, name)
+    }
+
+    /// Queues creating a new shell that is kept in step with a folder,
+    /// without making it current.
+    pub fn plan_init_folder_shell(&self, file_ops: &mut FileOperations, name: &str) -> Result<()> {
+        config::validate_shell_name(name)?;
+        file_ops.create_dir(Shell::new(name, self.config.clone()).root_path());
+        self.shell_created(name);
+        Ok(())
+    }
+
+    /// The folder that the current shell is kept in step with, if it is
+    /// synced with one rather than with git. A `~` at its start stands
+    /// for `target_root`. Fails if the folder is missing, since syncing
+    /// with it would remove all of the shell's files.
+    pub fn sync_dir(&self, target_root: impl AsRef<Path>) -> Result<Option<PathBuf>> {
+        let shell = self.current_shell()?;
+        let folder = match ShellInfo::load(shell.root_path())?.sync_dir {
+            Some(folder) => folder,
+            None => return Ok(None),
+        };
+        let folder = match folder.strip_prefix("~") {
+            Ok(rest) => target_root.as_ref().join(rest),
+            Err(_) => folder,
+        };
+        if !folder.is_dir() {
+            return Err(Error::FolderSyncFailed(folder, "it isn't there".to_owned()));
+        }
+        Ok(Some(folder))
+    }
+
+    /// Makes the directory that holds the shells into a git repository
+    /// that all of them share. This only works before any shells
+    /// exist, since they would each have a repository of their own.
+    pub fn init_shared_repo(&self, file_ops: &mut FileOperations) -> Result<()> {
+        if self.config.is_shared_repo() {
+            return Ok(());
+        }
+        if !self.config.shell_names().is_empty() {
+            return Err(Error::ShellsAlreadyExist);
+        }
+        file_ops.create_git_repo(self.config.shell_root_path());
+        Ok(())
+    }
+
+    /// Clones a repository that holds several shells, one in each of
+    /// its subdirectories.
+    pub fn clone_shared_repo(
+        &self,
+        file_ops: &mut FileOperations,
+        url: &str,
+        options: &git::CloneOptions,
+    ) -> Result<()> {
+        if self.config.is_shared_repo() || !self.config.shell_names().is_empty() {
+            return Err(Error::ShellsAlreadyExist);
+        }
+        file_ops.clone_git_repo(url, self.config.shell_root_path(), options);
+        Ok(())
+    }
+
+    /// Has hermit check, from now on, that the commits that the shell
+    /// `name` is switched to and synced with are signed by a key that
+    /// git trusts, starting with the one it is on. Without a name, it
+    /// is the repository that holds all the shells that is checked.
+    pub fn require_signatures(&self, name: Option<&str>) -> Result<()> {
+        let repo_root = match name {
+            Some(name) => self.shell(name)?.root_path(),
+            None => self.config.shell_root_path(),
+        };
+        git::require_signatures(&repo_root)?;
+        git::verify_signature(&repo_root, "HEAD")
+    }
+
+    /// Creates a shell from an existing repository, named after the
+    /// repository unless a name is given. Returns the shell's name.
+    pub fn clone_shell(
+        &self,
+        file_ops: &mut FileOperations,
+        url: &str,
+        name: Option<&str>,
+        options: &git::CloneOptions,
+    ) -> Result<String> {
+        let name = match name {
+            Some(name) => name,
+            None => git::repo_name_from_url(url)
+                .ok_or_else(|| Error::NoShellNameInUrl(url.to_owned()))?,
+        };
+        config::validate_shell_name(name)?;
         if self.config.shell_exists(name) {
-            if let Ok(shell) = self.current_shell() {
-                shell.unlink(file_ops)
-            }
+            return Err(Error::ShellAlreadyExists(name.to_owned()));
+        }
+
+        let shell = Shell::new(name, self.config.clone());
+        file_ops.create_dir(self.config.shell_root_path());
+        file_ops.clone_git_repo(url, shell.root_path(), options);
+        self.shell_created(name);
+        Ok(name.to_owned())
+    }
+
+    /// Fills a shell that `init_shell` has queued the creation of with
+    /// the files from a template.
+    pub fn apply_template(
+        &self,
+        file_ops: &mut FileOperations,
+        shell_name: &str,
+        files: &[TemplateFile],
+    ) {
+        let shell_root = Shell::new(shell_name, self.config.clone()).root_path();
+        let dirs = files
+            .iter()
+            .filter_map(|file| file.path.parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect::<BTreeSet<_>>();
+        for dir in dirs {
+            file_ops.create_dir(shell_root.join(dir));
+        }
+        for file in files {
+            file_ops.write_file(shell_root.join(&file.path), file.contents.clone());
+        }
+    }
 
-            self.set_current_shell(name)?;
+    /// Queues up a copy of the shell `source` as a new shell, `name`.
+    /// A shell with a repository of its own is cloned, keeping its
+    /// history, and a shell in a shared repository has its files
+    /// copied into a new directory. The copy's metadata says it was
+    /// created today, with `origin` as its remote.
+    pub fn fork_shell(
+        &self,
+        file_ops: &mut FileOperations,
+        source: &str,
+        name: &str,
+        origin: Option<&str>,
+    ) -> Result<()> {
+        let source = self.shell(source)?;
+        config::validate_shell_name(name)?;
+        if self.config.shell_exists(name) {
+            return Err(Error::ShellAlreadyExists(name.to_owned()));
+        }
 
-            if let Ok(shell) = self.current_shell() {
-                shell.link(file_ops)
+        let source_root = source.root_path();
+        let fork_root = Shell::new(name, self.config.clone()).root_path();
+        if self.config.is_shared_repo() {
+            let local_dir = source_root.join(METADATA_DIR).join(LOCAL_DIR);
+            file_ops.create_dir(&fork_root);
+            for entry in WalkDir::new(&source_root)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_entry(|entry| entry.path() != local_dir)
+                .filter_map(StdResult::ok)
+                .filter(|entry| entry.file_type().is_file())
+            {
+                if let Ok(relative) = entry.path().strip_prefix(&source_root) {
+                    file_ops.copy(fork_root.join(relative), entry.path());
+                }
             }
-            Ok(())
         } else {
-            Err(Error::ShellDoesNotExist)
+            let url = source_root
+                .to_str()
+                .ok_or_else(|| Error::PathNotUtf8(source_root.clone()))?;
+            file_ops.clone_git_repo(url, &fork_root, &git::CloneOptions::default());
         }
+
+        let info = ShellInfo {
+            created: ShellInfo::new(None, None).created,
+            origin: origin.map(ToOwned::to_owned),
+            ..ShellInfo::load(&source_root)?
+        };
+        file_ops.write_file(fork_root.join(ShellInfo::relative_path()), info.to_toml()?);
+        self.shell_created(name);
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// The URL of the `origin` remote of a shell's repository, if it has
+    /// one.
+    pub fn origin_url(&self, name: &str) -> Result<Option<String>> {
+        let shell = self.shell(name)?;
+        if self.config.is_shared_repo() {
+            return Ok(None);
+        }
+        Ok(git::remotes(shell.root_path())?
+            .into_iter()
+            .find(|remote| remote.name == "origin")
+            .and_then(|remote| remote.url))
+    }
 
-    use std::{path::PathBuf, rc::Rc};
+    /// Points the `origin` remote of a shell forked by `fork_shell` at
+    /// `url`, or removes it, since right after the fork it points at
+    /// the shell that was forked. Shells in a shared repository share
+    /// its remotes, so they are left alone.
+    pub fn set_fork_origin(&self, name: &str, url: Option<&str>) -> Result<()> {
+        if self.config.is_shared_repo() {
+            return Ok(());
+        }
+        let shell_root = self.shell(name)?.root_path();
+        match url {
+            Some(url) => git::set_remote_url(&shell_root, "origin", url),
+            None => git::remove_remote(&shell_root, "origin"),
+        }
+    }
 
-    use crate::{
-        config::mock::MockConfig, config::Config, file_operations::FileOperations,
-        file_operations::Op, test_helpers::ops::*,
-    };
+    /// Merges the files of the shell `from` into the shell `into`.
+    /// Files that both shells have with the same contents are left
+    /// alone, and `resolve` is asked what to do about each file they
+    /// both have with different contents. The shells' metadata and git
+    /// repositories aren't merged.
+    pub fn merge_shells(
+        &self,
+        file_ops: &mut FileOperations,
+        from: &str,
+        into: &str,
+        mut resolve: impl FnMut(&Path) -> Result<Resolution>,
+    ) -> Result<Merge> {
+        let from_root = self.shell(from)?.root_path();
+        let into_root = self.shell(into)?.root_path();
+        if from_root == into_root {
+            return Err(Error::MergeIntoItself(into.to_owned()));
+        }
 
-    fn hermit(config: &MockConfig) -> Hermit<MockConfig> {
-        Hermit::new(config.clone())
+        let files = WalkDir::new(&from_root)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() != 1
+                    || (entry.file_name() != ".git" && entry.file_name() != METADATA_DIR)
+            })
+            .filter_map(StdResult::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| Some(entry.path().strip_prefix(&from_root).ok()?.to_path_buf()))
+            .collect::<Vec<_>>();
+
+        let mut merge = Merge::default();
+        let mut taken = BTreeSet::new();
+        for path in files {
+            let source = from_root.join(&path);
+            let destination = into_root.join(&path);
+            if fs::symlink_metadata(&destination).is_err() {
+                file_ops.copy(&destination, &source);
+                taken.insert(path.clone());
+                merge.added.push(path);
+                continue;
+            }
+            let same = match (
+                status::content_hash(&source),
+                status::content_hash(&destination),
+            ) {
+                (Ok(source), Ok(destination)) => source == destination,
+                _ => false,
+            };
+            if same {
+                merge.kept.push(path);
+                continue;
+            }
+
+            match resolve(&path)? {
+                Resolution::Ours => merge.kept.push(path),
+                Resolution::Theirs => {
+                    file_ops.copy(&destination, &source);
+                    merge.replaced.push(path);
+                }
+                Resolution::Rename(new_path) => {
+                    if fs::symlink_metadata(into_root.join(&new_path)).is_ok()
+                        || !taken.insert(new_path.clone())
+                    {
+                        return Err(Error::AlreadyInShell(new_path));
+                    }
+                    file_ops.copy(into_root.join(&new_path), &source);
+                    merge.renamed.push((path, new_path));
+                }
+            }
+        }
+        Ok(merge)
     }
 
-    #[test]
-    fn returns_the_current_shell() {
-        let config = MockConfig::new();
-        let hermit = hermit(&config);
+    /// Removes a shell and all of its files. A shell with a repository
+    /// of its own takes the repository with it.
+    pub fn nuke_shell(&self, file_ops: &mut FileOperations, name: &str) -> Result<()> {
+        let shell = self.shell(name)?;
+        if self
+            .current_shell()
+            .is_ok_and(|current| current.name == shell.name)
+        {
+            return Err(Error::ShellInUse(shell.name));
+        }
+        file_ops.remove(shell.root_path());
+        Ok(())
+    }
 
-        let shell = hermit.current_shell().unwrap();
-        assert_eq!(shell.name, "default");
-        assert_eq!(shell.config, Rc::new(config));
+    /// Configures a remote for a shell's repository. The repository
+    /// may be one that `init_shell` has only queued the creation of.
+    pub fn add_remote(
+        &self,
+        file_ops: &mut FileOperations,
+        shell_name: &str,
+        name: &str,
+        url: &str,
+    ) {
+        let shell = Shell::new(shell_name, self.config.clone());
+        file_ops.add_git_remote(shell.root_path(), name, url);
     }
 
-    #[test]
-    fn can_set_the_current_shell() {
-        let mut config = MockConfig::new();
-        config
-            .set_current_shell_name("current")
-            .expect("Setting shell name failed");
-        let mut hermit = hermit(&config);
+    /// Resolves the shell name `-` to the previously active shell,
+    /// like `cd -` does. Any other name is returned as is.
+    pub fn resolve_shell_name(&self, name: &str) -> Result<String> {
+        if name == "-" {
+            self.config
+                .previous_shell_name()
+                .map(ToOwned::to_owned)
+                .ok_or(Error::NoPreviousShell)
+        } else {
+            Ok(name.to_owned())
+        }
+    }
 
-        assert_eq!(hermit.current_shell().unwrap().name, "current");
-        hermit
-            .set_current_shell("default")
-            .expect("Setting shell name failed");
-        assert_eq!(hermit.current_shell().unwrap().name, "default");
+    pub fn inhabit(&mut self, file_ops: &mut FileOperations, name: &str) -> Result<()> {
+        self.inhabit_with_tags(file_ops, name, &TagFilter::default())
     }
 
-    #[test]
-    fn can_initialize_a_new_shell() {
-        let config = MockConfig::with_root(".hermit-config");
-        let mut hermit = hermit(&config);
-        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+    /// Switches to a shell like `inhabit`, but only links the files
+    /// that `tags` allows.
+    pub fn inhabit_with_tags(
+        &mut self,
+        file_ops: &mut FileOperations,
+        name: &str,
+        tags: &TagFilter,
+    ) -> Result<()> {
+        let name = &self.existing_shell_name(&self.resolve_shell_name(name)?)?;
+        self.plan_inhabit(file_ops, name, tags)?;
+        self.set_current_shell(name)
+    }
 
-        hermit
-            .init_shell(&mut file_ops, "new-one")
-            .expect("Init shell failed");
-        let first_op = &file_ops.operations()[0];
-        assert_eq!(
-            *first_op,
-            Op::MkDir(PathBuf::from("/home/geoff/.hermit-config/shells"))
-        );
-        let second_op = &file_ops.operations()[1];
-        assert_eq!(
-            *second_op,
-            Op::GitInit(PathBuf::from("/home/geoff/.hermit-config/shells/new-one"))
-        );
+    /// Queues the changes that switching to the shell `name` makes,
+    /// without switching to it.
+    pub fn plan_inhabit(
+        &self,
+        file_ops: &mut FileOperations,
+        name: &str,
+        tags: &TagFilter,
+    ) -> Result<()> {
+        let shell = self.shell(&self.resolve_shell_name(name)?)?;
+        if self.shell_info(&shell.name)?.system.is_some() {
+            return Err(Error::SystemShell(shell.name));
+        }
+        self.check_signature(&shell.name)?;
+        if let Ok(current) = self.current_shell() {
+            current.unlink(file_ops)?;
+        }
+        shell.link_with_tags(file_ops, tags)?;
+        file_ops.merge_relinks();
+        Ok(())
     }
 
-    #[test]
-    fn can_inhabit_and_change_shells() {
-        let hermit_root = PathBuf::from(".hermit-config");
-        let mut config = MockConfig::with_root(&hermit_root);
-        config.set_paths(vec![".bashrc", ".boot/profile.boot"]);
-        let mut hermit = hermit(&config);
-        let op_root_path = PathBuf::from("/home/geoff");
-        let mut file_ops = FileOperations::rooted_at(&op_root_path);
+    /// Queues the changes that put the shell `name` in place below the
+    /// root of `file_ops`, when that is another directory than the home
+    /// directory, like another user's or a chroot's. The links hermit
+    /// made there to the files of other shells are removed, and the
+    /// shell's files are linked. Which shell is current is left alone,
+    /// since that is about the home directory.
+    pub fn link_into_target(
+        &self,
+        file_ops: &mut FileOperations,
+        name: &str,
+        tags: &TagFilter,
+    ) -> Result<()> {
+        let shell = self.shell(name)?;
+        if self.shell_info(&shell.name)?.system.is_some() {
+            return Err(Error::SystemShell(shell.name));
+        }
+        self.check_signature(&shell.name)?;
+        let shell_root = shell.root_path();
+        for (path, target) in self.journal().created_links()? {
+            if path.starts_with(file_ops.root()) && !target.starts_with(&shell_root) {
+                file_ops.unlink(&path, &target);
+            }
+        }
+        shell.link_with_tags(file_ops, tags)?;
+        file_ops.merge_relinks();
+        Ok(())
+    }
 
-        hermit
-            .inhabit(&mut file_ops, "default")
-            .expect("Inhabit failed");
+    /// Checks that the commit the shell `name` is on is signed, when
+    /// hermit was asked to check its signatures. Anything that links
+    /// the shell's files or runs its hooks checks this first.
+    pub fn check_signature(&self, name: &str) -> Result<()> {
+        let shell_root = self.shell(name)?.root_path();
+        if git::requires_signatures(&shell_root) {
+            git::verify_signature(&shell_root, "HEAD")?;
+        }
+        Ok(())
+    }
 
-        let new_shell_root = hermit_root.join("shells/default");
-        assert_eq!(
-            file_ops.operations(),
-            &vec![
-                Op::Remove(op_root_path.join(".bashrc")),
-                Op::Remove(op_root_path.join(".boot/profile.boot")),
-                link_op_for(&new_shell_root, &op_root_path, ".bashrc"),
-                link_op_for(&new_shell_root, &op_root_path, ".boot/profile.boot"),
-            ]
-        );
+    /// The hooks of the shell `name`, run with hermit's environment as
+    /// if that shell were the current one, once its signature checks
+    /// out.
+    pub fn hooks(&self, name: &str, target_root: impl AsRef<Path>) -> Result<Hooks> {
+        self.check_signature(name)?;
+        let shell_root = self.shell(name)?.root_path();
+        let mut env = self.environment(target_root);
+        env.retain(|(var, _)| *var != env::SHELL_VAR && *var != env::SHELL_PATH_VAR);
+        env.push((env::SHELL_PATH_VAR, shell_root.clone()));
+        env.push((env::SHELL_VAR, PathBuf::from(name)));
+        Ok(Hooks::new(shell_root)
+            .with_timeout(Duration::from_secs(self.settings().hooks.timeout))
+            .with_env(env))
+    }
+
+    /// Runs the hooks of the shell `name` for `event`, telling the
+    /// observers about each one once it has run, and returns how many
+    /// ran. The first hook that fails stops the others, and is what
+    /// the error is about.
+    pub fn run_hooks(
+        &self,
+        name: &str,
+        target_root: impl AsRef<Path>,
+        event: HookEvent,
+    ) -> Result<usize> {
+        let hooks = self.hooks(name, target_root)?;
+        let dir = hooks.dir();
+        let runs = hooks.run(event);
+        for run in &runs {
+            self.observers.notify(Event::HookRan {
+                event,
+                hook: run
+                    .path
+                    .strip_prefix(&dir)
+                    .unwrap_or(&run.path)
+                    .to_path_buf(),
+                output: run.output.clone(),
+                failed: run.failure.is_some(),
+            });
+            run.check(event)?;
+        }
+        Ok(runs.len())
+    }
+
+    /// Runs the bootstrap steps of the shell `name` that haven't
+    /// completed on this machine yet, telling the observers about each
+    /// one before it starts, and returns the names of those that ran.
+    /// Without `input`, the steps are run the way
+    /// `Bootstrap::without_input` runs them.
+    pub fn run_bootstrap(
+        &self,
+        name: &str,
+        target_root: impl AsRef<Path>,
+        input: bool,
+    ) -> Result<Vec<String>> {
+        let mut bootstrap = self
+            .bootstrap(name)?
+            .with_env(self.environment(target_root));
+        if !input {
+            bootstrap = bootstrap.without_input();
+        }
+        let mut ran = vec![];
+        for step in bootstrap.pending_steps() {
+            self.observers.notify(Event::BootstrapStepStarted {
+                name: step.name.clone(),
+            });
+            bootstrap.run_step(&step)?;
+            ran.push(step.name);
+        }
+        Ok(ran)
+    }
+
+    /// Switches to the shell `name` like `inhabit_with_tags`, and sees
+    /// the switch through: the shell's pre-use hooks run first, then
+    /// the queued changes are carried out, then its bootstrap steps run
+    /// if it has never been bootstrapped on this machine, and its
+    /// post-use hooks run last. What carrying out the changes did is
+    /// added to `report`, even when a later step fails.
+    pub fn use_shell(
+        &mut self,
+        file_ops: &mut FileOperations,
+        name: &str,
+        tags: &TagFilter,
+        input: bool,
+        report: &mut CommitReport,
+    ) -> Result<()> {
+        let name = &self.existing_shell_name(&self.resolve_shell_name(name)?)?;
+        self.run_hooks(name, file_ops.root(), HookEvent::PreUse)?;
+        self.inhabit_with_tags(file_ops, name, tags)?;
+
+        let bootstrap = self.bootstrap(name)?;
+        if !bootstrap.has_run() && !bootstrap.pending_steps().is_empty() {
+            report.extend(file_ops.flush());
+            self.run_bootstrap(name, file_ops.root(), input)?;
+        }
+        report.extend(file_ops.flush());
+        self.run_hooks(name, file_ops.root(), HookEvent::PostUse)?;
+        Ok(())
+    }
+
+    /// Makes `change` to the current shell, like a git command that
+    /// can change which files it has, with the shell's files unlinked
+    /// while it runs, so that the home directory never links to files
+    /// halfway through changing, like ones with conflict markers.
+    /// Linking them again is queued afterwards, and carried out right
+    /// away if `change` fails, since callers don't carry out what is
+    /// queued once something has failed. What carrying out the changes
+    /// did is added to `report`.
+    pub fn relinking<R>(
+        &self,
+        file_ops: &mut FileOperations,
+        report: &mut CommitReport,
+        change: impl FnOnce(&Path) -> Result<R>,
+    ) -> Result<R> {
+        let shell = self.current_shell()?;
+        shell.unlink(file_ops)?;
+        report.extend(file_ops.flush());
+
+        let result = change(&shell.root_path());
+        shell.link(file_ops)?;
+        if result.is_err() {
+            report.extend(file_ops.flush());
+        }
+        result
+    }
+
+    /// Sets this machine up with the shell `request` asks for, the way
+    /// `hermit provision` does: the hermit root is scaffolded, the
+    /// shell is cloned unless it already is, it is switched to with its
+    /// use hooks, or brought up to date if it is already current, and
+    /// its pending bootstrap steps run without input. The changes of
+    /// each step are carried out before the next one starts, stopping
+    /// at the first failure, and what carrying them out did is added to
+    /// `report`. `log` is handed a record of each step once it is done,
+    /// and of the step that failed, if one did. Provisioning a machine
+    /// that is already set up changes nothing.
+    pub fn provision(
+        &mut self,
+        file_ops: &mut FileOperations,
+        request: &provision::Request,
+        report: &mut CommitReport,
+        mut log: impl FnMut(&Record),
+    ) -> Result<()> {
+        file_ops.set_fail_fast(true);
+        self.provision_steps(file_ops, request, report, &mut log)
+            .map_err(|(step, err)| {
+                log(&Record::new(step, &request.name, Provisioned::Failed)
+                    .with_detail(err.to_string()));
+                err
+            })
+    }
+
+    /// Carries out the steps of `provision` one after the other.
+    /// Returns the step that failed, and why.
+    fn provision_steps(
+        &mut self,
+        file_ops: &mut FileOperations,
+        request: &provision::Request,
+        report: &mut CommitReport,
+        log: &mut impl FnMut(&Record),
+    ) -> StdResult<(), (&'static str, Error)> {
+        let (url, name) = (request.url.as_str(), request.name.as_str());
+        let mut done = |step, changed: usize, detail: Option<String>| {
+            let outcome = if changed > 0 {
+                Provisioned::Changed
+            } else {
+                Provisioned::Unchanged
+            };
+            let record = Record::new(step, name, outcome);
+            log(&match detail {
+                Some(detail) => record.with_detail(detail),
+                None => record,
+            });
+        };
+        let at = |step| move |err| (step, err);
+
+        self.scaffold(file_ops);
+        let changed = flush_strictly(file_ops, report).map_err(at("root"))?;
+        done("root", changed, None);
+
+        if self.shell_names().iter().any(|shell| shell == name) {
+            let origin = self.origin_url(name).map_err(at("clone"))?;
+            if origin.is_some_and(|origin| origin != url) {
+                return Err(("clone", Error::ShellAlreadyExists(name.to_owned())));
+            }
+            done("clone", 0, None);
+        } else {
+            let options = git::CloneOptions {
+                branch: request.branch.clone(),
+                depth: None,
+            };
+            self.clone_shell(file_ops, url, Some(name), &options)
+                .and_then(|name| {
+                    let info = ShellInfo::new(None, Some(url));
+                    self.record_shell_info(file_ops, &name, &info)
+                })
+                .map_err(at("clone"))?;
+            flush_strictly(file_ops, report).map_err(at("clone"))?;
+            done("clone", 1, Some(url.to_owned()));
+        }
+
+        let switching = self
+            .current_shell()
+            .map_or(true, |shell| shell.name != name);
+        if switching {
+            let ran = self
+                .run_hooks(name, file_ops.root(), HookEvent::PreUse)
+                .map_err(at(HookEvent::PreUse.name()))?;
+            done(HookEvent::PreUse.name(), ran, None);
+            self.inhabit_with_tags(file_ops, name, &request.tags)
+                .map_err(at("use"))?;
+        } else {
+            self.apply(file_ops, &request.tags).map_err(at("use"))?;
+        }
+        let changed = flush_strictly(file_ops, report).map_err(at("use"))?;
+        let detail = format!("{} change{}", changed, if changed == 1 { "" } else { "s" });
+        done("use", changed, (changed > 0).then_some(detail));
+
+        let ran = self
+            .run_bootstrap(name, file_ops.root(), false)
+            .map_err(at("bootstrap"))?;
+        done(
+            "bootstrap",
+            ran.len(),
+            (!ran.is_empty()).then(|| ran.join(", ")),
+        );
+
+        if switching {
+            let ran = self
+                .run_hooks(name, file_ops.root(), HookEvent::PostUse)
+                .map_err(at(HookEvent::PostUse.name()))?;
+            done(HookEvent::PostUse.name(), ran, None);
+        }
+        Ok(())
+    }
+
+    /// Brings the current shell up to date with its remote. What is
+    /// fetched has its signature checked before anything is checked
+    /// out, when the shell requires signed commits, and it is that very
+    /// commit that the shell is rebased onto, rather than whatever a
+    /// second fetch would bring. On a machine with a branch of its own,
+    /// that branch is rebased onto the shell's main branch instead of
+    /// its upstream. The rebase runs `relinking`, so conflict markers
+    /// never show up in the home directory, and `choose` settles each
+    /// file that changed both here and in the remote.
+    pub fn pull(
+        &self,
+        file_ops: &mut FileOperations,
+        report: &mut CommitReport,
+        choose: impl FnMut(&Path) -> Result<ConflictChoice>,
+    ) -> Result<()> {
+        let shell_root = self.current_shell()?.root_path();
+        let machine_branch = MachineBranches::load(&shell_root)?
+            .branch_for(&self.host().hostname)
+            .map(ToOwned::to_owned);
+
+        git::run_remote(&shell_root, &["fetch", "--all", "--prune"])?;
+        let rebase_onto =
+            if machine_branch.is_some() && machine_branch == git::current_branch(&shell_root)? {
+                Some(git::main_branch(&shell_root)?.ok_or(Error::NoMainBranch)?)
+            } else {
+                None
+            };
+        let onto = git::commit_id(&shell_root, rebase_onto.as_deref().unwrap_or("@{upstream}"))?;
+        if git::requires_signatures(&shell_root) {
+            git::verify_signature(&shell_root, &onto)?;
+        }
+        let rebased = self.relinking(file_ops, report, |shell_root| {
+            let pulled = git::run(shell_root, &["rebase", &onto]);
+            if pulled.is_err() && git::rebase_in_progress(shell_root)? {
+                return git::resolve_rebase(shell_root, choose);
+            }
+            pulled
+        });
+        self.audited(format!("rebase onto {}", &onto[..7]), rebased)
+    }
+
+    /// The environment variables that describe hermit's state to
+    /// child processes like plugins, with links being made in
+    /// `target_root`.
+    pub fn environment(&self, target_root: impl AsRef<Path>) -> Vec<(&'static str, PathBuf)> {
+        let mut vars = vec![
+            (env::ROOT_VAR, self.config.root_path().clone()),
+            (env::HOME_VAR, PathBuf::from(target_root.as_ref())),
+        ];
+        if let Ok(shell) = self.current_shell() {
+            vars.push((env::SHELL_PATH_VAR, shell.root_path()));
+            vars.push((env::SHELL_VAR, PathBuf::from(shell.name)));
+        }
+        let host = self.config.host();
+        vars.extend(vec![
+            (env::HOSTNAME_VAR, PathBuf::from(&host.hostname)),
+            (env::OS_VAR, PathBuf::from(&host.os)),
+            (env::ARCH_VAR, PathBuf::from(&host.arch)),
+            (env::USERNAME_VAR, PathBuf::from(&host.username)),
+        ]);
+        vars
+    }
+
+    /// The message for a commit that hermit makes in the shell `name`
+    /// of changes to `files`, where `summary` is what hermit would
+    /// say on its own. The shell's manifest can word it differently,
+    /// with `{{summary}}`, `{{files}}`, `{{date}}`, `{{shell}}` and the
+    /// host's variables, like `{{hostname}}`, standing for their values.
+    pub fn commit_message(
+        &self,
+        name: &str,
+        summary: &str,
+        files: &[impl AsRef<Path>],
+    ) -> Result<String> {
+        let template = match self.shell(name)?.manifest()?.commit.message {
+            Some(template) => template,
+            None => return Ok(summary.to_owned()),
+        };
+        let files = message::paths(files);
+        let mut placeholders = Placeholders::for_shell(name, self.config.host().variables());
+        placeholders.set("summary", summary);
+        placeholders.set("files", files);
+        placeholders.set("date", shell_info::today());
+        let message = placeholders.expand(template.into_bytes());
+        Ok(String::from_utf8_lossy(&message).trim().to_owned())
+    }
+
+    /// Commits `paths` in the shell `name`, with a message worded by
+    /// `commit_message`, signed if the shell asks for that. The
+    /// checksums of the shell's copied and secret files are recorded
+    /// and committed along with them, since git doesn't run the
+    /// pre-commit hook that would record them for commits that hermit
+    /// makes.
+    pub fn commit(&self, name: &str, summary: &str, paths: &[impl AsRef<Path>]) -> Result<()> {
+        let shell = self.shell(name)?;
+        let message = self.commit_message(name, summary, paths)?;
+        let sign = shell.manifest()?.commit.sign;
+        let mut paths = paths
+            .iter()
+            .map(|path| path.as_ref().to_path_buf())
+            .collect::<Vec<_>>();
+        let recorded = shell.root_path().join(Checksums::relative_path()).exists();
+        let checked = self
+            .config
+            .shell_files(name)?
+            .into_iter()
+            .any(|file| checksums::is_checked(&file));
+        if recorded || checked {
+            paths.push(self.record_checksums(name)?.0);
+        }
+        let committed = git::commit_paths(shell.root_path(), &paths, &message, sign);
+        self.audited(
+            format!("commit \"{}\" in {}", summary, shell.name),
+            committed.map(drop),
+        )
+    }
+
+    /// Whether the commits that hermit makes in the shell `name` are
+    /// signed, either because its manifest or git asks for that.
+    pub fn signs_commits(&self, name: &str) -> Result<bool> {
+        let shell = self.shell(name)?;
+        Ok(shell.manifest()?.commit.sign || git::signs_commits_by_default(shell.root_path()))
+    }
+
+    /// The environment variables that the current shell's manifest
+    /// declares, with a leading `~` in their values standing for
+    /// `target_root`.
+    pub fn exported_env(&self, target_root: impl AsRef<Path>) -> Result<Vec<(String, String)>> {
+        let manifest = self.current_shell()?.manifest()?;
+        let home = target_root.as_ref().display().to_string();
+        manifest
+            .env
+            .into_iter()
+            .map(|(name, value)| {
+                if !env::is_var_name(&name) {
+                    return Err(Error::InvalidManifest(format!(
+                        "'{}' can't be the name of an environment variable",
+                        name
+                    )));
+                }
+                let value = match value.strip_prefix('~') {
+                    Some(rest) if rest.is_empty() || rest.starts_with('/') => home.clone() + rest,
+                    _ => value,
+                };
+                Ok((name, value))
+            })
+            .collect()
+    }
+
+    /// The built-in variables that templates can use: those that
+    /// describe the machine, along with the directory links are made
+    /// in as `home`, and the current shell as `shell`. Commands that
+    /// hermit runs get the same values from its environment.
+    pub fn variables(&self, target_root: impl AsRef<Path>) -> Vec<(&'static str, String)> {
+        let mut vars = self.config.host().variables();
+        vars.push(("home", target_root.as_ref().display().to_string()));
+        if let Ok(shell) = self.current_shell() {
+            vars.push(("shell", shell.name));
+        }
+        vars
+    }
+
+    /// The links in `target_root` that point into the shells of some
+    /// other hermit root.
+    pub fn foreign_links(&self, target_root: impl AsRef<Path>) -> Vec<ForeignLink> {
+        doctor::foreign_links(target_root.as_ref(), self.config.root_path())
+    }
+
+    /// What keeps the shells from keeping up with their remotes. When
+    /// `offline`, the remotes aren't asked anything.
+    pub fn remote_problems(&self, offline: bool) -> Vec<RemoteProblem> {
+        let shells = self
+            .shell_names()
+            .into_iter()
+            .filter_map(|name| {
+                let root = self.shell(&name).ok()?.root_path();
+                Some((name, root))
+            })
+            .collect::<Vec<_>>();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
+        doctor::remote_problems(&shells, now, offline)
+    }
+
+    /// The directories in the shell `name` with more files in them than
+    /// the `large_directory` setting allows.
+    pub fn large_directories(&self, name: &str) -> Result<Vec<LargeDirectory>> {
+        let files = self
+            .config
+            .shell_files(name)?
+            .into_iter()
+            .map(|file| file.source);
+        Ok(doctor::large_directories(
+            files,
+            self.settings().walk.large_directory,
+        ))
+    }
+
+    /// Inspects how the files of the current shell are linked into
+    /// `target_root`.
+    pub fn status(&self, target_root: impl AsRef<Path>) -> Result<Status> {
+        let shell = self.current_shell()?;
+        let shell_root = shell.root_path();
+        let files = self
+            .config
+            .shell_files(&shell.name)?
+            .into_iter()
+            .collect::<Vec<_>>();
+        let target_root = target_root.as_ref();
+        let states = parallel::map(&files, |file| {
+            FileState::of_file(file, target_root, &shell_root)
+        });
+        let files = files
+            .into_iter()
+            .zip(states)
+            .map(|(file, state)| FileStatus { file, state })
+            .collect();
+
+        Ok(Status {
+            info: ShellInfo::load(&shell_root)?,
+            shell: shell.name,
+            files,
+        })
+    }
+
+    /// The names of all of the shells, in alphabetical order.
+    pub fn shell_names(&self) -> Vec<String> {
+        self.config.shell_names()
+    }
+
+    /// How many files the shell `name` has and how much room they take
+    /// up, with the `top` largest of them.
+    pub fn stats(&self, name: &str, top: usize) -> Result<ShellStats> {
+        let shell = self.shell(name)?;
+        let shell_root = shell.root_path();
+        let mut stats = ShellStats::of_files(&shell.name, &shell_root, &shell.files()?, top);
+        stats.last_commit = git::last_commit_time(&shell_root).unwrap_or(None);
+        Ok(stats)
+    }
+
+    /// Bakes the files of the shell `name` that `tags` allows into a
+    /// container image in the directory `out`, following `recipe`.
+    pub fn bake(&self, name: &str, tags: &TagFilter, recipe: &Recipe, out: &Path) -> Result<Baked> {
+        let shell = self.shell(name)?;
+        let files = shell
+            .files()?
+            .into_iter()
+            .filter(|file| tags.allows(file))
+            .collect::<Vec<_>>();
+        let baked = recipe.bake(&shell.name, &shell.root_path(), &files, out);
+        self.audited(format!("bake {} into {}", shell.name, out.display()), baked)
+    }
+
+    /// Records the checksums of the copied and secret files of the shell
+    /// `name` as they are now, in the shell's metadata directory.
+    /// Returns where they were recorded and how many there are.
+    pub fn record_checksums(&self, name: &str) -> Result<(PathBuf, usize)> {
+        let shell_root = self.shell(name)?.root_path();
+        let files = self
+            .config
+            .shell_files(name)?
+            .into_iter()
+            .collect::<Vec<_>>();
+        let checksums = Checksums::of_files(&shell_root, &files)?;
+        checksums.save(&shell_root)?;
+        Ok((Checksums::relative_path(), checksums.len()))
+    }
+
+    /// Checks the copied and secret files of the current shell, and
+    /// their copies in `target_root`, against the checksums recorded
+    /// when the shell was last committed.
+    pub fn verify(&self, target_root: impl AsRef<Path>) -> Result<Vec<Verification>> {
+        let shell = self.current_shell()?;
+        let shell_root = shell.root_path();
+        let checksums = Checksums::load(&shell_root)?;
+        Ok(self
+            .config
+            .shell_files(&shell.name)?
+            .into_iter()
+            .filter(checksums::is_checked)
+            .map(|file| {
+                let copy = (file.strategy == Strategy::Copy)
+                    .then(|| target_root.as_ref().join(&file.target));
+                let verdict = checksums.verify(
+                    &file,
+                    &shell_root.join(&file.source),
+                    copy.as_deref(),
+                    // Without git, there's no telling; assume the user
+                    // changed it.
+                    || git::has_changes(&shell_root, &file.source).unwrap_or(true),
+                );
+                Verification { file, verdict }
+            })
+            .collect())
+    }
+
+    /// The files of the shell `name` that are kept in Git LFS, but
+    /// whose contents haven't been fetched, so that the shell only has
+    /// pointers to them.
+    pub fn missing_lfs_objects(&self, name: &str) -> Result<Vec<PathBuf>> {
+        let shell_root = self.shell(name)?.root_path();
+        if git::open(&shell_root).is_err() {
+            return Ok(vec![]);
+        }
+        let sources = self
+            .config
+            .shell_files(name)?
+            .into_iter()
+            .map(|file| file.source)
+            .collect::<Vec<_>>();
+        lfs::missing_objects(&shell_root, &sources)
+    }
+
+    /// Walks the files of every shell afresh, instead of going by the
+    /// index of them kept in the hermit root, and returns how many
+    /// files each shell has.
+    pub fn refresh_index(&self) -> Result<Vec<(String, usize)>> {
+        self.shell_names()
+            .into_iter()
+            .map(|name| {
+                let count = self.config.refresh_index(&name)?;
+                Ok((name, count))
+            })
+            .collect()
+    }
+
+    /// What is known about a shell, from its metadata directory.
+    pub fn shell_info(&self, name: &str) -> Result<ShellInfo> {
+        ShellInfo::load(self.shell(name)?.root_path())
+    }
+
+    /// Writes the metadata of a newly created or cloned shell, unless
+    /// it already has some.
+    pub fn record_shell_info(
+        &self,
+        file_ops: &mut FileOperations,
+        shell_name: &str,
+        info: &ShellInfo,
+    ) -> Result<()> {
+        let shell = Shell::new(shell_name, self.config.clone());
+        file_ops.create_file(
+            shell.root_path().join(ShellInfo::relative_path()),
+            info.to_toml()?,
+        );
+        Ok(())
+    }
+
+    /// Moves files from where shells are linked into the current shell,
+    /// and links them back in their place. `files` must be inside the
+    /// root of `file_ops`; files that are already links into the shell,
+    /// and anything inside the hermit root, are skipped. Returns the
+    /// files that will be added.
+    ///
+    /// With the copy strategy, the files are copied into the shell
+    /// instead and left where they are, and the shell's manifest gets
+    /// an entry saying they are copies.
+    pub fn add(
+        &self,
+        file_ops: &mut FileOperations,
+        files: &[PathBuf],
+        strategy: Strategy,
+    ) -> Result<Vec<ShellFile>> {
+        let shell_root = self.current_shell()?.root_path();
+        let add_dots = ShellInfo::load(&shell_root)?.add_dots;
+
+        let mut added = vec![];
+        for path in files {
+            let relative = path
+                .strip_prefix(file_ops.root())
+                .map_err(|_| Error::NotInTargetRoot(path.clone()))?;
+            let is_linked = fs::read_link(path).is_ok_and(|target| target.starts_with(&shell_root));
+            if is_linked || path.starts_with(self.config.root_path()) {
+                continue;
+            }
+            let source = if add_dots {
+                manifest::without_leading_dot(relative)
+            } else {
+                relative.to_path_buf()
+            };
+            if fs::symlink_metadata(shell_root.join(&source)).is_ok() {
+                return Err(Error::AlreadyInShell(source));
+            }
+            if let Some(max_depth) = self.settings().walk.max_depth {
+                if source.components().count() > max_depth {
+                    return Err(Error::TooDeep {
+                        path: source,
+                        max_depth,
+                    });
+                }
+            }
+            added.push(ShellFile::mapped(source, relative).with_strategy(strategy));
+        }
+
+        match strategy {
+            Strategy::Link => {
+                for file in &added {
+                    let source = shell_root.join(&file.source);
+                    paths::check_link(&file_ops.root().join(&file.target), &source, &shell_root)?;
+                }
+                for file in &added {
+                    let source = shell_root.join(&file.source);
+                    file_ops.move_file(&file.target, &source);
+                    file_ops.link(&file.target, &source);
+                }
+            }
+            Strategy::Copy if !added.is_empty() => {
+                let manifest_path = shell_root.join(MANIFEST_FILE_NAME);
+                let mut manifest = match fs::read_to_string(&manifest_path) {
+                    Ok(contents) => contents,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+                    Err(err) => return Err(Error::InvalidManifest(err.to_string())),
+                };
+                let entries = Manifest::parse(&manifest)?.files;
+                for file in &added {
+                    file_ops.copy(
+                        shell_root.join(&file.source),
+                        file_ops.root().join(&file.target),
+                    );
+                    if !entries.iter().any(|entry| entry.source == file.source) {
+                        manifest::append_entry(&mut manifest, file)?;
+                    }
+                }
+                file_ops.write_file(manifest_path, manifest);
+            }
+            Strategy::Copy => (),
+        }
+        Ok(added)
+    }
+
+    /// Brings the files of the current shell up to date without
+    /// switching shells, after a `git pull` or an edit to the
+    /// manifest. Files that `tags` allows and that are missing get
+    /// linked or copied, copies that are out of date are refreshed,
+    /// and so is anything in the way if `file_ops` is allowed to
+    /// replace it. Links that hermit made below the root of `file_ops`
+    /// to files the shell no longer puts there are removed. Returns
+    /// what will be done.
+    ///
+    /// A system shell is only applied when `file_ops` is rooted at its
+    /// system root, and only if all of its files are in its prefixes.
+    pub fn apply(&self, file_ops: &mut FileOperations, tags: &TagFilter) -> Result<Delta> {
+        let shell = self.current_shell()?;
+        let shell_root = shell.root_path();
+        let system = self.shell_info(&shell.name)?.system;
+        if system
+            .as_ref()
+            .is_some_and(|system| system.root != file_ops.root())
+        {
+            return Err(Error::SystemShell(shell.name));
+        }
+        self.check_signature(&shell.name)?;
+        let forced = file_ops.conflict_policy() != ConflictPolicy::Error;
+
+        let files = self
+            .status(file_ops.root())?
+            .files
+            .into_iter()
+            .filter(|status| tags.allows(&status.file))
+            .collect::<Vec<_>>();
+        for status in &files {
+            paths::check_target(&status.file.target)?;
+        }
+        if let Some(system) = system {
+            system.check(files.iter().map(|status| status.file.target.as_path()))?;
+        }
+        let targets = files
+            .iter()
+            .map(|status| file_ops.root().join(&status.file.target))
+            .collect::<BTreeSet<_>>();
+        let unneeded = self
+            .journal()
+            .created_links()?
+            .into_iter()
+            .filter(|(path, target)| {
+                path.starts_with(file_ops.root())
+                    && target.starts_with(&shell_root)
+                    && !targets.contains(path)
+            })
+            .collect::<Vec<_>>();
+
+        let mut delta = Delta::default();
+        for FileStatus { file, state } in files {
+            let path = file_ops.root().join(&file.target);
+            let source = shell_root.join(&file.source);
+            let stale = match state {
+                FileState::Missing | FileState::OutOfDate => true,
+                FileState::Conflict | FileState::LinkedElsewhere => forced,
+                FileState::Linked | FileState::Copied => false,
+            };
+            if !stale {
+                continue;
+            }
+            match file.strategy {
+                Strategy::Link => {
+                    // A file below a directory link that is about to
+                    // be removed is only inside the shell until it is.
+                    if !unneeded.iter().any(|(link, _)| path.starts_with(link)) {
+                        paths::check_link(&path, &source, &shell_root)?;
+                    }
+                    if file.granularity == Granularity::Directory {
+                        file_ops.unlink_file_by_file(&file.target, &source);
+                    }
+                    file_ops.link(&file.target, source);
+                    delta.linked.push(file);
+                }
+                Strategy::Copy => {
+                    file_ops.copy(&file.target, source);
+                    delta.copied.push(file);
+                }
+            }
+        }
+
+        for (path, target) in unneeded {
+            file_ops.unlink(&path, &target);
+            delta.unlinked.push(path);
+        }
+        Ok(delta)
+    }
+
+    /// Removes the links that hermit made to the files of the shell
+    /// `name`, going by the journal, without touching the shell itself
+    /// or which shell is current. Returns the links that will be
+    /// removed.
+    pub fn unlink(&self, file_ops: &mut FileOperations, name: &str) -> Result<Vec<PathBuf>> {
+        let shell_root = self.shell(name)?.root_path();
+        let mut unlinked = vec![];
+        for (path, target) in self.journal().created_links()? {
+            if target.starts_with(&shell_root) {
+                file_ops.unlink(&path, &target);
+                unlinked.push(path);
+            }
+        }
+        Ok(unlinked)
+    }
+
+    /// Checks what is staged to be committed in a shell's repository
+    /// for secrets in plain text and a broken manifest.
+    pub fn commit_problems(&self, name: &str) -> Result<Vec<CommitProblem>> {
+        let shell = self.shell(name)?;
+        let staged = git::staged_files(shell.root_path())?;
+        Ok(doctor::commit_problems(&staged, shell.manifest()))
+    }
+
+    /// Installs a git pre-commit hook in a shell's repository that runs
+    /// `hermit doctor` on the shell, returning where the hook is. A
+    /// hook that hermit didn't install is left alone.
+    pub fn install_hooks(&self, name: &str) -> Result<PathBuf> {
+        let shell = self.shell(name)?;
+        let path = git::hooks_dir(shell.root_path())?.join("pre-commit");
+        let failed =
+            |err: io::Error| Error::HookNotInstalled(format!("{}: {}", message::path(&path), err));
+        if let Ok(existing) = fs::read_to_string(&path) {
+            if !existing.contains(HOOK_MARKER) {
+                return Err(Error::HookNotInstalled(format!(
+                    "there is already a hook at {}",
+                    message::path(&path)
+                )));
+            }
+        }
+
+        let doctor =
+            env::hermit_command(self.config.root_path(), &["doctor", "--shell", &shell.name])?;
+        let record = env::hermit_command(
+            self.config.root_path(),
+            &["verify", "--record", "--shell", &shell.name],
+        )?;
+        let hook = format!(
+            "#!/bin/sh\n{}\n{} || exit 1\n{}\n",
+            HOOK_MARKER, doctor, record
+        );
+        fs::create_dir_all(path.parent().unwrap_or(&path)).map_err(failed)?;
+        fs::write(&path, hook).map_err(failed)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).map_err(failed)?;
+        Ok(path)
+    }
+
+    /// Saves the current shell as it was last committed in an annotated
+    /// tag, along with where its files are linked, so that it can be
+    /// rolled back to later. Without a name, the snapshot is named
+    /// after the time. Returns the name of the snapshot.
+    pub fn snapshot(&self, name: Option<&str>) -> Result<String> {
+        let shell = self.current_shell()?;
+        let shell_root = shell.root_path();
+        if git::has_uncommitted_changes(&shell_root)? {
+            return Err(Error::UncommittedChanges(shell.name));
+        }
+
+        let name = match name {
+            Some(name) => name.to_owned(),
+            None => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs());
+                let seconds = now % 86400;
+                format!(
+                    "{}-{:02}{:02}{:02}",
+                    shell_info::date_from_timestamp(now),
+                    seconds / 3600,
+                    seconds / 60 % 60,
+                    seconds % 60
+                )
+            }
+        };
+        let plan = shell
+            .files()?
+            .iter()
+            .map(|file| {
+                format!(
+                    "{} -> {}",
+                    message::path(&file.source),
+                    message::path(Path::new("~").join(&file.target))
+                )
+            })
+            .collect::<Vec<_>>();
+        let message = format!(
+            "Snapshot {} of {}\n\n{}\n",
+            name,
+            shell.name,
+            plan.join("\n")
+        );
+        git::create_tag(&shell_root, &snapshot_tag(&shell.name, &name), &message)?;
+        Ok(name)
+    }
+
+    /// The snapshots of the current shell, oldest first.
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        let shell = self.current_shell()?;
+        let prefix = snapshot_tag(&shell.name, "");
+        Ok(git::tags(shell.root_path(), &prefix)?
+            .into_iter()
+            .map(|tag| Snapshot {
+                name: tag.name[prefix.len()..].to_owned(),
+                time: tag.time.max(0) as u64,
+                plan: tag.message.lines().skip(2).map(ToOwned::to_owned).collect(),
+            })
+            .collect())
+    }
+
+    /// Puts the current shell's files back the way they were in a
+    /// snapshot, leaving the changes staged on the current branch, and
+    /// queues up the links that they need.
+    pub fn rollback(&self, file_ops: &mut FileOperations, name: &str) -> Result<Delta> {
+        let shell = self.current_shell()?;
+        let shell_root = shell.root_path();
+        if !self
+            .snapshots()?
+            .iter()
+            .any(|snapshot| snapshot.name == name)
+        {
+            return Err(Error::UnknownSnapshot(name.to_owned()));
+        }
+        if git::has_uncommitted_changes(&shell_root)? {
+            return Err(Error::UncommittedChanges(shell.name));
+        }
+
+        git::restore_tag(&shell_root, &snapshot_tag(&shell.name, name))?;
+        self.apply(file_ops, &TagFilter::default())
+    }
+
+    /// Where the key for secret files is kept, unless it is in the
+    /// keychain.
+    pub fn secret_key_path(&self) -> PathBuf {
+        self.config.root_path().join(secret::KEY_FILE_NAME)
+    }
+
+    /// The key for secret files, from the keychain if it has been
+    /// unlocked there, or from its file otherwise.
+    pub fn secret_key(&self) -> Result<Option<Key>> {
+        match Key::load_from(&*self.keyring, &self.keyring_account())? {
+            Some(key) => Ok(Some(key)),
+            None => Key::load(self.secret_key_path()),
+        }
+    }
+
+    /// Stores the key for secret files in the keychain, so that this
+    /// machine can decrypt them without a key file. The key is read
+    /// from `key_file`, or from the hermit root's key file, which is
+    /// then removed. Returns whether it was removed.
+    pub fn unlock_secrets(&self, key_file: Option<&Path>) -> Result<bool> {
+        let path = key_file.map_or_else(|| self.secret_key_path(), Path::to_path_buf);
+        let key = Key::load(&path)?
+            .ok_or_else(|| Error::Secret(format!("there is no key at {}", message::path(&path))))?;
+        let stored = key.store_in(&*self.keyring, &self.keyring_account());
+        self.audited("store the secret key in the keychain", stored)?;
+
+        if key_file.is_some() {
+            return Ok(false);
+        }
+        let removed = fs::remove_file(&path).map_err(|err| {
+            Error::Secret(format!(
+                "could not remove {}: {}",
+                message::path(&path),
+                err
+            ))
+        });
+        self.audited(format!("remove {}", path.display()), removed)?;
+        Ok(true)
+    }
+
+    /// Forgets the key stored in the keychain, so that secret files
+    /// can't be decrypted until it is unlocked again. Unless `force` is
+    /// set, this fails when the keychain has the only copy of the key
+    /// on this machine.
+    pub fn lock_secrets(&self, force: bool) -> Result<()> {
+        let account = self.keyring_account();
+        if Key::load_from(&*self.keyring, &account)?.is_none() {
+            return Err(Error::Secret("the key isn't in the keychain".to_owned()));
+        }
+        if !force && !self.secret_key_path().exists() {
+            return Err(Error::Secret(
+                "the keychain has the only copy of the key on this machine; \
+                 make sure you have another, then lock it with --force"
+                    .to_owned(),
+            ));
+        }
+        let forgotten = self
+            .keyring
+            .delete(&account)
+            .map(drop)
+            .map_err(|err| Error::Secret(format!("could not forget the key: {}", err)));
+        self.audited("forget the secret key in the keychain", forgotten)
+    }
+
+    /// Keys of different hermit roots are kept apart in the keychain.
+    fn keyring_account(&self) -> String {
+        self.config.root_path().display().to_string()
+    }
+
+    /// Sets up git in a shell's repository to encrypt the shell's secret
+    /// files when they are committed and decrypt them when they are
+    /// checked out, making a key if there isn't one yet. Returns the
+    /// secret files that weren't already being encrypted.
+    pub fn init_secret_filters(&self, name: &str) -> Result<Vec<PathBuf>> {
+        let shell = self.shell(name)?;
+        if self.secret_key()?.is_none() {
+            Key::load_or_create(self.secret_key_path())?;
+        }
+
+        let root = self.config.root_path();
+        let set = git::set_filter(
+            shell.root_path(),
+            secret::FILTER_NAME,
+            &secret::filter_command(root, "clean")?,
+            &secret::filter_command(root, "smudge")?,
+        );
+        self.audited(format!("set up the secret filters of {}", shell.name), set)?;
+
+        let sources = shell
+            .files()?
+            .into_iter()
+            .filter(|file| file.secret)
+            .map(|file| file.source)
+            .collect::<Vec<_>>();
+        let added = secret::add_attributes(&shell.root_path(), &sources);
+        self.audited(
+            format!("mark the secret files of {} for encryption", shell.name),
+            added,
+        )
+    }
+
+    /// Collects the bootstrap steps of a shell that apply to this
+    /// machine, along with which of them have already completed.
+    pub fn bootstrap(&self, name: &str) -> Result<Bootstrap> {
+        let shell = self.shell(name)?;
+        let host = self.config.host();
+        let steps = shell
+            .manifest()?
+            .bootstrap
+            .into_iter()
+            .filter(|step| step.applies_to(host))
+            .collect();
+        let log_path = self.config.state_path().join("bootstrap").join(name);
+
+        Ok(Bootstrap::new(
+            shell.root_path(),
+            steps,
+            BootstrapLog::load(log_path),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{path::PathBuf, rc::Rc};
+
+    use crate::{
+        config::mock::MockConfig,
+        config::Config,
+        config::FsConfig,
+        file_operations::FileOperations,
+        file_operations::Op,
+        fs_backend::{FsBackend, MemoryFs},
+        journal::Change,
+        keyring::MemoryKeyring,
+        observer::Recorder,
+        test_helpers::filesystem::set_up,
+    };
+
+    fn hermit(config: &MockConfig) -> Hermit<MockConfig> {
+        Hermit::new(config.clone()).with_keyring(Rc::new(MemoryKeyring::new()))
+    }
+
+    #[test]
+    fn returns_the_current_shell() {
+        let config = MockConfig::new();
+        let hermit = hermit(&config);
+
+        let shell = hermit.current_shell().unwrap();
+        assert_eq!(shell.name, "default");
+        assert_eq!(shell.config, Rc::new(config));
+    }
+
+    #[test]
+    fn finds_existing_shells_by_name() {
+        let config = MockConfig::new();
+        let hermit = hermit(&config);
+
+        assert_eq!(hermit.shell("default").unwrap().name, "default");
+        assert!(matches!(
+            hermit.shell("nonexistent"),
+            Err(Error::UnknownShell { .. })
+        ));
+    }
+
+    #[test]
+    fn accepts_unambiguous_shell_name_prefixes() {
+        let mut config = MockConfig::new();
+        config.add_shell("work");
+        config.add_shell("workstation");
+        let hermit = hermit(&config);
+
+        assert_eq!(hermit.existing_shell_name("def"), Ok("default".to_owned()));
+        assert_eq!(hermit.existing_shell_name("work"), Ok("work".to_owned()));
+        assert!(hermit.existing_shell_name("wor").is_err());
+    }
+
+    #[test]
+    fn rejects_shell_names_that_leave_the_shells_directory() {
+        let mut config = MockConfig::new();
+        config.add_shell("..");
+        config.add_shell("work/laptop");
+        let hermit = hermit(&config);
+
+        for name in &["..", "work/laptop", "../default"] {
+            assert!(
+                matches!(
+                    hermit.existing_shell_name(name),
+                    Err(Error::InvalidShellName { .. })
+                ),
+                "'{}' should be rejected",
+                name
+            );
+        }
+        assert!(matches!(
+            hermit.shell(".."),
+            Err(Error::InvalidShellName { .. })
+        ));
+    }
+
+    #[test]
+    fn suggests_similar_shell_names() {
+        let mut config = MockConfig::new();
+        config.add_shell("work");
+        let hermit = hermit(&config);
+
+        let err = hermit.existing_shell_name("defualt").unwrap_err();
+        assert_eq!(
+            err,
+            Error::UnknownShell {
+                name: "defualt".to_owned(),
+                suggestions: vec!["default".to_owned()],
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "There is no shell named 'defualt'. Did you mean 'default'?"
+        );
+    }
+
+    #[test]
+    fn cannot_inhabit_a_nonexistent_shell() {
+        let config = MockConfig::new();
+        let mut hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        assert!(matches!(
+            hermit.inhabit(&mut file_ops, "nonexistent"),
+            Err(Error::UnknownShell { .. })
+        ));
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn can_set_the_current_shell() {
+        let mut config = MockConfig::new();
+        config
+            .set_current_shell_name("current")
+            .expect("Setting shell name failed");
+        let mut hermit = hermit(&config);
+
+        assert_eq!(hermit.current_shell().unwrap().name, "current");
+        hermit
+            .set_current_shell("default")
+            .expect("Setting shell name failed");
+        assert_eq!(hermit.current_shell().unwrap().name, "default");
+    }
+
+    #[test]
+    fn scaffolds_the_hermit_root() {
+        let config = MockConfig::with_root("/home/geoff/.hermit-config");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        hermit.scaffold(&mut file_ops);
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::MkDir(PathBuf::from("/home/geoff/.hermit-config/shells")),
+                Op::CreateFile {
+                    path: PathBuf::from("/home/geoff/.hermit-config/config.toml"),
+                    contents: DEFAULT_SETTINGS.as_bytes().to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_a_missing_hermit_root() {
+        let mut config = MockConfig::with_root("/home/geoff/.hermit-config");
+        assert_eq!(hermit(&config).check_initialized(), Ok(()));
+
+        config.set_initialized(false);
+        assert_eq!(
+            hermit(&config).check_initialized(),
+            Err(Error::NotInitialized(PathBuf::from(
+                "/home/geoff/.hermit-config"
+            )))
+        );
+    }
+
+    #[test]
+    fn can_initialize_a_new_shell() {
+        let config = MockConfig::with_root(".hermit-config");
+        let mut hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        hermit
+            .init_shell(&mut file_ops, "new-one")
+            .expect("Init shell failed");
+        let first_op = &file_ops.operations()[0];
+        assert_eq!(
+            *first_op,
+            Op::MkDir(PathBuf::from("/home/geoff/.hermit-config/shells"))
+        );
+        let second_op = &file_ops.operations()[1];
+        assert_eq!(
+            *second_op,
+            Op::GitInit(PathBuf::from("/home/geoff/.hermit-config/shells/new-one"))
+        );
+    }
+
+    #[test]
+    fn observers_hear_about_new_shells() {
+        let config = MockConfig::with_root(".hermit-config");
+        let recorder = Rc::new(Recorder::default());
+        let mut hermit = hermit(&config).with_observer(recorder.clone());
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        hermit
+            .init_shell(&mut file_ops, "new-one")
+            .expect("Init shell failed");
+
+        assert_eq!(
+            recorder.events(),
+            vec![
+                Event::ChangeMade {
+                    description: "make new-one the current shell".to_owned(),
+                    error: None,
+                },
+                Event::ShellCreated {
+                    name: "new-one".to_owned()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn can_fill_a_new_shell_from_a_template() {
+        let config = MockConfig::with_root(".hermit-config");
+        let mut hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+        let files = vec![
+            TemplateFile {
+                path: PathBuf::from("hermit.toml"),
+                contents: b"".to_vec(),
+            },
+            TemplateFile {
+                path: PathBuf::from("hooks/README.md"),
+                contents: b"hooks".to_vec(),
+            },
+        ];
+
+        hermit
+            .init_shell(&mut file_ops, "new-one")
+            .expect("Init shell failed");
+        hermit.apply_template(&mut file_ops, "new-one", &files);
+
+        let shell_root = PathBuf::from("/home/geoff/.hermit-config/shells/new-one");
+        assert_eq!(
+            &file_ops.operations()[2..],
+            &[
+                Op::MkDir(shell_root.join("hooks")),
+                Op::WriteFile {
+                    path: shell_root.join("hermit.toml"),
+                    contents: b"".to_vec(),
+                },
+                Op::WriteFile {
+                    path: shell_root.join("hooks/README.md"),
+                    contents: b"hooks".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn new_shells_in_a_shared_repository_are_directories() {
+        let mut config = MockConfig::with_root(".hermit-config");
+        config.set_shared_repo(true);
+        let mut hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        hermit
+            .init_shell(&mut file_ops, "new-one")
+            .expect("Init shell failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::MkDir(PathBuf::from(
+                "/home/geoff/.hermit-config/shells/new-one"
+            ))]
+        );
+    }
+
+    #[test]
+    fn existing_shells_cannot_be_moved_into_a_shared_repository() {
+        let config = MockConfig::with_root(".hermit-config");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        assert_eq!(
+            hermit.init_shared_repo(&mut file_ops),
+            Err(Error::ShellsAlreadyExist)
+        );
+        assert_eq!(
+            hermit.clone_shared_repo(&mut file_ops, "git@example.com:shells", &Default::default()),
+            Err(Error::ShellsAlreadyExist)
+        );
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn clones_shells_named_after_their_repository() {
+        let config = MockConfig::with_root(".hermit-config");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        let name = hermit
+            .clone_shell(
+                &mut file_ops,
+                "git@example.com:geoff/laptop.git",
+                None,
+                &Default::default(),
+            )
+            .expect("Clone failed");
+
+        assert_eq!(name, "laptop");
+        assert_eq!(
+            file_ops.operations()[1],
+            Op::GitClone {
+                url: "git@example.com:geoff/laptop.git".to_owned(),
+                path: PathBuf::from("/home/geoff/.hermit-config/shells/laptop"),
+                options: Default::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn cannot_clone_over_an_existing_shell() {
+        let config = MockConfig::with_root(".hermit-config");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        assert_eq!(
+            hermit.clone_shell(
+                &mut file_ops,
+                "git@example.com:dotfiles",
+                Some("default"),
+                &Default::default()
+            ),
+            Err(Error::ShellAlreadyExists("default".to_owned()))
+        );
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn forks_shells_by_cloning_them() {
+        let config = MockConfig::with_root("/home/geoff/.hermit-config");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        hermit
+            .fork_shell(
+                &mut file_ops,
+                "default",
+                "work",
+                Some("git@example.com:work"),
+            )
+            .expect("Fork failed");
+
+        let fork_root = PathBuf::from("/home/geoff/.hermit-config/shells/work");
+        assert_eq!(
+            file_ops.operations()[0],
+            Op::GitClone {
+                url: "/home/geoff/.hermit-config/shells/default".to_owned(),
+                path: fork_root.clone(),
+                options: git::CloneOptions::default(),
+            }
+        );
+        match &file_ops.operations()[1] {
+            Op::WriteFile { path, contents } => {
+                assert_eq!(*path, fork_root.join(".hermit/shell.toml"));
+                assert!(
+                    String::from_utf8_lossy(contents).contains("origin = \"git@example.com:work\"")
+                );
+            }
+            op => panic!("Expected the fork's metadata to be written, not {:?}", op),
+        }
+        assert_eq!(
+            hermit.fork_shell(&mut file_ops, "default", "default", None),
+            Err(Error::ShellAlreadyExists("default".to_owned()))
+        );
+    }
+
+    #[test]
+    fn forks_shells_in_a_shared_repository_by_copying_them() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut config = MockConfig::with_root(root);
+        config.set_shared_repo(true);
+        let source = root.join("shells/default");
+        fs::create_dir_all(source.join(".hermit/local")).unwrap();
+        fs::write(source.join(".bashrc"), "").unwrap();
+        fs::write(source.join(".hermit/local/folder-sync.json"), "{}").unwrap();
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at(root);
+
+        hermit
+            .fork_shell(&mut file_ops, "default", "work", None)
+            .expect("Fork failed");
+
+        let fork_root = root.join("shells/work");
+        assert_eq!(
+            file_ops.operations()[..2],
+            [
+                Op::MkDir(fork_root.clone()),
+                Op::Copy {
+                    path: fork_root.join(".bashrc"),
+                    source: source.join(".bashrc"),
+                },
+            ]
+        );
+        assert_eq!(file_ops.operations().len(), 3);
+    }
+
+    #[test]
+    fn inhabits_shells_in_memory() {
+        let fs = Rc::new(MemoryFs::new());
+        fs.add_file("/hermit/shells/default/.bashrc", "home");
+        fs.add_file("/hermit/shells/work/.bashrc", "work");
+        fs.add_file("/hermit/shells/work/.config/git/config", "");
+        fs.add_file("/hermit/shells/work/.hermitignore", "/notes.md\n");
+        fs.add_file("/hermit/shells/work/notes.md", "");
+        fs.add_file("/hermit/current_shell", "default");
+        fs.create_dir_all(Path::new("/home/geoff")).unwrap();
+        fs.symlink(
+            Path::new("/hermit/shells/default/.bashrc"),
+            Path::new("/home/geoff/.bashrc"),
+        )
+        .unwrap();
+        let config = FsConfig::with_backend("/hermit", fs.clone()).unwrap();
+        let mut hermit = Hermit::new(config);
+        let mut file_ops = FileOperations::with_backend("/home/geoff", fs.clone());
+
+        hermit.inhabit(&mut file_ops, "work").unwrap();
+        for result in file_ops.commit() {
+            result.expect("Op failed");
+        }
+
+        assert_eq!(
+            fs.read_to_string(Path::new("/hermit/current_shell"))
+                .unwrap(),
+            "work"
+        );
+        assert_eq!(
+            fs.read_link(Path::new("/home/geoff/.bashrc")).unwrap(),
+            Path::new("/hermit/shells/work/.bashrc")
+        );
+        assert_eq!(
+            fs.read_link(Path::new("/home/geoff/.config/git/config"))
+                .unwrap(),
+            Path::new("/hermit/shells/work/.config/git/config")
+        );
+        assert!(!fs.exists(Path::new("/home/geoff/notes.md")));
+    }
+
+    #[test]
+    fn merges_shells_resolving_collisions() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut config = MockConfig::with_root(root);
+        config.add_shell("work");
+        let from = root.join("shells/work");
+        let into = root.join("shells/default");
+        for shell in &[&from, &into] {
+            fs::create_dir_all(shell.join(".hermit")).unwrap();
+            fs::write(shell.join(".hermit/shell.toml"), "").unwrap();
+            fs::write(shell.join(".inputrc"), "set editing-mode vi").unwrap();
+        }
+        fs::create_dir_all(from.join(".git")).unwrap();
+        fs::write(from.join(".git/HEAD"), "ref").unwrap();
+        fs::write(from.join(".bashrc"), "work").unwrap();
+        fs::write(into.join(".bashrc"), "home").unwrap();
+        fs::write(from.join(".vimrc"), "work").unwrap();
+        fs::write(into.join(".vimrc"), "home").unwrap();
+        fs::write(from.join(".gitconfig"), "").unwrap();
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at(root);
+
+        let mut asked = vec![];
+        let merge = hermit
+            .merge_shells(&mut file_ops, "work", "default", |path| {
+                asked.push(path.to_path_buf());
+                Ok(if path == Path::new(".bashrc") {
+                    Resolution::Rename(".bashrc.work".into())
+                } else {
+                    Resolution::Theirs
+                })
+            })
+            .expect("Merge failed");
+
+        assert_eq!(
+            asked,
+            vec![PathBuf::from(".bashrc"), PathBuf::from(".vimrc")]
+        );
+        assert_eq!(
+            merge,
+            Merge {
+                added: vec![PathBuf::from(".gitconfig")],
+                replaced: vec![PathBuf::from(".vimrc")],
+                renamed: vec![(PathBuf::from(".bashrc"), PathBuf::from(".bashrc.work"))],
+                kept: vec![PathBuf::from(".inputrc")],
+            }
+        );
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::Copy {
+                    path: into.join(".bashrc.work"),
+                    source: from.join(".bashrc"),
+                },
+                Op::Copy {
+                    path: into.join(".gitconfig"),
+                    source: from.join(".gitconfig"),
+                },
+                Op::Copy {
+                    path: into.join(".vimrc"),
+                    source: from.join(".vimrc"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_files_cannot_be_renamed_over_others() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut config = MockConfig::with_root(root);
+        config.add_shell("work");
+        fs::create_dir_all(root.join("shells/work")).unwrap();
+        fs::create_dir_all(root.join("shells/default")).unwrap();
+        fs::write(root.join("shells/work/.bashrc"), "work").unwrap();
+        fs::write(root.join("shells/default/.bashrc"), "home").unwrap();
+        fs::write(root.join("shells/default/.profile"), "").unwrap();
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at(root);
+
+        let result = hermit.merge_shells(&mut file_ops, "work", "default", |_| {
+            Ok(Resolution::Rename(".profile".into()))
+        });
+
+        assert_eq!(result, Err(Error::AlreadyInShell(".profile".into())));
+        assert_eq!(
+            hermit.merge_shells(&mut file_ops, "default", "default", |_| Ok(
+                Resolution::Ours
+            )),
+            Err(Error::MergeIntoItself("default".to_owned()))
+        );
+    }
+
+    #[test]
+    fn only_shells_not_in_use_can_be_nuked() {
+        let mut config = MockConfig::with_root(".hermit-config");
+        config.add_shell("work");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        assert_eq!(
+            hermit.nuke_shell(&mut file_ops, "default"),
+            Err(Error::ShellInUse("default".to_owned()))
+        );
+        hermit.nuke_shell(&mut file_ops, "work").unwrap();
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::Remove(PathBuf::from(
+                "/home/geoff/.hermit-config/shells/work"
+            ))]
+        );
+    }
+
+    #[test]
+    fn can_add_a_remote_to_a_new_shell() {
+        let config = MockConfig::with_root(".hermit-config");
+        let mut hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        hermit
+            .init_shell(&mut file_ops, "new-one")
+            .expect("Init shell failed");
+        hermit.add_remote(
+            &mut file_ops,
+            "new-one",
+            "origin",
+            "git@example.com:dotfiles",
+        );
+
+        assert_eq!(
+            file_ops.operations()[2],
+            Op::GitRemote {
+                repo: PathBuf::from("/home/geoff/.hermit-config/shells/new-one"),
+                name: "origin".to_owned(),
+                url: "git@example.com:dotfiles".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn can_inhabit_and_change_shells() {
+        let hermit_root = PathBuf::from(".hermit-config");
+        let mut config = MockConfig::with_root(&hermit_root);
+        config.set_paths(vec![".bashrc", ".boot/profile.boot"]);
+        let mut hermit = hermit(&config);
+        let op_root_path = PathBuf::from("/home/geoff");
+        let mut file_ops = FileOperations::rooted_at(&op_root_path);
+
+        hermit
+            .inhabit(&mut file_ops, "default")
+            .expect("Inhabit failed");
+
+        let new_shell_root = hermit_root.join("shells/default");
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::Relink {
+                    path: op_root_path.join(".bashrc"),
+                    source: new_shell_root.join(".bashrc"),
+                    target: new_shell_root.join(".bashrc"),
+                },
+                Op::Relink {
+                    path: op_root_path.join(".boot/profile.boot"),
+                    source: new_shell_root.join(".boot/profile.boot"),
+                    target: new_shell_root.join(".boot/profile.boot"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn dash_means_the_previous_shell() {
+        let mut config = MockConfig::new();
+        assert_eq!(
+            hermit(&config).resolve_shell_name("-"),
+            Err(Error::NoPreviousShell)
+        );
+
+        config.set_current_shell_name("work").unwrap();
+        let hermit = hermit(&config);
+        assert_eq!(hermit.resolve_shell_name("-"), Ok("default".to_owned()));
+        assert_eq!(hermit.resolve_shell_name("work"), Ok("work".to_owned()));
+    }
+
+    #[test]
+    fn can_inhabit_the_previous_shell() {
+        let mut config = MockConfig::new();
+        config.set_current_shell_name("work").unwrap();
+        let mut hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        hermit.inhabit(&mut file_ops, "-").expect("Inhabit failed");
+
+        assert_eq!(hermit.current_shell().unwrap().name, "default");
+        assert_eq!(hermit.resolve_shell_name("-"), Ok("work".to_owned()));
+    }
+
+    #[test]
+    fn describes_itself_to_child_processes() {
+        let config = MockConfig::with_root("/home/geoff/.config/hermit");
+        let hermit = hermit(&config);
+
+        assert_eq!(
+            hermit.environment("/home/geoff"),
+            vec![
+                ("HERMIT_ROOT", PathBuf::from("/home/geoff/.config/hermit")),
+                ("HERMIT_HOME", PathBuf::from("/home/geoff")),
+                (
+                    "HERMIT_SHELL_PATH",
+                    PathBuf::from("/home/geoff/.config/hermit/shells/default")
+                ),
+                ("HERMIT_SHELL", PathBuf::from("default")),
+                ("HERMIT_HOSTNAME", PathBuf::from("")),
+                ("HERMIT_OS", PathBuf::from("")),
+                ("HERMIT_ARCH", PathBuf::from("")),
+                ("HERMIT_USERNAME", PathBuf::from("")),
+            ]
+        );
+    }
+
+    #[test]
+    fn exports_the_variables_a_manifest_declares() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let shell_root = root.join("shells/default");
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::write(
+            shell_root.join(MANIFEST_FILE_NAME),
+            "[env]\nEDITOR = \"nvim\"\nGOPATH = \"~/go\"\nTILDE = \"~user\"\n",
+        )
+        .unwrap();
+        let config = MockConfig::with_root(root);
+        let hermit = hermit(&config);
+
+        assert_eq!(
+            hermit.exported_env("/home/geoff"),
+            Ok(vec![
+                ("EDITOR".to_owned(), "nvim".to_owned()),
+                ("GOPATH".to_owned(), "/home/geoff/go".to_owned()),
+                ("TILDE".to_owned(), "~user".to_owned()),
+            ])
+        );
+
+        fs::write(
+            shell_root.join(MANIFEST_FILE_NAME),
+            "[env]\n\"MY-VAR\" = \"x\"\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            hermit.exported_env("/home/geoff"),
+            Err(Error::InvalidManifest(_))
+        ));
+    }
+
+    #[test]
+    fn commit_messages_follow_the_manifest() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let shell_root = root.join("shells/default");
+        fs::create_dir_all(&shell_root).unwrap();
+        let config = MockConfig::with_root(root);
+        let hermit = hermit(&config);
+
+        assert_eq!(
+            hermit.commit_message("default", "Update .bashrc", &[".bashrc"]),
+            Ok("Update .bashrc".to_owned())
+        );
+
+        fs::write(
+            shell_root.join(MANIFEST_FILE_NAME),
+            "[commit]\nmessage = \"\"\"\n{{summary}} from {{hostname}}\n\nFiles: {{files}}\n\"\"\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            hermit.commit_message("default", "Add files", &[".bashrc", ".vimrc"]),
+            Ok(format!(
+                "Add files from {}\n\nFiles: .bashrc, .vimrc",
+                config.host().hostname
+            ))
+        );
+    }
+
+    #[test]
+    fn describes_the_machine_to_templates() {
+        let config = MockConfig::new();
+        let hermit = hermit(&config);
+
+        let variables = hermit.variables("/home/geoff");
+
+        let names = variables.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["hostname", "os", "arch", "username", "home", "shell"]
+        );
+        assert!(variables.contains(&("home", "/home/geoff".to_owned())));
+        assert!(variables.contains(&("shell", "default".to_owned())));
+    }
+
+    #[test]
+    fn reports_the_status_of_each_file() {
+        let mut config = MockConfig::with_root("/nonexistent/hermit");
+        config.set_paths(vec![".bashrc"]);
+        let hermit = hermit(&config);
+
+        let status = hermit.status("/nonexistent/home").expect("Status failed");
+
+        assert_eq!(status.shell, "default");
+        assert_eq!(
+            status.files,
+            vec![FileStatus {
+                file: ShellFile::mirrored(".bashrc"),
+                state: FileState::Missing,
+            }]
+        );
+    }
+
+    #[test]
+    fn new_shells_record_their_metadata() {
+        let config = MockConfig::with_root("/nonexistent/hermit");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/nonexistent/home");
+        let info = ShellInfo {
+            description: Some("Work laptop".to_owned()),
+            created: Some("2020-12-24".to_owned()),
+            ..ShellInfo::default()
+        };
+
+        hermit
+            .record_shell_info(&mut file_ops, "work", &info)
+            .expect("Recording failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::CreateFile {
+                path: PathBuf::from("/nonexistent/hermit/shells/work/.hermit/shell.toml"),
+                contents: b"description = \"Work laptop\"\ncreated = \"2020-12-24\"\n".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn adding_moves_files_into_the_shell_and_links_them_back() {
+        let config = MockConfig::with_root("/nonexistent/hermit");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/nonexistent/home");
+        let shell_file = PathBuf::from("/nonexistent/hermit/shells/default/.config/nvim/init.lua");
+
+        let added = hermit
+            .add(
+                &mut file_ops,
+                &[PathBuf::from("/nonexistent/home/.config/nvim/init.lua")],
+                Strategy::Link,
+            )
+            .expect("Add failed");
+
+        assert_eq!(added, vec![ShellFile::mirrored(".config/nvim/init.lua")]);
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::Move {
+                    path: PathBuf::from("/nonexistent/home/.config/nvim/init.lua"),
+                    destination: shell_file.clone(),
+                },
+                Op::Link {
+                    path: PathBuf::from("/nonexistent/home/.config/nvim/init.lua"),
+                    target: shell_file,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn adding_to_a_shell_without_dots_takes_them_off() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path().join("hermit");
+        let config = MockConfig::with_root(&root);
+        let hermit = hermit(&config);
+        let shell_root = root.join("shells/default");
+        let info_path = shell_root.join(ShellInfo::relative_path());
+        fs::create_dir_all(info_path.parent().unwrap()).unwrap();
+        fs::write(info_path, "add_dots = true\n").unwrap();
+        let home = test_root_dir.path().join("home");
+        let mut file_ops = FileOperations::rooted_at(&home);
+
+        let added = hermit
+            .add(&mut file_ops, &[home.join(".vimrc")], Strategy::Link)
+            .expect("Add failed");
+
+        assert_eq!(added, vec![ShellFile::mapped("vimrc", ".vimrc")]);
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::Move {
+                    path: home.join(".vimrc"),
+                    destination: shell_root.join("vimrc"),
+                },
+                Op::Link {
+                    path: home.join(".vimrc"),
+                    target: shell_root.join("vimrc"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn adding_copies_leaves_the_original_and_records_the_strategy() {
+        let config = MockConfig::with_root("/nonexistent/hermit");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/nonexistent/home");
+        let shell_root = PathBuf::from("/nonexistent/hermit/shells/default");
+
+        let added = hermit
+            .add(
+                &mut file_ops,
+                &[PathBuf::from("/nonexistent/home/.gnupg/gpg.conf")],
+                Strategy::Copy,
+            )
+            .expect("Add failed");
+
+        assert_eq!(
+            added,
+            vec![ShellFile::mirrored(".gnupg/gpg.conf").with_strategy(Strategy::Copy)]
+        );
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::Copy {
+                    path: shell_root.join(".gnupg/gpg.conf"),
+                    source: PathBuf::from("/nonexistent/home/.gnupg/gpg.conf"),
+                },
+                Op::WriteFile {
+                    path: shell_root.join("hermit.toml"),
+                    contents: b"[[file]]\nsource = \".gnupg/gpg.conf\"\ntarget = \"~/.gnupg/gpg.conf\"\nstrategy = \"copy\"\n".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn can_only_add_files_from_where_shells_are_linked() {
+        let config = MockConfig::with_root("/nonexistent/hermit");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/nonexistent/home");
+
+        assert_eq!(
+            hermit.add(
+                &mut file_ops,
+                &[PathBuf::from("/etc/hosts")],
+                Strategy::Link
+            ),
+            Err(Error::NotInTargetRoot(PathBuf::from("/etc/hosts")))
+        );
+        assert_eq!(file_ops.operations(), &vec![]);
+    }
+
+    #[test]
+    fn applying_links_and_copies_missing_files() {
+        let mut config = MockConfig::with_root("/nonexistent/hermit");
+        config.set_files(vec![
+            ShellFile::mirrored(".bashrc"),
+            ShellFile::mirrored(".gnupg/gpg.conf").with_strategy(Strategy::Copy),
+            ShellFile::mirrored(".config/sway/config").with_tags(["gui"]),
+        ]);
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/nonexistent/home");
+        let tags = TagFilter {
+            skip: ["gui".to_owned()].into(),
+            ..TagFilter::default()
+        };
+
+        let delta = hermit.apply(&mut file_ops, &tags).expect("Apply failed");
+
+        assert_eq!(
+            delta,
+            Delta {
+                linked: vec![ShellFile::mirrored(".bashrc")],
+                copied: vec![ShellFile::mirrored(".gnupg/gpg.conf").with_strategy(Strategy::Copy)],
+                unlinked: vec![],
+            }
+        );
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::Link {
+                    path: PathBuf::from("/nonexistent/home/.bashrc"),
+                    target: PathBuf::from("/nonexistent/hermit/shells/default/.bashrc"),
+                },
+                Op::Copy {
+                    path: PathBuf::from("/nonexistent/home/.gnupg/gpg.conf"),
+                    source: PathBuf::from("/nonexistent/hermit/shells/default/.gnupg/gpg.conf"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn applying_removes_links_the_shell_no_longer_needs() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path().join("hermit");
+        let home = test_root_dir.path().join("home");
+        let shell_root = root.join("shells/default");
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::create_dir_all(&home).unwrap();
+        let mut config = MockConfig::with_root(&root);
+        config.set_files(vec![ShellFile::mirrored(".bashrc")]);
+        let hermit = hermit(&config);
+        unix::fs::symlink(shell_root.join(".bashrc"), home.join(".bashrc")).unwrap();
+        unix::fs::symlink(shell_root.join(".zshrc"), home.join(".zshrc")).unwrap();
+        let link = |name: &str| Change::CreatedLink {
+            path: home.join(name),
+            target: shell_root.join(name),
+        };
+        hermit
+            .journal()
+            .record("use", None, vec![link(".bashrc"), link(".zshrc")])
+            .unwrap();
+        let mut file_ops = FileOperations::rooted_at(&home);
+
+        let delta = hermit
+            .apply(&mut file_ops, &TagFilter::default())
+            .expect("Apply failed");
+
+        assert_eq!(delta.unlinked, vec![home.join(".zshrc")]);
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::Unlink {
+                path: home.join(".zshrc"),
+                source: shell_root.join(".zshrc"),
+            }]
+        );
+    }
+
+    #[test]
+    fn applying_links_a_directory_whose_files_were_linked_one_by_one() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path().join("hermit");
+        let home = test_root_dir.path().join("home");
+        let shell_root = root.join("shells/default");
+        fs::create_dir_all(shell_root.join(".config/nvim")).unwrap();
+        fs::create_dir_all(home.join(".config/nvim")).unwrap();
+        let mut config = MockConfig::with_root(&root);
+        config.set_files(vec![
+            ShellFile::mirrored(".config/nvim").with_granularity(Granularity::Directory)
+        ]);
+        let hermit = hermit(&config);
+        unix::fs::symlink(
+            shell_root.join(".config/nvim/init.vim"),
+            home.join(".config/nvim/init.vim"),
+        )
+        .unwrap();
+        let mut file_ops = FileOperations::rooted_at(&home);
+
+        hermit
+            .apply(&mut file_ops, &TagFilter::default())
+            .expect("Apply failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::Unlink {
+                    path: home.join(".config/nvim/init.vim"),
+                    source: shell_root.join(".config/nvim/init.vim"),
+                },
+                Op::Remove(home.join(".config/nvim")),
+                Op::Link {
+                    path: home.join(".config/nvim"),
+                    target: shell_root.join(".config/nvim"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn applying_links_files_one_by_one_in_place_of_their_directory() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path().join("hermit");
+        let home = test_root_dir.path().join("home");
+        let shell_root = root.join("shells/default");
+        fs::create_dir_all(shell_root.join(".config/nvim")).unwrap();
+        fs::create_dir_all(home.join(".config")).unwrap();
+        let mut config = MockConfig::with_root(&root);
+        config.set_files(vec![ShellFile::mirrored(".config/nvim/init.vim")]);
+        let hermit = hermit(&config);
+        unix::fs::symlink(shell_root.join(".config/nvim"), home.join(".config/nvim")).unwrap();
+        let change = Change::CreatedLink {
+            path: home.join(".config/nvim"),
+            target: shell_root.join(".config/nvim"),
+        };
+        hermit.journal().record("use", None, vec![change]).unwrap();
+        let mut file_ops = FileOperations::rooted_at(&home);
+
+        let delta = hermit
+            .apply(&mut file_ops, &TagFilter::default())
+            .expect("Apply failed");
+
+        assert_eq!(
+            delta.linked,
+            vec![ShellFile::mirrored(".config/nvim/init.vim")]
+        );
+        assert_eq!(delta.unlinked, vec![home.join(".config/nvim")]);
+    }
+
+    #[test]
+    fn system_shells_are_only_applied_below_their_system_root() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path().join("hermit");
+        let home = test_root_dir.path().join("home");
+        let system_root = test_root_dir.path().join("system");
+        let shell_root = root.join("shells/default");
+        fs::create_dir_all(shell_root.join(".hermit")).unwrap();
+        fs::write(
+            shell_root.join(".hermit/shell.toml"),
+            format!("[system]\nroot = {:?}\n", system_root),
+        )
+        .unwrap();
+        let mut config = MockConfig::with_root(&root);
+        config.set_files(vec![ShellFile::mirrored("etc/ssh/sshd_config")]);
+        let system_shell = hermit(&config);
+
+        assert_eq!(
+            system_shell.plan_inhabit(
+                &mut FileOperations::rooted_at(&home),
+                "default",
+                &TagFilter::default()
+            ),
+            Err(Error::SystemShell("default".to_owned()))
+        );
+        assert_eq!(
+            system_shell.apply(&mut FileOperations::rooted_at(&home), &TagFilter::default()),
+            Err(Error::SystemShell("default".to_owned()))
+        );
+
+        let mut file_ops = FileOperations::rooted_at(&system_root);
+        let delta = system_shell
+            .apply(&mut file_ops, &TagFilter::default())
+            .expect("Apply failed");
+        assert_eq!(
+            delta.linked,
+            vec![ShellFile::mirrored("etc/ssh/sshd_config")]
+        );
+
+        config.set_files(vec![ShellFile::mirrored("usr/bin/sudo")]);
+        assert_eq!(
+            hermit(&config).apply(
+                &mut FileOperations::rooted_at(&system_root),
+                &TagFilter::default()
+            ),
+            Err(Error::OutsideSystemPrefixes(vec![PathBuf::from(
+                "usr/bin/sudo"
+            )]))
+        );
+    }
+
+    #[test]
+    fn unlinking_removes_only_the_links_hermit_made_to_the_shell() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path().join("hermit");
+        let home = test_root_dir.path().join("home");
+        fs::create_dir_all(root.join("shells/work")).unwrap();
+        let config = MockConfig::with_root(&root);
+        let hermit = hermit(&config);
+        let link = |shell: &str, name: &str| {
+            let target = root.join("shells").join(shell).join(name);
+            unix::fs::symlink(&target, home.join(name)).unwrap();
+            Change::CreatedLink {
+                path: home.join(name),
+                target,
+            }
+        };
+        fs::create_dir_all(&home).unwrap();
+        let changes = vec![link("default", ".bashrc"), link("work", ".gitconfig")];
+        hermit.journal().record("use", None, changes).unwrap();
+        unix::fs::symlink(root.join("shells/default/.vimrc"), home.join(".vimrc")).unwrap();
+        let mut file_ops = FileOperations::rooted_at(&home);
+
+        let unlinked = hermit
+            .unlink(&mut file_ops, "default")
+            .expect("Unlink failed");
+
+        assert_eq!(unlinked, vec![home.join(".bashrc")]);
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::Unlink {
+                path: home.join(".bashrc"),
+                source: root.join("shells/default/.bashrc"),
+            }]
+        );
+    }
+
+    #[test]
+    fn shells_are_linked_into_other_targets_on_their_own() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path().join("hermit");
+        let home = test_root_dir.path().join("home");
+        let target = test_root_dir.path().join("srv");
+        fs::create_dir_all(root.join("shells/work")).unwrap();
+        let mut config = MockConfig::with_root(&root);
+        config.add_shell("work");
+        config.set_paths(vec![".bashrc"]);
+        let hermit = hermit(&config);
+        let mut changes = vec![];
+        for dir in &[&home, &target] {
+            fs::create_dir_all(dir).unwrap();
+            for name in &[".bashrc", ".gitconfig"] {
+                let source = root.join("shells/default").join(name);
+                unix::fs::symlink(&source, dir.join(name)).unwrap();
+                changes.push(Change::CreatedLink {
+                    path: dir.join(name),
+                    target: source,
+                });
+            }
+        }
+        hermit.journal().record("use", None, changes).unwrap();
+        let mut file_ops = FileOperations::rooted_at(&target);
+
+        hermit
+            .link_into_target(&mut file_ops, "work", &TagFilter::default())
+            .expect("Linking failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::Unlink {
+                    path: target.join(".gitconfig"),
+                    source: root.join("shells/default/.gitconfig"),
+                },
+                Op::Relink {
+                    path: target.join(".bashrc"),
+                    source: root.join("shells/default/.bashrc"),
+                    target: root.join("shells/work/.bashrc"),
+                },
+            ]
+        );
+        assert_eq!(hermit.current_shell().unwrap().name, "default");
+    }
+
+    #[test]
+    fn secret_filters_cover_the_shells_secret_files() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut config = MockConfig::with_root(root);
+        config.set_files(vec![
+            ShellFile::mirrored(".bashrc"),
+            ShellFile::mirrored(".netrc").with_secret(true),
+        ]);
+        let shell_root = root.join("shells/default");
+        git::test_support::init_repo(&shell_root);
+        let hermit = hermit(&config);
+
+        assert_eq!(
+            hermit.init_secret_filters("default"),
+            Ok(vec![PathBuf::from(".netrc")])
+        );
+        let git_config = git2::Repository::open(&shell_root)
+            .unwrap()
+            .config()
+            .unwrap();
+        assert_eq!(
+            git_config.get_string("filter.hermit.clean").unwrap(),
+            secret::filter_command(root, "clean").unwrap()
+        );
+        assert!(git_config.get_bool("filter.hermit.required").unwrap());
+        assert!(root.join(secret::KEY_FILE_NAME).is_file());
+    }
+
+    #[test]
+    fn unlocked_keys_move_into_the_keychain() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let config = MockConfig::with_root(root);
+        let hermit = hermit(&config);
+        let key_path = hermit.secret_key_path();
+        let encrypted = Key::load_or_create(&key_path).unwrap().encrypt(b"token");
+
+        assert_eq!(hermit.unlock_secrets(None), Ok(true));
+        assert!(!key_path.exists());
+        let key = hermit.secret_key().unwrap().expect("No key");
+        assert_eq!(key.decrypt(&encrypted).unwrap(), b"token");
+
+        assert!(hermit.lock_secrets(false).is_err());
+        assert_eq!(hermit.lock_secrets(true), Ok(()));
+        assert!(hermit.secret_key().unwrap().is_none());
+        assert!(hermit.lock_secrets(true).is_err());
+    }
+
+    #[test]
+    fn can_roll_back_to_a_snapshot() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut config = MockConfig::with_root(root.join("hermit"));
+        config.set_files(vec![ShellFile::mirrored(".bashrc")]);
+        let shell_root = root.join("hermit/shells/default");
+        git::test_support::init_repo(&shell_root);
+        fs::write(shell_root.join(".bashrc"), "old").unwrap();
+        git::commit_paths(&shell_root, &[".bashrc"], "Add bashrc", false).unwrap();
+        let hermit = hermit(&config);
+
+        assert_eq!(hermit.snapshot(Some("before")), Ok("before".to_owned()));
+        fs::write(shell_root.join(".bashrc"), "new").unwrap();
+        assert_eq!(
+            hermit.rollback(&mut FileOperations::rooted_at(root), "before"),
+            Err(Error::UncommittedChanges("default".to_owned()))
+        );
+        git::commit_paths(&shell_root, &[".bashrc"], "Change bashrc", false).unwrap();
+
+        let snapshots = hermit.snapshots().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].name, "before");
+        assert_eq!(snapshots[0].plan, vec![".bashrc -> ~/.bashrc"]);
+
+        let mut file_ops = FileOperations::rooted_at(root.join("home"));
+        let delta = hermit.rollback(&mut file_ops, "before").unwrap();
+        assert_eq!(
+            fs::read_to_string(shell_root.join(".bashrc")).unwrap(),
+            "old"
+        );
+        assert_eq!(delta.linked, vec![ShellFile::mirrored(".bashrc")]);
+        assert_eq!(
+            hermit.rollback(&mut file_ops, "after"),
+            Err(Error::UnknownSnapshot("after".to_owned()))
+        );
+    }
+
+    #[test]
+    fn commits_record_the_checksums_of_copied_files() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut config = MockConfig::with_root(root.join("hermit"));
+        config.set_files(vec![
+            ShellFile::mirrored(".gitconfig").with_strategy(Strategy::Copy)
+        ]);
+        let shell_root = root.join("hermit/shells/default");
+        git::test_support::init_repo(&shell_root);
+        fs::write(shell_root.join(".gitconfig"), "[user]").unwrap();
+        let hermit = hermit(&config);
+
+        hermit
+            .commit("default", "Add gitconfig", &[".gitconfig"])
+            .unwrap();
+        let checksums = Checksums::load(&shell_root).unwrap();
+        assert_eq!(
+            checksums.get(Path::new(".gitconfig")),
+            Some(
+                checksums::checksum(shell_root.join(".gitconfig"))
+                    .unwrap()
+                    .as_str()
+            )
+        );
+        assert!(!git::has_changes(&shell_root, Checksums::relative_path()).unwrap());
+
+        fs::write(shell_root.join(".gitconfig"), "[user]\nname = a").unwrap();
+        hermit
+            .commit("default", "Change gitconfig", &[".gitconfig"])
+            .unwrap();
+        let verdicts = hermit
+            .verify(root.join("home"))
+            .unwrap()
+            .into_iter()
+            .map(|verification| verification.verdict)
+            .collect::<Vec<_>>();
+        assert!(!verdicts.iter().any(|verdict| verdict.is_problem()));
+    }
+
+    #[test]
+    fn unsigned_shells_are_neither_applied_nor_hooked_into() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut config = MockConfig::with_root(root.join("hermit"));
+        config.set_files(vec![ShellFile::mirrored(".bashrc")]);
+        let shell_root = root.join("hermit/shells/default");
+        git::test_support::init_repo(&shell_root);
+        fs::write(shell_root.join(".bashrc"), "unsigned").unwrap();
+        git::commit_paths(&shell_root, &[".bashrc"], "Add bashrc", false).unwrap();
+        git::require_signatures(&shell_root).unwrap();
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at(root.join("home"));
+
+        assert!(matches!(
+            hermit.apply(&mut file_ops, &TagFilter::default()),
+            Err(Error::UnverifiedCommit { .. })
+        ));
+        assert!(matches!(
+            hermit.hooks("default", root.join("home")),
+            Err(Error::UnverifiedCommit { .. })
+        ));
+        assert!(matches!(
+            hermit.link_into_target(&mut file_ops, "default", &TagFilter::default()),
+            Err(Error::UnverifiedCommit { .. })
+        ));
+        assert!(file_ops.operations().is_empty());
+    }
+
+    fn write_script(path: &Path, script: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn using_a_shell_runs_its_hooks_around_linking_and_bootstraps_it_once() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut config = MockConfig::with_root(root.join("hermit"));
+        config.add_shell("work");
+        config.set_files(vec![ShellFile::mirrored(".bashrc")]);
+        let shell_root = root.join("hermit/shells/work");
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::write(shell_root.join(".bashrc"), "work").unwrap();
+        fs::write(
+            shell_root.join(MANIFEST_FILE_NAME),
+            "[[bootstrap]]\nname = \"setup\"\nrun = \"test -L \\\"$HERMIT_HOME/.bashrc\\\"\"\n",
+        )
+        .unwrap();
+        let hooks = shell_root.join(".hermit/hooks");
+        write_script(
+            &hooks.join("pre-use"),
+            r#"test ! -e "$HERMIT_HOME/.bashrc" || echo linked"#,
+        );
+        write_script(&hooks.join("post-use.d/10-say"), "echo done");
+        let home = root.join("home");
+        fs::create_dir_all(&home).unwrap();
+        let recorder = Rc::new(Recorder::default());
+        let mut hermit = hermit(&config).with_observer(recorder.clone());
+        let mut file_ops = FileOperations::rooted_at(&home);
+        let mut report = CommitReport::default();
+
+        hermit
+            .use_shell(
+                &mut file_ops,
+                "work",
+                &TagFilter::default(),
+                false,
+                &mut report,
+            )
+            .unwrap();
+        hermit
+            .use_shell(
+                &mut file_ops,
+                "work",
+                &TagFilter::default(),
+                false,
+                &mut report,
+            )
+            .unwrap();
+
+        assert_eq!(hermit.current_shell().unwrap().name, "work");
+        assert!(fs::symlink_metadata(home.join(".bashrc"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(report.is_success());
+        let ran = |event: &Event| {
+            matches!(
+                event,
+                Event::HookRan { .. } | Event::BootstrapStepStarted { .. }
+            )
+        };
+        let hook = |event, hook: &str, output: &str, failed| Event::HookRan {
+            event,
+            hook: PathBuf::from(hook),
+            output: output.as_bytes().to_vec(),
+            failed,
+        };
+        assert_eq!(
+            recorder
+                .events()
+                .into_iter()
+                .filter(ran)
+                .collect::<Vec<_>>(),
+            vec![
+                hook(HookEvent::PreUse, "pre-use", "", false),
+                Event::BootstrapStepStarted {
+                    name: "setup".to_owned()
+                },
+                hook(HookEvent::PostUse, "post-use.d/10-say", "done\n", false),
+                hook(HookEvent::PreUse, "pre-use", "linked\n", false),
+                hook(HookEvent::PostUse, "post-use.d/10-say", "done\n", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_failing_pre_use_hook_keeps_the_current_shell() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut config = MockConfig::with_root(root.join("hermit"));
+        config.add_shell("work");
+        let shell_root = root.join("hermit/shells/work");
+        write_script(&shell_root.join(".hermit/hooks/pre-use"), "exit 1");
+        let mut hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at(root.join("home"));
+        let mut report = CommitReport::default();
+
+        let used = hermit.use_shell(
+            &mut file_ops,
+            "work",
+            &TagFilter::default(),
+            true,
+            &mut report,
+        );
+
+        assert!(matches!(used, Err(Error::HookFailed { .. })));
+        assert_eq!(hermit.current_shell().unwrap().name, "default");
+        assert!(file_ops.operations().is_empty());
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn changes_made_while_relinking_see_no_links() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut config = MockConfig::with_root(root.join("hermit"));
+        config.set_files(vec![ShellFile::mirrored(".bashrc")]);
+        let shell_root = root.join("hermit/shells/default");
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::write(shell_root.join(".bashrc"), "default").unwrap();
+        let home = root.join("home");
+        fs::create_dir_all(&home).unwrap();
+        std::os::unix::fs::symlink(shell_root.join(".bashrc"), home.join(".bashrc")).unwrap();
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at(&home);
+        let mut report = CommitReport::default();
+
+        let changed = hermit.relinking(&mut file_ops, &mut report, |changed_root| {
+            assert_eq!(changed_root, shell_root);
+            assert!(!home.join(".bashrc").exists());
+            Err::<(), _>(Error::Git("conflict".to_owned()))
+        });
+
+        assert_eq!(changed, Err(Error::Git("conflict".to_owned())));
+        assert!(home.join(".bashrc").exists());
+        assert_eq!(report.changed(), 2);
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn pulls_what_was_fetched_once_its_signature_checks_out() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let origin = root.join("origin");
+        git::test_support::init_repo(&origin);
+        let commit = |contents: &str| {
+            fs::write(origin.join(".bashrc"), contents).unwrap();
+            git::commit_paths(&origin, &[".bashrc"], contents, false).unwrap();
+        };
+        commit("one");
+        let shell_root = root.join("hermit/shells/default");
+        let url = format!("file://{}", origin.display());
+        git::clone(&url, &shell_root, &Default::default()).unwrap();
+        let mut config = MockConfig::with_root(root.join("hermit"));
+        config.set_files(vec![ShellFile::mirrored(".bashrc")]);
+        let home = root.join("home");
+        fs::create_dir_all(&home).unwrap();
+        std::os::unix::fs::symlink(shell_root.join(".bashrc"), home.join(".bashrc")).unwrap();
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at(&home);
+        let mut report = CommitReport::default();
+
+        commit("two");
+        hermit
+            .pull(&mut file_ops, &mut report, |_| Ok(ConflictChoice::Abort))
+            .unwrap();
+        report.extend(file_ops.flush());
+        assert!(report.is_success());
+        assert_eq!(fs::read_to_string(home.join(".bashrc")).unwrap(), "two");
+
+        git::require_signatures(&shell_root).unwrap();
+        commit("three");
+        assert!(matches!(
+            hermit.pull(&mut file_ops, &mut report, |_| Ok(ConflictChoice::Abort)),
+            Err(Error::UnverifiedCommit { .. })
+        ));
+        assert_eq!(fs::read_to_string(home.join(".bashrc")).unwrap(), "two");
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn provisioning_logs_each_step_and_changes_nothing_the_second_time() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let origin = root.join("dotfiles");
+        git::test_support::init_repo(&origin);
+        fs::write(origin.join(".bashrc"), "alias ll='ls -l'").unwrap();
+        fs::write(
+            origin.join(MANIFEST_FILE_NAME),
+            "[[bootstrap]]\nname = \"mark\"\nrun = \"true\"\n",
+        )
+        .unwrap();
+        git::commit_paths(&origin, &[".bashrc", MANIFEST_FILE_NAME], "Add", false).unwrap();
+        let home = root.join("home");
+        fs::create_dir_all(&home).unwrap();
+        let mut hermit = Hermit::new(FsConfig::new(root.join("hermit")).unwrap());
+        let request = provision::Request {
+            url: origin.display().to_string(),
+            name: "dotfiles".to_owned(),
+            ..Default::default()
+        };
+        let provision = |hermit: &mut Hermit<FsConfig>| {
+            let mut file_ops = FileOperations::rooted_at(&home);
+            let mut report = CommitReport::default();
+            let mut steps = vec![];
+            let provisioned = hermit.provision(&mut file_ops, &request, &mut report, |record| {
+                steps.push((record.step, record.outcome))
+            });
+            (provisioned, steps)
+        };
+
+        assert_eq!(
+            provision(&mut hermit),
+            (
+                Ok(()),
+                vec![
+                    ("root", Provisioned::Changed),
+                    ("clone", Provisioned::Changed),
+                    ("pre-use", Provisioned::Unchanged),
+                    ("use", Provisioned::Changed),
+                    ("bootstrap", Provisioned::Changed),
+                    ("post-use", Provisioned::Unchanged),
+                ]
+            )
+        );
+        assert_eq!(
+            fs::read_link(home.join(".bashrc")).unwrap(),
+            root.join("hermit/shells/dotfiles/.bashrc")
+        );
+
+        let (provisioned, steps) = provision(&mut hermit);
+        assert_eq!(provisioned, Ok(()));
+        assert_eq!(steps.len(), 4);
+        assert!(steps
+            .iter()
+            .all(|(_, outcome)| *outcome == Provisioned::Unchanged));
+
+        let request = provision::Request {
+            url: "/elsewhere/dotfiles".to_owned(),
+            ..request.clone()
+        };
+        let mut steps = vec![];
+        let provisioned = hermit.provision(
+            &mut FileOperations::rooted_at(&home),
+            &request,
+            &mut CommitReport::default(),
+            |record| steps.push((record.step, record.outcome)),
+        );
+        assert_eq!(
+            provisioned,
+            Err(Error::ShellAlreadyExists("dotfiles".to_owned()))
+        );
+        assert_eq!(steps.last(), Some(&("clone", Provisioned::Failed)));
+    }
+
+    #[test]
+    fn installs_a_pre_commit_hook_that_runs_doctor() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let config = MockConfig::with_root(root);
+        let shell_root = root.join("shells/default");
+        git::test_support::init_repo(&shell_root);
+        let hermit = hermit(&config);
+
+        let hook = hermit.install_hooks("default").expect("Install failed");
+
+        assert_eq!(hook, shell_root.join(".git/hooks/pre-commit"));
+        let script = fs::read_to_string(&hook).unwrap();
+        assert!(script.ends_with(&format!(
+            "{} || exit 1\n{}\n",
+            env::hermit_command(root, &["doctor", "--shell", "default"]).unwrap(),
+            env::hermit_command(root, &["verify", "--record", "--shell", "default"]).unwrap()
+        )));
+        assert_eq!(
+            fs::metadata(&hook).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+        assert_eq!(hermit.install_hooks("default"), Ok(hook.clone()));
+
+        fs::write(&hook, "#!/bin/sh\nmake lint\n").unwrap();
+        assert!(matches!(
+            hermit.install_hooks("default"),
+            Err(Error::HookNotInstalled(_))
+        ));
+    }
+
+    #[test]
+    fn cannot_bootstrap_a_nonexistent_shell() {
+        let config = MockConfig::new();
+        let hermit = hermit(&config);
+
+        assert!(matches!(
+            hermit.bootstrap("nonexistent"),
+            Err(Error::UnknownShell { .. })
+        ));
     }
 }
@@ -0,0 +1,278 @@
+//! Scripts that a shell runs when something happens to it, kept in
+//! its `.hermit/hooks` directory. The hook for an event is the
+//! executable named after it, like `.hermit/hooks/post-use`, followed
+//! by the executables in the directory named after it with `.d` on the
+//! end, like `.hermit/hooks/post-use.d/`, in the order of their names.
+
+use crate::{common::*, manifest::METADATA_DIR};
+
+use std::{
+    io::Read,
+    os::unix::fs::PermissionsExt,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The directory inside a shell's metadata directory that its hooks
+/// are kept in.
+pub const HOOKS_DIR_NAME: &str = "hooks";
+
+/// The event a hook runs for, set for every hook it runs.
+pub const EVENT_VAR: &str = "HERMIT_HOOK_EVENT";
+
+/// How often to check whether a hook has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long to wait for the output of a hook that has finished, in
+/// case something it started in the background still holds on to it.
+const OUTPUT_GRACE: Duration = Duration::from_millis(100);
+
+/// Something that happens to a shell that it can run hooks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The shell is about to be switched to. A failing hook stops the
+    /// switch.
+    PreUse,
+    /// The shell has been switched to, and its files linked.
+    PostUse,
+    /// Files have been added to the shell.
+    PostAdd,
+    /// The shell has been synced with its remote or sync folder.
+    PostSync,
+    /// The shell is about to be removed. A failing hook keeps it.
+    PreNuke,
+}
+
+impl Event {
+    /// The name of the event, which is also the name of its hook.
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::PreUse => "pre-use",
+            Event::PostUse => "post-use",
+            Event::PostAdd => "post-add",
+            Event::PostSync => "post-sync",
+            Event::PreNuke => "pre-nuke",
+        }
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// One hook that has run, with everything it printed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HookRun {
+    pub path: PathBuf,
+    pub output: Vec<u8>,
+    /// Why the hook failed, if it did.
+    pub failure: Option<String>,
+}
+
+impl HookRun {
+    pub fn check(&self, event: Event) -> Result<()> {
+        match &self.failure {
+            Some(reason) => Err(Error::HookFailed {
+                event: event.name(),
+                path: self.path.clone(),
+                reason: reason.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The hooks of one shell.
+#[derive(Clone, Debug)]
+pub struct Hooks {
+    shell_root: PathBuf,
+    timeout: Duration,
+    env: Vec<(&'static str, PathBuf)>,
+}
+
+impl Hooks {
+    pub fn new(shell_root: impl AsRef<Path>) -> Hooks {
+        Hooks {
+            shell_root: PathBuf::from(shell_root.as_ref()),
+            timeout: Duration::from_secs(60),
+            env: vec![],
+        }
+    }
+
+    /// Stops hooks that are still running after `timeout`.
+    pub fn with_timeout(self, timeout: Duration) -> Hooks {
+        Hooks { timeout, ..self }
+    }
+
+    /// Sets environment variables for the hooks, on top of hermit's
+    /// own environment.
+    pub fn with_env(self, env: Vec<(&'static str, PathBuf)>) -> Hooks {
+        Hooks { env, ..self }
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        self.shell_root.join(METADATA_DIR).join(HOOKS_DIR_NAME)
+    }
+
+    /// The hooks that run for `event`, in the order they run in. Files
+    /// that aren't executable, like a README, are left out.
+    pub fn scripts(&self, event: Event) -> Vec<PathBuf> {
+        let dir = self.dir();
+        let mut scripts = vec![dir.join(event.name())];
+        if let Ok(entries) = fs::read_dir(dir.join(format!("{}.d", event.name()))) {
+            let mut found = entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .collect::<Vec<_>>();
+            found.sort();
+            scripts.extend(found);
+        }
+        scripts.retain(|path| is_executable(path));
+        scripts
+    }
+
+    /// Runs the hooks for `event` from the root of the shell, one at a
+    /// time, and stops after the first one that fails.
+    pub fn run(&self, event: Event) -> Vec<HookRun> {
+        let mut runs = vec![];
+        for path in self.scripts(event) {
+            let run = self.run_one(event, path);
+            let failed = run.failure.is_some();
+            runs.push(run);
+            if failed {
+                break;
+            }
+        }
+        runs
+    }
+
+    fn run_one(&self, event: Event, path: PathBuf) -> HookRun {
+        match self.spawn(event, &path) {
+            Ok((output, failure)) => HookRun {
+                path,
+                output,
+                failure,
+            },
+            Err(err) => HookRun {
+                path,
+                output: vec![],
+                failure: Some(err.to_string()),
+            },
+        }
+    }
+
+    /// Runs a hook with its output captured, and returns the output
+    /// along with why it failed, if it did.
+    fn spawn(&self, event: Event, path: &Path) -> io::Result<(Vec<u8>, Option<String>)> {
+        let (mut reader, writer) = io::pipe()?;
+        let mut child = {
+            let mut command = process::Command::new(path);
+            command
+                .current_dir(&self.shell_root)
+                .envs(self.env.iter().cloned())
+                .env(EVENT_VAR, event.name())
+                .stdin(process::Stdio::null())
+                .stdout(writer.try_clone()?)
+                .stderr(writer);
+            // The command holds on to the writing end of the pipe,
+            // which has to be closed for the output to ever end.
+            command.spawn()?
+        };
+
+        let output = Arc::new(Mutex::new(vec![]));
+        let (done, finished) = mpsc::channel();
+        {
+            let output = Arc::clone(&output);
+            thread::spawn(move || {
+                let mut buffer = [0; 4096];
+                while let Ok(read @ 1..) = reader.read(&mut buffer) {
+                    output.lock().unwrap().extend_from_slice(&buffer[..read]);
+                }
+                let _ = done.send(());
+            });
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let failure = loop {
+            if let Some(status) = child.try_wait()? {
+                break (!status.success()).then(|| status.to_string());
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                child.wait()?;
+                break Some(format!("timed out after {:?}", self.timeout));
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        let _ = finished.recv_timeout(OUTPUT_GRACE);
+        let output = output.lock().unwrap().clone();
+        Ok((output, failure))
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    fn write_hook(path: &Path, script: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn hooks_run_in_order_until_one_fails() {
+        let test_root_dir = set_up();
+        let hooks = Hooks::new(test_root_dir.path());
+        let dir = hooks.dir();
+        write_hook(&dir.join("post-use"), "echo \"$HERMIT_HOOK_EVENT\"");
+        write_hook(&dir.join("post-use.d/20-fail"), "echo oops >&2; exit 3");
+        write_hook(&dir.join("post-use.d/10-pwd"), "pwd");
+        write_hook(&dir.join("post-use.d/30-never"), "echo never");
+        fs::write(dir.join("README.md"), "Not a hook").unwrap();
+
+        let runs = hooks.run(Event::PostUse);
+
+        let root = test_root_dir.path().canonicalize().unwrap();
+        assert_eq!(
+            runs.iter().map(|run| &run.output[..]).collect::<Vec<_>>(),
+            vec![
+                &b"post-use\n"[..],
+                format!("{}\n", root.display()).as_bytes(),
+                &b"oops\n"[..],
+            ]
+        );
+        assert_eq!(runs[1].failure, None);
+        assert!(matches!(
+            runs[2].check(Event::PostUse),
+            Err(Error::HookFailed {
+                event: "post-use",
+                ..
+            })
+        ));
+        assert!(hooks.run(Event::PreNuke).is_empty());
+    }
+
+    #[test]
+    fn slow_hooks_are_stopped() {
+        let test_root_dir = set_up();
+        let hooks = Hooks::new(test_root_dir.path()).with_timeout(Duration::from_millis(200));
+        write_hook(&hooks.dir().join("pre-use"), "echo started; exec sleep 5");
+
+        let runs = hooks.run(Event::PreUse);
+
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].output, b"started\n");
+        assert_eq!(runs[0].failure.as_deref(), Some("timed out after 200ms"));
+    }
+}
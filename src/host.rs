@@ -0,0 +1,64 @@
+use crate::common::*;
+
+use std::collections::BTreeMap;
+
+/// The facts about the current machine that manifests can make
+/// decisions with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Host {
+    pub os: String,
+    pub arch: String,
+    pub hostname: String,
+    pub username: String,
+    pub env: BTreeMap<String, String>,
+}
+
+impl Host {
+    /// Describes the machine hermit is currently running on.
+    pub fn current() -> Host {
+        let hostname = hostname::get()
+            .ok()
+            .and_then(|name| name.into_string().ok())
+            .unwrap_or_default();
+
+        let env = std::env::vars().collect::<BTreeMap<_, _>>();
+        let username = ["USER", "LOGNAME"]
+            .iter()
+            .find_map(|name| env.get(*name).filter(|value| !value.is_empty()))
+            .cloned()
+            .unwrap_or_default();
+
+        Host {
+            os: std::env::consts::OS.to_owned(),
+            arch: std::env::consts::ARCH.to_owned(),
+            hostname,
+            username,
+            env,
+        }
+    }
+
+    /// The built-in variables that describe the machine, by the names
+    /// templates know them by.
+    pub fn variables(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("hostname", self.hostname.clone()),
+            ("os", self.os.clone()),
+            ("arch", self.arch.clone()),
+            ("username", self.username.clone()),
+        ]
+    }
+
+    pub fn env_var(&self, name: &str) -> Option<&str> {
+        self.env.get(name).map(Borrow::borrow)
+    }
+}
+
+/// Whether hermit is running as root, as it has to be to change the
+/// files of a system shell.
+pub fn is_root() -> bool {
+    process::Command::new("id")
+        .arg("-u")
+        .stdin(process::Stdio::null())
+        .output()
+        .is_ok_and(|output| output.status.success() && output.stdout.trim_ascii() == b"0")
+}
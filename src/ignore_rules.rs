@@ -0,0 +1,296 @@
+use crate::common::*;
+
+use std::collections::HashMap;
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+
+use crate::fs_backend::{FsBackend, RealFs};
+
+/// The files that list paths to leave out of a shell. `.gitignore`
+/// keeps build artifacts and caches that git doesn't track from being
+/// linked too, and `.hermitignore` is for files that should be in the
+/// repository but not linked.
+pub const IGNORE_FILE_NAMES: &[&str] = &[".gitignore", ".hermitignore"];
+
+/// The lines around the entries that hermit keeps in a shell's
+/// `.gitignore`, so that they can be updated without touching the
+/// user's own entries.
+const MANAGED_START: &str = "# Machine-local files, kept up to date by hermit";
+const MANAGED_END: &str = "# End of hermit's entries";
+
+/// Makes sure that the `.gitignore` at the root of `shell_root` ignores
+/// each of `patterns`, so that files hermit makes for this machine
+/// alone are never committed. Hermit's entries are kept together in a
+/// block of their own. Returns whether the file had to change.
+pub fn ensure_ignored(shell_root: &Path, patterns: &[&str]) -> io::Result<bool> {
+    let path = shell_root.join(".gitignore");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+
+    let lines = contents.lines().collect::<Vec<_>>();
+    let start = lines.iter().position(|line| *line == MANAGED_START);
+    let end = start.and_then(|start| {
+        lines[start..]
+            .iter()
+            .position(|line| *line == MANAGED_END)
+            .map(|end| start + end)
+    });
+    let (before, mut managed, after) = match (start, end) {
+        (Some(start), Some(end)) => (
+            &lines[..start],
+            lines[start + 1..end].to_vec(),
+            &lines[end + 1..],
+        ),
+        _ => (&lines[..], vec![], &[][..]),
+    };
+
+    let missing = patterns
+        .iter()
+        .filter(|pattern| !managed.contains(pattern))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(false);
+    }
+    managed.extend(missing);
+
+    let mut updated = before.to_vec();
+    if start.is_none() && updated.last().is_some_and(|line| !line.is_empty()) {
+        updated.push("");
+    }
+    updated.push(MANAGED_START);
+    updated.extend(managed);
+    updated.push(MANAGED_END);
+    updated.extend(after);
+
+    let mut updated = updated.join("\n");
+    updated.push('\n');
+    fs::write(path, updated)?;
+    Ok(true)
+}
+
+/// The ignore rules of every directory in a tree, read as they are
+/// needed, along with the repository's `.git/info/exclude`. git's own
+/// rules for them are followed, by way of the `ignore` crate.
+///
+/// Like git, the rules in a directory apply to everything below it,
+/// deeper rules take precedence over shallower ones, the last matching
+/// rule in a file wins, and `.git/info/exclude` comes last of all.
+pub struct IgnoreRules {
+    root: PathBuf,
+    fs: Rc<dyn FsBackend>,
+    rules: HashMap<PathBuf, Gitignore>,
+    excludes: Gitignore,
+}
+
+impl IgnoreRules {
+    pub fn new(root: impl AsRef<Path>) -> IgnoreRules {
+        IgnoreRules::with_backend(root, Rc::new(RealFs))
+    }
+
+    /// Like `new`, but reads the ignore files from `fs`.
+    pub fn with_backend(root: impl AsRef<Path>, fs: Rc<dyn FsBackend>) -> IgnoreRules {
+        let root = root.as_ref().to_path_buf();
+        // The repository can hold more than the tree, when all of the
+        // shells share one.
+        let excludes = match root.ancestors().find(|dir| fs.is_dir(&dir.join(".git"))) {
+            Some(repo) => read_rules(&*fs, repo, &[repo.join(".git/info/exclude")]),
+            None => Gitignore::empty(),
+        };
+        IgnoreRules {
+            root,
+            fs,
+            rules: HashMap::new(),
+            excludes,
+        }
+    }
+
+    /// Whether the file or directory at `path`, which must be inside
+    /// the root, is ignored. Paths inside ignored directories aren't
+    /// checked, since the directories are never walked.
+    pub fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => return false,
+        };
+
+        for dir in relative.ancestors().skip(1) {
+            match self.rules_in(dir).matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => (),
+            }
+        }
+        self.excludes.matched(path, is_dir).is_ignore()
+    }
+
+    fn rules_in(&mut self, dir: &Path) -> &Gitignore {
+        let dir = self.root.join(dir);
+        let fs = &self.fs;
+        self.rules.entry(dir.clone()).or_insert_with(|| {
+            let files = IGNORE_FILE_NAMES
+                .iter()
+                .map(|name| dir.join(name))
+                .collect::<Vec<_>>();
+            read_rules(&**fs, &dir, &files)
+        })
+    }
+}
+
+/// The rules in the ignore `files` that there are, for the paths
+/// below `dir`. Lines that aren't valid patterns are skipped, as git
+/// does.
+fn read_rules(fs: &dyn FsBackend, dir: &Path, files: &[PathBuf]) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(dir);
+    for file in files {
+        if let Ok(contents) = fs.read_to_string(file) {
+            for line in contents.lines() {
+                let _ = builder.add_line(Some(file.clone()), line);
+            }
+        }
+    }
+    builder.build().unwrap_or_else(|_| Gitignore::empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    fn rules(root: &Path, files: &[(&str, &str)]) -> IgnoreRules {
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        IgnoreRules::new(root)
+    }
+
+    #[test]
+    fn hermit_keeps_its_entries_in_a_block_of_their_own() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        fs::write(root.join(".gitignore"), "*.log").unwrap();
+
+        assert!(ensure_ignored(root, &["/.hermit/local/"]).unwrap());
+        assert!(!ensure_ignored(root, &["/.hermit/local/"]).unwrap());
+        fs::write(
+            root.join(".gitignore"),
+            fs::read_to_string(root.join(".gitignore")).unwrap() + "build/\n",
+        )
+        .unwrap();
+        assert!(ensure_ignored(root, &["/.hermit/local/", "/vars.local"]).unwrap());
+
+        assert_eq!(
+            fs::read_to_string(root.join(".gitignore")).unwrap(),
+            "*.log\n\n\
+             # Machine-local files, kept up to date by hermit\n\
+             /.hermit/local/\n\
+             /vars.local\n\
+             # End of hermit's entries\n\
+             build/\n"
+        );
+        assert!(IgnoreRules::new(root).is_ignored(&root.join("vars.local"), false));
+    }
+
+    #[test]
+    fn nothing_is_ignored_without_ignore_files() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut rules = rules(root, &[]);
+
+        assert!(!rules.is_ignored(&root.join(".bashrc"), false));
+    }
+
+    #[test]
+    fn unanchored_patterns_match_names_at_any_depth() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut rules = rules(root, &[(".gitignore", "# caches\n*.log\n")]);
+
+        assert!(rules.is_ignored(&root.join("debug.log"), false));
+        assert!(rules.is_ignored(&root.join(".config/app/debug.log"), false));
+        assert!(!rules.is_ignored(&root.join(".config/app/config.toml"), false));
+    }
+
+    #[test]
+    fn patterns_with_a_slash_are_relative_to_their_file() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut rules = rules(root, &[(".hermitignore", "/README.md\n.config/*/cache\n")]);
+
+        assert!(rules.is_ignored(&root.join("README.md"), false));
+        assert!(!rules.is_ignored(&root.join("docs/README.md"), false));
+        assert!(rules.is_ignored(&root.join(".config/app/cache"), false));
+        assert!(!rules.is_ignored(&root.join(".config/app/sub/cache"), false));
+    }
+
+    #[test]
+    fn trailing_slashes_only_match_directories() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut rules = rules(root, &[(".gitignore", "build/\n")]);
+
+        assert!(rules.is_ignored(&root.join("build"), true));
+        assert!(!rules.is_ignored(&root.join("build"), false));
+    }
+
+    #[test]
+    fn negations_and_deeper_files_take_precedence() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut rules = rules(
+            root,
+            &[
+                (".gitignore", "*.local\n!keep.local\n"),
+                ("nested/.gitignore", "!*.local\n"),
+            ],
+        );
+
+        assert!(rules.is_ignored(&root.join("a.local"), false));
+        assert!(!rules.is_ignored(&root.join("keep.local"), false));
+        assert!(!rules.is_ignored(&root.join("nested/a.local"), false));
+    }
+
+    #[test]
+    fn backslashes_escape_special_characters() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let mut rules = rules(
+            root,
+            &[(".gitignore", "\\#notes\n\\!important\nspace\\ \n")],
+        );
+
+        assert!(rules.is_ignored(&root.join("#notes"), false));
+        assert!(rules.is_ignored(&root.join("!important"), false));
+        assert!(rules.is_ignored(&root.join("space "), false));
+        assert!(!rules.is_ignored(&root.join("important"), false));
+    }
+
+    #[test]
+    fn the_repositorys_excludes_come_last() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let shell = root.join("shells/work");
+        fs::create_dir_all(&shell).unwrap();
+        let mut rules = rules(
+            root,
+            &[
+                (".git/info/exclude", "*.swp\nscratch\n"),
+                ("shells/work/.gitignore", "!keep.swp\n"),
+            ],
+        );
+        let mut shell_rules = IgnoreRules::new(&shell);
+
+        assert!(rules.is_ignored(&root.join("a.swp"), false));
+        assert!(shell_rules.is_ignored(&shell.join("scratch"), false));
+        assert!(shell_rules.is_ignored(&shell.join(".vim/a.swp"), false));
+        assert!(!shell_rules.is_ignored(&shell.join("keep.swp"), false));
+    }
+}
@@ -0,0 +1,196 @@
+//! A cache of the files found by walking each shell, kept in the
+//! hermit root's state directory, so that commands don't have to walk a large shell
+//! every time they run.
+//!
+//! Along with the files, the index notes when each directory that the
+//! walk read, and the ignore files in it, last changed. Adding,
+//! removing or renaming anything in a directory changes when it last
+//! changed, so the index is used for as long as none of them have.
+
+use crate::{
+    common::*, config::Files, fs_backend::FsBackend, ignore_rules::IGNORE_FILE_NAMES,
+    journal::os_path, state,
+};
+
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+/// The directory in the hermit root that the index of each shell is
+/// kept in.
+pub const INDEX_DIR_NAME: &str = "index";
+
+/// The files that a walk of a shell found, and what it looked at to
+/// find them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Index {
+    /// How the shell was walked. A walk with other limits, or other
+    /// directories linked as a whole, finds other files.
+    key: String,
+    watched: Vec<Watched>,
+    files: Vec<IndexedFile>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Watched {
+    #[serde(with = "os_path")]
+    path: PathBuf,
+    /// When it last changed, or nothing if it didn't exist.
+    modified: Option<SystemTime>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct IndexedFile(#[serde(with = "os_path")] PathBuf);
+
+/// Where the index of the shell `name` is kept, in the hermit root's
+/// state directory at `state_path`.
+pub fn path(state_path: &Path, name: &str) -> PathBuf {
+    state_path
+        .join(INDEX_DIR_NAME)
+        .join(format!("{}.json", name))
+}
+
+/// The files that `files` walks, from the index at `index_path` if it
+/// was made by a walk like it and nothing it looked at has changed
+/// since. Otherwise the files are walked, and the index is made again.
+/// Filesystems that can't tell when things changed are always walked.
+pub fn files(fs: &dyn FsBackend, index_path: &Path, key: &str, files: Files) -> Vec<PathBuf> {
+    if let Some(index) = Index::load(fs, index_path) {
+        if index.key == key && index.is_fresh(fs) {
+            return index.files.into_iter().map(|file| file.0).collect();
+        }
+    }
+
+    let mut walk = files.into_iter();
+    let found = walk.by_ref().collect::<Vec<_>>();
+    if let Ok(index) = Index::new(fs, key, walk.walked_dirs(), &found) {
+        // The index only saves time, so a hermit root that can't be
+        // written to just doesn't get one.
+        let _ = index.save(fs, index_path);
+    }
+    found
+}
+
+impl Index {
+    fn new(
+        fs: &dyn FsBackend,
+        key: &str,
+        dirs: &[PathBuf],
+        files: &[PathBuf],
+    ) -> io::Result<Index> {
+        let mut watched = vec![];
+        for dir in dirs {
+            watched.push(Watched::of(fs, dir.clone())?);
+            for name in IGNORE_FILE_NAMES {
+                watched.push(Watched::of(fs, dir.join(name))?);
+            }
+        }
+        Ok(Index {
+            key: key.to_owned(),
+            watched,
+            files: files.iter().cloned().map(IndexedFile).collect(),
+        })
+    }
+
+    /// The index at `path`, if there is a readable one.
+    fn load(fs: &dyn FsBackend, path: &Path) -> Option<Index> {
+        serde_json::from_slice(&fs.read(path).ok()?).ok()
+    }
+
+    fn save(&self, fs: &dyn FsBackend, path: &Path) -> io::Result<()> {
+        state::write_atomic(fs, path, &serde_json::to_vec(self)?)
+    }
+
+    /// Whether nothing that the walk looked at has changed since.
+    fn is_fresh(&self, fs: &dyn FsBackend) -> bool {
+        self.watched
+            .iter()
+            .all(|watched| Watched::of(fs, watched.path.clone()).is_ok_and(|now| now == *watched))
+    }
+}
+
+impl Watched {
+    fn of(fs: &dyn FsBackend, path: PathBuf) -> io::Result<Watched> {
+        let modified = match fs.modified(&path) {
+            Ok(modified) => Some(modified),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        Ok(Watched { path, modified })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{fs_backend::RealFs, test_helpers::filesystem::set_up};
+
+    use std::rc::Rc;
+
+    fn walk(shell_root: &Path, index_path: &Path) -> Vec<PathBuf> {
+        files(
+            &RealFs,
+            index_path,
+            "key",
+            Files::with_backend(Some(shell_root), Rc::new(RealFs)),
+        )
+    }
+
+    #[test]
+    fn walks_are_indexed_until_the_shell_changes() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path().join("shell");
+        let index_path = path(test_root_dir.path(), "shell");
+        fs::create_dir_all(shell_root.join(".config")).unwrap();
+        fs::write(shell_root.join(".bashrc"), "").unwrap();
+
+        assert_eq!(
+            walk(&shell_root, &index_path),
+            vec![PathBuf::from(".bashrc")]
+        );
+        assert!(index_path.is_file());
+
+        // A file that the index doesn't know about is only found once
+        // the index is stale.
+        let mut index = Index::load(&RealFs, &index_path).unwrap();
+        index.files.push(IndexedFile(PathBuf::from(".cached")));
+        index.save(&RealFs, &index_path).unwrap();
+        assert_eq!(
+            walk(&shell_root, &index_path),
+            vec![PathBuf::from(".bashrc"), PathBuf::from(".cached")]
+        );
+
+        fs::write(shell_root.join(".config/starship.toml"), "").unwrap();
+        assert_eq!(
+            walk(&shell_root, &index_path),
+            vec![
+                PathBuf::from(".bashrc"),
+                PathBuf::from(".config/starship.toml")
+            ]
+        );
+    }
+
+    #[test]
+    fn ignore_files_changing_makes_the_index_stale() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path().join("shell");
+        let index_path = path(test_root_dir.path(), "shell");
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::write(shell_root.join(".bashrc"), "").unwrap();
+        fs::write(shell_root.join(".hermitignore"), "").unwrap();
+        walk(&shell_root, &index_path);
+
+        let ignores = shell_root.join(".hermitignore");
+        let index = Index::load(&RealFs, &index_path).unwrap();
+        let earlier = SystemTime::UNIX_EPOCH;
+        fs::File::options()
+            .write(true)
+            .open(&ignores)
+            .unwrap()
+            .set_modified(earlier)
+            .unwrap();
+
+        assert!(!index.is_fresh(&RealFs));
+    }
+}
@@ -0,0 +1,273 @@
+use crate::{
+    common::*,
+    file_operations::backup_path,
+    manifest::Strategy,
+    status::{FileState, FileStatus, Status},
+};
+
+use crossterm::{
+    event::{self, Event, KeyCode},
+    execute,
+    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use tui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout},
+    style::{Modifier, Style},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    Terminal,
+};
+
+/// What to do about a file that isn't in place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Choice {
+    /// Leave the file as it is.
+    Skip,
+    /// Move whatever is in the way aside to `<name>.hermit-backup`, and
+    /// put the shell's file in place.
+    BackUpAndLink,
+    /// Take the file that is in the way into the shell, in place of the
+    /// shell's version, and put it back in place.
+    Adopt,
+}
+
+/// The files of a shell that aren't in place, along with what the user
+/// has chosen to do about each of them so far.
+#[derive(Clone, Debug)]
+pub struct Resolver {
+    files: Vec<FileStatus>,
+    choices: Vec<Choice>,
+    selected: usize,
+}
+
+/// Where the user left the resolver.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Done {
+    Apply,
+    Cancel,
+}
+
+impl Resolver {
+    pub fn new(status: &Status) -> Resolver {
+        let files = status
+            .files
+            .iter()
+            .filter(|file| !file.state.is_in_place())
+            .cloned()
+            .collect::<Vec<_>>();
+        Resolver {
+            choices: vec![Choice::Skip; files.len()],
+            files,
+            selected: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn choices(&self) -> &[Choice] {
+        &self.choices
+    }
+
+    /// Acts on a key press, returning whether the user is done.
+    pub fn press(&mut self, key: KeyCode) -> Option<Done> {
+        match key {
+            KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected = (self.selected + 1).min(self.files.len().saturating_sub(1))
+            }
+            KeyCode::Char('b') => self.choose(Choice::BackUpAndLink),
+            KeyCode::Char('a') => self.choose(Choice::Adopt),
+            KeyCode::Char('s') => self.choose(Choice::Skip),
+            KeyCode::Enter => return Some(Done::Apply),
+            KeyCode::Char('q') | KeyCode::Esc => return Some(Done::Cancel),
+            _ => (),
+        }
+        None
+    }
+
+    /// Queues the operations that carry out the choices. Links and
+    /// copies are made in `file_ops`'s root, from the shell at
+    /// `shell_root`.
+    pub fn queue(&self, file_ops: &mut FileOperations, shell_root: &Path) {
+        for (status, choice) in self.files.iter().zip(&self.choices) {
+            let target = file_ops.root().join(&status.file.target);
+            let source = shell_root.join(&status.file.source);
+            let put_in_place = |file_ops: &mut FileOperations| match status.file.strategy {
+                Strategy::Link => file_ops.link(&target, &source),
+                Strategy::Copy => file_ops.copy(&target, &source),
+            };
+            match (choice, status.file.strategy) {
+                (Choice::Skip, _) => (),
+                (Choice::BackUpAndLink, _) => {
+                    if status.state != FileState::Missing {
+                        file_ops.move_file(&target, backup_path(&target));
+                    }
+                    put_in_place(file_ops);
+                }
+                (Choice::Adopt, Strategy::Link) => {
+                    file_ops.move_file(&target, &source);
+                    put_in_place(file_ops);
+                }
+                (Choice::Adopt, Strategy::Copy) => file_ops.copy(&source, &target),
+            }
+        }
+    }
+
+    /// Chooses what to do about the selected file, unless it can't be
+    /// done to it. Only files in the way can be adopted, since there is
+    /// nothing else to adopt.
+    fn choose(&mut self, choice: Choice) {
+        let state = match self.files.get(self.selected) {
+            Some(file) => file.state,
+            None => return,
+        };
+        let adoptable = matches!(state, FileState::Conflict | FileState::OutOfDate);
+        if choice != Choice::Adopt || adoptable {
+            self.choices[self.selected] = choice;
+        }
+    }
+
+    fn describe(&self, index: usize) -> String {
+        let file = &self.files[index];
+        let verb = match file.file.strategy {
+            Strategy::Link => "link",
+            Strategy::Copy => "copy",
+        };
+        let choice = match (self.choices[index], file.state) {
+            (Choice::Skip, _) => "skip".to_owned(),
+            (Choice::BackUpAndLink, FileState::Missing) => verb.to_owned(),
+            (Choice::BackUpAndLink, _) => format!("back up & {}", verb),
+            (Choice::Adopt, _) => "adopt".to_owned(),
+        };
+        format!(
+            "{:<18}{:<16}{}",
+            file.state.label(),
+            choice,
+            message::path(&file.file.target)
+        )
+    }
+}
+
+/// Lets the user choose what to do about each file in `resolver` on the
+/// terminal, until they apply their choices or cancel.
+pub fn run(resolver: &mut Resolver, shell: &str) -> io::Result<Done> {
+    terminal::enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    let done = run_in_terminal(resolver, shell);
+    // Put the terminal back, even if drawing failed.
+    let restored = execute!(io::stdout(), LeaveAlternateScreen);
+    terminal::disable_raw_mode()?;
+    restored?;
+    done
+}
+
+fn run_in_terminal(resolver: &mut Resolver, shell: &str) -> io::Result<Done> {
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    let title = format!(" Files of {} that aren't in place ", shell);
+    loop {
+        terminal.draw(|frame| {
+            let areas = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(1), Constraint::Length(1)])
+                .split(frame.size());
+            let items = (0..resolver.files.len())
+                .map(|index| ListItem::new(resolver.describe(index)))
+                .collect::<Vec<_>>();
+            let list = List::new(items)
+                .block(Block::default().borders(Borders::ALL).title(title.as_str()))
+                .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+            let mut state = ListState::default();
+            state.select(Some(resolver.selected));
+            frame.render_stateful_widget(list, areas[0], &mut state);
+            frame.render_widget(
+                Paragraph::new(
+                    " ↑/↓ move   b back up & link   a adopt   s skip   enter apply   q quit",
+                ),
+                areas[1],
+            );
+        })?;
+
+        if let Event::Key(key) = event::read()? {
+            if let Some(done) = resolver.press(key.code) {
+                return Ok(done);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fs_backend::{FsBackend, MemoryFs};
+
+    fn resolver() -> Resolver {
+        let file = |path: &str, strategy, state| FileStatus {
+            file: ShellFile::mirrored(path).with_strategy(strategy),
+            state,
+        };
+        Resolver::new(&Status {
+            shell: "work".to_owned(),
+            info: Default::default(),
+            files: vec![
+                file(".bashrc", Strategy::Link, FileState::Conflict),
+                file(".inputrc", Strategy::Link, FileState::Linked),
+                file(".profile", Strategy::Link, FileState::LinkedElsewhere),
+                file(".vimrc", Strategy::Link, FileState::Conflict),
+                file(".npmrc", Strategy::Copy, FileState::OutOfDate),
+            ],
+        })
+    }
+
+    #[test]
+    fn keys_choose_what_happens_to_the_selected_file() {
+        let mut resolver = resolver();
+
+        for key in "bjajajja".chars() {
+            assert_eq!(resolver.press(KeyCode::Char(key)), None);
+        }
+
+        assert_eq!(
+            resolver.choices(),
+            [
+                Choice::BackUpAndLink,
+                Choice::Skip,
+                Choice::Adopt,
+                Choice::Adopt,
+            ]
+        );
+        assert_eq!(resolver.press(KeyCode::Enter), Some(Done::Apply));
+        assert_eq!(resolver.press(KeyCode::Esc), Some(Done::Cancel));
+    }
+
+    #[test]
+    fn choices_queue_operations() {
+        let fs = Rc::new(MemoryFs::new());
+        fs.add_file("/hermit/shells/work/.bashrc", "shell");
+        fs.add_file("/hermit/shells/work/.vimrc", "shell");
+        fs.add_file("/hermit/shells/work/.npmrc", "shell");
+        fs.add_file("/home/geoff/.bashrc", "mine");
+        fs.add_file("/home/geoff/.vimrc", "mine");
+        fs.add_file("/home/geoff/.npmrc", "mine");
+        let mut resolver = resolver();
+        for key in "bjjajja".chars() {
+            resolver.press(KeyCode::Char(key));
+        }
+        let mut file_ops = FileOperations::with_backend("/home/geoff", fs.clone());
+
+        resolver.queue(&mut file_ops, Path::new("/hermit/shells/work"));
+        for result in file_ops.commit() {
+            result.expect("Op failed");
+        }
+
+        let read = |path: &str| fs.read_to_string(Path::new(path)).unwrap();
+        assert_eq!(read("/home/geoff/.bashrc"), "shell");
+        assert_eq!(read("/home/geoff/.bashrc.hermit-backup"), "mine");
+        assert_eq!(read("/home/geoff/.vimrc"), "mine");
+        assert_eq!(read("/hermit/shells/work/.vimrc"), "mine");
+        assert!(fs.read_link(Path::new("/home/geoff/.vimrc")).is_ok());
+        assert_eq!(read("/hermit/shells/work/.npmrc"), "mine");
+    }
+}
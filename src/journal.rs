@@ -0,0 +1,477 @@
+use crate::{
+    common::*,
+    fs_backend::{FsBackend, RealFs},
+    state,
+};
+
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::status;
+
+/// The name of the file in the hermit root's state directory that
+/// holds the journal.
+pub const JOURNAL_FILE_NAME: &str = "journal";
+
+/// Something that carrying out an operation did to the filesystem,
+/// recorded so that it can be reverted later.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    CreatedDir {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+    },
+    CreatedLink {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        #[serde(with = "os_path")]
+        target: PathBuf,
+    },
+    /// A new file, along with a hash of its contents so that a file
+    /// that has been edited since isn't thrown away.
+    CreatedFile {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        hash: String,
+    },
+    RemovedLink {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        #[serde(with = "os_path")]
+        target: PathBuf,
+    },
+    /// A copy of `source` that was removed.
+    RemovedCopy {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        #[serde(with = "os_path")]
+        source: PathBuf,
+    },
+    /// A file that was moved from `path` to `destination`.
+    Moved {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        #[serde(with = "os_path")]
+        destination: PathBuf,
+    },
+    /// A file that was in the way, and was moved to `backup`.
+    MovedAside {
+        #[serde(with = "os_path")]
+        path: PathBuf,
+        #[serde(with = "os_path")]
+        backup: PathBuf,
+    },
+    /// A change that can't be undone, such as deleting a file or
+    /// creating a git repository.
+    Irreversible { description: String },
+}
+
+impl Change {
+    pub fn created_file(path: impl Into<PathBuf>) -> io::Result<Change> {
+        let path = path.into();
+        let hash = hex(&status::content_hash(&path)?);
+        Ok(Change::CreatedFile { path, hash })
+    }
+
+    pub fn irreversible(description: impl Into<String>) -> Change {
+        Change::Irreversible {
+            description: description.into(),
+        }
+    }
+
+    /// Puts things back the way they were before this change, unless
+    /// they have been changed again since.
+    pub fn revert(&self) -> anyhow::Result<()> {
+        match self {
+            Change::CreatedDir { path } => match fs::remove_dir(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    anyhow::bail!("could not remove {}: {}", message::path(path), err)
+                }
+                _ => (),
+            },
+            Change::CreatedLink { path, target } => match fs::read_link(path) {
+                Ok(link) if link == *target => fs::remove_file(path)?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                _ => changed_since(path)?,
+            },
+            Change::CreatedFile { path, hash } => match status::content_hash(path) {
+                Ok(current) if hex(&current) == *hash => fs::remove_file(path)?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                _ => changed_since(path)?,
+            },
+            Change::RemovedLink { path, target } => match fs::read_link(path) {
+                Ok(link) if link == *target => (),
+                _ => {
+                    make_room_for(path)?;
+                    unix::fs::symlink(target, path)?
+                }
+            },
+            Change::RemovedCopy { path, source } => {
+                make_room_for(path)?;
+                RealFs.copy(source, path)?;
+                RealFs.copy_owner(source, path)?;
+            }
+            Change::Moved { path, destination } => {
+                make_room_for(path)?;
+                fs::rename(destination, path)?
+            }
+            Change::MovedAside { path, backup } => {
+                make_room_for(path)?;
+                fs::rename(backup, path)?
+            }
+            Change::Irreversible { description } => {
+                anyhow::bail!("cannot undo: {}", description)
+            }
+        }
+        Ok(())
+    }
+}
+
+fn changed_since(path: &Path) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "{} has changed since, leaving it alone",
+        message::path(path)
+    )
+}
+
+fn make_room_for(path: &Path) -> anyhow::Result<()> {
+    if fs::symlink_metadata(path).is_ok() {
+        anyhow::bail!("{} is in the way, leaving it alone", message::path(path))
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+/// Paths are written as strings when they are valid UTF-8, which
+/// JSON requires, and as arrays of bytes when they aren't.
+pub(crate) mod os_path {
+    use std::{
+        ffi::OsString,
+        os::unix::ffi::{OsStrExt, OsStringExt},
+        path::{Path, PathBuf},
+    };
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum Repr<'a> {
+        Utf8(&'a str),
+        Bytes(&'a [u8]),
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OwnedRepr {
+        Utf8(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(path) => Repr::Utf8(path),
+            None => Repr::Bytes(path.as_os_str().as_bytes()),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(match OwnedRepr::deserialize(deserializer)? {
+            OwnedRepr::Utf8(path) => PathBuf::from(path),
+            OwnedRepr::Bytes(bytes) => PathBuf::from(OsString::from_vec(bytes)),
+        })
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The changes made by one hermit command.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Batch {
+    pub id: u64,
+    /// When the command ran, in seconds since the Unix epoch.
+    pub time: u64,
+    pub command: String,
+    /// The shell that was current before the command changed it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell_before: Option<String>,
+    pub changes: Vec<Change>,
+}
+
+impl Batch {
+    /// Reverts each of the changes, newest first.
+    pub fn revert(&self) -> Vec<anyhow::Result<()>> {
+        self.changes.iter().rev().map(Change::revert).collect()
+    }
+}
+
+/// The history of changes hermit has made, one batch per command,
+/// kept as lines of JSON in the hermit root's state directory.
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn in_root(root: impl AsRef<Path>) -> Journal {
+        Journal {
+            path: state::dir(root).join(JOURNAL_FILE_NAME),
+        }
+    }
+
+    /// All of the recorded batches, oldest first.
+    pub fn batches(&self) -> Result<Vec<Batch>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(Error::InvalidJournal(err.to_string())),
+        };
+        contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str(line).map_err(|err| Error::InvalidJournal(err.to_string()))
+            })
+            .collect()
+    }
+
+    /// Adds a batch for `command` to the end of the journal.
+    pub fn record(
+        &self,
+        command: &str,
+        shell_before: Option<String>,
+        changes: Vec<Change>,
+    ) -> Result<Batch> {
+        let id = self.batches()?.last().map_or(1, |batch| batch.id + 1);
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let batch = Batch {
+            id,
+            time,
+            command: command.to_owned(),
+            shell_before,
+            changes,
+        };
+
+        let line =
+            serde_json::to_string(&batch).map_err(|err| Error::InvalidJournal(err.to_string()))?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|err| Error::InvalidJournal(err.to_string()))?;
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", line))
+            .map_err(|err| Error::InvalidJournal(err.to_string()))?;
+        Ok(batch)
+    }
+
+    /// The links that hermit has created and that are still there,
+    /// pointing where hermit left them, by path.
+    pub fn created_links(&self) -> Result<BTreeMap<PathBuf, PathBuf>> {
+        let mut links = BTreeMap::new();
+        for change in self.batches()?.into_iter().flat_map(|batch| batch.changes) {
+            match change {
+                Change::CreatedLink { path, target } => {
+                    links.insert(path, target);
+                }
+                Change::RemovedLink { path, .. } => {
+                    links.remove(&path);
+                }
+                _ => (),
+            }
+        }
+        links.retain(|path, target| fs::read_link(path).is_ok_and(|link| link == *target));
+        Ok(links)
+    }
+
+    /// Removes the most recent batch from the journal and returns it.
+    pub fn pop(&self) -> Result<Option<Batch>> {
+        let mut batches = self.batches()?;
+        let last = batches.pop();
+        let contents = batches
+            .iter()
+            .map(|batch| serde_json::to_string(batch).map(|line| line + "\n"))
+            .collect::<StdResult<String, _>>()
+            .map_err(|err| Error::InvalidJournal(err.to_string()))?;
+        state::write_atomic(&RealFs, &self.path, contents.as_bytes())
+            .map_err(|err| Error::InvalidJournal(err.to_string()))?;
+        Ok(last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn an_empty_journal_has_no_batches() {
+        let test_root_dir = set_up();
+        let journal = Journal::in_root(test_root_dir.path());
+
+        assert_eq!(journal.batches().unwrap(), vec![]);
+        assert_eq!(journal.pop().unwrap(), None);
+    }
+
+    #[test]
+    fn batches_are_numbered_and_popped_newest_first() {
+        let test_root_dir = set_up();
+        let journal = Journal::in_root(test_root_dir.path());
+        let link = Change::CreatedLink {
+            path: PathBuf::from("/home/.bashrc"),
+            target: PathBuf::from("/shells/default/.bashrc"),
+        };
+
+        journal.record("use", None, vec![]).unwrap();
+        journal
+            .record("use", Some("default".to_owned()), vec![link.clone()])
+            .unwrap();
+
+        let batches = journal.batches().unwrap();
+        assert_eq!(batches.iter().map(|b| b.id).collect::<Vec<_>>(), [1, 2]);
+
+        let last = journal.pop().unwrap().unwrap();
+        assert_eq!(last.id, 2);
+        assert_eq!(last.shell_before.as_deref(), Some("default"));
+        assert_eq!(last.changes, vec![link]);
+        assert_eq!(journal.batches().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn paths_that_are_not_utf8_survive_a_round_trip() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let test_root_dir = set_up();
+        let journal = Journal::in_root(test_root_dir.path());
+        let change = Change::CreatedLink {
+            path: PathBuf::from(OsStr::from_bytes(b"/home/caf\xe9")),
+            target: PathBuf::from("/shells/default/café"),
+        };
+
+        journal.record("add", None, vec![change.clone()]).unwrap();
+
+        assert_eq!(journal.pop().unwrap().unwrap().changes, vec![change]);
+    }
+
+    #[test]
+    fn knows_which_of_its_links_are_still_there() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let journal = Journal::in_root(test_root);
+        let link = |name: &str| Change::CreatedLink {
+            path: test_root.join(name),
+            target: PathBuf::from("/shells/default").join(name),
+        };
+        unix::fs::symlink("/shells/default/.bashrc", test_root.join(".bashrc")).unwrap();
+        unix::fs::symlink("/elsewhere/.zshrc", test_root.join(".zshrc")).unwrap();
+        unix::fs::symlink("/shells/default/.vimrc", test_root.join(".vimrc")).unwrap();
+
+        journal
+            .record(
+                "use",
+                None,
+                vec![link(".bashrc"), link(".zshrc"), link(".vimrc")],
+            )
+            .unwrap();
+        journal
+            .record(
+                "use",
+                None,
+                vec![Change::RemovedLink {
+                    path: test_root.join(".vimrc"),
+                    target: PathBuf::from("/shells/default/.vimrc"),
+                }],
+            )
+            .unwrap();
+
+        assert_eq!(
+            journal.created_links().unwrap(),
+            [(
+                test_root.join(".bashrc"),
+                PathBuf::from("/shells/default/.bashrc")
+            )]
+            .into()
+        );
+    }
+
+    #[test]
+    fn reverting_puts_backed_up_files_back() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let path = test_root.join(".bashrc");
+        let backup = test_root.join(".bashrc.hermit-backup");
+        let target = test_root.join("shell_bashrc");
+        fs::write(&backup, "mine").unwrap();
+        unix::fs::symlink(&target, &path).unwrap();
+
+        let batch = Batch {
+            id: 1,
+            time: 0,
+            command: "use".to_owned(),
+            shell_before: None,
+            changes: vec![
+                Change::MovedAside {
+                    path: path.clone(),
+                    backup: backup.clone(),
+                },
+                Change::CreatedLink {
+                    path: path.clone(),
+                    target,
+                },
+            ],
+        };
+
+        for result in batch.revert() {
+            result.expect("revert failed");
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "mine");
+        assert!(!backup.exists());
+    }
+
+    #[test]
+    fn reverting_recreates_removed_links() {
+        let test_root_dir = set_up();
+        let path = test_root_dir.path().join(".vimrc");
+        let target = PathBuf::from("/shells/default/.vimrc");
+
+        Change::RemovedLink {
+            path: path.clone(),
+            target: target.clone(),
+        }
+        .revert()
+        .unwrap();
+
+        assert_eq!(fs::read_link(&path).unwrap(), target);
+    }
+
+    #[test]
+    fn reverting_leaves_changed_files_alone() {
+        let test_root_dir = set_up();
+        let path = test_root_dir.path().join(".npmrc");
+        fs::write(&path, "original").unwrap();
+        let created = Change::created_file(&path).unwrap();
+        fs::write(&path, "edited").unwrap();
+
+        assert!(created.revert().is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "edited");
+    }
+
+    #[test]
+    fn irreversible_changes_cannot_be_reverted() {
+        assert!(Change::irreversible("initialized a repository")
+            .revert()
+            .is_err());
+    }
+}
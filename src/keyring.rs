@@ -0,0 +1,154 @@
+use crate::common::*;
+
+use std::{cell::RefCell, collections::BTreeMap, process::Stdio};
+
+/// The service that hermit's secrets are filed under in the keychain.
+pub const SERVICE: &str = "hermit";
+
+/// A place to keep secrets that is safer than a file, because the
+/// operating system keeps it encrypted while the user is logged out.
+/// `SystemKeyring` is the one the operating system provides, and
+/// `MemoryKeyring` keeps secrets in memory for tests.
+pub trait Keyring {
+    /// The secret stored for `account`, if there is one.
+    fn get(&self, account: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Stores `secret` for `account`, replacing any secret it had.
+    fn set(&self, account: &str, secret: &[u8]) -> io::Result<()>;
+
+    /// Forgets the secret stored for `account`, returning whether there
+    /// was one.
+    fn delete(&self, account: &str) -> io::Result<bool>;
+}
+
+/// The macOS Keychain, or a Secret Service like GNOME Keyring or
+/// KWallet elsewhere, driven through the `security` and `secret-tool`
+/// commands. Without them nothing is ever found, and nothing can be
+/// stored. The Windows Credential Manager isn't supported. Secrets are
+/// always handed over on standard input, never as arguments, where
+/// anyone could see them in `ps`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemKeyring;
+
+impl SystemKeyring {
+    fn command(action: &str, account: &str) -> process::Command {
+        let mut command;
+        if cfg!(target_os = "macos") {
+            command = process::Command::new("security");
+            let action = match action {
+                "lookup" => "find-generic-password",
+                "store" => "add-generic-password",
+                _ => "delete-generic-password",
+            };
+            command.args([action, "-s", SERVICE, "-a", account]);
+        } else {
+            command = process::Command::new("secret-tool");
+            command.arg(action);
+            if action == "store" {
+                command.arg("--label=hermit secret key");
+            }
+            command.args(["service", SERVICE, "account", account]);
+        }
+        command
+    }
+}
+
+impl Keyring for SystemKeyring {
+    fn get(&self, account: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut command = SystemKeyring::command("lookup", account);
+        if cfg!(target_os = "macos") {
+            command.arg("-w");
+        }
+        let output = match command.stderr(Stdio::null()).output() {
+            Ok(output) => output,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if !output.status.success() || output.stdout.trim_ascii().is_empty() {
+            return Ok(None);
+        }
+        base64::decode(output.stdout.trim_ascii())
+            .map(Some)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "the stored secret is corrupt"))
+    }
+
+    fn set(&self, account: &str, secret: &[u8]) -> io::Result<()> {
+        if cfg!(windows) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "hermit can't use the Windows Credential Manager",
+            ));
+        }
+        let encoded = base64::encode(secret);
+        let mut command = SystemKeyring::command("store", account);
+        let mut input = encoded.clone();
+        if cfg!(target_os = "macos") {
+            // Given as the last argument without a value, `-w` has
+            // `security` read the password from its input, twice over
+            // as it would ask for it at a terminal.
+            command.args(["-U", "-w"]);
+            input = format!("{}\n{}\n", encoded, encoded);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => {
+                    io::Error::new(err.kind(), "there is no keychain to store it in")
+                }
+                _ => err,
+            })?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "the keychain refused it ({})",
+                status
+            )));
+        }
+        Ok(())
+    }
+
+    fn delete(&self, account: &str) -> io::Result<bool> {
+        if self.get(account)?.is_none() {
+            return Ok(false);
+        }
+        let status = SystemKeyring::command("clear", account)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        Ok(status.success())
+    }
+}
+
+/// A keyring that only exists in memory.
+#[derive(Debug, Default)]
+pub struct MemoryKeyring {
+    secrets: RefCell<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryKeyring {
+    pub fn new() -> MemoryKeyring {
+        MemoryKeyring::default()
+    }
+}
+
+impl Keyring for MemoryKeyring {
+    fn get(&self, account: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.secrets.borrow().get(account).cloned())
+    }
+
+    fn set(&self, account: &str, secret: &[u8]) -> io::Result<()> {
+        self.secrets
+            .borrow_mut()
+            .insert(account.to_owned(), secret.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, account: &str) -> io::Result<bool> {
+        Ok(self.secrets.borrow_mut().remove(account).is_some())
+    }
+}
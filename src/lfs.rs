@@ -0,0 +1,106 @@
+//! Shells that keep large binary files, like fonts and wallpapers, in
+//! Git LFS. Until git-lfs has fetched them, such files are only small
+//! pointers to where their contents are stored, and linking those into
+//! the target root would leave the user with a broken font.
+
+use crate::{common::*, git};
+
+use std::io::Read;
+
+/// How every LFS pointer file starts.
+const POINTER_PREFIX: &[u8] = b"version https://git-lfs.github.com/spec/v1";
+
+/// LFS pointer files are never bigger than this.
+const MAX_POINTER_SIZE: u64 = 1024;
+
+/// Whether the file at `path` is an LFS pointer, rather than the
+/// contents it points to.
+pub fn is_pointer(path: impl AsRef<Path>) -> bool {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    let mut start = Vec::new();
+    file.take(MAX_POINTER_SIZE + 1)
+        .read_to_end(&mut start)
+        .is_ok()
+        && start.len() as u64 <= MAX_POINTER_SIZE
+        && start.starts_with(POINTER_PREFIX)
+}
+
+/// Those of `files`, relative to `shell_root`, that are still LFS
+/// pointers in the shell because their contents haven't been fetched.
+pub fn missing_objects(shell_root: &Path, files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    Ok(git::lfs_tracked(shell_root, files)?
+        .into_iter()
+        .filter(|file| is_pointer(shell_root.join(file)))
+        .collect())
+}
+
+/// Configures the LFS filters in the repository at `repo_root`, and
+/// fetches the contents of the files it keeps in LFS, if it keeps any.
+/// libgit2 doesn't know about LFS, so a freshly cloned shell only has
+/// the pointers until this has run.
+pub fn set_up(repo_root: &Path) -> Result<bool> {
+    if !git::uses_lfs(repo_root)? {
+        return Ok(false);
+    }
+    let installed = process::Command::new("git")
+        .args(["lfs", "version"])
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !installed {
+        return Err(Error::LfsNotInstalled(repo_root.to_owned()));
+    }
+    git::run(repo_root, &["lfs", "install", "--local"])?;
+    git::run_remote(repo_root, &["lfs", "pull"])?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up as set_up_dir;
+
+    const POINTER: &str = "version https://git-lfs.github.com/spec/v1\n\
+                           oid sha256:4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393\n\
+                           size 12345\n";
+
+    #[test]
+    fn only_unfetched_lfs_files_are_missing() {
+        let test_root_dir = set_up_dir();
+        let root = test_root_dir.path();
+        git::test_support::init_repo(root);
+        fs::write(
+            root.join(".gitattributes"),
+            "*.ttf filter=lfs diff=lfs merge=lfs -text\n",
+        )
+        .unwrap();
+        fs::write(root.join("fetched.ttf"), "font").unwrap();
+        fs::write(root.join("missing.ttf"), POINTER).unwrap();
+        fs::write(root.join("pointer.txt"), POINTER).unwrap();
+        let files = ["fetched.ttf", "missing.ttf", "pointer.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+
+        assert!(is_pointer(root.join("pointer.txt")));
+        assert!(!is_pointer(root.join("fetched.ttf")));
+        assert_eq!(
+            missing_objects(root, &files).unwrap(),
+            vec![PathBuf::from("missing.ttf")]
+        );
+    }
+
+    #[test]
+    fn repositories_without_lfs_files_are_left_alone() {
+        let test_root_dir = set_up_dir();
+        let root = test_root_dir.path();
+        git::test_support::init_repo(root);
+
+        assert_eq!(set_up(root), Ok(false));
+    }
+}
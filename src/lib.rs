@@ -0,0 +1,102 @@
+//! Hermit is a home directory configuration management assistant.
+//!
+//! Your dotfiles live in *shells*, which are git repositories kept
+//! under the hermit root (usually `~/.config/hermit/shells`). Exactly
+//! one shell is inhabited at a time, and its files are symlinked into
+//! your home directory.
+//!
+//! This crate is the library behind the `hermit` command line tool,
+//! and can be used to drive hermit from other programs:
+//!
+//! - A [`Config`] knows where the hermit root is, which shell is
+//!   current, and which files each shell tracks. [`FsConfig`] is the
+//!   implementation backed by the real filesystem.
+//! - [`Hermit`] implements the high level commands on top of a
+//!   `Config`.
+//! - Commands never touch the home directory directly. Instead they
+//!   queue up changes in a [`FileOperations`], which are only carried
+//!   out when it is committed. This makes it possible to inspect what
+//!   a command would do before doing it.
+//! - [`Status`] describes how the files of a shell are currently
+//!   linked.
+//! - `FsConfig` and `FileOperations` read and change the filesystem
+//!   through an [`FsBackend`](fs_backend::FsBackend). Giving both the
+//!   same [`MemoryFs`](fs_backend::MemoryFs) runs hermit entirely in
+//!   memory, which is handy for testing plugins.
+//!
+//! ```no_run
+//! use hermit::{FileOperations, FsConfig, Hermit};
+//!
+//! let config = FsConfig::new("/home/me/.config/hermit")?;
+//! let mut hermit = Hermit::new(config);
+//! let mut file_operations = FileOperations::rooted_at("/home/me");
+//!
+//! hermit.inhabit(&mut file_operations, "work")?;
+//! for result in file_operations.commit() {
+//!     result?;
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+mod common;
+
+pub mod ansible;
+pub mod audit;
+pub mod bake;
+pub mod bootstrap;
+pub mod branches;
+pub mod checksums;
+pub mod commands;
+pub mod config;
+pub mod diff;
+pub mod doctor;
+pub mod env;
+pub mod exit_code;
+pub mod file_operations;
+pub mod folder_sync;
+pub mod fs_backend;
+pub mod git;
+pub mod hermit;
+pub mod hooks;
+pub mod host;
+pub mod ignore_rules;
+pub mod index;
+#[cfg(feature = "interactive")]
+pub mod interactive;
+pub mod journal;
+pub mod keyring;
+pub mod lfs;
+pub mod manifest;
+pub mod message;
+pub mod observer;
+pub mod packages;
+pub mod parallel;
+pub mod paths;
+pub mod plan;
+pub mod prompt;
+pub mod provision;
+pub mod rpc;
+pub mod secret;
+pub mod settings;
+pub mod shell;
+pub mod shell_info;
+pub mod shell_init;
+pub mod state;
+pub mod stats;
+pub mod status;
+pub mod suggestions;
+pub mod template;
+#[cfg(feature = "upgrade")]
+pub mod upgrade;
+
+#[cfg(test)]
+mod test_helpers;
+
+pub use crate::{
+    config::{Config, FsConfig},
+    file_operations::FileOperations,
+    hermit::{Error, Hermit, Result},
+    manifest::{Manifest, ShellFile},
+    shell::Shell,
+    status::{FileState, FileStatus, Status},
+};
@@ -1,67 +1,390 @@
-use crate::common::*;
+use std::{
+    cell::Cell,
+    fs,
+    io::{self, IsTerminal, Read, Write},
+    os::unix::{ffi::OsStringExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-pub mod common;
-pub mod config;
-pub mod env;
-pub mod file_operations;
-pub mod hermit;
-pub mod message;
-pub mod shell;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
-#[cfg(test)]
-mod test_helpers;
+use hermit::{
+    ansible,
+    audit::{self, AuditTrail},
+    bake::{Format, Recipe},
+    branches::MachineBranches,
+    checksums::Verdict,
+    commands::{Invocation, ReadOnly},
+    doctor::{self, human_size},
+    env::{self, ExportFormat},
+    exit_code,
+    file_operations::{CommitReport, Outcome, PermissionPolicy},
+    folder_sync::{self, SyncReport},
+    git::{self, ConflictChoice},
+    hermit::Resolution,
+    hooks::Event,
+    host,
+    journal::Change,
+    manifest::{Strategy, TagFilter},
+    message,
+    observer::{self, Observer},
+    packages::{self, Manager, Package, PackageList},
+    paths,
+    plan::{Plan, SavedPlan},
+    prompt::PromptInfo,
+    provision,
+    rpc::Server,
+    secret,
+    shell_info::ShellInfo,
+    shell_init::Dialect,
+    status::{Delta, Difference, Status},
+    template::{Placeholders, Template},
+    Config, Error, FileOperations, FsConfig, Hermit, Result,
+};
+
+#[cfg(feature = "interactive")]
+use hermit::interactive::{self, Done, Resolver};
 
 const SHELL_NAME_ARG: &str = "SHELL_NAME";
+const MANAGER_ARG: &str = "MANAGER";
+const PACKAGES_ARG: &str = "PACKAGES";
+const DIALECT_ARG: &str = "DIALECT";
+const FILE_ARG: &str = "FILE";
+const COMMAND_ARG: &str = "COMMAND";
+const URL_ARG: &str = "URL";
+const REMOTE_ARG: &str = "REMOTE";
+const BRANCH_ARG: &str = "BRANCH";
+const SNAPSHOT_ARG: &str = "SNAPSHOT";
+const SOURCE_ARG: &str = "SOURCE";
+const INTO_ARG: &str = "INTO";
+
+/// The commands that can run before `hermit init` has set up the
+/// hermit root.
+const COMMANDS_WITHOUT_ROOT: &[&str] = &[
+    "clone",
+    "help",
+    "history",
+    "init",
+    "prompt-info",
+    "provision",
+    "shell-init",
+    "undo",
+    "upgrade",
+    "version",
+];
 
 fn main() {
-    match run() {
-        Ok(()) => (),
-        Err(err) => {
-            eprintln!("{}: {}", env::get_program_name(), err);
-            process::exit(1)
-        }
-    }
+    let code = match run() {
+        Ok(()) => exit_code::SUCCESS,
+        Err(err) => match err.downcast::<clap::Error>() {
+            Ok(err) if err.use_stderr() => {
+                eprintln!("{}", err.message);
+                exit_code::USAGE
+            }
+            // Help and version information.
+            Ok(err) => err.exit(),
+            Err(err) => {
+                message::report(&err);
+                if err
+                    .chain()
+                    .any(|cause| git::is_network_failure(&cause.to_string()))
+                {
+                    message::note(
+                        "the network can't be reached; with --offline, the commands that \
+                         need it stop straight away, and the others work as usual",
+                    );
+                }
+                exit_code::of(&err)
+            }
+        },
+    };
+    process::exit(code)
 }
 
 #[rustfmt::skip]
 fn run() -> anyhow::Result<()>{
     let app = make_app_config();
-    let app_matches = app.get_matches();
+    let app_matches = app.get_matches_safe()?;
 
     let hermit_root = env::get_hermit_dir().expect("Could not determine hermit root location.");
-    let fs_config = FsConfig::new(hermit_root)?;
+    let shell_override = app_matches
+        .subcommand()
+        .1
+        .and_then(|matches| matches.value_of("shell"))
+        .map(ToOwned::to_owned)
+        .or_else(env::shell_override);
+    let fs_config = FsConfig::new(hermit_root)?.with_shell_override(shell_override.clone());
     let mut hermit = Hermit::new(fs_config);
+    if let Some(name) = &shell_override {
+        hermit.shell(name)?;
+    }
 
     let home_dir = env::home_dir().expect("Could not determine home directory.");
     let mut file_operations = FileOperations::rooted_at(home_dir);
+    file_operations.add_observer(Rc::new(Warnings));
+    if io::stderr().is_terminal() {
+        let progress = Rc::new(Progress::default());
+        hermit = hermit.with_observer(progress.clone());
+        file_operations.add_observer(progress);
+    }
+    let flag = |name| {
+        app_matches.is_present(name)
+            || app_matches.subcommand().1.is_some_and(|matches| matches.is_present(name))
+    };
+    file_operations.set_fail_fast(
+        flag("fail-fast") || (hermit.settings().operations.fail_fast && !flag("keep-going")),
+    );
+    let permissions = app_matches.value_of("permissions").or_else(|| {
+        app_matches
+            .subcommand()
+            .1
+            .and_then(|matches| matches.value_of("permissions"))
+    });
+    file_operations.set_permission_policy(
+        permissions
+            .and_then(PermissionPolicy::from_name)
+            .unwrap_or(hermit.settings().copy.permissions),
+    );
+    file_operations.set_preserve_xattrs(hermit.settings().copy.xattrs);
+    if hermit.settings().copy.xattrs && cfg!(not(feature = "xattrs")) {
+        message::note(
+            "this hermit was built without the xattrs feature, so copies won't keep \
+             their extended attributes",
+        );
+    }
+    let author = app_matches.value_of("author").or_else(|| {
+        app_matches
+            .subcommand()
+            .1
+            .and_then(|matches| matches.value_of("author"))
+    });
+    if let Some(author) = author.and_then(git::Identity::parse) {
+        // Git reads these too, so commits that git makes for hermit,
+        // like merges during a sync, are made by the same author.
+        for var in &[git::AUTHOR_NAME_VAR, "GIT_COMMITTER_NAME"] {
+            std::env::set_var(var, &author.name);
+        }
+        for var in &[git::AUTHOR_EMAIL_VAR, "GIT_COMMITTER_EMAIL"] {
+            std::env::set_var(var, &author.email);
+        }
+    }
+    let shell_before = hermit.current_shell().ok().map(|shell| shell.name);
+    let command = app_matches.subcommand_name().unwrap_or_default();
+    let audit_trail = Rc::new(AuditTrail::new(hermit.audit_log(), command, hermit.host()));
+    file_operations.add_observer(audit_trail.clone());
+    hermit = hermit.with_observer(audit_trail);
+    // These print JSON for other programs to read, which what the
+    // shell's scripts print would get mixed into.
+    if !["provision", "serve"].contains(&command) {
+        hermit = hermit.with_observer(Rc::new(Scripts {
+            verbose: flag("verbose"),
+        }));
+    }
+    if !COMMANDS_WITHOUT_ROOT.contains(&command) {
+        hermit.check_initialized()?;
+    }
+    let no_matches = ArgMatches::default();
+    let matches = app_matches.subcommand().1.unwrap_or(&no_matches);
+    let given = |name: &str| matches.is_present(name);
+    let invocation = Invocation {
+        command,
+        subcommand: matches.subcommand_name(),
+        flag: &given,
+    };
+    if flag("offline") && invocation.needs_network(&hermit, file_operations.root()) {
+        return Err(Error::Offline(command.to_owned()).into());
+    }
+    let read_only = flag("read-only") || env::read_only() || hermit.settings().operations.read_only;
+    if read_only && invocation.read_only() == ReadOnly::Refused {
+        return Err(Error::ReadOnly(command.to_owned()).into());
+    }
 
-    match app_matches.subcommand() {
-        ("add",     Some(matches)) => handle_add     (matches, &mut hermit, &mut file_operations),
-        ("clone",   Some(matches)) => handle_clone   (matches, &mut hermit, &mut file_operations),
-        ("doctor",  Some(matches)) => handle_doctor  (matches, &mut hermit, &mut file_operations),
-        ("git",     Some(matches)) => handle_git     (matches, &mut hermit, &mut file_operations),
-        ("init",    Some(matches)) => handle_init    (matches, &mut hermit, &mut file_operations),
-        ("nuke",    Some(matches)) => handle_nuke    (matches, &mut hermit, &mut file_operations),
-        ("shell",   Some(matches)) => handle_shell   (matches, &mut hermit, &mut file_operations),
-        ("status",  Some(matches)) => handle_status  (matches, &mut hermit, &mut file_operations),
-        ("inhabit", Some(matches)) => handle_inhabit (matches, &mut hermit, &mut file_operations),
-        _ => unreachable!(message::error_str("unknown subcommand passed"))
-    }?;
+    let result = match app_matches.subcommand() {
+        ("add",       Some(matches)) => handle_add       (matches, &mut hermit, &mut file_operations),
+        ("apply",     Some(matches)) => handle_apply     (matches, &mut hermit, &mut file_operations),
+        ("bake",      Some(matches)) => handle_bake      (matches, &mut hermit, &mut file_operations),
+        ("bootstrap", Some(matches)) => handle_bootstrap (matches, &mut hermit, &mut file_operations),
+        ("branch",    Some(matches)) => handle_branch    (matches, &mut hermit, &mut file_operations),
+        ("cd",        Some(matches)) => handle_cd        (matches, &mut hermit, &mut file_operations),
+        ("clone",     Some(matches)) => handle_clone     (matches, &mut hermit, &mut file_operations),
+        ("doctor",    Some(matches)) => handle_doctor    (matches, &mut hermit, &mut file_operations),
+        ("edit",      Some(matches)) => handle_edit      (matches, &mut hermit, &mut file_operations),
+        ("env",       Some(matches)) => handle_env       (matches, &mut hermit, &mut file_operations),
+        ("exec",      Some(matches)) => handle_exec      (matches, &mut hermit, &mut file_operations),
+        ("files",     Some(matches)) => handle_files     (matches, &mut hermit, &mut file_operations),
+        ("fork",      Some(matches)) => handle_fork      (matches, &mut hermit, &mut file_operations),
+        ("git",       Some(matches)) => handle_git       (matches, &mut hermit, &mut file_operations),
+        ("history",   Some(matches)) => handle_history   (matches, &mut hermit, &mut file_operations),
+        ("hooks",     Some(matches)) => handle_hooks     (matches, &mut hermit, &mut file_operations),
+        ("init",      Some(matches)) => handle_init      (matches, &mut hermit, &mut file_operations),
+        ("list",      Some(matches)) => handle_list      (matches, &mut hermit, &mut file_operations),
+        ("merge",     Some(matches)) => handle_merge     (matches, &mut hermit, &mut file_operations),
+        ("nuke",      Some(matches)) => handle_nuke      (matches, &mut hermit, &mut file_operations),
+        ("packages",  Some(matches)) => handle_packages  (matches, &mut hermit, &mut file_operations),
+        ("plan",      Some(matches)) => handle_plan      (matches, &mut hermit, &mut file_operations),
+        ("prompt-info",Some(matches)) => handle_prompt_info(matches, &mut hermit, &mut file_operations),
+        ("provision", Some(matches)) => handle_provision (matches, &mut hermit, &mut file_operations),
+        ("push",      Some(matches)) => handle_push      (matches, &mut hermit, &mut file_operations),
+        ("refresh",   Some(matches)) => handle_refresh   (matches, &mut hermit, &mut file_operations),
+        ("remote",    Some(matches)) => handle_remote    (matches, &mut hermit, &mut file_operations),
+        ("rollback",  Some(matches)) => handle_rollback  (matches, &mut hermit, &mut file_operations),
+        ("secret",    Some(matches)) => handle_secret    (matches, &mut hermit, &mut file_operations),
+        ("serve",     Some(matches)) => handle_serve     (matches, &mut hermit, &mut file_operations),
+        ("shell",     Some(matches)) => handle_shell     (matches, &mut hermit, &mut file_operations),
+        ("shell-init",Some(matches)) => handle_shell_init(matches, &mut hermit, &mut file_operations),
+        ("snapshot",  Some(matches)) => handle_snapshot  (matches, &mut hermit, &mut file_operations),
+        ("stash",     Some(matches)) => handle_stash     (matches, &mut hermit, &mut file_operations),
+        ("stats",     Some(matches)) => handle_stats     (matches, &mut hermit, &mut file_operations),
+        ("status",    Some(matches)) => handle_status    (matches, &mut hermit, &mut file_operations),
+        ("sync",      Some(matches)) => handle_sync      (matches, &mut hermit, &mut file_operations),
+        ("undo",      Some(matches)) => handle_undo      (matches, &mut hermit, &mut file_operations),
+        ("unlink",    Some(matches)) => handle_unlink    (matches, &mut hermit, &mut file_operations),
+        ("upgrade",   Some(matches)) => handle_upgrade   (matches, &mut hermit, &mut file_operations),
+        ("verify",    Some(matches)) => handle_verify    (matches, &mut hermit, &mut file_operations),
+        ("version",   Some(matches)) => handle_version   (matches, &mut hermit, &mut file_operations),
+        ("inhabit",   Some(matches)) => handle_inhabit   (matches, &mut hermit, &mut file_operations),
+        (name,        Some(matches)) => handle_external  (name, matches, &mut hermit, &mut file_operations),
+        _ => unreachable!("{}", message::error_str("unknown subcommand passed"))
+    };
 
-    report_errors(file_operations.commit());
+    if result.is_ok() && !read_only {
+        report_errors(file_operations.flush());
+    }
+    record_changes(&hermit, command, shell_before, file_operations.take_changes());
+    result?;
 
-    Ok(())
+    match file_operations.failures() {
+        0 => Ok(()),
+        failures => Err(Error::ChangesFailed(failures).into()),
+    }
+}
+
+/// Counts the links being made on a line of its own while there are a
+/// lot of them to make, and clears the line once they are made.
+#[derive(Default)]
+struct Progress {
+    linked: Cell<usize>,
+    shown: Cell<bool>,
+}
+
+impl Progress {
+    /// How many links to make before showing how many have been made.
+    const EVERY: usize = 100;
+}
+
+impl Observer for Progress {
+    fn notify(&self, event: &observer::Event) {
+        match event {
+            observer::Event::FileLinked { .. } => {
+                let linked = self.linked.get() + 1;
+                self.linked.set(linked);
+                if linked.is_multiple_of(Progress::EVERY) {
+                    eprint!("\rLinked {} files", linked);
+                    self.shown.set(true);
+                }
+            }
+            observer::Event::OperationsDone { .. } => {
+                if self.shown.replace(false) {
+                    eprint!("\r\x1b[K");
+                }
+                self.linked.set(0);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Points out what the changes being made couldn't carry over, as
+/// they are made.
+struct Warnings;
+
+impl Observer for Warnings {
+    fn notify(&self, event: &observer::Event) {
+        if let observer::Event::AttributesDropped { path, names } = event {
+            message::note(format!(
+                "the filesystem of {} can't store its extended attributes {}",
+                message::path(path),
+                names.join(", ")
+            ));
+        }
+    }
+}
+
+/// Shows the hooks and bootstrap steps of a shell as they run. What a
+/// hook printed is shown with `--verbose`, and always for a hook that
+/// failed.
+struct Scripts {
+    verbose: bool,
 }
 
-fn report_errors(results: Vec<file_operations::Result>) {
-    for result in results {
-        match result {
-            Ok(()) => (),
-            Err(e) => println!("{}", message::error(e)),
+impl Observer for Scripts {
+    fn notify(&self, event: &observer::Event) {
+        match event {
+            observer::Event::HookRan {
+                event,
+                hook,
+                output,
+                failed,
+            } if self.verbose || *failed => {
+                println!("Ran the {} hook {}", event, message::path(hook));
+                for line in String::from_utf8_lossy(output).lines() {
+                    println!("    {}", line);
+                }
+            }
+            observer::Event::BootstrapStepStarted { name } => {
+                println!("Running bootstrap step {}", name);
+            }
+            _ => (),
         }
     }
 }
 
+/// Adds what a command changed to the journal, so that it can be
+/// undone later.
+fn record_changes<C: Config>(
+    hermit: &Hermit<C>,
+    command: &str,
+    shell_before: Option<String>,
+    changes: Vec<Change>,
+) {
+    let shell_after = hermit.current_shell().ok().map(|shell| shell.name);
+    let shell_changed = shell_before.is_some() && shell_before != shell_after;
+    if command == "undo" || (changes.is_empty() && !shell_changed) {
+        return;
+    }
+
+    let shell_before = shell_before.filter(|_| shell_changed);
+    if let Err(err) = hermit.journal().record(command, shell_before, changes) {
+        message::report(err);
+    }
+}
+
+/// Runs something that carries out queued changes as it goes, and
+/// reports the changes that failed along the way.
+fn reporting<R>(run: impl FnOnce(&mut CommitReport) -> Result<R>) -> Result<R> {
+    let mut report = CommitReport::default();
+    let result = run(&mut report);
+    report_errors(report);
+    result
+}
+
+fn report_errors(report: CommitReport) {
+    for err in report.errors() {
+        message::report(err);
+    }
+    let skipped = report.skipped();
+    if skipped > 0 {
+        message::note(format!(
+            "stopped at the first failure, skipping {} other {}",
+            skipped,
+            if skipped == 1 { "change" } else { "changes" }
+        ));
+    }
+}
+
 #[allow(clippy::let_and_return)]
 fn make_app_config<'a, 'b>() -> App<'a, 'b> {
     let app = App::new("hermit")
@@ -69,16 +392,114 @@ fn make_app_config<'a, 'b>() -> App<'a, 'b> {
         .author("A product of the Bike Barn <https://github.com/bike-barn/hermit>")
         .about("A home directory configuration management assistant.")
         .setting(AppSettings::SubcommandRequiredElseHelp)
-        .setting(AppSettings::VersionlessSubcommands);
+        .setting(AppSettings::AllowExternalSubcommands)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("fail-fast")
+                .long("fail-fast")
+                .global(true)
+                .help(
+                    "Stop at the first change that fails, instead of making \
+                    the rest of them anyway.",
+                ),
+        )
+        .arg(
+            Arg::with_name("author")
+                .long("author")
+                .value_name("AUTHOR")
+                .global(true)
+                .validator(|author| match git::Identity::parse(&author) {
+                    Some(_) => Ok(()),
+                    None => Err("the author must look like \"Name <email>\"".to_owned()),
+                })
+                .help(
+                    "Make the commits that hermit makes as AUTHOR, written as \
+                    \"Name <email>\", instead of as the user in your git \
+                    configuration.",
+                ),
+        )
+        .arg(
+            Arg::with_name("permissions")
+                .long("permissions")
+                .value_name("POLICY")
+                .possible_values(&["preserve", "umask"])
+                .global(true)
+                .help(
+                    "What copied files take their permissions from: the file \
+                    they are a copy of, along with its owner when running as \
+                    root, or the umask. Overrides the settings.",
+                ),
+        )
+        .arg(Arg::with_name("offline").long("offline").global(true).help(
+            "Work without the network: commands that need it, like \
+                    clone, sync and push, fail straight away, and doctor \
+                    doesn't ask the shells' remotes whether they can be \
+                    reached.",
+        ))
+        .arg(
+            Arg::with_name("read-only")
+                .long("read-only")
+                .global(true)
+                .help(
+                    "Don't change anything: commands that can show what they \
+                    would change do that instead, as with --dry-run, and the \
+                    others refuse to run. Setting HERMIT_READONLY does the \
+                    same.",
+                ),
+        )
+        .arg(
+            Arg::with_name("keep-going")
+                .long("keep-going")
+                .global(true)
+                .conflicts_with("fail-fast")
+                .help(
+                    "Make the rest of the changes after one fails, even if \
+                    the settings say to fail fast.",
+                ),
+        );
 
     let app = add_add_subcommand(app);
+    let app = add_apply_subcommand(app);
+    let app = add_bake_subcommand(app);
+    let app = add_bootstrap_subcommand(app);
+    let app = add_branch_subcommand(app);
+    let app = add_cd_subcommand(app);
     let app = add_clone_subcommand(app);
     let app = add_doctor_subcommand(app);
+    let app = add_edit_subcommand(app);
+    let app = add_env_subcommand(app);
+    let app = add_exec_subcommand(app);
+    let app = add_files_subcommand(app);
+    let app = add_fork_subcommand(app);
     let app = add_git_subcommand(app);
+    let app = add_history_subcommand(app);
+    let app = add_hooks_subcommand(app);
     let app = add_init_subcommand(app);
+    let app = add_list_subcommand(app);
+    let app = add_merge_subcommand(app);
     let app = add_nuke_subcommand(app);
+    let app = add_packages_subcommand(app);
+    let app = add_plan_subcommand(app);
+    let app = add_prompt_info_subcommand(app);
+    let app = add_provision_subcommand(app);
+    let app = add_push_subcommand(app);
+    let app = add_refresh_subcommand(app);
+    let app = add_remote_subcommand(app);
+    let app = add_rollback_subcommand(app);
+    let app = add_secret_subcommand(app);
+    let app = add_serve_subcommand(app);
     let app = add_shell_subcommand(app);
+    let app = add_shell_init_subcommand(app);
+    let app = add_snapshot_subcommand(app);
+    let app = add_stash_subcommand(app);
+    let app = add_stats_subcommand(app);
     let app = add_status_subcommand(app);
+    let app = add_sync_subcommand(app);
+    let app = add_undo_subcommand(app);
+    let app = add_unlink_subcommand(app);
+    let app = add_upgrade_subcommand(app);
+    let app = add_verify_subcommand(app);
+    let app = add_version_subcommand(app);
     let app = add_inhabit_subcommand(app);
 
     app
@@ -108,148 +529,2898 @@ macro_rules! subcommand {
 
 subcommand! {
   fn add_add_subcommand("add") {
-      about("Add files to your hermit shell")
+    about("Move files into the current shell, leaving links to them in \
+           their place. Directories add every file inside them, and glob \
+           patterns like '~/.config/nvim/**/*.lua' are expanded by hermit.")
+    arg(Arg::with_name(FILE_ARG)
+        .help("The files, directories or patterns to add.")
+        .required(true)
+        .multiple(true))
+    arg(Arg::with_name("copy")
+        .long("copy")
+        .help("Copy the files into the shell and leave them where they are, \
+               for files that must not be symlinks."))
+    arg(Arg::with_name("large")
+        .long("large")
+        .help("Add directories even if they have more files in them than \
+               the `large_directory` setting allows."))
+    arg(Arg::with_name("allow-large")
+        .long("allow-large")
+        .help("Add files even if they are bigger than the `large_file` \
+               setting allows, or aren't text."))
+    arg(verbose_hooks_arg())
   }
 }
 
 fn handle_add<C: Config>(
-    _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
-    _file_operations: &mut FileOperations,
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("add")
+    let cwd = std::env::current_dir().unwrap_or_else(|_| file_operations.root().to_owned());
+    let limit = hermit.settings().walk.large_directory;
+    let mut files = vec![];
+    for arg in matches.values_of_os(FILE_ARG).unwrap() {
+        let expanded = paths::expand(arg, &cwd, file_operations.root())?;
+        if expanded.len() > limit && !matches.is_present("large") {
+            return Err(Error::TooManyFiles {
+                path: PathBuf::from(arg),
+                files: expanded.len(),
+                limit,
+            });
+        }
+        for file in expanded {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+
+    if !matches.is_present("allow-large") {
+        let limit = hermit.settings().add.large_file;
+        let large = doctor::large_files(&files, limit);
+        for file in &large {
+            let path = file
+                .path
+                .strip_prefix(file_operations.root())
+                .unwrap_or(&file.path);
+            message::note(format!(
+                "Leaving out {} ({}), which would bloat the shell's history",
+                message::path(path),
+                file.reason(limit)
+            ));
+        }
+        if !large.is_empty() {
+            message::note(
+                "Keep large files in Git LFS, or add them with --copy; \
+                 pass --allow-large to add them anyway",
+            );
+            files.retain(|file| !large.iter().any(|large| large.path == *file));
+        }
+    }
+
+    let strategy = if matches.is_present("copy") {
+        Strategy::Copy
+    } else {
+        Strategy::Link
+    };
+    let added = hermit.add(file_operations, &files, strategy)?;
+    if is_dry_run(matches, hermit) {
+        print_plan(file_operations);
+        return Ok(());
+    }
+    for file in &added {
+        println!("Adding {}", message::path(&file.target));
+    }
+    if added.is_empty() {
+        return Ok(());
+    }
+    report_errors(file_operations.flush());
+    let shell_name = hermit.current_shell()?.name;
+    hermit.run_hooks(&shell_name, file_operations.root(), Event::PostAdd)?;
+    Ok(())
 }
 
 subcommand! {
-  fn add_clone_subcommand("clone") {
-    about("Create a local shell from an existing remote shell")
+  fn add_apply_subcommand("apply") {
+    about("Bring the links and copies of the current shell up to date \
+           without switching shells, after its files or its manifest have \
+           changed")
+    arg(force_arg())
+    arg(no_backup_arg())
+    arg(dry_run_arg())
+    arg(tag_arg("only")
+        .help("Only link the tagged files that have one of these tags. \
+               Files without tags are always linked."))
+    arg(tag_arg("skip")
+        .help("Don't link files that have any of these tags."))
+    arg(Arg::with_name("plan")
+        .long("plan")
+        .value_name("FILE")
+        .conflicts_with_all(&["force", "only", "skip"])
+        .help("Carry out a plan saved by `hermit plan --output` instead, \
+               exactly as it was made. Fails if anything it touches has \
+               changed since."))
+    arg(shell_flag_arg())
+    arg(target_arg()
+        .conflicts_with("plan"))
+    arg(Arg::with_name("system")
+        .long("system")
+        .conflicts_with_all(&["plan", "target"])
+        .help("Apply a system shell, which links files below its system \
+               root, like /etc, instead of the home directory. Needs root, \
+               and asks before changing anything."))
+    arg(Arg::with_name("yes")
+        .long("yes")
+        .requires("system")
+        .help("Don't ask before applying a system shell."))
   }
 }
 
-fn handle_clone<C: Config>(
-    _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
-    _file_operations: &mut FileOperations,
+fn handle_apply<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("clone")
+    if let Some(path) = matches.value_of_os("plan") {
+        return apply_saved_plan(matches, Path::new(path), hermit, file_operations);
+    }
+    set_conflict_policy(matches, hermit, file_operations);
+    set_target(matches, file_operations)?;
+    let system = matches.is_present("system");
+    if system {
+        use_system_root(hermit, file_operations, is_dry_run(matches, hermit))?;
+    }
+    let delta = hermit.apply(file_operations, &selected_tags(matches))?;
+    if is_dry_run(matches, hermit) {
+        print_plan(file_operations);
+        return Ok(());
+    }
+    if system && !delta.is_empty() && !matches.is_present("yes") {
+        let name = hermit.current_shell()?.name;
+        print_plan_of(file_operations);
+        let answer = ask(&format!(
+            "This changes system files on this machine. Type the name of the shell, {}, to go ahead:",
+            name
+        ))?;
+        if answer != name {
+            file_operations.clear();
+            println!("Nothing was changed");
+            return Ok(());
+        }
+    }
+    if delta.is_empty() {
+        println!("Everything is up to date");
+    }
+    print_delta(&delta, file_operations);
+    Ok(())
 }
 
 subcommand! {
-  fn add_doctor_subcommand("doctor") {
-    about("Make sure your hermit setup is sane")
+  fn add_bake_subcommand("bake") {
+    about("Bake a shell's files into a container image, so that CI jobs \
+           and dev containers get the same dotfiles. Writes a Dockerfile \
+           and the files to copy into the image's home directory. Secret \
+           files are left out.")
+    arg(optional_shell_name_arg("The shell to bake. Without one, bakes the \
+                                 current shell."))
+    arg(Arg::with_name("format")
+        .long("format")
+        .value_name("FORMAT")
+        .possible_values(Format::NAMES)
+        .default_value("docker")
+        .help("The kind of image to bake."))
+    arg(Arg::with_name("output")
+        .long("output")
+        .short("o")
+        .value_name("DIR")
+        .default_value("hermit-bake")
+        .help("The directory to bake into. It has to be empty, or an \
+               earlier bake, which is replaced."))
+    arg(Arg::with_name("home")
+        .long("home")
+        .value_name("DIR")
+        .default_value("/root")
+        .help("The home directory in the image."))
+    arg(Arg::with_name("chown")
+        .long("chown")
+        .value_name("USER[:GROUP]")
+        .help("Who owns the files in the image, instead of root."))
+    arg(tag_arg("only")
+        .help("Only bake the tagged files that have one of these tags. \
+               Files without tags are always baked."))
+    arg(tag_arg("skip")
+        .help("Don't bake files that have any of these tags."))
   }
 }
 
-fn handle_doctor<C: Config>(
-    _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
+fn handle_bake<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
     _file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("doctor")
+    let name = match matches.value_of(SHELL_NAME_ARG) {
+        Some(name) => hermit.existing_shell_name(name)?,
+        None => hermit.current_shell()?.name,
+    };
+    let recipe = Recipe {
+        format: matches
+            .value_of("format")
+            .and_then(Format::from_name)
+            .unwrap_or(Format::Docker),
+        home: PathBuf::from(matches.value_of("home").unwrap()),
+        owner: matches.value_of("chown").map(ToOwned::to_owned),
+    };
+    let out = Path::new(matches.value_of_os("output").unwrap());
+    let baked = hermit.bake(&name, &selected_tags(matches), &recipe, out)?;
+    for secret in &baked.secrets {
+        message::note(format!(
+            "Left out {}, since it holds secrets",
+            message::path(secret)
+        ));
+    }
+    println!(
+        "Baked {} file{} of {} into {}; build the image with `docker build {}`",
+        baked.files,
+        if baked.files == 1 { "" } else { "s" },
+        name,
+        message::path(out),
+        message::path(out)
+    );
+    Ok(())
+}
+
+fn apply_saved_plan<C: Config>(
+    matches: &ArgMatches<'_>,
+    path: &Path,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let json = fs::read_to_string(path)
+        .map_err(|err| Error::InvalidPlan(format!("{}: {}", message::path(path), err)))?;
+    let plan = SavedPlan::from_json(&json)?;
+    let current_shell = hermit.current_shell().ok().map(|shell| shell.name);
+    let switch_to = plan.switch_to.clone();
+    plan.queue(file_operations, current_shell.as_deref())?;
+    if is_dry_run(matches, hermit) {
+        print_plan(file_operations);
+        return Ok(());
+    }
+
+    println!(
+        "Carrying out the plan: {}",
+        Plan::of(file_operations).summary()
+    );
+    if let Some(name) = switch_to {
+        hermit.set_current_shell(&name)?;
+    }
+    Ok(())
+}
+
+/// Shows what the operations queued so far would do, and forgets them
+/// so that nothing is done.
+fn print_plan(file_operations: &mut FileOperations) {
+    print_plan_of(file_operations);
+    file_operations.clear();
+}
+
+/// Shows what the operations queued so far would do.
+fn print_plan_of(file_operations: &FileOperations) {
+    let color = io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    print!(
+        "{}",
+        Plan::of(file_operations).render(file_operations.root(), color)
+    );
+}
+
+/// Roots the changes at the system root of the shell being applied,
+/// once it is clear that it is a system shell and, unless nothing is
+/// going to be changed, that hermit is running as root.
+fn use_system_root<C: Config>(
+    hermit: &Hermit<C>,
+    file_operations: &mut FileOperations,
+    dry_run: bool,
+) -> Result<()> {
+    let name = hermit.current_shell()?.name;
+    let system = hermit
+        .shell_info(&name)?
+        .system
+        .ok_or(Error::NotASystemShell(name))?;
+    if !dry_run && !host::is_root() {
+        return Err(Error::NeedsRoot);
+    }
+    file_operations.set_root(system.root);
+    Ok(())
+}
+
+fn print_delta(delta: &Delta, file_operations: &FileOperations) {
+    for path in &delta.unlinked {
+        let path = path.strip_prefix(file_operations.root()).unwrap_or(path);
+        println!("Unlinking {}", message::path(path));
+    }
+    for file in &delta.linked {
+        println!("Linking {}", message::path(&file.target));
+    }
+    for file in &delta.copied {
+        println!("Copying {}", message::path(&file.target));
+    }
 }
 
 subcommand! {
-  fn add_git_subcommand("git") {
-    about("Run git operations on the current shell")
+  fn add_bootstrap_subcommand("bootstrap") {
+    about("Run the setup steps of a shell that have not completed on this \
+           machine yet. If no shell name is given, the current shell is used.")
+    arg(optional_shell_name_arg("The name of the shell to bootstrap."))
   }
 }
 
-fn handle_git<C: Config>(
-    _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
-    _file_operations: &mut FileOperations,
+fn handle_bootstrap<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("git")
+    let shell_name = match matches.value_of(SHELL_NAME_ARG) {
+        Some(name) => hermit.existing_shell_name(name)?,
+        None => hermit.current_shell()?.name,
+    };
+    if hermit.bootstrap(&shell_name)?.pending_steps().is_empty() {
+        println!("Shell {} is already bootstrapped", shell_name);
+    }
+    hermit.run_bootstrap(&shell_name, file_operations.root(), true)?;
+    Ok(())
 }
 
 subcommand! {
-  fn add_init_subcommand("init") {
-    about("Create a new hermit shell called SHELL_NAME. If no shell name \
-           is given, \"default\" is used.")
-    arg(shell_name_arg("The name of the shell to be created."))
+  fn add_branch_subcommand("branch") {
+    about("Manage the branch of the current shell that this machine uses. \
+           Without a subcommand, this machine's branch is shown.")
+    subcommand(SubCommand::with_name("create")
+               .about("Create a branch for this machine from the checked out \
+                       branch, and switch to it")
+               .arg(Arg::with_name(BRANCH_ARG)
+                    .help("The name of the new branch. Defaults to the hostname.")))
+    subcommand(SubCommand::with_name("switch")
+               .about("Make this machine use an existing branch")
+               .arg(Arg::with_name(BRANCH_ARG)
+                    .required(true)
+                    .help("The branch to use.")))
   }
 }
 
-fn handle_init<C: Config>(
+fn handle_branch<C: Config>(
     matches: &ArgMatches<'_>,
     hermit: &mut Hermit<C>,
     file_operations: &mut FileOperations,
 ) -> Result<()> {
-    let shell_name = matches.value_of(SHELL_NAME_ARG).unwrap();
-    hermit.init_shell(file_operations, shell_name)?;
+    let shell = hermit.current_shell()?;
+    let shell_root = shell.root_path();
+    let hostname = hermit.host().hostname.clone();
+    let (branch, create) = match matches.subcommand() {
+        ("create", Some(matches)) => (matches.value_of(BRANCH_ARG).unwrap_or(&hostname), true),
+        ("switch", Some(matches)) => (matches.value_of(BRANCH_ARG).unwrap(), false),
+        _ => {
+            match MachineBranches::load(&shell_root)?.branch_for(&hostname) {
+                Some(branch) => println!("{} uses branch {}", hostname, branch),
+                None => println!("{} has no branch of its own", hostname),
+            }
+            return Ok(());
+        }
+    };
+
+    relinking(hermit, file_operations, |shell_root| {
+        if create {
+            git::run(shell_root, &["checkout", "-b", branch])
+        } else {
+            git::run(shell_root, &["checkout", branch])
+        }
+    })?;
+
+    let mut branches = MachineBranches::load(&shell_root)?;
+    branches.set_branch(&hostname, branch);
+    branches.save(&shell_root)?;
+    let paths = [MachineBranches::path_in("")];
+    let summary = format!("Use branch {} on {}", branch, hostname);
+    hermit.commit(&shell.name, &summary, &paths)?;
     Ok(())
 }
 
 subcommand! {
-  fn add_nuke_subcommand("nuke") {
-    about("Permanently remove a hermit shell")
+  fn add_cd_subcommand("cd") {
+    about("Print the directory of a shell. With the functions from \
+           `hermit shell-init` loaded, change into it instead. If no shell \
+           name is given, the current shell is used.")
+    arg(optional_shell_name_arg("The name of the shell to change into."))
   }
 }
 
-fn handle_nuke<C: Config>(
-    _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
+fn handle_cd<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
     _file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("nuke")
+    let shell = match matches.value_of(SHELL_NAME_ARG) {
+        Some(name) => hermit.shell(name)?,
+        None => hermit.current_shell()?,
+    };
+    // Written as raw bytes, so that `cd "$(hermit path)"` works even
+    // when the path isn't valid UTF-8.
+    let mut path = shell.root_path().into_os_string().into_vec();
+    path.push(b'\n');
+    io::stdout()
+        .write_all(&path)
+        .expect("failed printing to stdout");
+    Ok(())
 }
 
 subcommand! {
-  fn add_shell_subcommand("shell") {
-    about("Display the shell you are currently inhabiting")
+  fn add_clone_subcommand("clone") {
+    about("Create a local shell from an existing remote shell. If no shell \
+           name is given, the shell is named after the repository.")
+    arg(Arg::with_name(URL_ARG)
+        .required(true)
+        .help("The git URL of the shell to clone."))
+    arg(optional_shell_name_arg("The name of the new shell."))
+    arg(Arg::with_name("branch")
+        .long("branch")
+        .short("b")
+        .value_name("BRANCH")
+        .help("Only fetch BRANCH, and check it out."))
+    arg(Arg::with_name("depth")
+        .long("depth")
+        .value_name("DEPTH")
+        .validator(|depth| depth.parse::<u32>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("Only fetch the last DEPTH commits of history."))
+    arg(Arg::with_name("shared")
+        .long("shared")
+        .help("Clone a repository that holds several shells, one in each \
+               directory. SHELL_NAME then picks the shell that --use and \
+               --link switch to."))
+    arg(Arg::with_name("verify-signatures")
+        .long("verify-signatures")
+        .help("Refuse to use the shell unless the commit it is on is signed \
+               by a key that git trusts, now and after every sync."))
+    arg(Arg::with_name("use")
+        .long("use")
+        .help("Switch to the new shell like `hermit use` does, linking its \
+               files and bootstrapping it."))
+    arg(verbose_hooks_arg().requires("use"))
+    arg(Arg::with_name("link")
+        .long("link")
+        .conflicts_with("use")
+        .help("Switch to the new shell and link its files, but skip its \
+               bootstrap steps."))
+    arg(force_arg())
+    arg(no_backup_arg())
   }
 }
 
-fn handle_shell<C: Config>(
-    _matches: &ArgMatches<'_>,
+fn handle_clone<C: Config>(
+    matches: &ArgMatches<'_>,
     hermit: &mut Hermit<C>,
-    _file_operations: &mut FileOperations,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    set_conflict_policy(matches, hermit, file_operations);
+    let url = matches.value_of(URL_ARG).unwrap();
+    let options = git::CloneOptions {
+        branch: matches.value_of("branch").map(ToOwned::to_owned),
+        depth: matches
+            .value_of("depth")
+            .map(|depth| depth.parse().unwrap()),
+    };
+    hermit.scaffold(file_operations);
+    let shell_name = if matches.is_present("shared") {
+        hermit.clone_shared_repo(file_operations, url, &options)?;
+        matches
+            .value_of(SHELL_NAME_ARG)
+            .unwrap_or("default")
+            .to_owned()
+    } else {
+        let shell_name = hermit.clone_shell(
+            file_operations,
+            url,
+            matches.value_of(SHELL_NAME_ARG),
+            &options,
+        )?;
+        let info = ShellInfo::new(None, Some(url));
+        hermit.record_shell_info(file_operations, &shell_name, &info)?;
+        shell_name
+    };
+
+    let verify = matches.is_present("verify-signatures");
+    if verify || matches.is_present("link") || matches.is_present("use") {
+        for result in file_operations.flush() {
+            result.map_err(|err| Error::Git(err.to_string()))?;
+        }
+    }
+    if verify {
+        let name = (!matches.is_present("shared")).then_some(shell_name.as_str());
+        hermit.require_signatures(name)?;
+    }
+
+    if matches.is_present("use") {
+        let tags = TagFilter::default();
+        reporting(|report| hermit.use_shell(file_operations, &shell_name, &tags, true, report))
+    } else if matches.is_present("link") {
+        hermit.inhabit(file_operations, &shell_name)
+    } else {
+        Ok(())
+    }
+}
+
+subcommand! {
+  fn add_doctor_subcommand("doctor") {
+    about("Make sure your hermit setup is sane. With --shell, check \
+           what is about to be committed to that shell instead, as \
+           the hook from `hermit hooks install` does.")
+    arg(shell_flag_arg())
+  }
+}
+
+fn handle_doctor<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    if matches.is_present("shell") {
+        let problems = hermit.commit_problems(&hermit.current_shell()?.name)?;
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        return if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::CommitProblems)
+        };
+    }
+
+    let large_directories = match hermit.current_shell() {
+        Ok(shell) => hermit.large_directories(&shell.name)?,
+        Err(_) => vec![],
+    };
+    if !large_directories.is_empty() {
+        println!(
+            "These directories in the shell have a lot of files in them, which slows hermit down:"
+        );
+        for dir in &large_directories {
+            println!("    {} ({} files)", message::path(&dir.path), dir.files);
+        }
+        println!("Unless they belong in the shell, remove them or list them in .hermitignore.");
+    }
+
+    let missing_identity = match hermit.current_shell() {
+        Ok(shell) => git::identity(shell.root_path()) == Err(Error::NoGitIdentity),
+        Err(_) => false,
+    };
+    if missing_identity {
+        println!("{}", Error::NoGitIdentity);
+    }
+
+    let signing_problem = match hermit.current_shell() {
+        Ok(shell) if hermit.signs_commits(&shell.name)? => git::signing_key(shell.root_path())
+            .and_then(|key| key.check())
+            .err(),
+        _ => None,
+    };
+    if let Some(problem) = &signing_problem {
+        println!("{}", problem);
+    }
+
+    let remote_problems = hermit.remote_problems(matches.is_present("offline"));
+    for problem in &remote_problems {
+        println!("{}", problem);
+    }
+    let cut_off = remote_problems.iter().any(|problem| {
+        matches!(problem, doctor::RemoteProblem::Unreachable { reason, .. }
+            if git::is_network_failure(reason))
+    });
+    if cut_off {
+        println!("The network can't be reached; give --offline to leave the remotes out.");
+    }
+
+    let foreign_links = hermit.foreign_links(file_operations.root());
+    if foreign_links.is_empty() {
+        if large_directories.is_empty()
+            && !missing_identity
+            && signing_problem.is_none()
+            && remote_problems.is_empty()
+        {
+            println!("Everything looks fine");
+        }
+        return Ok(());
+    }
+
+    println!("These links point into the shells of another hermit root:");
+    for link in &foreign_links {
+        println!(
+            "    {} -> {}",
+            message::path(&link.path),
+            message::path(&link.target)
+        );
+    }
+
+    let fixable = foreign_links
+        .iter()
+        .filter_map(|link| Some((&link.path, link.replacement.as_ref()?)))
+        .collect::<Vec<_>>();
+    if !fixable.is_empty()
+        && confirm(&format!(
+            "Point {} of them at the same files in this hermit root?",
+            fixable.len()
+        ))
+    {
+        if is_read_only(matches, hermit) {
+            note_read_only();
+            return Ok(());
+        }
+        for (path, replacement) in fixable {
+            file_operations.remove(path);
+            file_operations.link(path, replacement);
+        }
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_edit_subcommand("edit") {
+    about("Open a file from the current shell in your editor")
+    arg(Arg::with_name(FILE_ARG)
+        .required(true)
+        .help("The file to edit, either by its path in the shell or where \
+               it is linked in your home directory."))
+    arg(Arg::with_name("commit")
+        .short("c")
+        .long("commit")
+        .help("Offer to commit the file afterwards if it changed."))
+  }
+}
+
+fn handle_edit<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
 ) -> Result<()> {
     let shell = hermit.current_shell()?;
-    println!("{}", shell.name);
+    let file = shell.find_file(
+        matches.value_of_os(FILE_ARG).unwrap(),
+        file_operations.root(),
+    )?;
+    let shell_root = shell.root_path();
+
+    let editor = env::editor();
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg("sh")
+        .arg(shell_root.join(&file.source))
+        .status()
+        .map_err(|err| Error::EditorFailed(format!("{}: {}", editor, err)))?;
+    if !status.success() {
+        return Err(Error::EditorFailed(format!("{} {}", editor, status)));
+    }
+
+    if matches.is_present("commit")
+        && git::has_changes(&shell_root, &file.source)?
+        && confirm(&format!(
+            "Commit changes to {}?",
+            message::path(&file.source)
+        ))
+    {
+        let summary = format!("Update {}", message::path(&file.source));
+        hermit.commit(&shell.name, &summary, &[&file.source])?;
+    }
     Ok(())
 }
 
 subcommand! {
-  fn add_status_subcommand("status") {
-      about("Display the status of your hermit shell")
+  fn add_env_subcommand("env") {
+    about("Print the environment variables that the current shell's \
+           manifest declares, to evaluate in your shell's startup file: \
+           eval \"$(hermit env)\"")
+    arg(Arg::with_name("format")
+        .long("format")
+        .value_name("FORMAT")
+        .possible_values(&ExportFormat::NAMES)
+        .default_value("sh")
+        .help("Print commands for sh and its relatives (bash, zsh), \
+               commands for fish, or a JSON object."))
   }
 }
 
-fn handle_status<C: Config>(
-    _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
-    _file_operations: &mut FileOperations,
+fn handle_env<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("status")
+    let format = matches
+        .value_of("format")
+        .and_then(ExportFormat::from_name)
+        .unwrap();
+    print!(
+        "{}",
+        format.export(&hermit.exported_env(file_operations.root())?)
+    );
+    Ok(())
 }
 
 subcommand! {
-  fn add_inhabit_subcommand("inhabit") {
-    about("Switch to using a different hermit shell")
+  fn add_exec_subcommand("exec") {
+    about("Run a command from inside the current shell's directory. \
+           HERMIT_ROOT, HERMIT_SHELL and HERMIT_SHELL_PATH are set in \
+           its environment.")
+    setting(AppSettings::TrailingVarArg)
+    arg(Arg::with_name(COMMAND_ARG)
+        .required(true)
+        .multiple(true)
+        .allow_hyphen_values(true)
+        .help("The command to run, followed by its arguments."))
   }
 }
 
-fn handle_inhabit<C: Config>(
+fn handle_exec<C: Config>(
     matches: &ArgMatches<'_>,
     hermit: &mut Hermit<C>,
     file_operations: &mut FileOperations,
 ) -> Result<()> {
-    let shell_name = matches.value_of(SHELL_NAME_ARG).unwrap();
-    hermit.inhabit(file_operations, shell_name)?;
+    let shell = hermit.current_shell()?;
+    let mut command = matches.values_of_os(COMMAND_ARG).unwrap();
+    let program = command.next().unwrap();
+
+    let err = process::Command::new(program)
+        .args(command)
+        .current_dir(shell.root_path())
+        .envs(hermit.environment(file_operations.root()))
+        .exec();
+    Err(Error::CommandFailed(
+        program.to_string_lossy().into_owned(),
+        err.to_string(),
+    ))
+}
+
+subcommand! {
+  fn add_files_subcommand("files") {
+    about("List the files a shell tracks and where each of them goes, \
+           after the manifest and this machine's overrides are applied. \
+           If no shell name is given, the current shell is used.")
+    arg(optional_shell_name_arg("The name of the shell to list the files of."))
+    arg(Arg::with_name("json")
+        .long("json")
+        .help("Print the files as JSON, for other programs to read."))
+  }
+}
+
+fn handle_files<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell = match matches.value_of(SHELL_NAME_ARG) {
+        Some(name) => hermit.shell(name)?,
+        None => hermit.current_shell()?,
+    };
+    let files = shell.files()?;
+
+    if matches.is_present("json") {
+        let utf8 = |path: &Path| {
+            path.to_str()
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| Error::PathNotUtf8(path.to_path_buf()))
+        };
+        let entries = files
+            .iter()
+            .map(|file| {
+                Ok(serde_json::json!({
+                    "source": utf8(&file.source)?,
+                    "target": utf8(&file_operations.root().join(&file.target))?,
+                    "strategy": file.strategy.label(),
+                    "tags": file.tags,
+                }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        println!("{}", serde_json::Value::Array(entries));
+        return Ok(());
+    }
+
+    for file in &files {
+        let mut notes = file.tags.iter().cloned().collect::<Vec<_>>();
+        if file.strategy == Strategy::Copy {
+            notes.insert(0, "copy".to_owned());
+        }
+        println!(
+            "{} -> {}{}",
+            message::path(&file.source),
+            message::path(file_operations.root().join(&file.target)),
+            if notes.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", notes.join(", "))
+            }
+        );
+    }
     Ok(())
 }
 
-// **************************************************
-// Utility functions
-// **************************************************
+subcommand! {
+  fn add_fork_subcommand("fork") {
+    about("Make a new shell that starts out as a copy of another, to \
+           derive one setup from another. A shell with a repository of \
+           its own is cloned, history and all.")
+    arg(Arg::with_name(SOURCE_ARG)
+        .required(true)
+        .help("The name of the shell to copy."))
+    arg(Arg::with_name(SHELL_NAME_ARG)
+        .required(true)
+        .help("The name of the new shell."))
+    arg(Arg::with_name("remote")
+        .long("remote")
+        .value_name("URL")
+        .help("Make URL the \"origin\" remote of the new shell, instead of \
+               sharing the remote of the shell it was copied from."))
+    arg(Arg::with_name("use")
+        .long("use")
+        .help("Switch to the new shell like `hermit use` does."))
+  }
+}
 
-fn shell_name_arg<'a, 'b>(message: &'static str) -> Arg<'a, 'b> {
-    Arg::with_name(SHELL_NAME_ARG)
-        .default_value("default")
-        .help(message)
+fn handle_fork<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let source = hermit.existing_shell_name(matches.value_of(SOURCE_ARG).unwrap())?;
+    let name = matches.value_of(SHELL_NAME_ARG).unwrap();
+    let origin = match matches.value_of("remote") {
+        Some(url) => Some(url.to_owned()),
+        None => hermit.origin_url(&source)?,
+    };
+
+    hermit.fork_shell(file_operations, &source, name, origin.as_deref())?;
+    for result in file_operations.flush() {
+        result.map_err(|err| Error::Git(err.to_string()))?;
+    }
+    hermit.set_fork_origin(name, origin.as_deref())?;
+    println!("Forked {} into {}", source, name);
+
+    if matches.is_present("use") {
+        let tags = TagFilter::default();
+        reporting(|report| hermit.use_shell(file_operations, name, &tags, true, report))
+    } else {
+        Ok(())
+    }
 }
 
-fn not_implemented(name: &'static str) -> Result<()> {
-    Err(Error::SubcommandNotImplemented(name))
+subcommand! {
+  fn add_git_subcommand("git") {
+    about("Run git in the current shell's repository")
+    setting(AppSettings::TrailingVarArg)
+    arg(shell_flag_arg())
+    arg(Arg::with_name(COMMAND_ARG)
+        .multiple(true)
+        .allow_hyphen_values(true)
+        .help("The git subcommand to run, followed by its arguments."))
+  }
+}
+
+fn handle_git<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell = hermit.current_shell()?;
+    let args = matches.values_of_os(COMMAND_ARG).into_iter().flatten();
+
+    let err = process::Command::new("git")
+        .args(args)
+        .current_dir(shell.root_path())
+        .envs(hermit.environment(file_operations.root()))
+        .exec();
+    Err(Error::CommandFailed("git".to_owned(), err.to_string()))
+}
+
+subcommand! {
+  fn add_history_subcommand("history") {
+    about("List the changes hermit has made, most recent first")
+    arg(Arg::with_name("audit")
+        .long("audit")
+        .help("Show the audit log instead: every change hermit has made \
+               or failed to make, oldest first, with when, by whom and \
+               with which command. Unlike the history, it keeps what has \
+               been undone."))
+    arg(Arg::with_name("command")
+        .long("command")
+        .value_name("COMMAND")
+        .requires("audit")
+        .help("Only show the changes made by this command, named as the \
+               audit log names it, like `inhabit` for `use`."))
+    arg(Arg::with_name("user")
+        .long("user")
+        .value_name("USER")
+        .requires("audit")
+        .help("Only show the changes made by this user."))
+    arg(Arg::with_name("outcome")
+        .long("outcome")
+        .value_name("OUTCOME")
+        .possible_values(audit::Outcome::NAMES)
+        .requires("audit")
+        .help("Only show the changes that turned out this way."))
+  }
+}
+
+fn handle_history<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    if matches.is_present("audit") {
+        let filter = audit::Filter {
+            command: matches.value_of("command").map(ToOwned::to_owned),
+            user: matches.value_of("user").map(ToOwned::to_owned),
+            outcome: matches
+                .value_of("outcome")
+                .and_then(audit::Outcome::from_name),
+        };
+        for entry in hermit.audit_log().entries(&filter)? {
+            println!(
+                "{}  {:<11} {:<11} {:<8} {}",
+                entry.when(),
+                entry.who(),
+                entry.command,
+                entry.outcome.name(),
+                entry.op
+            );
+            if let Some(error) = &entry.error {
+                println!("    {}", error);
+            }
+        }
+        return Ok(());
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    for batch in hermit.journal().batches()?.iter().rev() {
+        println!(
+            "{:>4}  {:<12}{:<16}{} change{}",
+            batch.id,
+            batch.command,
+            describe_age(now.saturating_sub(batch.time)),
+            batch.changes.len(),
+            if batch.changes.len() == 1 { "" } else { "s" }
+        );
+    }
+    Ok(())
+}
+
+fn describe_age(seconds: u64) -> String {
+    let (count, unit) = match seconds {
+        0..=59 => return "just now".to_owned(),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    format!(
+        "{} {}{} ago",
+        count,
+        unit,
+        if count == 1 { "" } else { "s" }
+    )
+}
+
+subcommand! {
+  fn add_hooks_subcommand("hooks") {
+    about("Manage the git hooks of a shell's repository")
+    setting(AppSettings::SubcommandRequiredElseHelp)
+    subcommand(SubCommand::with_name("install")
+               .about("Install a pre-commit hook that stops commits of secrets \
+                       in plain text or of a broken manifest")
+               .arg(optional_shell_name_arg("The name of the shell to install the hook in.")))
+  }
+}
+
+fn handle_hooks<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    match matches.subcommand() {
+        ("install", Some(matches)) => {
+            let name = match matches.value_of(SHELL_NAME_ARG) {
+                Some(name) => hermit.existing_shell_name(name)?,
+                None => hermit.current_shell()?.name,
+            };
+            let hook = hermit.install_hooks(&name)?;
+            println!("Installed {}", message::path(hook));
+            Ok(())
+        }
+        _ => unreachable!("{}", message::error_str("unknown hooks subcommand passed")),
+    }
+}
+
+subcommand! {
+  fn add_init_subcommand("init") {
+    about("Create a new hermit shell called SHELL_NAME. If no shell name \
+           is given, \"default\" is used.")
+    arg(shell_name_arg("The name of the shell to be created."))
+    arg(Arg::with_name("shared")
+        .long("shared")
+        .help("Keep all shells in one shared repository, with the new shell \
+               as a directory in it. Only possible before any shells exist."))
+    arg(Arg::with_name("template")
+        .long("template")
+        .value_name("TEMPLATE")
+        .help("Start the shell with the files of a built-in template (\"basic\") \
+               or of a git repository. {{name}}, {{email}} and {{shell}} in \
+               its files are filled in."))
+    arg(Arg::with_name("remote")
+        .long("remote")
+        .value_name("URL")
+        .help("Add URL as the \"origin\" remote of the new shell."))
+    arg(Arg::with_name("push")
+        .long("push")
+        .requires("remote")
+        .help("Make an initial commit and push it to the remote."))
+    arg(Arg::with_name("description")
+        .long("description")
+        .short("d")
+        .value_name("TEXT")
+        .help("Describe what the shell is for, to tell it apart from others."))
+    arg(Arg::with_name("sync-dir")
+        .long("sync-dir")
+        .value_name("DIR")
+        .conflicts_with_all(&["shared", "remote"])
+        .help("Keep the shell in step with a folder, like one in Dropbox or \
+               iCloud, instead of using git. `hermit sync` copies changes \
+               both ways."))
+  }
+}
+
+fn handle_init<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell_name = matches.value_of(SHELL_NAME_ARG).unwrap();
+    let branch = hermit
+        .settings()
+        .init
+        .default_branch
+        .clone()
+        .unwrap_or_else(git::default_branch);
+    file_operations.set_initial_branch(&branch);
+    hermit.scaffold(file_operations);
+    let dry_run = is_dry_run(matches, hermit);
+    if matches.is_present("shared") {
+        hermit.init_shared_repo(file_operations)?;
+        if !dry_run {
+            report_errors(file_operations.flush());
+        }
+    }
+    let sync_dir = matches.value_of_os("sync-dir").map(|dir| {
+        let dir = paths::normalize(&std::env::current_dir().unwrap_or_default().join(dir));
+        match dir.strip_prefix(file_operations.root()) {
+            Ok(rest) => Path::new("~").join(rest),
+            Err(_) => dir,
+        }
+    });
+    let uses_git = sync_dir.is_none();
+    match (sync_dir.is_some(), dry_run) {
+        (true, false) => hermit.init_folder_shell(file_operations, shell_name)?,
+        (true, true) => hermit.plan_init_folder_shell(file_operations, shell_name)?,
+        (false, false) => hermit.init_shell(file_operations, shell_name)?,
+        (false, true) => hermit.plan_init_shell(file_operations, shell_name)?,
+    }
+
+    let template_files = match matches.value_of("template") {
+        Some(spec) => {
+            let variables = hermit.variables(file_operations.root());
+            Template::from_spec(spec).files(&Placeholders::for_shell(shell_name, variables))?
+        }
+        None => vec![],
+    };
+    hermit.apply_template(file_operations, shell_name, &template_files);
+
+    let remote = matches.value_of("remote");
+    let mut info = ShellInfo::new(matches.value_of("description"), remote);
+    if let Some(sync_dir) = sync_dir {
+        // A folder that another machine already syncs with has the
+        // shell's metadata in it, and using it as it is keeps the first
+        // sync from seeing two different versions of it.
+        let folder = match sync_dir.strip_prefix("~") {
+            Ok(rest) => file_operations.root().join(rest),
+            Err(_) => sync_dir.clone(),
+        };
+        if folder.join(ShellInfo::relative_path()).is_file() {
+            info = ShellInfo::load(&folder)?;
+        }
+        info.sync_dir = Some(sync_dir);
+    }
+    hermit.record_shell_info(file_operations, shell_name, &info)?;
+
+    if let Some(url) = remote {
+        hermit.add_remote(file_operations, shell_name, "origin", url);
+    }
+
+    if dry_run {
+        print_plan(file_operations);
+        return Ok(());
+    }
+    if !uses_git {
+        return Ok(());
+    }
+
+    // Committing the shell's metadata straight away means it starts
+    // out on a branch, rather than as an empty repository that some
+    // git commands trip over.
+    let report = file_operations.flush();
+    let created = report.is_success();
+    report_errors(report);
+    if !created {
+        return Ok(());
+    }
+    let shell_root = hermit.shell(shell_name)?.root_path();
+    let info_path = ShellInfo::relative_path();
+    let paths = template_files
+        .iter()
+        .map(|file| &file.path)
+        .chain(Some(&info_path))
+        .collect::<Vec<_>>();
+    let committed = hermit.commit(shell_name, "Initial commit", &paths);
+    if matches.is_present("push") {
+        committed?;
+        git::push(&shell_root, "origin")?;
+    } else if let Err(err) = committed {
+        message::note(format!("Could not make an initial commit: {}", err));
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_list_subcommand("list") {
+    about("List your hermit shells, marking the current one with *")
+    alias("ls")
+    arg(Arg::with_name("long")
+        .long("long")
+        .short("l")
+        .help("Also show when each shell was created, where it came from, \
+               and its description."))
+  }
+}
+
+fn handle_list<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    let current = hermit.current_shell().ok().map(|shell| shell.name);
+    for name in hermit.shell_names() {
+        let marker = if Some(&name) == current.as_ref() {
+            '*'
+        } else {
+            ' '
+        };
+        if matches.is_present("long") {
+            let info = hermit.shell_info(&name)?;
+            println!(
+                "{} {:<16}{:<12}{}",
+                marker,
+                name,
+                info.created.as_deref().unwrap_or("-"),
+                info.origin.as_deref().unwrap_or("-")
+            );
+            if let Some(description) = &info.description {
+                println!("      {}", description);
+            }
+        } else {
+            println!("{} {}", marker, name);
+        }
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_merge_subcommand("merge") {
+    about("Merge the files of one shell into another. For each file that \
+           both shells have with different contents, asks whether to keep \
+           the one in the shell being merged into, take the other one, or \
+           keep both by renaming the other one.")
+    arg(Arg::with_name(SOURCE_ARG)
+        .required(true)
+        .help("The name of the shell to merge."))
+    arg(Arg::with_name(INTO_ARG)
+        .required(true)
+        .help("The name of the shell to merge it into."))
+    arg(Arg::with_name("ours")
+        .long("ours")
+        .conflicts_with("theirs")
+        .help("Keep the files of the shell being merged into, without asking."))
+    arg(Arg::with_name("theirs")
+        .long("theirs")
+        .help("Take the files of the shell being merged, without asking."))
+    arg(Arg::with_name("remove-source")
+        .long("remove-source")
+        .help("Remove the merged shell afterwards. If it is the current \
+               shell, the shell it was merged into is inhabited first."))
+    arg(permanent_arg()
+        .requires("remove-source"))
+  }
+}
+
+fn handle_merge<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let from = hermit.existing_shell_name(matches.value_of(SOURCE_ARG).unwrap())?;
+    let into = hermit.existing_shell_name(matches.value_of(INTO_ARG).unwrap())?;
+    file_operations.set_permanent_removal(matches.is_present("permanent"));
+
+    let merge = hermit.merge_shells(file_operations, &from, &into, |path| {
+        if matches.is_present("ours") {
+            Ok(Resolution::Ours)
+        } else if matches.is_present("theirs") {
+            Ok(Resolution::Theirs)
+        } else {
+            ask_resolution(path, &from, &into)
+        }
+    })?;
+    let report = file_operations.flush();
+    let failed = !report.is_success();
+    report_errors(report);
+
+    for path in &merge.added {
+        println!("Added {}", message::path(path));
+    }
+    for path in &merge.replaced {
+        println!("Replaced {}", message::path(path));
+    }
+    for (path, new_path) in &merge.renamed {
+        println!(
+            "Added {} as {}",
+            message::path(path),
+            message::path(new_path)
+        );
+    }
+    if merge.added.is_empty() && merge.replaced.is_empty() && merge.renamed.is_empty() {
+        println!("{} has nothing that {} doesn't", from, into);
+    }
+
+    let current = hermit.current_shell().ok().map(|shell| shell.name);
+    if failed {
+        println!(
+            "Keeping {}, since not all of its files could be merged",
+            from
+        );
+    } else if matches.is_present("remove-source") {
+        if current.as_deref() == Some(from.as_str()) {
+            let tags = TagFilter::default();
+            reporting(|report| hermit.use_shell(file_operations, &into, &tags, true, report))?;
+        }
+        hermit.run_hooks(&from, file_operations.root(), Event::PreNuke)?;
+        hermit.nuke_shell(file_operations, &from)?;
+        println!("Removed {}", from);
+        return Ok(());
+    }
+    if current.as_deref() == Some(into.as_str()) && !merge.added.is_empty() {
+        println!("Run `hermit apply` to link the new files");
+    }
+    Ok(())
+}
+
+/// Asks what to do about a file that two shells being merged both have.
+fn ask_resolution(path: &Path, from: &str, into: &str) -> Result<Resolution> {
+    loop {
+        let answer = ask(&format!(
+            "{} in {} is different from the one in {}. Keep [o]urs, take [t]heirs, or [r]ename theirs?",
+            message::path(path),
+            from,
+            into
+        ))?;
+        match answer.as_str() {
+            "o" | "ours" => return Ok(Resolution::Ours),
+            "t" | "theirs" => return Ok(Resolution::Theirs),
+            "r" | "rename" => {
+                let default = format!("{}.{}", path.display(), from);
+                let new_path = ask(&format!("New name [{}]:", default))?;
+                let new_path = if new_path.is_empty() {
+                    default
+                } else {
+                    new_path
+                };
+                return Ok(Resolution::Rename(new_path.into()));
+            }
+            _ => println!("Please answer o, t or r"),
+        }
+    }
+}
+
+subcommand! {
+  fn add_nuke_subcommand("nuke") {
+    about("Permanently remove a hermit shell")
+    arg(Arg::with_name(SHELL_NAME_ARG)
+        .required(true)
+        .help("The name of the shell to remove. It can't be the one in use."))
+    arg(dry_run_arg())
+    arg(permanent_arg())
+    arg(verbose_hooks_arg())
+  }
+}
+
+fn handle_nuke<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let name = hermit.existing_shell_name(matches.value_of(SHELL_NAME_ARG).unwrap())?;
+    file_operations.set_permanent_removal(matches.is_present("permanent"));
+    hermit.nuke_shell(file_operations, &name)?;
+    if is_dry_run(matches, hermit) {
+        print_plan(file_operations);
+    } else if confirm(&format!("Remove the shell {} and all of its files?", name)) {
+        if let Err(err) = hermit.run_hooks(&name, file_operations.root(), Event::PreNuke) {
+            file_operations.clear();
+            return Err(err);
+        }
+        println!("Removing {}", name);
+    } else {
+        file_operations.clear();
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_packages_subcommand("packages") {
+    about("Track the packages your shell needs and install the missing ones")
+    setting(AppSettings::SubcommandRequiredElseHelp)
+    subcommand(SubCommand::with_name("add")
+               .about("Add packages to one of the current shell's package lists")
+               .arg(manager_arg().required(true))
+               .arg(Arg::with_name(PACKAGES_ARG)
+                    .multiple(true)
+                    .required(true)
+                    .help("The packages to add."))
+               .arg(Arg::with_name("cask")
+                    .long("cask")
+                    .help("Add Homebrew casks instead of formulae.")))
+    subcommand(SubCommand::with_name("diff")
+               .about("Show the listed packages that are not installed")
+               .arg(manager_arg().long("manager")))
+    subcommand(SubCommand::with_name("install")
+               .about("Install the listed packages that are not installed")
+               .arg(manager_arg().long("manager")))
+  }
+}
+
+fn handle_packages<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell_root = hermit.current_shell()?.root_path();
+    match matches.subcommand() {
+        ("add", Some(matches)) => {
+            let manager = selected_manager(matches).unwrap();
+            let mut list = PackageList::load(&shell_root, manager)
+                .map_err(|err| Error::PackageListUnwritable(err.to_string()))?;
+            let mut added = vec![];
+            for name in matches.values_of(PACKAGES_ARG).unwrap() {
+                let package = if matches.is_present("cask") {
+                    Package::cask(name)
+                } else {
+                    Package::new(name)
+                };
+                if list.add(package) {
+                    added.push(name);
+                }
+            }
+            if added.is_empty() {
+                return Ok(());
+            }
+            list.save(file_operations);
+            if is_dry_run(matches, hermit) {
+                print_plan(file_operations);
+                return Ok(());
+            }
+            for name in added {
+                println!("Added {} to the {} list", name, manager.name());
+            }
+            Ok(())
+        }
+        ("diff", Some(matches)) => {
+            for (list, missing) in missing_packages(&shell_root, selected_manager(matches)) {
+                for package in missing {
+                    println!("{}: {} is not installed", list.manager.name(), package.name);
+                }
+            }
+            Ok(())
+        }
+        ("install", Some(matches)) => {
+            for (list, missing) in missing_packages(&shell_root, selected_manager(matches)) {
+                if !missing.is_empty() {
+                    list.manager.install(&missing)?;
+                }
+            }
+            Ok(())
+        }
+        _ => unreachable!(
+            "{}",
+            message::error_str("unknown packages subcommand passed")
+        ),
+    }
+}
+
+subcommand! {
+  fn add_plan_subcommand("plan") {
+    about("Work out what applying the current shell, or switching to \
+           another one, would change, and optionally save it to carry out \
+           later with `hermit apply --plan`")
+    arg(optional_shell_name_arg("The shell to plan switching to. Without \
+                                 one, plans applying the current shell."))
+    arg(Arg::with_name("output")
+        .long("output")
+        .short("o")
+        .value_name("FILE")
+        .help("Save the plan to this file."))
+    arg(Arg::with_name("format")
+        .long("format")
+        .value_name("FORMAT")
+        .possible_values(&["text", "ansible"])
+        .default_value("text")
+        .help("How to show the plan. `ansible` prints it as a list of \
+               Ansible tasks, or saves them with --output, for \
+               configuration management to carry out instead."))
+    arg(force_arg())
+    arg(no_backup_arg())
+    arg(tag_arg("only")
+        .help("Only link the tagged files that have one of these tags. \
+               Files without tags are always linked."))
+    arg(tag_arg("skip")
+        .help("Don't link files that have any of these tags."))
+  }
+}
+
+fn handle_plan<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    set_conflict_policy(matches, hermit, file_operations);
+    let current_shell = hermit.current_shell().ok().map(|shell| shell.name);
+    let switch_to = match matches.value_of(SHELL_NAME_ARG) {
+        Some(name) => {
+            let name = hermit.existing_shell_name(&hermit.resolve_shell_name(name)?)?;
+            hermit.plan_inhabit(file_operations, &name, &selected_tags(matches))?;
+            Some(name)
+        }
+        None => {
+            hermit.apply(file_operations, &selected_tags(matches))?;
+            None
+        }
+    };
+
+    let output = matches.value_of_os("output").map(Path::new);
+    if matches.value_of("format") == Some("ansible") {
+        let tasks = ansible::tasks(file_operations)?;
+        file_operations.clear();
+        match output {
+            Some(output) => {
+                fs::write(output, tasks)
+                    .map_err(|err| Error::PlanUnwritable(output.into(), err.to_string()))?;
+                println!(
+                    "Saved the plan as Ansible tasks to {}",
+                    message::path(output)
+                );
+            }
+            None => print!("{}", tasks),
+        }
+        return Ok(());
+    }
+    if let Some(output) = output {
+        let unwritable = |err: io::Error| Error::PlanUnwritable(output.into(), err.to_string());
+        let saved = SavedPlan::of(file_operations, current_shell, switch_to).map_err(unwritable)?;
+        fs::write(output, saved.to_json()).map_err(unwritable)?;
+    }
+    print_plan(file_operations);
+    if let Some(output) = output {
+        println!(
+            "Saved the plan to {}; carry it out with `hermit apply --plan {0}`",
+            message::path(output)
+        );
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_prompt_info_subcommand("prompt-info") {
+    about("Print the current shell's name for a shell prompt, followed \
+           by * if it has uncommitted changes and ↑ and a count if it has \
+           unpushed commits. Prints nothing outside of a hermit setup.")
+    arg(Arg::with_name("budget")
+        .long("budget")
+        .value_name("MILLISECONDS")
+        .default_value("100")
+        .validator(|value| value.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("How long to spend looking at the shell's repository. \
+               Whatever isn't known by then is left out."))
+  }
+}
+
+fn handle_prompt_info<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell = match hermit
+        .check_initialized()
+        .and_then(|()| hermit.current_shell())
+    {
+        Ok(shell) => shell,
+        Err(_) => return Ok(()),
+    };
+    let budget = matches
+        .value_of("budget")
+        .and_then(|budget| budget.parse().ok())
+        .unwrap_or_default();
+    let info = PromptInfo::gather(
+        shell.name.clone(),
+        shell.root_path(),
+        Duration::from_millis(budget),
+    );
+    println!("{}", info);
+    Ok(())
+}
+
+subcommand! {
+  fn add_provision_subcommand("provision") {
+    about("Set up this machine with a shell in one go, for cloud-init, \
+           Ansible and CI: clone it unless it is here already, switch to \
+           it or bring it up to date, and run the bootstrap steps that \
+           haven't run yet. Nothing is asked, the first failure stops \
+           it, and each step is logged as a line of JSON. Running it \
+           again only changes what has changed in the shell.")
+    arg(Arg::with_name(URL_ARG)
+        .long("shell")
+        .value_name("URL")
+        .required(true)
+        .help("The git URL of the shell."))
+    arg(Arg::with_name("name")
+        .long("name")
+        .value_name("NAME")
+        .help("What to call the shell. Without it, the shell is named \
+               after the repository."))
+    arg(Arg::with_name("branch")
+        .long("branch")
+        .short("b")
+        .value_name("BRANCH")
+        .help("Only fetch BRANCH, and check it out, when cloning."))
+    arg(force_arg())
+    arg(no_backup_arg())
+    arg(tag_arg("only")
+        .help("Only link the tagged files that have one of these tags. \
+               Files without tags are always linked."))
+    arg(tag_arg("skip")
+        .help("Don't link files that have any of these tags."))
+  }
+}
+
+fn handle_provision<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let url = matches.value_of(URL_ARG).unwrap();
+    let name = match matches.value_of("name") {
+        Some(name) => name,
+        None => {
+            git::repo_name_from_url(url).ok_or_else(|| Error::NoShellNameInUrl(url.to_owned()))?
+        }
+    };
+    let request = provision::Request {
+        url: url.to_owned(),
+        name: name.to_owned(),
+        branch: matches.value_of("branch").map(ToOwned::to_owned),
+        tags: selected_tags(matches),
+    };
+    // Git would otherwise ask for credentials that nobody is there to
+    // type in.
+    std::env::set_var("GIT_TERMINAL_PROMPT", "0");
+    set_conflict_policy(matches, hermit, file_operations);
+
+    reporting(|report| {
+        hermit.provision(file_operations, &request, report, |record| {
+            println!("{}", record.to_json())
+        })
+    })
+}
+
+subcommand! {
+  fn add_push_subcommand("push") {
+    about("Push the current shell to its upstream remote, or to \"origin\" \
+           if it has no upstream yet, and then to every remote that is a \
+           mirror")
+    arg(Arg::with_name(REMOTE_ARG)
+        .help("The remote to push to, leaving out the mirrors."))
+    arg(Arg::with_name("all")
+        .long("all")
+        .conflicts_with(REMOTE_ARG)
+        .help("Push to every remote of the shell."))
+  }
+}
+
+fn handle_push<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell_root = hermit.current_shell()?.root_path();
+    let dry_run = is_dry_run(matches, hermit);
+    if let Some(remote) = matches.value_of(REMOTE_ARG) {
+        if dry_run {
+            return print_push_plan(&shell_root, &[remote.to_owned()]);
+        }
+        return git::push(&shell_root, remote);
+    }
+    // Only the remote a plain push goes to gets to be the upstream, so
+    // that pushing to the others doesn't change where the branch pulls
+    // from.
+    let upstream = git::push_remote(&shell_root)?;
+    let all = matches.is_present("all");
+    let remotes = git::remotes(&shell_root)?;
+    // The upstream's remote comes first, then the mirrors, or with
+    // `--all` every other remote.
+    let mut targets = vec![];
+    if !all || remotes.iter().any(|remote| remote.name == upstream) {
+        targets.push(upstream.clone());
+    }
+    targets.extend(
+        remotes
+            .into_iter()
+            .filter(|remote| remote.name != upstream && (all || remote.mirror))
+            .map(|remote| remote.name),
+    );
+    if dry_run {
+        return print_push_plan(&shell_root, &targets);
+    }
+    if targets.len() == 1 && !all {
+        return git::push(&shell_root, &upstream);
+    }
+    // One remote that can't be reached shouldn't keep the others from
+    // getting the push, so the ones that failed are only reported once
+    // all of them have been tried.
+    let mut failed = vec![];
+    for remote in &targets {
+        let pushed = if *remote == upstream {
+            git::push(&shell_root, remote)
+        } else {
+            git::push_to_mirror(&shell_root, remote)
+        };
+        match pushed {
+            Ok(()) => println!("Pushed to {}", remote),
+            Err(err) => {
+                message::report(format!("Could not push to {}: {}", remote, err));
+                failed.push(remote.clone());
+            }
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::PushFailed(failed))
+    }
+}
+
+/// Says what pushing to `remotes` would send, going by what was last
+/// fetched.
+fn print_push_plan(shell_root: &Path, remotes: &[String]) -> Result<()> {
+    let branch = git::current_branch(shell_root)?.unwrap_or_else(|| "HEAD".to_owned());
+    let ahead = match git::commits_ahead(shell_root)? {
+        Some(ahead) => format!(
+            " ({} ahead of its upstream, as of the last fetch)",
+            commits(ahead)
+        ),
+        None => String::new(),
+    };
+    for remote in remotes {
+        println!("Would push {} to {}{}", branch, remote, ahead);
+    }
+    Ok(())
+}
+
+fn commits(count: usize) -> String {
+    format!("{} commit{}", count, if count == 1 { "" } else { "s" })
+}
+
+subcommand! {
+  fn add_refresh_subcommand("refresh") {
+    about("Walk the files of every shell again, rebuilding the index of \
+           them that hermit keeps to avoid walking large shells every time")
+  }
+}
+
+fn handle_refresh<C: Config>(
+    _matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    for (name, count) in hermit.refresh_index()? {
+        println!("Indexed {} files in {}", count, name);
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_remote_subcommand("remote") {
+    about("Manage the git remotes of the current shell. Without a \
+           subcommand, the remotes are listed.")
+    subcommand(SubCommand::with_name("add")
+               .about("Add a remote")
+               .arg(Arg::with_name(REMOTE_ARG).required(true))
+               .arg(Arg::with_name(URL_ARG).required(true))
+               .arg(Arg::with_name("mirror")
+                    .long("mirror")
+                    .help("Make the remote a mirror, which `hermit push` \
+                           pushes to as well.")))
+    subcommand(SubCommand::with_name("list")
+               .about("List the remotes and their URLs"))
+    subcommand(SubCommand::with_name("mirror")
+               .about("Make a remote a mirror, which `hermit push` pushes to \
+                       as well")
+               .arg(Arg::with_name(REMOTE_ARG).required(true))
+               .arg(Arg::with_name("off")
+                    .long("off")
+                    .help("Stop the remote from being a mirror.")))
+    subcommand(SubCommand::with_name("remove")
+               .about("Remove a remote")
+               .alias("rm")
+               .arg(Arg::with_name(REMOTE_ARG).required(true)))
+    subcommand(SubCommand::with_name("set-url")
+               .about("Change the URL of a remote")
+               .arg(Arg::with_name(REMOTE_ARG).required(true))
+               .arg(Arg::with_name(URL_ARG).required(true)))
+  }
+}
+
+fn handle_remote<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell_root = hermit.current_shell()?.root_path();
+    match matches.subcommand() {
+        ("add", Some(matches)) => {
+            let name = matches.value_of(REMOTE_ARG).unwrap();
+            let url = matches.value_of(URL_ARG).unwrap();
+            let added = git::add_remote(&shell_root, name, url);
+            hermit.audited(format!("add the remote {} at {}", name, url), added)?;
+            if matches.is_present("mirror") {
+                let marked = git::set_mirror(&shell_root, name, true);
+                hermit.audited(format!("make the remote {} a mirror", name), marked)?;
+            }
+            Ok(())
+        }
+        ("mirror", Some(matches)) => {
+            let name = matches.value_of(REMOTE_ARG).unwrap();
+            let mirror = !matches.is_present("off");
+            let marked = git::set_mirror(&shell_root, name, mirror);
+            let description = if mirror {
+                format!("make the remote {} a mirror", name)
+            } else {
+                format!("stop the remote {} being a mirror", name)
+            };
+            hermit.audited(description, marked)
+        }
+        ("remove", Some(matches)) => {
+            let name = matches.value_of(REMOTE_ARG).unwrap();
+            let removed = git::remove_remote(&shell_root, name);
+            hermit.audited(format!("remove the remote {}", name), removed)
+        }
+        ("set-url", Some(matches)) => {
+            let name = matches.value_of(REMOTE_ARG).unwrap();
+            let url = matches.value_of(URL_ARG).unwrap();
+            let set = git::set_remote_url(&shell_root, name, url);
+            hermit.audited(format!("point the remote {} at {}", name, url), set)
+        }
+        ("list", _) | ("", None) => {
+            for remote in git::remotes(&shell_root)? {
+                let mirror = if remote.mirror { "\t(mirror)" } else { "" };
+                println!(
+                    "{}\t{}{}",
+                    remote.name,
+                    remote.url.unwrap_or_default(),
+                    mirror
+                );
+            }
+            Ok(())
+        }
+        _ => unreachable!("{}", message::error_str("unknown remote subcommand passed")),
+    }
+}
+
+subcommand! {
+  fn add_rollback_subcommand("rollback") {
+    about("Put the current shell's files back the way they were in a \
+           snapshot and link them. The changes are left staged for you \
+           to commit.")
+    arg(Arg::with_name(SNAPSHOT_ARG)
+        .required(true)
+        .help("The name of the snapshot to roll back to."))
+    arg(force_arg())
+    arg(no_backup_arg())
+  }
+}
+
+fn handle_rollback<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    set_conflict_policy(matches, hermit, file_operations);
+    let name = matches.value_of(SNAPSHOT_ARG).unwrap();
+    let delta = hermit.rollback(file_operations, name)?;
+    println!("Rolled back to {}", name);
+    print_delta(&delta, file_operations);
+    Ok(())
+}
+
+subcommand! {
+  fn add_secret_subcommand("secret") {
+    about("Keep a shell's secret files encrypted in git")
+    setting(AppSettings::SubcommandRequiredElseHelp)
+    subcommand(SubCommand::with_name("init-filters")
+               .about("Set up git to encrypt the files marked `secret` in the \
+                       manifest when they are committed, and decrypt them when \
+                       they are checked out. Run it again after marking more \
+                       files as secret.")
+               .arg(optional_shell_name_arg("The name of the shell to set up.")))
+    subcommand(SubCommand::with_name("unlock")
+               .about("Keep the key in the operating system's keychain, so that \
+                       this machine can decrypt secret files without a key \
+                       file lying around. The hermit root's key file is \
+                       removed once the key is in the keychain.")
+               .arg(Arg::with_name("KEY_FILE")
+                    .help("A copy of the key to unlock with, like one brought \
+                           over from another machine. It is left where it is.")))
+    subcommand(SubCommand::with_name("lock")
+               .about("Forget the key in the keychain, so that secret files \
+                       can't be decrypted until it is unlocked again")
+               .arg(force_arg()
+                    .help("Forget the key even though the keychain has the only \
+                           copy of it on this machine.")))
+    subcommand(SubCommand::with_name("clean")
+               .about("Encrypt standard input to standard output, for git"))
+    subcommand(SubCommand::with_name("smudge")
+               .about("Decrypt standard input to standard output, for git"))
+  }
+}
+
+fn handle_secret<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    let direction = match matches.subcommand() {
+        ("init-filters", Some(matches)) => {
+            let name = match matches.value_of(SHELL_NAME_ARG) {
+                Some(name) => hermit.existing_shell_name(name)?,
+                None => hermit.current_shell()?.name,
+            };
+            let added = hermit.init_secret_filters(&name)?;
+            for source in &added {
+                println!("Encrypting {}", message::path(source));
+            }
+            if added.is_empty() {
+                println!("No more files to encrypt in {}", name);
+            } else {
+                println!(
+                    "Commit {} to encrypt these files from now on. Copy {} to \
+                     your other machines to decrypt them there.",
+                    secret::ATTRIBUTES_FILE_NAME,
+                    message::path(hermit.secret_key_path())
+                );
+            }
+            return Ok(());
+        }
+        ("unlock", Some(matches)) => {
+            let key_file = matches.value_of_os("KEY_FILE").map(Path::new);
+            if hermit.unlock_secrets(key_file)? {
+                println!(
+                    "Moved the key into the keychain and removed {}",
+                    message::path(hermit.secret_key_path())
+                );
+            } else {
+                println!("Stored the key in the keychain");
+            }
+            return Ok(());
+        }
+        ("lock", Some(matches)) => {
+            hermit.lock_secrets(matches.is_present("force"))?;
+            println!("Forgot the key; unlock it again to decrypt secret files");
+            return Ok(());
+        }
+        (direction, _) => direction,
+    };
+
+    let failed = |err: io::Error| Error::Secret(err.to_string());
+    let mut contents = vec![];
+    io::stdin().read_to_end(&mut contents).map_err(failed)?;
+    let key = hermit.secret_key()?;
+    let filtered = match (direction, key) {
+        ("clean", Some(key)) => key.encrypt(&contents),
+        // Without the key, files that were left encrypted can't be
+        // told apart from ones that only look it.
+        ("clean", None) if secret::is_encrypted(&contents) => contents,
+        ("clean", None) => {
+            return Err(Error::Secret(format!(
+                "there is no key at {}",
+                message::path(hermit.secret_key_path())
+            )))
+        }
+        (_, Some(key)) => key.decrypt(&contents)?,
+        (_, None) => {
+            if secret::is_encrypted(&contents) {
+                message::report(format!(
+                    "leaving a secret file encrypted, since there is no key at {}",
+                    message::path(hermit.secret_key_path())
+                ));
+            }
+            contents
+        }
+    };
+    io::stdout().write_all(&filtered).map_err(failed)
+}
+
+subcommand! {
+  fn add_serve_subcommand("serve") {
+    about("Answer JSON-RPC requests to list shells, show the status, add \
+           files and switch shells, for editor plugins and other programs \
+           that drive hermit")
+    arg(Arg::with_name("stdio")
+        .long("stdio")
+        .required(true)
+        .help("Read requests from stdin and write responses to stdout, \
+               one per line."))
+  }
+}
+
+fn handle_serve<C: Config>(
+    _matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let stdin = io::stdin();
+    Server::new(hermit, file_operations)
+        .serve(stdin.lock(), io::stdout())
+        .map_err(|err| Error::RequestsFailed(err.to_string()))
+}
+
+subcommand! {
+  fn add_shell_subcommand("shell") {
+    about("Display the shell you are currently inhabiting")
+  }
+}
+
+fn handle_shell<C: Config>(
+    _matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell = hermit.current_shell()?;
+    println!("{}", shell.name);
+    Ok(())
+}
+
+subcommand! {
+  fn add_shell_init_subcommand("shell-init") {
+    about("Print functions that integrate hermit with your shell, \
+           enabling `hermit cd` and completion for `hermit edit`. \
+           Evaluate the output in your shell's startup file.")
+    arg(Arg::with_name(DIALECT_ARG)
+        .required(true)
+        .possible_values(&Dialect::NAMES)
+        .help("The shell to generate functions for."))
+  }
+}
+
+fn handle_shell_init<C: Config>(
+    matches: &ArgMatches<'_>,
+    _hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    let dialect = matches
+        .value_of(DIALECT_ARG)
+        .and_then(Dialect::from_name)
+        .unwrap();
+    print!("{}", dialect.script());
+    Ok(())
+}
+
+subcommand! {
+  fn add_snapshot_subcommand("snapshot") {
+    about("Save the current shell as it was last committed, and where \
+           its files are linked, so that you can roll back to it later")
+    arg(Arg::with_name(SNAPSHOT_ARG)
+        .help("The name of the snapshot. By default it is named after \
+               the time."))
+    arg(Arg::with_name("list")
+        .short("l")
+        .long("list")
+        .conflicts_with(SNAPSHOT_ARG)
+        .help("List the snapshots of the current shell instead."))
+  }
+}
+
+fn handle_snapshot<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    if matches.is_present("list") {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        for snapshot in hermit.snapshots()?.iter().rev() {
+            let age = now.saturating_sub(snapshot.time);
+            println!(
+                "{:<24}{:<16}{} file{}",
+                snapshot.name,
+                describe_age(age),
+                snapshot.plan.len(),
+                if snapshot.plan.len() == 1 { "" } else { "s" }
+            );
+        }
+        return Ok(());
+    }
+
+    let name = hermit.snapshot(matches.value_of(SNAPSHOT_ARG))?;
+    println!("Saved snapshot {}", name);
+    Ok(())
+}
+
+subcommand! {
+  fn add_stash_subcommand("stash") {
+    about("Set aside the uncommitted changes to the current shell, leaving \
+           its files as they were last committed.")
+    arg(Arg::with_name("message")
+        .long("message")
+        .short("m")
+        .value_name("MESSAGE")
+        .help("Say what the stashed changes are."))
+    subcommand(SubCommand::with_name("pop")
+               .about("Put back the changes that were stashed most recently"))
+  }
+}
+
+fn handle_stash<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell = hermit.current_shell()?;
+    if matches.subcommand_name() == Some("pop") {
+        relinking(hermit, file_operations, |shell_root| {
+            git::stash_pop(shell_root)
+        })?;
+        println!("Put back the stashed changes to {}", shell.name);
+        return Ok(());
+    }
+
+    if !git::has_uncommitted_changes(shell.root_path())? {
+        println!("{} has no uncommitted changes to stash", shell.name);
+        return Ok(());
+    }
+    relinking(hermit, file_operations, |shell_root| {
+        git::stash(shell_root, matches.value_of("message")).map(drop)
+    })?;
+    println!("Stashed the changes to {}", shell.name);
+    Ok(())
+}
+
+subcommand! {
+  fn add_stats_subcommand("stats") {
+    about("Show how many files each shell has, how much room they and \
+           the shell's history take up, its largest files and when it \
+           was last committed to")
+    arg(optional_shell_name_arg("Only show the shell with this name."))
+    arg(Arg::with_name("largest")
+        .long("largest")
+        .value_name("COUNT")
+        .default_value("5")
+        .validator(|count| count.parse::<usize>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("How many of the largest files of each shell to list."))
+  }
+}
+
+fn handle_stats<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    let names = match matches.value_of(SHELL_NAME_ARG) {
+        Some(name) => vec![hermit.existing_shell_name(name)?],
+        None => hermit.shell_names(),
+    };
+    let top = matches
+        .value_of("largest")
+        .map_or(5, |count| count.parse().unwrap());
+    for (i, name) in names.iter().enumerate() {
+        let stats = hermit.stats(name, top)?;
+        if i > 0 {
+            println!();
+        }
+        println!("{}", stats.name);
+        println!("    files:        {}", stats.files);
+        println!("    size:         {}", human_size(stats.size));
+        if let Some(history) = stats.history {
+            println!("    history:      {}", human_size(history));
+        }
+        println!(
+            "    last commit:  {}",
+            stats
+                .last_commit_date()
+                .unwrap_or_else(|| "never".to_owned())
+        );
+        if !stats.largest.is_empty() {
+            println!("    largest:");
+            for (path, size) in &stats.largest {
+                println!("        {:>10}  {}", human_size(*size), message::path(path));
+            }
+        }
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_status_subcommand("status") {
+      about("Display the status of your hermit shell")
+      arg(shell_flag_arg())
+      arg(target_arg())
+      arg(Arg::with_name("verbose")
+          .long("verbose")
+          .short("v")
+          .help("Show how files that are out of date or in the way differ \
+                 from the shell's versions. Secret files are only said \
+                 to differ."))
+      arg(Arg::with_name("show-secrets")
+          .long("show-secrets")
+          .requires("verbose")
+          .help("Show the differences in secret files too."))
+      arg(Arg::with_name("short")
+          .long("short")
+          .conflicts_with("verbose")
+          .help("Show the status in a stable format for scripts and \
+                 prompts: a `## <shell>` line, then a two character \
+                 code and the path of each file."))
+      arg(Arg::with_name("interactive")
+          .long("interactive")
+          .short("i")
+          .conflicts_with_all(&["short", "verbose"])
+          .help("List the files that aren't in place, and choose what \
+                 to do about each of them: back up what is in the way \
+                 and link the shell's file, adopt what is in the way \
+                 into the shell, or leave it alone."))
+  }
+}
+
+fn handle_status<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    set_target(matches, file_operations)?;
+    let status = hermit.status(file_operations.root())?;
+    if matches.is_present("short") {
+        println!("## {}", status.shell);
+        for file in &status.files {
+            println!("{} {}", file.short_code(), message::path(&file.file.target));
+        }
+        return Ok(());
+    }
+
+    let shell_root = hermit.current_shell()?.root_path();
+    if matches.is_present("interactive") {
+        let read_only = is_read_only(matches, hermit);
+        return resolve_interactively(&status, &shell_root, read_only, file_operations);
+    }
+
+    println!("On shell {}", status.shell);
+    if let Some(description) = &status.info.description {
+        println!("{}", description);
+    }
+    for file in &status.files {
+        println!(
+            "    {:<18}{}",
+            file.state.label(),
+            message::path(&file.file.target)
+        );
+        if !matches.is_present("verbose") {
+            continue;
+        }
+        let show_secrets = matches.is_present("show-secrets");
+        match file.difference(file_operations.root(), &shell_root, show_secrets) {
+            Some(Difference::Redacted) => println!("        contents differ (redacted)"),
+            Some(Difference::Binary) => println!("        binary contents differ"),
+            Some(Difference::TooLong) => println!("        contents differ"),
+            Some(Difference::Lines(lines)) => {
+                for line in lines {
+                    println!("        {}", line.unified());
+                }
+            }
+            None => (),
+        }
+    }
+
+    let missing = hermit.missing_lfs_objects(&status.shell)?;
+    if !missing.is_empty() {
+        message::note(
+            "These files are kept in Git LFS, but only pointers to them have been fetched; \
+             run `git lfs pull` in the shell before linking them:",
+        );
+        for path in &missing {
+            eprintln!("    {}", message::path(path));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "interactive")]
+fn resolve_interactively(
+    status: &Status,
+    shell_root: &Path,
+    read_only: bool,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let mut resolver = Resolver::new(status);
+    if resolver.is_empty() {
+        println!("Every file of {} is in place", status.shell);
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        return Err(Error::NotATerminal(
+            "hermit status --interactive".to_owned(),
+        ));
+    }
+
+    let done = interactive::run(&mut resolver, &status.shell)
+        .map_err(|err| Error::TerminalFailed(err.to_string()))?;
+    if done == Done::Apply {
+        if read_only {
+            note_read_only();
+        } else {
+            resolver.queue(file_operations, shell_root);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "interactive"))]
+fn resolve_interactively(
+    _status: &Status,
+    _shell_root: &Path,
+    _read_only: bool,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    Err(Error::SubcommandNotImplemented("status --interactive"))
+}
+
+subcommand! {
+  fn add_sync_subcommand("sync") {
+    about("Bring the current shell up to date with its remotes. If this \
+           machine has a branch of its own, it is rebased onto the main \
+           branch.")
+    arg(Arg::with_name("autostash")
+        .long("autostash")
+        .help("Stash uncommitted changes to the shell while syncing, and \
+               put them back afterwards, without asking first."))
+    arg(verbose_hooks_arg())
+  }
+}
+
+fn handle_sync<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let dry_run = is_dry_run(matches, hermit);
+    if let Some(folder) = hermit.sync_dir(file_operations.root())? {
+        if dry_run {
+            let shell_root = hermit.current_shell()?.root_path();
+            let state_path = folder_sync::state_path(&shell_root);
+            let report = folder_sync::preview(&shell_root, &folder, &state_path)
+                .map_err(|err| Error::FolderSyncFailed(folder.clone(), err.to_string()))?;
+            print_sync_report(&report, &folder, true);
+            return Ok(());
+        }
+        let mut report = SyncReport::default();
+        let synced = relinking(hermit, file_operations, |shell_root| {
+            report = folder_sync::sync(shell_root, &folder, &folder_sync::state_path(shell_root))
+                .map_err(|err| Error::FolderSyncFailed(folder.clone(), err.to_string()))?;
+            Ok(())
+        });
+        hermit.audited(format!("sync with {}", folder.display()), synced)?;
+        print_sync_report(&report, &folder, false);
+        return after_sync(hermit, file_operations);
+    }
+
+    let shell_root = hermit.current_shell()?.root_path();
+    if dry_run {
+        // Fetching is a change too, so this goes by what was fetched
+        // last.
+        let behind = git::behind(&shell_root)?.map_or(0, |behind| behind.commits);
+        let ahead = git::commits_ahead(&shell_root)?.unwrap_or(0);
+        println!(
+            "Would pull {} and push {}, as of the last fetch",
+            commits(behind),
+            commits(ahead)
+        );
+        return Ok(());
+    }
+    let stash = git::has_uncommitted_changes(&shell_root)?
+        && (matches.is_present("autostash")
+            || confirm("The shell has uncommitted changes. Stash them while syncing?"));
+    let stashed = stash && git::stash(&shell_root, Some("Stashed by hermit sync"))?;
+    let pulled = reporting(|report| hermit.pull(file_operations, report, ask_conflict_choice));
+    if stashed {
+        if let Err(err) = git::stash_pop(&shell_root) {
+            message::note(format!(
+                "Your changes are still stashed, because they couldn't be put back ({}); \
+                 use `hermit stash pop` once that is sorted out",
+                err
+            ));
+        }
+    }
+    pulled?;
+    after_sync(hermit, file_operations)
+}
+
+/// Asks how to settle a file that changed both here and in the remote.
+/// Without a terminal to ask on, the sync is given up.
+fn ask_conflict_choice(path: &Path) -> Result<ConflictChoice> {
+    if !io::stdin().is_terminal() {
+        return Ok(ConflictChoice::Abort);
+    }
+    loop {
+        let answer = ask(&format!(
+            "{} changed both here and in the remote. Keep [l]ocal, keep [r]emote, open [m]ergetool, or [a]bort the sync?",
+            message::path(path)
+        ))?;
+        match answer.as_str() {
+            "l" | "local" => return Ok(ConflictChoice::Local),
+            "r" | "remote" => return Ok(ConflictChoice::Remote),
+            "m" | "mergetool" => return Ok(ConflictChoice::MergeTool),
+            "a" | "abort" => return Ok(ConflictChoice::Abort),
+            _ => println!("Please answer l, r, m or a"),
+        }
+    }
+}
+
+/// Links the files of the synced shell, and runs its hooks.
+fn after_sync<C: Config>(
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    report_errors(file_operations.flush());
+    let shell_name = hermit.current_shell()?.name;
+    hermit.run_hooks(&shell_name, file_operations.root(), Event::PostSync)?;
+    Ok(())
+}
+
+/// Says what syncing with `folder` did, or for a `preview`, what it
+/// would do.
+fn print_sync_report(report: &SyncReport, folder: &Path, preview: bool) {
+    let verb = |did: &'static str, would: &'static str| if preview { would } else { did };
+    if report.is_empty() {
+        println!("Everything is in step with {}", message::path(folder));
+    }
+    for path in &report.pulled {
+        println!("{} {}", verb("Pulled", "Would pull"), message::path(path));
+    }
+    for path in &report.pushed {
+        println!("{} {}", verb("Pushed", "Would push"), message::path(path));
+    }
+    for path in &report.removed_here {
+        println!(
+            "{} {}",
+            verb("Removed", "Would remove"),
+            message::path(path)
+        );
+    }
+    for path in &report.removed_there {
+        println!(
+            "{} {} from {}",
+            verb("Removed", "Would remove"),
+            message::path(path),
+            message::path(folder)
+        );
+    }
+    for path in &report.conflicts {
+        println!(
+            "Conflict: {} changed both here and in {}; make the two the same and sync again",
+            message::path(path),
+            message::path(folder)
+        );
+    }
+}
+
+subcommand! {
+  fn add_undo_subcommand("undo") {
+    about("Revert the changes made by the most recent hermit command \
+           that changed anything. Links it created are removed, files it \
+           moved aside are put back, and links it removed are recreated.")
+  }
+}
+
+fn handle_undo<C: Config>(
+    _matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    let batch = hermit.journal().pop()?.ok_or(Error::NothingToUndo)?;
+    println!("Undoing '{}'", batch.command);
+    let report = CommitReport::new(
+        batch
+            .revert()
+            .into_iter()
+            .map(|result| result.map(|()| Outcome::Changed))
+            .collect(),
+    );
+    let failed = report.failed();
+    report_errors(report);
+    if let Some(shell_name) = &batch.shell_before {
+        hermit.set_current_shell(shell_name)?;
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(Error::ChangesFailed(failed)),
+    }
+}
+
+subcommand! {
+  fn add_unlink_subcommand("unlink") {
+    about("Remove the links hermit made to a shell's files, leaving the \
+           shell itself alone. If no shell name is given, the current \
+           shell is used.")
+    arg(optional_shell_name_arg("The name of the shell to unlink."))
+  }
+}
+
+fn handle_unlink<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell_name = match matches.value_of(SHELL_NAME_ARG) {
+        Some(name) => name.to_owned(),
+        None => hermit.current_shell()?.name,
+    };
+    let unlinked = hermit.unlink(file_operations, &shell_name)?;
+    if is_dry_run(matches, hermit) {
+        print_plan(file_operations);
+        return Ok(());
+    }
+    for path in unlinked {
+        let path = path.strip_prefix(file_operations.root()).unwrap_or(&path);
+        println!("Unlinking {}", message::path(path));
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_upgrade_subcommand("upgrade") {
+    about("Replace this hermit with the latest release from GitHub, once \
+           its checksum, and the signature of the checksums by hermit's \
+           release key, check out.")
+    arg(Arg::with_name("check")
+        .long("check")
+        .help("Only say whether there is a newer release."))
+    arg(Arg::with_name("allow-unsigned")
+        .long("allow-unsigned")
+        .help("Install a release that isn't signed on its checksum alone. \
+               That only shows the download is whole, not who built it."))
+  }
+}
+
+#[cfg(feature = "upgrade")]
+fn handle_upgrade<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    use hermit::upgrade::{self, Release};
+
+    let release = Release::latest()?;
+    if !release.is_newer() {
+        println!("hermit {} is the latest release", upgrade::CURRENT_VERSION);
+        return Ok(());
+    }
+    if matches.is_present("check") {
+        println!(
+            "hermit {} is out; this is {}",
+            release.version(),
+            upgrade::CURRENT_VERSION
+        );
+        return Ok(());
+    }
+
+    let (binary, signed) = release.fetch_binary(matches.is_present("allow-unsigned"))?;
+    if !signed {
+        message::note(format!(
+            "release {} wasn't checked against a signature, only its checksum",
+            release.tag
+        ));
+    }
+    let path = std::env::current_exe().map_err(|err| Error::UpgradeFailed(err.to_string()))?;
+    hermit.audited(
+        format!("upgrade {} to {}", path.display(), release.version()),
+        upgrade::install(&path, &binary),
+    )?;
+    println!(
+        "Upgraded {} from {} to {}",
+        message::path(&path),
+        upgrade::CURRENT_VERSION,
+        release.version()
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "upgrade"))]
+fn handle_upgrade<C: Config>(
+    _matches: &ArgMatches<'_>,
+    _hermit: &mut Hermit<C>,
+    _file_operations: &mut FileOperations,
+) -> Result<()> {
+    Err(Error::SubcommandNotImplemented("upgrade"))
+}
+
+subcommand! {
+  fn add_verify_subcommand("verify") {
+    about("Check the copied and secret files of the current shell, and \
+           their copies, against the checksums recorded when the shell \
+           was last committed.")
+    arg(Arg::with_name("record")
+        .long("record")
+        .help("Record the checksums of the shell's files as they are \
+               now, as the hook from `hermit hooks install` does."))
+    arg(shell_flag_arg())
+  }
+}
+
+fn handle_verify<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell = hermit.current_shell()?;
+    if matches.is_present("record") {
+        let (path, count) = hermit.record_checksums(&shell.name)?;
+        let shell_root = shell.root_path();
+        if git::open(&shell_root).is_ok() {
+            git::run(&shell_root, &["add", &path.to_string_lossy()])?;
+        }
+        println!("Recorded {} checksums", count);
+        return Ok(());
+    }
+
+    let verifications = hermit.verify(file_operations.root())?;
+    let mut problems = 0;
+    let mut intact = 0;
+    for verification in &verifications {
+        if verification.verdict == Verdict::Intact {
+            intact += 1;
+            continue;
+        }
+        if verification.verdict.is_problem() {
+            problems += 1;
+        }
+        println!(
+            "    {:<12} {}",
+            verification.verdict.label(),
+            message::path(&verification.file.target)
+        );
+    }
+    if problems > 0 {
+        return Err(Error::VerificationFailed(problems));
+    }
+    if intact == verifications.len() {
+        println!("Everything matches its checksum");
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_version_subcommand("version") {
+    about("Print hermit's version")
+    arg(Arg::with_name("verbose")
+        .short("v")
+        .long("verbose")
+        .help("Also print where hermit is running: its root, the current \
+               shell, how it links files, its git libraries and the \
+               platform, as bug reports need."))
+  }
+}
+
+fn handle_version<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    println!("hermit {}", env!("CARGO_PKG_VERSION"));
+    if matches.is_present("verbose") {
+        for (label, value) in hermit.version_details(file_operations.root()) {
+            println!("{:<10}{}", format!("{}:", label), value);
+        }
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_inhabit_subcommand("inhabit") {
+    about("Switch to using a different hermit shell. The first time a \
+           shell is used on a machine, its bootstrap steps are run after \
+           it has been linked.")
+    alias("use")
+    arg(shell_name_arg("The name of the shell to switch to, or - for the \
+                        shell that was in use before the current one."))
+    arg(force_arg())
+    arg(no_backup_arg())
+    arg(dry_run_arg())
+    arg(tag_arg("only")
+        .help("Only link the tagged files that have one of these tags. \
+               Files without tags are always linked."))
+    arg(tag_arg("skip")
+        .help("Don't link files that have any of these tags."))
+    arg(verbose_hooks_arg())
+    arg(target_arg()
+        .help("Link the shell into DIR, like the home directory of another \
+               user or a chroot, instead of the home directory. The links \
+               hermit made there to other shells are removed, but the \
+               current shell stays the same, and no bootstrap steps or \
+               hooks are run."))
+  }
+}
+
+fn verbose_hooks_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("verbose")
+        .long("verbose")
+        .short("v")
+        .help("Show what the shell's hooks print.")
+}
+
+fn tag_arg<'a, 'b>(name: &'a str) -> Arg<'a, 'b> {
+    Arg::with_name(name)
+        .long(name)
+        .value_name("TAG")
+        .multiple(true)
+        .number_of_values(1)
+        .use_delimiter(true)
+}
+
+fn handle_inhabit<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    set_conflict_policy(matches, hermit, file_operations);
+    let shell_name = hermit.resolve_shell_name(matches.value_of(SHELL_NAME_ARG).unwrap())?;
+    let shell_name = hermit.existing_shell_name(&shell_name)?;
+    if set_target(matches, file_operations)? {
+        hermit.link_into_target(file_operations, &shell_name, &selected_tags(matches))?;
+        if is_dry_run(matches, hermit) {
+            print_plan(file_operations);
+        } else {
+            println!(
+                "Linking {} into {}",
+                shell_name,
+                message::path(file_operations.root())
+            );
+        }
+        return Ok(());
+    }
+    if is_dry_run(matches, hermit) {
+        hermit.plan_inhabit(file_operations, &shell_name, &selected_tags(matches))?;
+        print_plan(file_operations);
+        return Ok(());
+    }
+    let tags = selected_tags(matches);
+    reporting(|report| hermit.use_shell(file_operations, &shell_name, &tags, true, report))
+}
+
+fn selected_tags(matches: &ArgMatches<'_>) -> TagFilter {
+    let tags = |name| {
+        matches
+            .values_of(name)
+            .into_iter()
+            .flatten()
+            .map(ToOwned::to_owned)
+            .collect()
+    };
+    TagFilter {
+        only: tags("only"),
+        skip: tags("skip"),
+    }
+}
+
+/// Makes `change` to the current shell with its files unlinked, like
+/// `Hermit::relinking` does, reporting the changes that failed.
+fn relinking<C: Config, R>(
+    hermit: &Hermit<C>,
+    file_operations: &mut FileOperations,
+    change: impl FnOnce(&Path) -> Result<R>,
+) -> Result<R> {
+    reporting(|report| hermit.relinking(file_operations, report, change))
+}
+
+/// Hands unknown subcommands over to a `hermit-<name>` executable on
+/// the PATH, the same way git finds its external commands.
+fn handle_external<C: Config>(
+    name: &str,
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let args = matches.values_of_os("").into_iter().flatten();
+    let err = process::Command::new(format!("hermit-{}", name))
+        .args(args)
+        .envs(hermit.environment(file_operations.root()))
+        .exec();
+
+    if err.kind() == io::ErrorKind::NotFound {
+        Err(Error::UnknownSubcommand(name.to_owned()))
+    } else {
+        Err(Error::PluginFailed(name.to_owned(), err.to_string()))
+    }
+}
+
+// **************************************************
+// Utility functions
+// **************************************************
+
+fn shell_name_arg<'a, 'b>(message: &'static str) -> Arg<'a, 'b> {
+    Arg::with_name(SHELL_NAME_ARG)
+        .default_value("default")
+        .help(message)
+}
+
+fn optional_shell_name_arg<'a, 'b>(message: &'static str) -> Arg<'a, 'b> {
+    Arg::with_name(SHELL_NAME_ARG).help(message)
+}
+
+/// Lets a command act on another shell than the current one, like
+/// setting `HERMIT_SHELL` does.
+fn shell_flag_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("shell")
+        .long("shell")
+        .short("s")
+        .value_name("SHELL")
+        .help("Act on this shell instead of the current one.")
+}
+
+fn target_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("target")
+        .long("target")
+        .value_name("DIR")
+        .help(
+            "Act on the links in DIR, like the home directory of another \
+             user or a chroot, instead of the home directory.",
+        )
+}
+
+/// Roots the changes at the directory given with `--target`, if one
+/// is. Returns whether one was.
+fn set_target(matches: &ArgMatches<'_>, file_operations: &mut FileOperations) -> Result<bool> {
+    let dir = match matches.value_of_os("target") {
+        Some(dir) => Path::new(dir),
+        None => return Ok(false),
+    };
+    let dir = fs::canonicalize(dir)
+        .ok()
+        .filter(|dir| dir.is_dir())
+        .ok_or_else(|| Error::NoSuchFile(dir.to_path_buf()))?;
+    file_operations.set_root(dir);
+    Ok(true)
+}
+
+fn force_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("force").long("force").short("f").help(
+        "Replace files that are in the way of links, backing them up \
+               to <name>.hermit-backup first.",
+    )
+}
+
+/// Whether to only show what would change, because of `--dry-run` or
+/// read-only mode.
+fn is_dry_run<C: Config>(matches: &ArgMatches<'_>, hermit: &Hermit<C>) -> bool {
+    matches.is_present("dry-run") || is_read_only(matches, hermit)
+}
+
+/// Whether read-only mode is on, because of `--read-only`,
+/// `HERMIT_READONLY` or the `read_only` setting.
+fn is_read_only<C: Config>(matches: &ArgMatches<'_>, hermit: &Hermit<C>) -> bool {
+    matches.is_present("read-only") || env::read_only() || hermit.settings().operations.read_only
+}
+
+/// Says that the changes just asked for weren't made, since read-only
+/// mode is on.
+fn note_read_only() {
+    message::note("read-only mode is on, so nothing was changed");
+}
+
+fn dry_run_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("dry-run")
+        .long("dry-run")
+        .help("Show what would change, without changing anything.")
+}
+
+fn permanent_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("permanent")
+        .long("permanent")
+        .help("Delete the shell outright instead of sending it to the trash.")
+}
+
+fn no_backup_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("no-backup")
+        .long("no-backup")
+        .requires("force")
+        .help("Delete files that --force replaces instead of backing them up.")
+}
+
+/// Decides what happens to files in the way of links, from the
+/// `--force` flags and the `[link]` settings.
+fn set_conflict_policy<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &Hermit<C>,
+    file_operations: &mut FileOperations,
+) {
+    let policy = hermit
+        .settings()
+        .link
+        .conflict_policy(matches.is_present("force"), matches.is_present("no-backup"));
+    file_operations.set_conflict_policy(policy);
+}
+
+fn manager_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(MANAGER_ARG)
+        .takes_value(true)
+        .possible_values(&["brew", "apt", "pacman"])
+        .help("The package manager whose list to use.")
+}
+
+fn selected_manager(matches: &ArgMatches<'_>) -> Option<Manager> {
+    matches.value_of(MANAGER_ARG).and_then(Manager::from_name)
+}
+
+/// The packages from each package list in the shell that are not
+/// installed, reporting the lists whose package manager can't be asked
+/// on this machine.
+fn missing_packages(shell_root: &Path, only: Option<Manager>) -> Vec<(PackageList, Vec<Package>)> {
+    packages::missing(shell_root, only)
+        .into_iter()
+        .filter_map(|(list, missing)| match missing {
+            Ok(missing) => Some((list, missing)),
+            Err(err) => {
+                message::report(err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Asks a yes or no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    let _ = io::stdout().flush();
+
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim(), "y" | "Y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+/// Asks a question and returns the trimmed answer.
+fn ask(question: &str) -> Result<String> {
+    print!("{} ", question);
+    let _ = io::stdout().flush();
+
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(0) | Err(_) => Err(Error::Cancelled),
+        Ok(_) => Ok(answer.trim().to_owned()),
+    }
 }
@@ -0,0 +1,932 @@
+use crate::common::*;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{OsStr, OsString},
+    iter,
+    os::unix::ffi::OsStrExt,
+    path::Component,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    bootstrap::BootstrapStep,
+    fs_backend::{FsBackend, RealFs},
+    host::Host,
+    paths,
+};
+
+/// The name of the optional manifest file at the root of a shell.
+pub const MANIFEST_FILE_NAME: &str = "hermit.toml";
+
+/// The directory in a shell where hermit keeps metadata about the
+/// shell. Like the manifest, it is never linked.
+pub const METADATA_DIR: &str = ".hermit";
+
+/// The directory inside a shell's metadata directory for files that
+/// only make sense on this machine. Hermit keeps it out of git.
+pub const LOCAL_DIR: &str = "local";
+
+/// The entries at the root of a shell that belong to hermit or git
+/// rather than being dotfiles. They are never linked.
+pub const INTERNAL_ENTRIES: &[&str] = &[".git", ".gitattributes", METADATA_DIR, MANIFEST_FILE_NAME];
+
+/// Whether the entry named `name`, `depth` levels below the root of a
+/// shell, is one of hermit's or git's own. Repositories nested deeper
+/// in a shell, like submodules, have their `.git` skipped too, and so
+/// do the `.hermitignore` files of every directory.
+pub fn is_internal(name: &std::ffi::OsStr, depth: usize) -> bool {
+    name == ".git"
+        || name == ".hermitignore"
+        || (depth == 1 && INTERNAL_ENTRIES.iter().any(|entry| name == *entry))
+}
+
+/// A file tracked by a shell, along with where it should be linked.
+///
+/// `source` is relative to the root of the shell, and `target` is
+/// relative to the directory that links are created in (usually
+/// `$HOME`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShellFile {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub strategy: Strategy,
+    /// The tags of the manifest entry the file falls under, for
+    /// linking only some of a shell's files.
+    pub tags: BTreeSet<String>,
+    /// Whether the file holds secrets, which hermit shouldn't print.
+    pub secret: bool,
+    /// Whether `source` is a directory that is linked as a whole.
+    pub granularity: Granularity,
+}
+
+/// How a shell file is put in place.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// The target is a symlink to the file in the shell.
+    #[default]
+    Link,
+    /// The target is a copy of the file in the shell, for programs
+    /// that refuse to read symlinks. Copies can drift out of date.
+    Copy,
+}
+
+/// Whether a directory in a shell is linked file by file or as a
+/// whole.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    /// Each file below the directory is linked on its own, inside real
+    /// directories, so that other programs can put their own files
+    /// next to them.
+    #[default]
+    File,
+    /// The directory is one link, so that files added to it, like
+    /// plugins, show up in the shell without being added.
+    Directory,
+}
+
+impl Strategy {
+    pub fn label(self) -> &'static str {
+        match self {
+            Strategy::Link => "link",
+            Strategy::Copy => "copy",
+        }
+    }
+}
+
+impl ShellFile {
+    /// A file that is linked at the same relative path it has in the
+    /// shell, which is the default when no manifest entry applies.
+    pub fn mirrored(path: impl AsRef<Path>) -> ShellFile {
+        let path = PathBuf::from(path.as_ref());
+        ShellFile {
+            source: path.clone(),
+            target: path,
+            strategy: Strategy::Link,
+            tags: BTreeSet::new(),
+            secret: false,
+            granularity: Granularity::File,
+        }
+    }
+
+    pub fn mapped(source: impl AsRef<Path>, target: impl AsRef<Path>) -> ShellFile {
+        ShellFile {
+            source: PathBuf::from(source.as_ref()),
+            target: PathBuf::from(target.as_ref()),
+            strategy: Strategy::Link,
+            tags: BTreeSet::new(),
+            secret: false,
+            granularity: Granularity::File,
+        }
+    }
+
+    pub fn with_strategy(self, strategy: Strategy) -> ShellFile {
+        ShellFile { strategy, ..self }
+    }
+
+    pub fn with_secret(self, secret: bool) -> ShellFile {
+        ShellFile { secret, ..self }
+    }
+
+    pub fn with_granularity(self, granularity: Granularity) -> ShellFile {
+        ShellFile {
+            granularity,
+            ..self
+        }
+    }
+
+    pub fn with_tags<I, S>(self, tags: I) -> ShellFile
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        ShellFile {
+            tags: tags.into_iter().map(Into::into).collect(),
+            ..self
+        }
+    }
+}
+
+/// Which tagged files to link, so that one shell can serve machines
+/// that need different parts of it. Files without tags are meant for
+/// every machine, and are always linked.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TagFilter {
+    /// If there are any, tagged files are only linked if they have
+    /// one of these tags.
+    pub only: BTreeSet<String>,
+    /// Files with any of these tags are never linked.
+    pub skip: BTreeSet<String>,
+}
+
+impl TagFilter {
+    pub fn allows(&self, file: &ShellFile) -> bool {
+        if file.tags.is_empty() {
+            return true;
+        }
+        let only = self.only.is_empty() || !self.only.is_disjoint(&file.tags);
+        only && self.skip.is_disjoint(&file.tags)
+    }
+}
+
+/// The parsed contents of a shell's `hermit.toml`.
+///
+/// A manifest is a list of `[[file]]` entries, each of which maps a
+/// `source` path inside the shell to a `target` path relative to the
+/// home directory. A source may name either a single file or a
+/// directory, in which case everything underneath it is relocated
+/// together. Files that no entry mentions keep mirroring the layout
+/// of the shell.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct Manifest {
+    #[serde(default, rename = "file")]
+    pub files: Vec<Entry>,
+    #[serde(default)]
+    pub bootstrap: Vec<BootstrapStep>,
+    /// Environment variables for `hermit env` to export, by name. A
+    /// leading `~` in a value stands for the home directory.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub commit: CommitSettings,
+    /// Whether files that no entry mentions are stored without their
+    /// leading dot, and get it back when they are linked. This comes
+    /// from the shell's metadata rather than the manifest itself.
+    #[serde(skip)]
+    pub add_dots: bool,
+}
+
+/// How hermit makes commits in a shell.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CommitSettings {
+    /// The message, with placeholders like `{{summary}}` for the
+    /// message hermit would have used on its own.
+    pub message: Option<String>,
+    /// Whether commits are signed, with the key that git is set up to
+    /// sign with.
+    #[serde(default)]
+    pub sign: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct Entry {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    #[serde(default)]
+    pub when: Option<When>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    /// Targets to use instead of `target` on particular operating
+    /// systems, keyed by the same names as `when.os`, for files like
+    /// `gitconfig` that live in XDG directories on some systems and
+    /// in the home directory on others.
+    #[serde(default)]
+    pub targets: BTreeMap<String, PathBuf>,
+    /// Marks the entry's files as sensitive, so that their contents
+    /// are never shown.
+    #[serde(default)]
+    pub secret: bool,
+    /// Whether a directory `source` is linked as a whole, rather than
+    /// file by file.
+    #[serde(default)]
+    pub granularity: Granularity,
+}
+
+impl Entry {
+    fn applies_to(&self, host: &Host) -> bool {
+        self.when.as_ref().is_none_or(|when| when.matches(host))
+    }
+
+    /// Where the entry's files go on `host`.
+    pub fn target_for(&self, host: &Host) -> &Path {
+        self.targets.get(&host.os).unwrap_or(&self.target)
+    }
+}
+
+/// The conditions under which a manifest entry applies. Every
+/// condition that is given must hold.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct When {
+    /// The operating system, as named by Rust (`linux`, `macos`, ...).
+    pub os: Option<String>,
+    /// A glob pattern matched against the machine's hostname.
+    pub hostname: Option<String>,
+    /// Environment variables that must be set to exactly these values.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+impl When {
+    pub fn matches(&self, host: &Host) -> bool {
+        let os_matches = self.os.as_ref().is_none_or(|os| *os == host.os);
+        let hostname_matches = self.hostname.as_ref().is_none_or(|pattern| {
+            glob::Pattern::new(pattern)
+                .map(|pattern| pattern.matches(&host.hostname))
+                .unwrap_or(false)
+        });
+        let env_matches = self
+            .env
+            .iter()
+            .all(|(name, value)| host.env_var(name) == Some(value.as_str()));
+
+        os_matches && hostname_matches && env_matches
+    }
+}
+
+impl Manifest {
+    /// Reads the manifest from the root of a shell. A shell without a
+    /// manifest gets an empty one.
+    pub fn load(shell_root: impl AsRef<Path>) -> Result<Manifest> {
+        Manifest::load_with(&RealFs, shell_root)
+    }
+
+    /// Like `load`, but reads the manifest from `fs`.
+    pub fn load_with(fs: &dyn FsBackend, shell_root: impl AsRef<Path>) -> Result<Manifest> {
+        let path = shell_root.as_ref().join(MANIFEST_FILE_NAME);
+        match fs.read_to_string(&path) {
+            Ok(contents) => Manifest::parse(&contents),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(err) => Err(Error::InvalidManifest(err.to_string())),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Manifest> {
+        let manifest: Manifest =
+            toml::from_str(contents).map_err(|err| Error::InvalidManifest(err.to_string()))?;
+        let copied_dir = manifest.files.iter().find(|entry| {
+            entry.granularity == Granularity::Directory && entry.strategy == Strategy::Copy
+        });
+        if let Some(entry) = copied_dir {
+            return Err(Error::InvalidManifest(format!(
+                "{} can't be copied as a whole directory, only linked",
+                entry.source.display()
+            )));
+        }
+        let escaping = manifest
+            .files
+            .iter()
+            .flat_map(|entry| iter::once(&entry.target).chain(entry.targets.values()))
+            .find(|target| !paths::stays_below(&home_relative(target)));
+        if let Some(target) = escaping {
+            return Err(Error::InvalidManifest(format!(
+                "the target {} is outside the home directory",
+                target.display()
+            )));
+        }
+        Ok(manifest)
+    }
+
+    /// The directories that are linked as a whole on `host`, relative
+    /// to the root of the shell. Nothing below them is linked on its
+    /// own.
+    pub fn whole_directories(&self, host: &Host) -> BTreeSet<PathBuf> {
+        self.files
+            .iter()
+            .filter(|entry| entry.granularity == Granularity::Directory && entry.applies_to(host))
+            .map(|entry| entry.source.clone())
+            .collect()
+    }
+
+    /// Works out where a file from the shell should be linked on
+    /// `host`, or `None` if it should not be linked there at all.
+    ///
+    /// When several entries apply, the one with the longest source
+    /// path wins, so a file entry can override the entry for the
+    /// directory that contains it.
+    pub fn resolve(&self, source: impl AsRef<Path>, host: &Host) -> Option<ShellFile> {
+        let source = source.as_ref();
+        let mut claimed = false;
+        let best_match = self
+            .files
+            .iter()
+            .filter_map(|entry| {
+                source
+                    .strip_prefix(&entry.source)
+                    .ok()
+                    .map(|rest| (entry, rest))
+            })
+            .inspect(|_| claimed = true)
+            .filter(|(entry, _)| entry.applies_to(host))
+            .max_by_key(|(entry, _)| entry.source.components().count());
+
+        match best_match {
+            Some((entry, rest)) => Some(
+                ShellFile::mapped(
+                    source,
+                    join_rest(home_relative(entry.target_for(host)), rest),
+                )
+                .with_strategy(entry.strategy)
+                .with_tags(&entry.tags)
+                .with_secret(entry.secret)
+                .with_granularity(if rest.as_os_str().is_empty() {
+                    entry.granularity
+                } else {
+                    Granularity::File
+                }),
+            ),
+            None if claimed => None,
+            None if self.add_dots => Some(ShellFile::mapped(source, with_leading_dot(source))),
+            None => Some(ShellFile::mirrored(source)),
+        }
+    }
+}
+
+/// Adds a `[[file]]` entry for `file` to the end of the text of a
+/// manifest, leaving the rest of it, comments and all, as it was.
+/// TOML can only hold UTF-8, so other paths can't be added.
+pub fn append_entry(manifest: &mut String, file: &ShellFile) -> Result<()> {
+    let quote = |path: &Path| match path.to_str() {
+        Some(path) => Ok(toml::Value::String(path.to_owned()).to_string()),
+        None => Err(Error::PathNotUtf8(path.to_path_buf())),
+    };
+    let source = quote(&file.source)?;
+    let target = quote(&Path::new("~").join(&file.target))?;
+
+    if !manifest.is_empty() {
+        if !manifest.ends_with('\n') {
+            manifest.push('\n');
+        }
+        manifest.push('\n');
+    }
+    manifest.push_str("[[file]]\n");
+    manifest.push_str(&format!("source = {}\n", source));
+    manifest.push_str(&format!("target = {}\n", target));
+    if file.strategy == Strategy::Copy {
+        manifest.push_str("strategy = \"copy\"\n");
+    }
+    Ok(())
+}
+
+/// Joins the rest of a source path below a manifest entry onto the
+/// entry's target. Joining an empty path would add a trailing slash,
+/// so an entry for a single file keeps its target as is.
+fn join_rest(target: PathBuf, rest: &Path) -> PathBuf {
+    if rest.as_os_str().is_empty() {
+        target
+    } else {
+        target.join(rest)
+    }
+}
+
+/// Adds a dot to the start of the first component of `path`, if it
+/// doesn't have one already.
+pub fn with_leading_dot(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    match components.next() {
+        Some(Component::Normal(first)) if !first.as_bytes().starts_with(b".") => {
+            let mut dotted = OsString::from(".");
+            dotted.push(first);
+            Path::new(&dotted).join(components.as_path())
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Takes the dot off the start of the first component of `path`, the
+/// reverse of `with_leading_dot`.
+pub fn without_leading_dot(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    match components.next() {
+        Some(Component::Normal(first)) if first.len() > 1 && first.as_bytes().starts_with(b".") => {
+            Path::new(OsStr::from_bytes(&first.as_bytes()[1..])).join(components.as_path())
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Strips a leading `~` from a manifest target, since all targets are
+/// already interpreted relative to the home directory.
+fn home_relative(target: &Path) -> PathBuf {
+    target
+        .strip_prefix("~")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(contents: &str) -> Manifest {
+        Manifest::parse(contents).expect("Failed to parse manifest")
+    }
+
+    fn host(os: &str, hostname: &str, env: Vec<(&str, &str)>) -> Host {
+        Host {
+            os: os.to_owned(),
+            hostname: hostname.to_owned(),
+            env: env
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+            ..Host::default()
+        }
+    }
+
+    fn any_host() -> Host {
+        host("linux", "laptop", vec![])
+    }
+
+    #[test]
+    fn appended_entries_can_be_read_back() {
+        let mut contents = "# My shell\n[[file]]\nsource = \"a\"\ntarget = \"~/b\"".to_owned();
+        let file =
+            ShellFile::mapped("gnupg/gpg.conf", ".gnupg/gpg.conf").with_strategy(Strategy::Copy);
+
+        append_entry(&mut contents, &file).unwrap();
+
+        assert!(contents.starts_with("# My shell\n"));
+        assert_eq!(
+            manifest(&contents).resolve("gnupg/gpg.conf", &any_host()),
+            Some(file)
+        );
+    }
+
+    #[test]
+    fn entries_for_paths_that_are_not_utf8_are_refused() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let mut contents = String::new();
+        let source = Path::new(OsStr::from_bytes(b"caf\xe9"));
+
+        assert_eq!(
+            append_entry(&mut contents, &ShellFile::mirrored(source)),
+            Err(Error::PathNotUtf8(source.to_path_buf()))
+        );
+        assert_eq!(contents, "");
+    }
+
+    #[test]
+    fn an_empty_manifest_has_no_entries() {
+        assert_eq!(manifest(""), Manifest::default());
+    }
+
+    #[test]
+    fn parses_file_entries() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = "kitty.conf"
+            target = "~/.config/kitty/kitty.conf"
+            "#,
+        );
+
+        assert_eq!(
+            manifest.files,
+            vec![Entry {
+                source: PathBuf::from("kitty.conf"),
+                target: PathBuf::from("~/.config/kitty/kitty.conf"),
+                when: None,
+                strategy: Strategy::Link,
+                tags: BTreeSet::new(),
+                targets: BTreeMap::new(),
+                secret: false,
+                granularity: Granularity::File,
+            }]
+        );
+    }
+
+    #[test]
+    fn directories_can_be_linked_as_a_whole() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = "nvim"
+            target = "~/.config/nvim"
+            granularity = "directory"
+
+            [[file]]
+            source = "sway"
+            target = "~/.config/sway"
+            granularity = "directory"
+            when = { os = "linux" }
+            "#,
+        );
+
+        assert_eq!(
+            manifest.whole_directories(&host("macos", "laptop", vec![])),
+            vec![PathBuf::from("nvim")].into_iter().collect()
+        );
+        assert_eq!(
+            manifest
+                .resolve("nvim", &any_host())
+                .map(|file| file.granularity),
+            Some(Granularity::Directory)
+        );
+        assert!(Manifest::parse(
+            "[[file]]\nsource = \"a\"\ntarget = \"~/a\"\n\
+             granularity = \"directory\"\nstrategy = \"copy\"\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn entries_can_copy_instead_of_linking() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = "gnupg"
+            target = "~/.gnupg"
+            strategy = "copy"
+            "#,
+        );
+
+        assert_eq!(
+            manifest.resolve("gnupg/gpg.conf", &Host::default()),
+            Some(
+                ShellFile::mapped("gnupg/gpg.conf", ".gnupg/gpg.conf")
+                    .with_strategy(Strategy::Copy)
+            )
+        );
+        assert_eq!(
+            manifest
+                .resolve(".bashrc", &Host::default())
+                .map(|file| file.strategy),
+            Some(Strategy::Link)
+        );
+    }
+
+    #[test]
+    fn targets_can_be_overridden_per_os() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = "gitconfig"
+            target = "~/.gitconfig"
+            targets.linux = "~/.config/git/config"
+            "#,
+        );
+
+        assert_eq!(
+            manifest.resolve("gitconfig", &host("linux", "laptop", vec![])),
+            Some(ShellFile::mapped("gitconfig", ".config/git/config"))
+        );
+        assert_eq!(
+            manifest.resolve("gitconfig", &host("macos", "laptop", vec![])),
+            Some(ShellFile::mapped("gitconfig", ".gitconfig"))
+        );
+    }
+
+    #[test]
+    fn adds_and_removes_leading_dots() {
+        assert_eq!(
+            with_leading_dot(Path::new("vimrc")),
+            PathBuf::from(".vimrc")
+        );
+        assert_eq!(
+            with_leading_dot(Path::new("config/nvim/init.lua")),
+            PathBuf::from(".config/nvim/init.lua")
+        );
+        assert_eq!(
+            with_leading_dot(Path::new(".bashrc")),
+            PathBuf::from(".bashrc")
+        );
+        assert_eq!(
+            without_leading_dot(Path::new(".vimrc")),
+            PathBuf::from("vimrc")
+        );
+        assert_eq!(
+            without_leading_dot(Path::new(".config/nvim")),
+            PathBuf::from("config/nvim")
+        );
+        assert_eq!(
+            without_leading_dot(Path::new("bin/x")),
+            PathBuf::from("bin/x")
+        );
+    }
+
+    #[test]
+    fn unmapped_files_can_get_leading_dots() {
+        let mut manifest = manifest(
+            r#"
+            [[file]]
+            source = "README.md"
+            target = "~/Documents/README.md"
+            "#,
+        );
+        manifest.add_dots = true;
+
+        assert_eq!(
+            manifest.resolve("vimrc", &any_host()),
+            Some(ShellFile::mapped("vimrc", ".vimrc"))
+        );
+        assert_eq!(
+            manifest.resolve("README.md", &any_host()),
+            Some(ShellFile::mapped("README.md", "Documents/README.md"))
+        );
+    }
+
+    #[test]
+    fn entries_can_mark_files_as_secret() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = ".netrc"
+            target = "~/.netrc"
+            secret = true
+            "#,
+        );
+
+        assert!(manifest.resolve(".netrc", &any_host()).unwrap().secret);
+        assert!(!manifest.resolve(".bashrc", &any_host()).unwrap().secret);
+    }
+
+    #[test]
+    fn files_carry_the_tags_of_their_entry() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = ".config/sway"
+            target = "~/.config/sway"
+            tags = ["gui", "linux"]
+            "#,
+        );
+
+        assert_eq!(
+            manifest.resolve(".config/sway/config", &Host::default()),
+            Some(ShellFile::mirrored(".config/sway/config").with_tags(["gui", "linux"]))
+        );
+    }
+
+    #[test]
+    fn tag_filters_always_allow_untagged_files() {
+        let filter = TagFilter {
+            only: ["server".to_owned()].into(),
+            skip: ["gui".to_owned()].into(),
+        };
+
+        assert!(filter.allows(&ShellFile::mirrored(".bashrc")));
+        assert!(filter.allows(&ShellFile::mirrored(".tmux.conf").with_tags(["server"])));
+        assert!(!filter.allows(&ShellFile::mirrored(".config/sway").with_tags(["gui"])));
+        assert!(!filter.allows(&ShellFile::mirrored(".work").with_tags(["work"])));
+        assert!(!filter.allows(&ShellFile::mirrored(".x").with_tags(["server", "gui"])));
+        assert!(TagFilter::default().allows(&ShellFile::mirrored(".x").with_tags(["gui"])));
+    }
+
+    #[test]
+    fn parses_bootstrap_steps_in_order() {
+        let manifest = manifest(
+            r#"
+            [[bootstrap]]
+            name = "packages"
+            run = "brew bundle"
+
+            [[bootstrap]]
+            name = "plugins"
+            script = "bin/install-plugins"
+            when = { os = "linux" }
+            "#,
+        );
+
+        let names = manifest
+            .bootstrap
+            .iter()
+            .map(|step| step.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["packages", "plugins"]);
+        assert_eq!(
+            manifest.bootstrap[1].script,
+            Some(PathBuf::from("bin/install-plugins"))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_manifests() {
+        let result = Manifest::parse("[[file]]\nsource = 12");
+        assert!(matches!(result, Err(Error::InvalidManifest(_))));
+    }
+
+    #[test]
+    fn rejects_targets_outside_the_home_directory() {
+        for target in &["/etc/passwd", "~/../../etc/passwd", ".config/../../x"] {
+            let result = Manifest::parse(&format!(
+                "[[file]]\nsource = \"bashrc\"\ntarget = \"{}\"",
+                target
+            ));
+            assert!(
+                matches!(result, Err(Error::InvalidManifest(_))),
+                "'{}' should be rejected",
+                target
+            );
+        }
+        let result = Manifest::parse(
+            "[[file]]\nsource = \"gitconfig\"\ntarget = \".gitconfig\"\n\
+             targets = { macos = \"/Library/gitconfig\" }",
+        );
+        assert!(matches!(result, Err(Error::InvalidManifest(_))));
+        assert!(
+            Manifest::parse("[[file]]\nsource = \"bashrc\"\ntarget = \"~/.config/./bash\"").is_ok()
+        );
+    }
+
+    #[test]
+    fn unmapped_files_mirror_the_shell_layout() {
+        let manifest = manifest("");
+        assert_eq!(
+            manifest.resolve(".bashrc", &any_host()),
+            Some(ShellFile::mirrored(".bashrc"))
+        );
+    }
+
+    #[test]
+    fn maps_a_file_to_its_target() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = "kitty.conf"
+            target = "~/.config/kitty/kitty.conf"
+            "#,
+        );
+
+        assert_eq!(
+            manifest.resolve("kitty.conf", &any_host()),
+            Some(ShellFile::mapped("kitty.conf", ".config/kitty/kitty.conf"))
+        );
+    }
+
+    #[test]
+    fn maps_files_underneath_a_directory_entry() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = "nvim"
+            target = ".config/nvim"
+            "#,
+        );
+
+        assert_eq!(
+            manifest.resolve("nvim/lua/plugins.lua", &any_host()),
+            Some(ShellFile::mapped(
+                "nvim/lua/plugins.lua",
+                ".config/nvim/lua/plugins.lua"
+            ))
+        );
+        assert_eq!(
+            manifest.resolve("nvimrc", &any_host()),
+            Some(ShellFile::mirrored("nvimrc"))
+        );
+    }
+
+    #[test]
+    fn the_most_specific_entry_wins() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = "nvim"
+            target = ".config/nvim"
+
+            [[file]]
+            source = "nvim/init.vim"
+            target = ".vimrc"
+            "#,
+        );
+
+        assert_eq!(
+            manifest.resolve("nvim/init.vim", &any_host()),
+            Some(ShellFile::mapped("nvim/init.vim", ".vimrc"))
+        );
+    }
+
+    #[test]
+    fn when_clauses_match_the_os() {
+        let when = When {
+            os: Some("macos".to_owned()),
+            ..When::default()
+        };
+
+        assert!(when.matches(&host("macos", "laptop", vec![])));
+        assert!(!when.matches(&host("linux", "laptop", vec![])));
+    }
+
+    #[test]
+    fn when_clauses_match_hostname_globs() {
+        let when = When {
+            hostname: Some("work-*".to_owned()),
+            ..When::default()
+        };
+
+        assert!(when.matches(&host("linux", "work-desktop", vec![])));
+        assert!(!when.matches(&host("linux", "home-desktop", vec![])));
+    }
+
+    #[test]
+    fn when_clauses_match_environment_variables() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = "wsl.conf"
+            target = ".config/wsl.conf"
+            when = { env.WSL = "1" }
+            "#,
+        );
+        let when = manifest.files[0].when.as_ref().unwrap();
+
+        assert!(when.matches(&host("linux", "pc", vec![("WSL", "1")])));
+        assert!(!when.matches(&host("linux", "pc", vec![("WSL", "0")])));
+        assert!(!when.matches(&host("linux", "pc", vec![])));
+    }
+
+    #[test]
+    fn every_condition_must_hold() {
+        let when = When {
+            os: Some("linux".to_owned()),
+            hostname: Some("work-*".to_owned()),
+            ..When::default()
+        };
+
+        assert!(when.matches(&host("linux", "work-1", vec![])));
+        assert!(!when.matches(&host("macos", "work-1", vec![])));
+        assert!(!when.matches(&host("linux", "home-1", vec![])));
+    }
+
+    #[test]
+    fn conditional_entries_choose_between_files() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = "gitconfig-work"
+            target = ".gitconfig"
+            when = { hostname = "work-*" }
+
+            [[file]]
+            source = "gitconfig-home"
+            target = ".gitconfig"
+            when = { hostname = "home-*" }
+            "#,
+        );
+        let work = host("linux", "work-laptop", vec![]);
+
+        assert_eq!(
+            manifest.resolve("gitconfig-work", &work),
+            Some(ShellFile::mapped("gitconfig-work", ".gitconfig"))
+        );
+        assert_eq!(manifest.resolve("gitconfig-home", &work), None);
+    }
+
+    #[test]
+    fn a_more_general_entry_can_apply_when_a_specific_one_does_not() {
+        let manifest = manifest(
+            r#"
+            [[file]]
+            source = "nvim"
+            target = ".config/nvim"
+
+            [[file]]
+            source = "nvim/mac.vim"
+            target = ".config/nvim/plugin/mac.vim"
+            when = { os = "macos" }
+            "#,
+        );
+
+        assert_eq!(
+            manifest.resolve("nvim/mac.vim", &any_host()),
+            Some(ShellFile::mapped("nvim/mac.vim", ".config/nvim/mac.vim"))
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use crate::common::*;
 
+use std::os::unix::ffi::OsStrExt;
+
 pub fn error_str<T: 'static + Into<String>>(details: T) -> String {
     error(anyhow::Error::msg(details.into()))
 }
@@ -7,3 +9,90 @@ pub fn error_str<T: 'static + Into<String>>(details: T) -> String {
 pub fn error(failure: impl Display) -> String {
     format!("{}: error: {}", env::get_program_name(), failure)
 }
+
+/// Tells the user about an error on stderr, which is where every
+/// diagnostic goes, so that stdout only has what a command outputs.
+pub fn report(failure: impl Display) {
+    eprintln!("{}", error(failure));
+}
+
+/// Tells the user about something that went wrong on stderr, when it
+/// isn't an error in itself.
+pub fn note(details: impl Display) {
+    eprintln!("{}: {}", env::get_program_name(), details);
+}
+
+/// Shows a path so that it can be read back exactly. Plain paths are
+/// shown as they are, and paths with spaces, quotes, control
+/// characters or bytes that aren't UTF-8 are quoted, with the odd
+/// characters escaped, instead of being mangled the way
+/// `Path::display` mangles them.
+pub fn path(path: impl AsRef<Path>) -> String {
+    let bytes = path.as_ref().as_os_str().as_bytes();
+    if !bytes
+        .utf8_chunks()
+        .any(|chunk| !chunk.invalid().is_empty() || chunk.valid().chars().any(needs_quoting))
+    {
+        return path.as_ref().display().to_string();
+    }
+
+    let mut shown = String::from("\"");
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' | '\\' => {
+                    shown.push('\\');
+                    shown.push(c);
+                }
+                c if c.is_control() => shown.extend(c.escape_default()),
+                c => shown.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            shown.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    shown.push('"');
+    shown
+}
+
+/// Shows several paths, the way `path` shows each, separated by commas.
+pub fn paths(paths: &[impl AsRef<Path>]) -> String {
+    paths.iter().map(path).collect::<Vec<_>>().join(", ")
+}
+
+fn needs_quoting(c: char) -> bool {
+    c.is_whitespace() || c.is_control() || c == '"' || c == '\'' || c == '\\'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::OsStr;
+
+    #[test]
+    fn plain_paths_are_shown_as_they_are() {
+        assert_eq!(
+            path("/home/geoff/.config/nvim/init.lua"),
+            "/home/geoff/.config/nvim/init.lua"
+        );
+        assert_eq!(path("/home/geoff/café"), "/home/geoff/café");
+    }
+
+    #[test]
+    fn paths_with_spaces_and_quotes_are_quoted() {
+        assert_eq!(
+            path("/home/geoff/My Documents"),
+            "\"/home/geoff/My Documents\""
+        );
+        assert_eq!(path("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(path("tab\there"), "\"tab\\there\"");
+    }
+
+    #[test]
+    fn bytes_that_are_not_utf8_are_escaped() {
+        let name = Path::new(OsStr::from_bytes(b"/home/geoff/caf\xe9"));
+        assert_eq!(path(name), "\"/home/geoff/caf\\xe9\"");
+    }
+}
@@ -0,0 +1,91 @@
+//! Telling whoever uses hermit, like the command line or a GUI, what
+//! it is doing while it does it.
+//!
+//! Observers are handed to `Hermit` and `FileOperations`, which call
+//! them as things happen, rather than only reporting once a command
+//! is done.
+
+use crate::{common::*, file_operations::Outcome, hooks};
+
+/// Something that hermit did, or ran into.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A new shell has been set up. Like every change, it only exists
+    /// on disk once the queued operations have been carried out.
+    ShellCreated { name: String },
+    /// A link has been made at `path`, pointing to `target`.
+    FileLinked { path: PathBuf, target: PathBuf },
+    /// Something was in the way of a link or copy at `path`. Unless
+    /// conflicts are set to be forced, the operation then fails.
+    ConflictDetected { path: PathBuf },
+    /// A file was copied to `path`, but the extended attributes
+    /// `names` couldn't be copied along with it.
+    AttributesDropped { path: PathBuf, names: Vec<String> },
+    /// An operation was carried out, or skipped, with what it set out
+    /// to do.
+    OpDone {
+        description: String,
+        outcome: Outcome,
+    },
+    /// An operation failed, with what it set out to do and why.
+    OpFailed { description: String, error: String },
+    /// A change was made right away rather than queued as an
+    /// operation, like a commit or switching shells, with why it failed
+    /// if it did.
+    ChangeMade {
+        description: String,
+        error: Option<String>,
+    },
+    /// A hook of a shell has run for `event`, with its path within the
+    /// shell's hooks directory and everything it printed.
+    HookRan {
+        event: hooks::Event,
+        hook: PathBuf,
+        output: Vec<u8>,
+        failed: bool,
+    },
+    /// A bootstrap step of a shell is about to run.
+    BootstrapStepStarted { name: String },
+    /// A batch of queued operations has been carried out.
+    OperationsDone { changed: usize, failed: usize },
+}
+
+/// Something that wants to hear about hermit's events.
+pub trait Observer {
+    fn notify(&self, event: &Event);
+}
+
+/// Everything that is listening to one source of events.
+#[derive(Clone, Default)]
+pub struct Observers(Vec<Rc<dyn Observer>>);
+
+impl Observers {
+    pub fn add(&mut self, observer: Rc<dyn Observer>) {
+        self.0.push(observer);
+    }
+
+    pub fn notify(&self, event: Event) {
+        for observer in &self.0 {
+            observer.notify(&event);
+        }
+    }
+}
+
+/// An observer that keeps every event it hears about, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Recorder(std::cell::RefCell<Vec<Event>>);
+
+#[cfg(test)]
+impl Recorder {
+    pub fn events(&self) -> Vec<Event> {
+        self.0.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+impl Observer for Recorder {
+    fn notify(&self, event: &Event) {
+        self.0.borrow_mut().push(event.clone());
+    }
+}
@@ -0,0 +1,409 @@
+use crate::common::*;
+
+use std::collections::BTreeSet;
+
+/// The directory inside a shell where package lists are kept.
+pub const PACKAGES_DIR: &str = "packages";
+
+/// A package manager that hermit knows how to compare a package list
+/// against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Manager {
+    Brew,
+    Apt,
+    Pacman,
+}
+
+/// A package named in a package list. Only Homebrew distinguishes
+/// casks from ordinary formulae.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Package {
+    pub name: String,
+    pub cask: bool,
+}
+
+impl Package {
+    pub fn new(name: impl Into<String>) -> Package {
+        Package {
+            name: name.into(),
+            cask: false,
+        }
+    }
+
+    pub fn cask(name: impl Into<String>) -> Package {
+        Package {
+            name: name.into(),
+            cask: true,
+        }
+    }
+}
+
+impl Manager {
+    pub const ALL: [Manager; 3] = [Manager::Brew, Manager::Apt, Manager::Pacman];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Manager::Brew => "brew",
+            Manager::Apt => "apt",
+            Manager::Pacman => "pacman",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Manager> {
+        Manager::ALL
+            .iter()
+            .copied()
+            .find(|manager| manager.name() == name)
+    }
+
+    /// The name of this manager's package list inside `packages/`.
+    pub fn list_file_name(self) -> &'static str {
+        match self {
+            Manager::Brew => "Brewfile",
+            Manager::Apt => "apt.txt",
+            Manager::Pacman => "pacman.txt",
+        }
+    }
+
+    fn parse_line(self, line: &str) -> Option<Package> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        match self {
+            Manager::Brew => {
+                let (kind, rest) = line.split_at(line.find(char::is_whitespace)?);
+                let name = rest.trim().split(',').next()?.trim().trim_matches('"');
+                match kind {
+                    "brew" => Some(Package::new(name)),
+                    "cask" => Some(Package::cask(name)),
+                    _ => None,
+                }
+            }
+            Manager::Apt | Manager::Pacman => Some(Package::new(line)),
+        }
+    }
+
+    fn format_line(self, package: &Package) -> String {
+        match self {
+            Manager::Brew if package.cask => format!("cask \"{}\"", package.name),
+            Manager::Brew => format!("brew \"{}\"", package.name),
+            Manager::Apt | Manager::Pacman => package.name.clone(),
+        }
+    }
+
+    /// Asks the package manager which packages are installed.
+    pub fn installed(self) -> Result<BTreeSet<Package>> {
+        match self {
+            Manager::Brew => {
+                let formulae = self.query(&["brew", "list", "--formula", "-1"])?;
+                let casks = self.query(&["brew", "list", "--cask", "-1"])?;
+                Ok(formulae
+                    .into_iter()
+                    .map(Package::new)
+                    .chain(casks.into_iter().map(Package::cask))
+                    .collect())
+            }
+            Manager::Apt => Ok(self
+                .query(&["dpkg-query", "-W", "-f=${Package}\\n"])?
+                .into_iter()
+                .map(Package::new)
+                .collect()),
+            Manager::Pacman => Ok(self
+                .query(&["pacman", "-Qq"])?
+                .into_iter()
+                .map(Package::new)
+                .collect()),
+        }
+    }
+
+    /// Installs the given packages, handing the terminal over to the
+    /// package manager so it can prompt for passwords.
+    pub fn install(self, packages: &[Package]) -> Result<()> {
+        let names = |cask: bool| {
+            packages
+                .iter()
+                .filter(|package| package.cask == cask)
+                .map(|package| package.name.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        let (formulae, casks) = (names(false), names(true));
+        if !formulae.is_empty() {
+            let command: &[&str] = match self {
+                Manager::Brew => &["brew", "install"],
+                Manager::Apt => &["sudo", "apt-get", "install", "-y"],
+                Manager::Pacman => &["sudo", "pacman", "-S", "--needed", "--noconfirm"],
+            };
+            self.run(command, &formulae)?;
+        }
+        if !casks.is_empty() {
+            self.run(&["brew", "install", "--cask"], &casks)?;
+        }
+        Ok(())
+    }
+
+    fn query(self, command: &[&str]) -> Result<Vec<String>> {
+        let output = process::Command::new(command[0])
+            .args(&command[1..])
+            .output()
+            .map_err(|err| self.failed(err))?;
+        if !output.status.success() {
+            return Err(self.failed(output.status));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn run(self, command: &[&str], args: &[&str]) -> Result<()> {
+        let status = process::Command::new(command[0])
+            .args(&command[1..])
+            .args(args)
+            .status()
+            .map_err(|err| self.failed(err))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(self.failed(status))
+        }
+    }
+
+    fn failed(self, reason: impl Display) -> Error {
+        Error::PackageManagerFailed(self.name(), reason.to_string())
+    }
+}
+
+/// The packages a shell wants one package manager to install.
+#[derive(Clone, Debug)]
+pub struct PackageList {
+    pub manager: Manager,
+    path: PathBuf,
+    packages: Vec<Package>,
+    contents: String,
+}
+
+impl PackageList {
+    pub fn path_in(shell_root: impl AsRef<Path>, manager: Manager) -> PathBuf {
+        shell_root
+            .as_ref()
+            .join(PACKAGES_DIR)
+            .join(manager.list_file_name())
+    }
+
+    /// Reads the package list for `manager` from a shell. A shell
+    /// without a list for it gets an empty one.
+    pub fn load(shell_root: impl AsRef<Path>, manager: Manager) -> io::Result<PackageList> {
+        let path = PackageList::path_in(shell_root, manager);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(PackageList::parse(manager, &path, &contents))
+    }
+
+    fn parse(manager: Manager, path: &Path, contents: &str) -> PackageList {
+        let packages = contents
+            .lines()
+            .filter_map(|line| manager.parse_line(line))
+            .collect();
+        PackageList {
+            manager,
+            path: PathBuf::from(path),
+            packages,
+            contents: contents.to_owned(),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.is_file()
+    }
+
+    pub fn packages(&self) -> &[Package] {
+        &self.packages
+    }
+
+    /// The packages in this list that are not installed.
+    pub fn missing(&self, installed: &BTreeSet<Package>) -> Vec<Package> {
+        self.packages
+            .iter()
+            .filter(|package| !installed.contains(package))
+            .cloned()
+            .collect()
+    }
+
+    /// Adds a line for a package to the end of the list, leaving any
+    /// lines hermit does not understand (like Homebrew taps) alone.
+    /// Returns false if the package was already listed. Nothing is
+    /// written until the list is [saved](PackageList::save).
+    pub fn add(&mut self, package: Package) -> bool {
+        if self.packages.contains(&package) {
+            return false;
+        }
+
+        if !self.contents.is_empty() && !self.contents.ends_with('\n') {
+            self.contents.push('\n');
+        }
+        self.contents.push_str(&self.manager.format_line(&package));
+        self.contents.push('\n');
+        self.packages.push(package);
+        true
+    }
+
+    /// Queues writing the list file as it is now.
+    pub fn save(&self, file_operations: &mut FileOperations) {
+        file_operations.write_file(&self.path, self.contents.clone());
+    }
+}
+
+/// Pairs each package list of the shell at `shell_root` with the
+/// packages from it that are not installed, or with why that can't be
+/// told, like a package manager that isn't on this machine. With
+/// `only`, the lists of the other package managers are left out.
+pub fn missing(
+    shell_root: impl AsRef<Path>,
+    only: Option<Manager>,
+) -> Vec<(PackageList, Result<Vec<Package>>)> {
+    let shell_root = shell_root.as_ref();
+    Manager::ALL
+        .iter()
+        .filter(|manager| only.is_none_or(|only| only == **manager))
+        .filter_map(|manager| PackageList::load(shell_root, *manager).ok())
+        .filter(PackageList::exists)
+        .map(|list| {
+            let missing = list
+                .manager
+                .installed()
+                .map(|installed| list.missing(&installed));
+            (list, missing)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    fn list(manager: Manager, contents: &str) -> PackageList {
+        PackageList::parse(manager, Path::new("list"), contents)
+    }
+
+    #[test]
+    fn parses_plain_package_lists() {
+        let list = list(Manager::Apt, "# tools\nripgrep\n\n  fd-find  \n");
+        assert_eq!(
+            list.packages(),
+            &[Package::new("ripgrep"), Package::new("fd-find")]
+        );
+    }
+
+    #[test]
+    fn parses_brewfiles() {
+        let list = list(
+            Manager::Brew,
+            r#"
+            tap "homebrew/cask-fonts"
+            brew "git"
+            brew "neovim", args: ["HEAD"]
+            cask "kitty"
+            "#,
+        );
+        assert_eq!(
+            list.packages(),
+            &[
+                Package::new("git"),
+                Package::new("neovim"),
+                Package::cask("kitty")
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_missing_packages() {
+        let list = list(Manager::Pacman, "git\nneovim\ntmux\n");
+        let installed = vec![Package::new("git"), Package::new("tmux")]
+            .into_iter()
+            .collect();
+
+        assert_eq!(list.missing(&installed), vec![Package::new("neovim")]);
+    }
+
+    #[test]
+    fn casks_and_formulae_are_different_packages() {
+        let list = list(Manager::Brew, "cask \"docker\"\n");
+        let installed = vec![Package::new("docker")].into_iter().collect();
+
+        assert_eq!(list.missing(&installed), vec![Package::cask("docker")]);
+    }
+
+    #[test]
+    fn a_shell_without_a_list_has_no_packages() {
+        let test_root_dir = set_up();
+        let list = PackageList::load(test_root_dir.path(), Manager::Apt).unwrap();
+
+        assert!(!list.exists());
+        assert!(list.packages().is_empty());
+    }
+
+    #[test]
+    fn adding_packages_appends_to_the_list_file() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let path = PackageList::path_in(test_root, Manager::Brew);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "tap \"homebrew/cask-fonts\"\n").unwrap();
+
+        let mut list = PackageList::load(test_root, Manager::Brew).unwrap();
+        assert!(list.add(Package::new("git")));
+        assert!(list.add(Package::cask("kitty")));
+        assert!(!list.add(Package::new("git")));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "tap \"homebrew/cask-fonts\"\n"
+        );
+
+        let mut file_operations = FileOperations::rooted_at(test_root);
+        list.save(&mut file_operations);
+        assert!(file_operations.commit().is_success());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "tap \"homebrew/cask-fonts\"\nbrew \"git\"\ncask \"kitty\"\n"
+        );
+    }
+
+    #[test]
+    fn only_lists_that_exist_are_checked_for_missing_packages() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        for manager in &[Manager::Brew, Manager::Apt] {
+            let path = PackageList::path_in(test_root, *manager);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "").unwrap();
+        }
+
+        let managers = |only| {
+            missing(test_root, only)
+                .into_iter()
+                .map(|(list, _)| list.manager)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(managers(None), vec![Manager::Brew, Manager::Apt]);
+        assert_eq!(managers(Some(Manager::Apt)), vec![Manager::Apt]);
+        assert_eq!(managers(Some(Manager::Pacman)), vec![]);
+    }
+
+    #[test]
+    fn managers_can_be_found_by_name() {
+        assert_eq!(Manager::from_name("pacman"), Some(Manager::Pacman));
+        assert_eq!(Manager::from_name("yum"), None);
+    }
+}
@@ -0,0 +1,49 @@
+//! Spreading work that mostly waits on the filesystem across threads,
+//! so that a shell with tens of thousands of files is still quick to
+//! look at.
+
+use std::{num::NonZeroUsize, panic, thread};
+
+/// Fewer items than this aren't worth starting threads for.
+pub const MIN_ITEMS: usize = 64;
+
+/// `f` applied to each of `items`, in the same order, with the items
+/// split between as many threads as the machine can run at once.
+pub fn map<T: Sync, U: Send>(items: &[T], f: impl Fn(&T) -> U + Sync) -> Vec<U> {
+    let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    if items.len() < MIN_ITEMS || threads == 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let chunk_size = items.len().div_ceil(threads);
+    let f = &f;
+    thread::scope(|scope| {
+        let chunks = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        chunks
+            .into_iter()
+            .flat_map(|chunk| {
+                chunk
+                    .join()
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic))
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_keep_the_order_of_the_items() {
+        let items = (0..1000).collect::<Vec<_>>();
+
+        assert_eq!(
+            map(&items, |n| n * 2),
+            (0..1000).map(|n| n * 2).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,398 @@
+use crate::common::*;
+
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Component};
+
+/// Turns a path given on the command line into the files it names.
+///
+/// `~` at the start stands for `home`, and relative paths are taken
+/// relative to `cwd`. Glob patterns are expanded here rather than by
+/// the user's shell, so that quoting them behaves the same
+/// everywhere, and directories are replaced by all of the files
+/// inside them. Paths don't have to be valid UTF-8, but patterns do.
+pub fn expand(arg: impl AsRef<OsStr>, cwd: &Path, home: &Path) -> Result<Vec<PathBuf>> {
+    let arg = arg.as_ref();
+    let bytes = arg.as_bytes();
+    let path = if bytes == b"~" {
+        home.to_path_buf()
+    } else if let Some(rest) = bytes.strip_prefix(b"~/") {
+        home.join(OsStr::from_bytes(rest))
+    } else {
+        cwd.join(arg)
+    };
+    let path = normalize(&path);
+
+    let matches = if is_pattern(bytes) {
+        let shown = message::path(arg);
+        let pattern = path.to_str().ok_or_else(|| {
+            Error::InvalidPattern(shown.clone(), "it isn't valid UTF-8".to_owned())
+        })?;
+        let options = glob::MatchOptions {
+            require_literal_leading_dot: false,
+            ..glob::MatchOptions::new()
+        };
+        let matches = glob::glob_with(pattern, options)
+            .map_err(|err| Error::InvalidPattern(shown.clone(), err.to_string()))?
+            .filter_map(StdResult::ok)
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            return Err(Error::NoMatches(shown));
+        }
+        matches
+    } else if fs::symlink_metadata(&path).is_ok() {
+        vec![path]
+    } else {
+        return Err(Error::NoSuchFile(PathBuf::from(arg)));
+    };
+
+    let mut files = vec![];
+    for path in matches {
+        if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+            files.extend(files_in(&path));
+        } else {
+            files.push(path);
+        }
+    }
+    files.dedup();
+    Ok(files)
+}
+
+fn is_pattern(arg: &[u8]) -> bool {
+    arg.iter().any(|byte| b"*?[".contains(byte))
+}
+
+/// All of the files below `dir`, skipping git repositories.
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    WalkDir::new(dir)
+        .follow_links(false)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .filter_map(StdResult::ok)
+        .filter(|entry| !entry.file_type().is_dir())
+        .map(walkdir::DirEntry::into_path)
+        .collect()
+}
+
+/// Removes `.` and `..` components from `path` without touching the
+/// filesystem.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Whether `path`, taken relative to some directory, stays below that
+/// directory: it isn't absolute and has no `..` in it.
+pub fn stays_below(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Makes sure that `target`, where one of a shell's files goes
+/// relative to the home directory, doesn't lead out of it.
+pub fn check_target(target: &Path) -> Result<()> {
+    if stays_below(target) {
+        return Ok(());
+    }
+    Err(Error::UnsafeLink {
+        path: target.to_path_buf(),
+        reason: "it would be outside the home directory",
+    })
+}
+
+/// The most links followed before giving up, the same as Linux.
+const MAX_LINK_HOPS: usize = 40;
+
+/// Makes sure that a link at `path` to `target`, a file inside
+/// `shell_root`, wouldn't link a file to itself, end up inside the
+/// shell because a directory above it is already linked there, or
+/// send anything following it around in circles.
+pub fn check_link(path: &Path, target: &Path, shell_root: &Path) -> Result<()> {
+    let unsafe_link = |reason| {
+        Err(Error::UnsafeLink {
+            path: path.to_path_buf(),
+            reason,
+        })
+    };
+
+    let link = resolve_parent(path);
+    if link == resolve_parent(target) {
+        return unsafe_link("it would link to itself");
+    }
+    if link.starts_with(resolve(shell_root)) {
+        return unsafe_link("it is inside the shell");
+    }
+
+    let mut hop = target.to_path_buf();
+    for _ in 0..MAX_LINK_HOPS {
+        match fs::read_link(&hop) {
+            Ok(next) => hop = resolve_parent(&hop.parent().unwrap_or(&hop).join(next)),
+            Err(_) => return Ok(()),
+        }
+        if hop == link {
+            return unsafe_link("the file it would link to links back to it");
+        }
+    }
+    unsafe_link("the links from the file it would link to go around in circles")
+}
+
+/// Resolves the links in the directories above `path`, but not
+/// `path` itself.
+fn resolve_parent(path: &Path) -> PathBuf {
+    let path = normalize(path);
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => resolve(parent).join(name),
+        _ => path,
+    }
+}
+
+/// Resolves all of the links in as much of `path` as exists.
+fn resolve(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find_map(|ancestor| {
+            let rest = path.strip_prefix(ancestor).ok()?;
+            fs::canonicalize(ancestor).ok().map(|real| real.join(rest))
+        })
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    fn create_files(root: &Path, paths: &[&str]) {
+        for path in paths {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+    }
+
+    #[test]
+    fn normalizes_dots_away() {
+        assert_eq!(
+            normalize(Path::new("/home/geoff/./src/../.bashrc")),
+            PathBuf::from("/home/geoff/.bashrc")
+        );
+    }
+
+    #[test]
+    fn targets_have_to_stay_below_the_home_directory() {
+        assert!(check_target(Path::new(".config/./kitty")).is_ok());
+        for target in &["/etc/passwd", "../other/.bashrc", ".config/../../x"] {
+            assert!(
+                matches!(
+                    check_target(Path::new(target)),
+                    Err(Error::UnsafeLink { .. })
+                ),
+                "'{}' should be rejected",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn expands_home_and_relative_paths() {
+        let test_root_dir = set_up();
+        let home = test_root_dir.path();
+        create_files(home, &[".bashrc"]);
+
+        assert_eq!(
+            expand("~/.bashrc", Path::new("/elsewhere"), home).unwrap(),
+            vec![home.join(".bashrc")]
+        );
+        assert_eq!(
+            expand("../.bashrc", &home.join("src"), home).unwrap(),
+            vec![home.join(".bashrc")]
+        );
+    }
+
+    #[test]
+    fn missing_files_are_an_error() {
+        let test_root_dir = set_up();
+        let home = test_root_dir.path();
+
+        assert_eq!(
+            expand(".bashrc", home, home),
+            Err(Error::NoSuchFile(PathBuf::from(".bashrc")))
+        );
+    }
+
+    #[test]
+    fn directories_expand_to_the_files_inside_them() {
+        let test_root_dir = set_up();
+        let home = test_root_dir.path();
+        create_files(
+            home,
+            &[".config/nvim/init.lua", ".config/nvim/lua/plugins.lua"],
+        );
+        fs::create_dir_all(home.join(".config/nvim/.git")).unwrap();
+        fs::write(home.join(".config/nvim/.git/HEAD"), "").unwrap();
+
+        assert_eq!(
+            expand("~/.config/nvim", home, home).unwrap(),
+            vec![
+                home.join(".config/nvim/init.lua"),
+                home.join(".config/nvim/lua/plugins.lua"),
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_glob_patterns() {
+        let test_root_dir = set_up();
+        let home = test_root_dir.path();
+        create_files(
+            home,
+            &[
+                ".config/nvim/init.lua",
+                ".config/nvim/lua/plugins.lua",
+                ".config/nvim/README.md",
+            ],
+        );
+
+        assert_eq!(
+            expand("~/.config/nvim/**/*.lua", home, home).unwrap(),
+            vec![
+                home.join(".config/nvim/init.lua"),
+                home.join(".config/nvim/lua/plugins.lua"),
+            ]
+        );
+        assert_eq!(
+            expand("~/.config/*.toml", home, home),
+            Err(Error::NoMatches("~/.config/*.toml".to_owned()))
+        );
+    }
+
+    #[test]
+    fn expands_paths_that_are_not_utf8() {
+        let test_root_dir = set_up();
+        let home = test_root_dir.path();
+        let name = OsStr::from_bytes(b"caf\xe9 notes");
+        fs::create_dir_all(home.join("docs")).unwrap();
+        fs::write(home.join("docs").join(name), "").unwrap();
+
+        assert_eq!(
+            expand(Path::new("~/docs").join(name), home, home).unwrap(),
+            vec![home.join("docs").join(name)]
+        );
+        assert_eq!(
+            expand("~/docs", home, home).unwrap(),
+            vec![home.join("docs").join(name)]
+        );
+        assert!(matches!(
+            expand(
+                Path::new("~/docs").join(OsStr::from_bytes(b"caf\xe9*")),
+                home,
+                home
+            ),
+            Err(Error::InvalidPattern(..))
+        ));
+    }
+
+    fn link_error(path: &Path, reason: &'static str) -> Result<()> {
+        Err(Error::UnsafeLink {
+            path: path.to_path_buf(),
+            reason,
+        })
+    }
+
+    #[test]
+    fn ordinary_links_are_fine() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let shell_root = root.join("shells/default");
+        create_files(&shell_root, &[".bashrc"]);
+
+        assert_eq!(
+            check_link(
+                &root.join("home/.bashrc"),
+                &shell_root.join(".bashrc"),
+                &shell_root
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn links_to_themselves_are_refused() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let shell_root = root.join("shells/default");
+        create_files(&shell_root, &[".bashrc"]);
+        unix::fs::symlink(&shell_root, root.join("home")).unwrap();
+        let path = root.join("home/.bashrc");
+
+        assert_eq!(
+            check_link(&path, &shell_root.join(".bashrc"), &shell_root),
+            link_error(&path, "it would link to itself")
+        );
+    }
+
+    #[test]
+    fn links_inside_linked_directories_are_refused() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let shell_root = root.join("shells/default");
+        create_files(&shell_root, &[".config/nvim/init.lua"]);
+        fs::create_dir_all(root.join("home")).unwrap();
+        unix::fs::symlink(shell_root.join(".config"), root.join("home/.config")).unwrap();
+        let path = root.join("home/.config/kitty.conf");
+
+        assert_eq!(
+            check_link(&path, &shell_root.join("kitty.conf"), &shell_root),
+            link_error(&path, "it is inside the shell")
+        );
+    }
+
+    #[test]
+    fn links_that_would_loop_are_refused() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let shell_root = root.join("shells/default");
+        let path = root.join("home/.bashrc");
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::create_dir_all(root.join("home")).unwrap();
+        unix::fs::symlink(&path, shell_root.join(".bashrc")).unwrap();
+        unix::fs::symlink(shell_root.join(".a"), shell_root.join(".b")).unwrap();
+        unix::fs::symlink(shell_root.join(".b"), shell_root.join(".a")).unwrap();
+
+        assert_eq!(
+            check_link(&path, &shell_root.join(".bashrc"), &shell_root),
+            link_error(&path, "the file it would link to links back to it")
+        );
+        assert_eq!(
+            check_link(&root.join("home/.a"), &shell_root.join(".a"), &shell_root),
+            link_error(
+                &root.join("home/.a"),
+                "the links from the file it would link to go around in circles"
+            )
+        );
+    }
+
+    #[test]
+    fn expands_very_deep_paths() {
+        let test_root_dir = set_up();
+        let home = test_root_dir.path();
+        let deep = (0..64)
+            .map(|n| format!("level {}", n))
+            .collect::<Vec<_>>()
+            .join("/");
+        create_files(home, &[&format!("{}/file", deep)]);
+
+        assert_eq!(
+            expand("~/level 0", home, home).unwrap(),
+            vec![home.join(&deep).join("file")]
+        );
+    }
+}
@@ -0,0 +1,603 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    common::*,
+    file_operations::{self, ConflictPolicy, Op},
+    fs_backend::{FileKind, FsBackend},
+    journal::{hex, os_path},
+};
+
+/// The version of the format plans are saved in. Plans saved in any
+/// other version are refused rather than guessed at.
+const SAVED_PLAN_VERSION: u32 = 1;
+
+/// What a step of a plan does to what is at its path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    Create,
+    Replace,
+    Remove,
+    /// Something is in the way, and the step will fail unless it is
+    /// forced.
+    Conflict,
+}
+
+impl Action {
+    /// The marker that shows the action in front of a step.
+    pub fn marker(self) -> char {
+        match self {
+            Action::Create => '+',
+            Action::Replace => '~',
+            Action::Remove => '-',
+            Action::Conflict => '!',
+        }
+    }
+
+    /// The ANSI escape code for the color of the action.
+    fn color(self) -> &'static str {
+        match self {
+            Action::Create => "\x1b[32m",
+            Action::Replace => "\x1b[33m",
+            Action::Remove => "\x1b[31m",
+            Action::Conflict => "\x1b[1;31m",
+        }
+    }
+}
+
+/// What a step of a plan acts on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Link,
+    Copy,
+    Directory,
+    File,
+    Repository,
+    Remote,
+    Move,
+}
+
+impl Kind {
+    fn noun(self, count: usize) -> &'static str {
+        let (one, many) = match self {
+            Kind::Link => ("link", "links"),
+            Kind::Copy => ("copy", "copies"),
+            Kind::Directory => ("directory", "directories"),
+            Kind::File => ("file", "files"),
+            Kind::Repository => ("repository", "repositories"),
+            Kind::Remote => ("remote", "remotes"),
+            Kind::Move => ("move", "moves"),
+        };
+        if count == 1 {
+            one
+        } else {
+            many
+        }
+    }
+}
+
+/// One change that carrying out the queued operations would make.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Step {
+    pub action: Action,
+    pub kind: Kind,
+    pub path: PathBuf,
+    /// More about the step, like where a link points.
+    pub detail: Option<String>,
+}
+
+/// The changes that a set of queued operations would make, worked out
+/// without making them. Operations that have nothing left to do are
+/// left out.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+    /// Works out what carrying out the operations queued in `file_ops`
+    /// would do, given the way things are now.
+    pub fn of(file_ops: &FileOperations) -> Plan {
+        let fs = file_ops.backend();
+        let forced = file_ops.conflict_policy() != ConflictPolicy::Error;
+        let steps = file_ops
+            .ordered_operations()
+            .into_iter()
+            .filter(|op| !op.is_satisfied(fs))
+            .map(|op| step(op, fs, forced))
+            .collect();
+        Plan { steps }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// How many steps of each kind there are for each action, as in
+    /// "3 links to create, 1 conflict, 2 removals".
+    pub fn summary(&self) -> String {
+        let mut counts: Vec<(Action, Option<Kind>, usize)> = vec![];
+        for step in self.sorted() {
+            // Removals and conflicts are counted together, whatever
+            // they are of.
+            let kind = match step.action {
+                Action::Create | Action::Replace => Some(step.kind),
+                Action::Remove | Action::Conflict => None,
+            };
+            match counts
+                .iter_mut()
+                .find(|(action, counted, _)| *action == step.action && *counted == kind)
+            {
+                Some((_, _, count)) => *count += 1,
+                None => counts.push((step.action, kind, 1)),
+            }
+        }
+
+        if counts.is_empty() {
+            return "no changes".to_owned();
+        }
+        counts
+            .into_iter()
+            .map(|(action, kind, count)| match (action, kind) {
+                (Action::Create, Some(kind)) => {
+                    format!("{} {} to create", count, kind.noun(count))
+                }
+                (Action::Replace, Some(kind)) => {
+                    format!("{} {} to replace", count, kind.noun(count))
+                }
+                (Action::Conflict, _) => plural(count, "conflict", "conflicts"),
+                _ => plural(count, "removal", "removals"),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// The plan as it is shown to the user, with the paths below `root`
+    /// shown relative to it, one step per line grouped by action, and a
+    /// summary at the end. With `color`, the markers are colored for a
+    /// terminal.
+    pub fn render(&self, root: &Path, color: bool) -> String {
+        if self.is_empty() {
+            return "No changes, everything is up to date\n".to_owned();
+        }
+
+        let mut shown = String::new();
+        for step in self.sorted() {
+            let path = step.path.strip_prefix(root).unwrap_or(&step.path);
+            let label = match step.action {
+                Action::Conflict => "conflict",
+                _ => step.kind.noun(1),
+            };
+            let marker = if color {
+                format!("{}{}\x1b[0m", step.action.color(), step.action.marker())
+            } else {
+                step.action.marker().to_string()
+            };
+            shown.push_str(&format!(
+                "  {} {:<10} {}",
+                marker,
+                label,
+                message::path(path)
+            ));
+            if let Some(detail) = &step.detail {
+                shown.push(' ');
+                shown.push_str(detail);
+            }
+            shown.push('\n');
+        }
+        shown.push_str(&format!("\nPlan: {}\n", self.summary()));
+        shown
+    }
+
+    /// The steps grouped by action, keeping the order they would be
+    /// carried out in within each group.
+    fn sorted(&self) -> Vec<&Step> {
+        let mut steps = self.steps.iter().collect::<Vec<_>>();
+        steps.sort_by_key(|step| step.action);
+        steps
+    }
+}
+
+fn plural(count: usize, one: &str, many: &str) -> String {
+    format!("{} {}", count, if count == 1 { one } else { many })
+}
+
+/// The step that `op` is, given the way things are now. `forced` says
+/// whether things in the way of links and copies get replaced.
+fn step(op: &Op, fs: &dyn FsBackend, forced: bool) -> Step {
+    let in_the_way = |path: &Path, replaceable: Option<FileKind>| match fs.kind(path) {
+        Err(_) => Action::Create,
+        Ok(kind) if Some(kind) == replaceable || forced => Action::Replace,
+        Ok(_) => Action::Conflict,
+    };
+    let new_step = |action, kind, path: &Path, detail: Option<String>| Step {
+        action,
+        kind,
+        path: path.to_path_buf(),
+        detail,
+    };
+
+    match op {
+        Op::MkDir(dir) => new_step(Action::Create, Kind::Directory, dir, None),
+        Op::GitInit(path) => new_step(Action::Create, Kind::Repository, path, None),
+        Op::GitClone { url, path, .. } => new_step(
+            Action::Create,
+            Kind::Repository,
+            path,
+            Some(format!("cloned from {}", url)),
+        ),
+        Op::GitRemote { repo, name, url } => new_step(
+            Action::Create,
+            Kind::Remote,
+            repo,
+            Some(format!("{} = {}", name, url)),
+        ),
+        Op::Link { path, target } => new_step(
+            in_the_way(path, None),
+            Kind::Link,
+            path,
+            Some(format!("-> {}", message::path(target))),
+        ),
+        Op::Relink {
+            path,
+            source,
+            target,
+        } => new_step(
+            if file_operations::is_put_in_place(fs, path, source) {
+                Action::Replace
+            } else {
+                in_the_way(path, None)
+            },
+            Kind::Link,
+            path,
+            Some(format!("-> {}", message::path(target))),
+        ),
+        Op::Copy { path, source } => new_step(
+            in_the_way(path, Some(FileKind::File)),
+            Kind::Copy,
+            path,
+            Some(format!("of {}", message::path(source))),
+        ),
+        Op::Move { path, destination } => new_step(
+            Action::Replace,
+            Kind::Move,
+            path,
+            Some(format!("to {}", message::path(destination))),
+        ),
+        Op::Remove(path) => {
+            let kind = match fs.kind(path) {
+                Ok(FileKind::Dir) => Kind::Directory,
+                Ok(FileKind::Symlink) => Kind::Link,
+                _ => Kind::File,
+            };
+            new_step(Action::Remove, kind, path, None)
+        }
+        Op::Unlink { path, .. } => {
+            let kind = match fs.kind(path) {
+                Ok(FileKind::Symlink) => Kind::Link,
+                _ => Kind::Copy,
+            };
+            new_step(Action::Remove, kind, path, None)
+        }
+        Op::WriteFile { path, .. } => {
+            let action = if fs.exists(path) {
+                Action::Replace
+            } else {
+                Action::Create
+            };
+            new_step(action, Kind::File, path, None)
+        }
+        Op::CreateFile { path, .. } => new_step(Action::Create, Kind::File, path, None),
+    }
+}
+
+/// What was at a path when a plan was saved.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum PathState {
+    Missing,
+    Dir,
+    Link {
+        #[serde(with = "os_path")]
+        target: PathBuf,
+    },
+    File {
+        hash: String,
+    },
+}
+
+impl PathState {
+    fn of(fs: &dyn FsBackend, path: &Path) -> io::Result<PathState> {
+        Ok(match fs.kind(path) {
+            Ok(FileKind::Dir) => PathState::Dir,
+            Ok(FileKind::Symlink) => PathState::Link {
+                target: fs.read_link(path)?,
+            },
+            Ok(FileKind::File) => PathState::File {
+                hash: hex(&Sha256::digest(&fs.read(path)?)),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => PathState::Missing,
+            Err(err) => return Err(err),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Fingerprint {
+    #[serde(with = "os_path")]
+    path: PathBuf,
+    #[serde(flatten)]
+    state: PathState,
+}
+
+/// A plan written down so that it can be reviewed, and carried out
+/// later exactly as it was made. It remembers what was at each path
+/// it touches, and is refused once any of them has changed.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SavedPlan {
+    version: u32,
+    /// The directory that the plan makes links in.
+    #[serde(with = "os_path")]
+    root: PathBuf,
+    /// The shell that was in use when the plan was made.
+    current_shell: Option<String>,
+    /// The shell that the plan switches to, if it switches shells.
+    pub switch_to: Option<String>,
+    conflict_policy: ConflictPolicy,
+    operations: Vec<Op>,
+    fingerprint: Vec<Fingerprint>,
+}
+
+impl SavedPlan {
+    /// Saves the operations queued in `file_ops` that have anything
+    /// left to do, along with what is at the paths they touch now.
+    pub fn of(
+        file_ops: &FileOperations,
+        current_shell: Option<String>,
+        switch_to: Option<String>,
+    ) -> io::Result<SavedPlan> {
+        let fs = file_ops.backend();
+        let operations = file_ops
+            .ordered_operations()
+            .into_iter()
+            .filter(|op| !op.is_satisfied(fs))
+            .cloned()
+            .collect::<Vec<_>>();
+        let fingerprint = operations
+            .iter()
+            .flat_map(touched_paths)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|path| {
+                Ok(Fingerprint {
+                    state: PathState::of(fs, path)?,
+                    path: path.to_path_buf(),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(SavedPlan {
+            version: SAVED_PLAN_VERSION,
+            root: file_ops.root().to_path_buf(),
+            current_shell,
+            switch_to,
+            conflict_policy: file_ops.conflict_policy(),
+            operations,
+            fingerprint,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("plans can always be written as JSON")
+    }
+
+    pub fn from_json(json: &str) -> Result<SavedPlan> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u32,
+        }
+
+        let invalid = |err: serde_json::Error| Error::InvalidPlan(err.to_string());
+        let Versioned { version } = serde_json::from_str(json).map_err(invalid)?;
+        if version != SAVED_PLAN_VERSION {
+            return Err(Error::InvalidPlan(format!(
+                "it was saved in version {} of the format, but only version {} can be read",
+                version, SAVED_PLAN_VERSION
+            )));
+        }
+        serde_json::from_str(json).map_err(invalid)
+    }
+
+    /// Queues the plan's operations in `file_ops`, failing without
+    /// queueing any of them if things aren't as they were when the plan
+    /// was made. `current_shell` is the shell in use now.
+    pub fn queue(self, file_ops: &mut FileOperations, current_shell: Option<&str>) -> Result<()> {
+        if file_ops.root() != self.root {
+            return Err(Error::StalePlan(format!(
+                "it makes links in {}, not {}",
+                message::path(&self.root),
+                message::path(file_ops.root())
+            )));
+        }
+        if current_shell != self.current_shell.as_deref() {
+            return Err(Error::StalePlan(match current_shell {
+                Some(name) => format!("the shell '{}' is in use now", name),
+                None => "no shell is in use now".to_owned(),
+            }));
+        }
+        for Fingerprint { path, state } in &self.fingerprint {
+            if PathState::of(file_ops.backend(), path).ok().as_ref() != Some(state) {
+                return Err(Error::StalePlan(format!(
+                    "{} has changed",
+                    message::path(path)
+                )));
+            }
+        }
+
+        file_ops.set_conflict_policy(self.conflict_policy);
+        for op in self.operations {
+            file_ops.queue(op);
+        }
+        Ok(())
+    }
+}
+
+/// The paths whose contents decide what `op` does.
+fn touched_paths(op: &Op) -> Vec<&Path> {
+    match op {
+        Op::MkDir(path) | Op::GitInit(path) | Op::Remove(path) => vec![path],
+        Op::GitClone { path, .. }
+        | Op::Link { path, .. }
+        | Op::WriteFile { path, .. }
+        | Op::CreateFile { path, .. } => vec![path],
+        Op::GitRemote { repo, .. } => vec![repo],
+        Op::Copy { path, source }
+        | Op::Unlink { path, source }
+        | Op::Relink { path, source, .. } => vec![path, source],
+        Op::Move { path, destination } => vec![path, destination],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fs_backend::MemoryFs;
+
+    fn file_ops(fs: &Rc<MemoryFs>) -> FileOperations {
+        fs.add_file("/hermit/shells/work/.bashrc", "work");
+        fs.add_file("/hermit/shells/work/.vimrc", "set nu");
+        fs.add_file("/hermit/shells/work/.gitconfig", "[user]");
+        fs.add_file("/home/geoff/.vimrc", "mine");
+        fs.create_dir_all(Path::new("/home/geoff")).unwrap();
+        fs.symlink(
+            Path::new("/hermit/shells/default/.profile"),
+            Path::new("/home/geoff/.profile"),
+        )
+        .unwrap();
+
+        let mut file_ops = FileOperations::with_backend("/home/geoff", fs.clone());
+        file_ops.unlink(".profile", "/hermit/shells/default/.profile");
+        file_ops.link(".bashrc", "/hermit/shells/work/.bashrc");
+        file_ops.link(".vimrc", "/hermit/shells/work/.vimrc");
+        file_ops.link(".gitconfig", "/hermit/shells/work/.gitconfig");
+        file_ops.unlink(".inputrc", "/hermit/shells/default/.inputrc");
+        file_ops
+    }
+
+    #[test]
+    fn plans_group_steps_by_action() {
+        let fs = Rc::new(MemoryFs::new());
+        let file_ops = file_ops(&fs);
+
+        let plan = Plan::of(&file_ops);
+
+        assert_eq!(
+            plan.render(file_ops.root(), false),
+            "  + link       .bashrc -> /hermit/shells/work/.bashrc\n\
+             \x20 + link       .gitconfig -> /hermit/shells/work/.gitconfig\n\
+             \x20 - link       .profile\n\
+             \x20 ! conflict   .vimrc -> /hermit/shells/work/.vimrc\n\
+             \n\
+             Plan: 2 links to create, 1 removal, 1 conflict\n"
+        );
+    }
+
+    #[test]
+    fn forced_plans_replace_what_is_in_the_way() {
+        let fs = Rc::new(MemoryFs::new());
+        let mut file_ops = file_ops(&fs);
+        file_ops.set_conflict_policy(ConflictPolicy::Backup);
+        file_ops.create_dir(".config");
+        file_ops.write_file(".config/hermit.toml", "");
+
+        assert_eq!(
+            Plan::of(&file_ops).summary(),
+            "2 links to create, 1 directory to create, 1 file to create, \
+             1 link to replace, 1 removal"
+        );
+    }
+
+    #[test]
+    fn plans_with_nothing_to_do_say_so() {
+        let fs = Rc::new(MemoryFs::new());
+        fs.add_file("/home/geoff/.bashrc", "");
+        let mut file_ops = FileOperations::with_backend("/home/geoff", fs.clone());
+        file_ops.create_dir(".");
+        file_ops.write_file(".bashrc", "");
+
+        let plan = Plan::of(&file_ops);
+
+        assert!(plan.is_empty());
+        assert_eq!(
+            plan.render(file_ops.root(), true),
+            "No changes, everything is up to date\n"
+        );
+    }
+
+    #[test]
+    fn saved_plans_can_be_carried_out_later() {
+        let fs = Rc::new(MemoryFs::new());
+        let file_ops = file_ops(&fs);
+        let json = SavedPlan::of(
+            &file_ops,
+            Some("default".to_owned()),
+            Some("work".to_owned()),
+        )
+        .unwrap()
+        .to_json();
+
+        let mut later = FileOperations::with_backend("/home/geoff", fs.clone());
+        let plan = SavedPlan::from_json(&json).unwrap();
+        assert_eq!(plan.switch_to.as_deref(), Some("work"));
+        plan.queue(&mut later, Some("default")).unwrap();
+
+        assert_eq!(later.operations().len(), 4);
+        later.flush();
+        assert_eq!(
+            fs.read_link(Path::new("/home/geoff/.bashrc")).unwrap(),
+            Path::new("/hermit/shells/work/.bashrc")
+        );
+        assert!(!fs.exists(Path::new("/home/geoff/.profile")));
+    }
+
+    #[test]
+    fn saved_plans_are_refused_once_things_change() {
+        let fs = Rc::new(MemoryFs::new());
+        let file_ops = file_ops(&fs);
+        let json = SavedPlan::of(&file_ops, Some("default".to_owned()), None)
+            .unwrap()
+            .to_json();
+        let refused = |current_shell, root| {
+            let mut later = FileOperations::with_backend(root, fs.clone());
+            let err = SavedPlan::from_json(&json)
+                .unwrap()
+                .queue(&mut later, current_shell)
+                .unwrap_err();
+            assert!(later.operations().is_empty());
+            err.to_string()
+        };
+
+        assert!(refused(Some("work"), "/home/geoff").contains("'work' is in use now"));
+        assert!(refused(Some("default"), "/home/other").contains("makes links in"));
+
+        fs.add_file("/home/geoff/.vimrc", "changed");
+        assert_eq!(
+            refused(Some("default"), "/home/geoff"),
+            "The plan is out of date, since /home/geoff/.vimrc has changed; make a new one"
+        );
+    }
+
+    #[test]
+    fn saved_plans_in_other_versions_are_refused() {
+        let json = r#"{"version": 2, "root": "/home/geoff"}"#;
+
+        assert_eq!(
+            SavedPlan::from_json(json).unwrap_err().to_string(),
+            "Could not read the plan: it was saved in version 2 of the format, \
+             but only version 1 can be read"
+        );
+    }
+}
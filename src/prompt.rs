@@ -0,0 +1,108 @@
+use crate::{common::*, git};
+
+use std::{
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// What `hermit prompt-info` shows about the current shell, for
+/// embedding in a shell prompt.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PromptInfo {
+    pub shell: String,
+    /// Whether the shell has changes that haven't been committed, if
+    /// that could be found out in time.
+    pub dirty: Option<bool>,
+    /// How many commits the shell has that haven't been pushed, if it
+    /// has an upstream and that could be found out in time.
+    pub ahead: Option<usize>,
+}
+
+impl PromptInfo {
+    /// Looks at the repository of the shell at `shell_root`, giving up
+    /// on whatever hasn't been found out after `budget`, so that a
+    /// slow disk or a huge repository never holds up a prompt.
+    pub fn gather(shell: String, shell_root: PathBuf, budget: Duration) -> PromptInfo {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let dirty = git::has_uncommitted_changes(&shell_root).ok();
+            let _ = sender.send((dirty, None));
+            let ahead = git::commits_ahead(&shell_root).ok().flatten();
+            let _ = sender.send((dirty, ahead));
+        });
+
+        let mut info = PromptInfo {
+            shell,
+            dirty: None,
+            ahead: None,
+        };
+        let deadline = Instant::now() + budget;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match receiver.recv_timeout(left) {
+                Ok((dirty, ahead)) => {
+                    info.dirty = dirty;
+                    info.ahead = ahead;
+                }
+                Err(_) => break,
+            }
+        }
+        info
+    }
+}
+
+/// The shell's name, followed by `*` if it is dirty and `↑` and a count
+/// if it has commits that haven't been pushed, as in `work*↑2`.
+impl Display for PromptInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.shell)?;
+        if self.dirty == Some(true) {
+            write!(f, "*")?;
+        }
+        match self.ahead {
+            Some(ahead) if ahead > 0 => write!(f, "↑{}", ahead),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{git::test_support::init_repo, test_helpers::filesystem::set_up};
+
+    #[test]
+    fn shows_the_shell_and_what_is_left_to_commit_and_push() {
+        let info = |dirty, ahead| PromptInfo {
+            shell: "work".to_owned(),
+            dirty,
+            ahead,
+        };
+
+        assert_eq!(info(Some(false), None).to_string(), "work");
+        assert_eq!(info(Some(true), Some(0)).to_string(), "work*");
+        assert_eq!(info(None, Some(2)).to_string(), "work↑2");
+        assert_eq!(info(Some(true), Some(3)).to_string(), "work*↑3");
+    }
+
+    #[test]
+    fn finds_uncommitted_changes() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path().join("work");
+        init_repo(&shell_root);
+        fs::write(shell_root.join(".bashrc"), "").unwrap();
+        git::commit_paths(&shell_root, &[".bashrc"], "Add bashrc", false).unwrap();
+
+        let gather = || {
+            PromptInfo::gather(
+                "work".to_owned(),
+                shell_root.clone(),
+                Duration::from_secs(5),
+            )
+        };
+        assert_eq!(gather().dirty, Some(false));
+        fs::write(shell_root.join(".bashrc"), "changed").unwrap();
+        assert_eq!(gather().to_string(), "work*");
+    }
+}
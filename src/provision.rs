@@ -0,0 +1,85 @@
+//! The log that `hermit provision` writes as it sets up a machine, one
+//! JSON object per line, for cloud-init, Ansible and CI jobs to read.
+
+use serde::Serialize;
+
+use crate::manifest::TagFilter;
+
+/// The shell that `hermit provision` sets a machine up with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Request {
+    /// Where to clone the shell from.
+    pub url: String,
+    /// The name of the shell on this machine.
+    pub name: String,
+    /// The branch to clone, instead of the remote's default branch.
+    pub branch: Option<String>,
+    /// Which of the shell's files to link.
+    pub tags: TagFilter,
+}
+
+/// What a step of provisioning did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The step changed something.
+    Changed,
+    /// Everything was already as the step would leave it.
+    Unchanged,
+    /// The step failed, and provisioning stopped.
+    Failed,
+}
+
+/// One line of the log.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Record {
+    /// The step, like `clone`, `use` or `bootstrap`.
+    pub step: &'static str,
+    pub shell: String,
+    pub outcome: Outcome,
+    /// What was changed, or why the step failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Record {
+    pub fn new(step: &'static str, shell: &str, outcome: Outcome) -> Record {
+        Record {
+            step,
+            shell: shell.to_owned(),
+            outcome,
+            detail: None,
+        }
+    }
+
+    pub fn with_detail(self, detail: impl Into<String>) -> Record {
+        Record {
+            detail: Some(detail.into()),
+            ..self
+        }
+    }
+
+    /// The record as a line of the log, without the line break.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("records are always valid JSON")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_single_lines_of_json() {
+        assert_eq!(
+            Record::new("clone", "work", Outcome::Unchanged).to_json(),
+            r#"{"step":"clone","shell":"work","outcome":"unchanged"}"#
+        );
+        assert_eq!(
+            Record::new("bootstrap", "work", Outcome::Failed)
+                .with_detail("step packages\nfailed")
+                .to_json(),
+            r#"{"step":"bootstrap","shell":"work","outcome":"failed","detail":"step packages\nfailed"}"#
+        );
+    }
+}
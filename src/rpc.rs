@@ -0,0 +1,354 @@
+//! A JSON-RPC 2.0 server, for editor plugins and GUIs that want to
+//! talk to a long running hermit instead of running it for every
+//! query.
+//!
+//! Requests and responses are sent one per line. The methods are:
+//!
+//! - `list`: the names of the shells, and which one is current.
+//! - `status`: the state of each file of the current shell.
+//! - `add`, with `paths` and optionally `copy`: adds files to the
+//!   current shell.
+//! - `use`, with `shell`: switches to another shell, running its hooks
+//!   and, the first time, its bootstrap steps.
+//!
+//! Errors from hermit itself have the exit code that the command line
+//! tool would exit with as their code.
+
+use crate::{
+    common::*,
+    file_operations::CommitReport,
+    manifest::{Strategy, TagFilter},
+    paths,
+    status::{FileState, Status},
+    Hermit,
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    /// Missing for notifications, which aren't answered.
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddParams {
+    paths: Vec<PathBuf>,
+    #[serde(default)]
+    copy: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct UseParams {
+    shell: String,
+}
+
+/// Why a request failed.
+struct Failure {
+    code: i64,
+    message: String,
+}
+
+impl Failure {
+    fn new(code: i64, message: impl Into<String>) -> Failure {
+        Failure {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Failure {
+        Failure::new(err.exit_code().into(), err.to_string())
+    }
+}
+
+/// Answers requests on behalf of a hermit whose links are made in the
+/// root of `file_ops`.
+pub struct Server<'a, C: Config> {
+    hermit: &'a mut Hermit<C>,
+    file_ops: &'a mut FileOperations,
+}
+
+impl<'a, C: Config> Server<'a, C> {
+    pub fn new(hermit: &'a mut Hermit<C>, file_ops: &'a mut FileOperations) -> Server<'a, C> {
+        Server { hermit, file_ops }
+    }
+
+    /// Answers each line of `input` on a line of `output`, until
+    /// `input` ends.
+    pub fn serve(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle(&line) {
+                writeln!(output, "{}", response)?;
+                output.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The response to one request, or nothing for a notification.
+    pub fn handle(&mut self, line: &str) -> Option<Value> {
+        let value = match serde_json::from_str::<Value>(line) {
+            Ok(value) => value,
+            Err(err) => {
+                return Some(error(
+                    Value::Null,
+                    Failure::new(PARSE_ERROR, err.to_string()),
+                ))
+            }
+        };
+        let id = value.get("id").cloned().unwrap_or(Value::Null);
+        let request = match serde_json::from_value::<Request>(value) {
+            Ok(request) if request.jsonrpc == "2.0" => request,
+            Ok(_) => return Some(error(id, Failure::new(INVALID_REQUEST, "not JSON-RPC 2.0"))),
+            Err(err) => return Some(error(id, Failure::new(INVALID_REQUEST, err.to_string()))),
+        };
+
+        let result = self.call(&request.method, request.params);
+        let id = request.id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(failure) => error(id, failure),
+        })
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> StdResult<Value, Failure> {
+        match method {
+            "list" => Ok(self.list()),
+            "status" => Ok(status_json(&self.hermit.status(self.file_ops.root())?)),
+            "add" => self.add(parse(params)?),
+            "use" => self.inhabit(parse(params)?),
+            _ => Err(Failure::new(
+                METHOD_NOT_FOUND,
+                format!("there is no method named '{}'", method),
+            )),
+        }
+    }
+
+    fn list(&self) -> Value {
+        let current = self.hermit.current_shell().ok().map(|shell| shell.name);
+        let shells = self
+            .hermit
+            .shell_names()
+            .into_iter()
+            .map(|name| json!({ "current": Some(&name) == current.as_ref(), "name": name }))
+            .collect::<Vec<_>>();
+        json!({ "shells": shells })
+    }
+
+    fn add(&mut self, params: AddParams) -> StdResult<Value, Failure> {
+        let root = self.file_ops.root().to_path_buf();
+        let mut files = vec![];
+        for path in &params.paths {
+            files.extend(paths::expand(path, &root, &root)?);
+        }
+        let strategy = if params.copy {
+            Strategy::Copy
+        } else {
+            Strategy::Link
+        };
+        let added = self.hermit.add(self.file_ops, &files, strategy)?;
+        let report = self.commit("add", None)?;
+        Ok(json!({
+            "added": added.iter().map(|file| json!(file.target)).collect::<Vec<_>>(),
+            "report": report_json(&report),
+        }))
+    }
+
+    /// Switches shells the way `hermit inhabit` does, hooks and all.
+    /// Bootstrap steps can't read from the client's requests, and what
+    /// they print goes to standard error, not into the responses.
+    fn inhabit(&mut self, params: UseParams) -> StdResult<Value, Failure> {
+        let shell_before = self.hermit.current_shell().ok().map(|shell| shell.name);
+        let mut report = CommitReport::default();
+        let tags = TagFilter::default();
+        let used = self
+            .hermit
+            .use_shell(self.file_ops, &params.shell, &tags, false, &mut report);
+        if used.is_err() {
+            // Whatever the switch queued before it failed is left out.
+            self.file_ops.clear();
+        }
+        report.extend(self.commit("inhabit", shell_before)?);
+        used?;
+        let shell = self.hermit.current_shell()?.name;
+        Ok(json!({ "shell": shell, "report": report_json(&report) }))
+    }
+
+    /// Carries out the queued changes, and records them in the journal
+    /// so that `hermit undo` can undo them.
+    fn commit(&mut self, command: &str, shell_before: Option<String>) -> Result<CommitReport> {
+        let report = self.file_ops.flush();
+        let changes = self.file_ops.take_changes();
+        let shell_after = self.hermit.current_shell().ok().map(|shell| shell.name);
+        let shell_before = shell_before.filter(|before| Some(before) != shell_after.as_ref());
+        if !changes.is_empty() || shell_before.is_some() {
+            self.hermit
+                .journal()
+                .record(command, shell_before, changes)?;
+        }
+        Ok(report)
+    }
+}
+
+fn parse<T: for<'de> Deserialize<'de>>(params: Value) -> StdResult<T, Failure> {
+    serde_json::from_value(params).map_err(|err| Failure::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn error(id: Value, failure: Failure) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": failure.code, "message": failure.message },
+    })
+}
+
+fn status_json(status: &Status) -> Value {
+    let files = status
+        .files
+        .iter()
+        .map(|file| {
+            json!({
+                "source": file.file.source,
+                "target": file.file.target,
+                "strategy": file.file.strategy.label(),
+                "state": state_name(file.state),
+                "code": file.short_code(),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "shell": status.shell, "files": files })
+}
+
+/// The name of a state in responses, which won't change between
+/// releases.
+fn state_name(state: FileState) -> &'static str {
+    match state {
+        FileState::Linked => "linked",
+        FileState::Missing => "missing",
+        FileState::LinkedElsewhere => "linked_elsewhere",
+        FileState::Conflict => "conflict",
+        FileState::Copied => "copied",
+        FileState::OutOfDate => "out_of_date",
+    }
+}
+
+fn report_json(report: &CommitReport) -> Value {
+    json!({
+        "changed": report.changed(),
+        "unchanged": report.unchanged(),
+        "skipped": report.skipped(),
+        "failed": report.failed(),
+        "errors": report.errors().map(|err| err.to_string()).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::{config::mock::MockConfig, exit_code, test_helpers::filesystem::set_up};
+
+    fn respond(line: &str) -> Value {
+        let mut config = MockConfig::new();
+        config.add_shell("work");
+        let mut hermit = Hermit::new(config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+        let mut server = Server::new(&mut hermit, &mut file_ops);
+        server.handle(line).expect("No response")
+    }
+
+    #[test]
+    fn lists_shells() {
+        assert_eq!(
+            respond(r#"{"jsonrpc": "2.0", "id": 1, "method": "list"}"#),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": { "shells": [
+                    { "name": "default", "current": true },
+                    { "name": "work", "current": false },
+                ]},
+            })
+        );
+    }
+
+    #[test]
+    fn bad_requests_are_errors() {
+        let code = |line| respond(line)["error"]["code"].clone();
+
+        assert_eq!(code("{"), json!(PARSE_ERROR));
+        assert_eq!(
+            code(r#"{"id": 1, "method": "list"}"#),
+            json!(INVALID_REQUEST)
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc": "2.0", "id": 1, "method": "nuke"}"#),
+            json!(METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc": "2.0", "id": 1, "method": "use", "params": {}}"#),
+            json!(INVALID_PARAMS)
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc": "2.0", "id": 1, "method": "use", "params": {"shell": "nope"}}"#),
+            json!(exit_code::USAGE)
+        );
+    }
+
+    #[test]
+    fn switching_shells_runs_their_hooks() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let hook = root.join("shells/work/.hermit/hooks/pre-use");
+        fs::create_dir_all(hook.parent().unwrap()).unwrap();
+        fs::write(&hook, "#!/bin/sh\nexit 1\n").unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut config = MockConfig::with_root(root);
+        config.add_shell("work");
+        let mut hermit = Hermit::new(config);
+        let mut file_ops = FileOperations::rooted_at(root.join("home"));
+        let mut server = Server::new(&mut hermit, &mut file_ops);
+
+        let response = server
+            .handle(r#"{"jsonrpc": "2.0", "id": 1, "method": "use", "params": {"shell": "work"}}"#)
+            .expect("No response");
+
+        assert!(response["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("pre-use"));
+        assert_eq!(hermit.current_shell().unwrap().name, "default");
+    }
+
+    #[test]
+    fn notifications_are_not_answered() {
+        let mut hermit = Hermit::new(MockConfig::new());
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+        let mut server = Server::new(&mut hermit, &mut file_ops);
+
+        assert_eq!(
+            server.handle(r#"{"jsonrpc": "2.0", "method": "list"}"#),
+            None
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use crate::common::*;
+
+use serde::Deserialize;
+
+use crate::{
+    file_operations::{ConflictPolicy, PermissionPolicy},
+    fs_backend::{FsBackend, RealFs},
+};
+
+/// The name of the optional settings file in the hermit root.
+pub const SETTINGS_FILE_NAME: &str = "config.toml";
+
+/// The settings file that `hermit init` writes into a new hermit root,
+/// spelling out the defaults.
+pub const DEFAULT_SETTINGS: &str = "\
+# Settings for every hermit shell on this machine.
+
+[link]
+# Replace whatever is in the way of a link, instead of reporting a
+# conflict.
+force = false
+# Keep a backup of anything that force replaces.
+backup = true
+
+[operations]
+# Stop at the first change that fails, instead of making the rest of
+# them anyway.
+fail_fast = false
+# Never change anything, as if --read-only were always given: use,
+# apply and nuke only show what they would do, and commands that only
+# change things refuse to run.
+read_only = false
+
+[copy]
+# What copied files take their permissions from: \"preserve\" copies the
+# mode bits of the file they are a copy of, and its owner too when
+# hermit runs as root, and \"umask\" gives new copies the permissions
+# of any new file.
+permissions = \"preserve\"
+# Copy the extended attributes of files along with them, which on Linux
+# include their ACLs. Hermit has to be built with the xattrs feature.
+xattrs = false
+
+[walk]
+# Don't look for files in a shell more than this many directories deep.
+# max_depth = 16
+# Warn about directories in a shell with more files than this in them,
+# and refuse to add them unless asked to.
+large_directory = 1000
+
+[add]
+# Warn about files bigger than this many bytes, or that aren't text,
+# and leave them out unless asked to add them.
+large_file = 1048576
+
+[init]
+# The branch that new shells start out on. Without it, git's
+# init.defaultBranch is used, or main.
+# default_branch = \"main\"
+
+[hooks]
+# Stop the scripts in a shell's .hermit/hooks directory if they are
+# still running after this many seconds.
+timeout = 60
+";
+
+/// Settings that apply to every shell, read from `config.toml` in the
+/// hermit root.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    #[serde(default)]
+    pub link: LinkSettings,
+    #[serde(default)]
+    pub operations: OperationSettings,
+    #[serde(default)]
+    pub copy: CopySettings,
+    #[serde(default)]
+    pub walk: WalkSettings,
+    #[serde(default)]
+    pub add: AddSettings,
+    #[serde(default)]
+    pub hooks: HookSettings,
+    #[serde(default)]
+    pub init: InitSettings,
+}
+
+/// How links are put in place.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkSettings {
+    /// Replace whatever is in the way of a link, instead of reporting
+    /// a conflict.
+    pub force: bool,
+    /// Keep a backup of anything that `force` replaces.
+    pub backup: bool,
+}
+
+impl Default for LinkSettings {
+    fn default() -> LinkSettings {
+        LinkSettings {
+            force: false,
+            backup: true,
+        }
+    }
+}
+
+/// How the changes a command makes are carried out.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OperationSettings {
+    /// Stop at the first change that fails, instead of making the rest
+    /// of them anyway.
+    pub fail_fast: bool,
+    /// Never change anything, as `--read-only` does.
+    pub read_only: bool,
+}
+
+/// How files are copied, for the copy strategy and when they are
+/// moved between filesystems.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CopySettings {
+    /// What the permissions of a copy are taken from.
+    pub permissions: PermissionPolicy,
+    /// Whether copies get the extended attributes of the files they
+    /// are copies of.
+    pub xattrs: bool,
+}
+
+/// Limits on how much of a shell is looked at, so that a huge
+/// directory that ends up in one by accident doesn't slow down every
+/// command.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalkSettings {
+    /// How many directories deep to look for files in a shell, or
+    /// without a limit if it's missing.
+    pub max_depth: Option<usize>,
+    /// How many files a directory can have in it before it counts as
+    /// large.
+    pub large_directory: usize,
+}
+
+impl Default for WalkSettings {
+    fn default() -> WalkSettings {
+        WalkSettings {
+            max_depth: None,
+            large_directory: 1000,
+        }
+    }
+}
+
+/// How files are added to a shell.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AddSettings {
+    /// How many bytes a file can have before it counts as too large to
+    /// keep in git.
+    pub large_file: u64,
+}
+
+impl Default for AddSettings {
+    fn default() -> AddSettings {
+        AddSettings {
+            large_file: 1024 * 1024,
+        }
+    }
+}
+
+/// How new shells are set up.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InitSettings {
+    /// The branch that new shells start out on.
+    pub default_branch: Option<String>,
+}
+
+/// How the hooks of a shell are run.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookSettings {
+    /// How many seconds a hook can run for before it is stopped.
+    pub timeout: u64,
+}
+
+impl Default for HookSettings {
+    fn default() -> HookSettings {
+        HookSettings { timeout: 60 }
+    }
+}
+
+impl LinkSettings {
+    /// The conflict policy these settings ask for, with `force` and
+    /// `no_backup` from the command line taking precedence.
+    pub fn conflict_policy(&self, force: bool, no_backup: bool) -> ConflictPolicy {
+        if !(force || self.force) {
+            ConflictPolicy::Error
+        } else if self.backup && !no_backup {
+            ConflictPolicy::Backup
+        } else {
+            ConflictPolicy::Overwrite
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the settings from the hermit root. A root without a
+    /// settings file gets the defaults.
+    pub fn load(root_path: impl AsRef<Path>) -> Result<Settings> {
+        Settings::load_with(&RealFs, root_path)
+    }
+
+    /// Like `load`, but reads the settings from `fs`.
+    pub fn load_with(fs: &dyn FsBackend, root_path: impl AsRef<Path>) -> Result<Settings> {
+        let path = root_path.as_ref().join(SETTINGS_FILE_NAME);
+        match fs.read_to_string(&path) {
+            Ok(contents) => Settings::parse(&contents),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Settings::default()),
+            Err(err) => Err(Error::InvalidSettings(err.to_string())),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Settings> {
+        toml::from_str(contents).map_err(|err| Error::InvalidSettings(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn a_root_without_settings_gets_the_defaults() {
+        let test_root_dir = set_up();
+        let settings = Settings::load(test_root_dir.path()).unwrap();
+
+        assert_eq!(settings, Settings::default());
+        assert!(!settings.link.force);
+        assert!(settings.link.backup);
+    }
+
+    #[test]
+    fn the_default_settings_file_has_the_defaults() {
+        assert_eq!(Settings::parse(DEFAULT_SETTINGS), Ok(Settings::default()));
+    }
+
+    #[test]
+    fn parses_link_settings() {
+        let settings = Settings::parse("[link]\nforce = true\n").unwrap();
+
+        assert!(settings.link.force);
+        assert!(settings.link.backup);
+    }
+
+    #[test]
+    fn parses_operation_settings() {
+        let settings =
+            Settings::parse("[operations]\nfail_fast = true\nread_only = true\n").unwrap();
+
+        assert!(settings.operations.fail_fast);
+        assert!(settings.operations.read_only);
+        assert!(!Settings::default().operations.fail_fast);
+        assert!(!Settings::default().operations.read_only);
+    }
+
+    #[test]
+    fn parses_walk_settings() {
+        let settings = Settings::parse("[walk]\nmax_depth = 4\n").unwrap();
+
+        assert_eq!(settings.walk.max_depth, Some(4));
+        assert_eq!(settings.walk.large_directory, 1000);
+    }
+
+    #[test]
+    fn parses_add_settings() {
+        let settings = Settings::parse("[add]\nlarge_file = 4096\n").unwrap();
+
+        assert_eq!(settings.add.large_file, 4096);
+        assert_eq!(Settings::default().add.large_file, 1024 * 1024);
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(matches!(
+            Settings::parse("[link]\nfroce = true\n"),
+            Err(Error::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn the_command_line_can_force_and_skip_backups() {
+        let defaults = LinkSettings::default();
+        assert_eq!(
+            defaults.conflict_policy(false, false),
+            ConflictPolicy::Error
+        );
+        assert_eq!(
+            defaults.conflict_policy(true, false),
+            ConflictPolicy::Backup
+        );
+        assert_eq!(
+            defaults.conflict_policy(true, true),
+            ConflictPolicy::Overwrite
+        );
+
+        let forced = LinkSettings {
+            force: true,
+            backup: false,
+        };
+        assert_eq!(
+            forced.conflict_policy(false, false),
+            ConflictPolicy::Overwrite
+        );
+    }
+}
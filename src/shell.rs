@@ -1,5 +1,11 @@
-use crate::common::*;
+use crate::{
+    common::*,
+    ignore_rules,
+    manifest::{Granularity, Strategy, TagFilter, LOCAL_DIR, METADATA_DIR},
+    paths,
+};
 
+/// A named collection of dotfiles inside the hermit root.
 pub struct Shell<T: Config> {
     pub name: String,
     pub config: Rc<T>,
@@ -15,22 +21,99 @@ impl<T: Config> Shell<T> {
         self.config.shell_root_path().join(&self.name)
     }
 
-    #[allow(dead_code)]
     pub fn path_for(&self, filename: &str) -> PathBuf {
         self.root_path().join(filename)
     }
 
-    pub fn link(&self, file_operations: &mut FileOperations) {
+    /// The directory in this shell for files that hermit makes for this
+    /// machine alone, creating it and making sure that the shell's
+    /// `.gitignore` keeps it from being committed.
+    pub fn local_dir(&self) -> Result<PathBuf> {
+        let root = self.root_path();
+        let pattern = format!("/{}/{}/", METADATA_DIR, LOCAL_DIR);
+        ignore_rules::ensure_ignored(&root, &[&pattern])
+            .map_err(|err| Error::LocalDirUnwritable(err.to_string()))?;
+
+        let dir = root.join(METADATA_DIR).join(LOCAL_DIR);
+        fs::create_dir_all(&dir).map_err(|err| Error::LocalDirUnwritable(err.to_string()))?;
+        Ok(dir)
+    }
+
+    pub fn manifest(&self) -> Result<Manifest> {
+        Manifest::load(self.root_path())
+    }
+
+    /// Finds the tracked file that `path` refers to, either by its
+    /// path inside the shell or by where it gets linked. Link paths
+    /// may be given relative to `target_root`, starting with `~`, or
+    /// as absolute paths inside `target_root`.
+    pub fn find_file(
+        &self,
+        path: impl AsRef<Path>,
+        target_root: impl AsRef<Path>,
+    ) -> Result<ShellFile> {
+        let path = path.as_ref();
+        let relative = path
+            .strip_prefix(target_root)
+            .or_else(|_| path.strip_prefix("~"))
+            .unwrap_or(path);
+
+        self.config
+            .shell_files(&self.name)?
+            .into_iter()
+            .find(|file| file.source == relative || file.target == relative)
+            .ok_or_else(|| Error::FileNotTracked(PathBuf::from(path)))
+    }
+
+    /// The files this shell tracks, with their targets worked out
+    /// from its manifest for this machine.
+    pub fn files(&self) -> Result<Vec<ShellFile>> {
+        Ok(self.config.shell_files(&self.name)?.into_iter().collect())
+    }
+
+    pub fn link(&self, file_operations: &mut FileOperations) -> Result<()> {
+        self.link_with_tags(file_operations, &TagFilter::default())
+    }
+
+    /// Links the files that `tags` allows, leaving the rest out.
+    pub fn link_with_tags(
+        &self,
+        file_operations: &mut FileOperations,
+        tags: &TagFilter,
+    ) -> Result<()> {
         let shell_root = self.root_path();
-        for path in self.config.shell_files(&self.name) {
-            file_operations.link(&path, shell_root.join(&path))
+        for file in self.config.shell_files(&self.name)? {
+            if !tags.allows(&file) {
+                continue;
+            }
+            paths::check_target(&file.target)?;
+            let source = shell_root.join(&file.source);
+            match file.strategy {
+                Strategy::Link => {
+                    paths::check_link(
+                        &file_operations.root().join(&file.target),
+                        &source,
+                        &shell_root,
+                    )?;
+                    if file.granularity == Granularity::Directory {
+                        file_operations.unlink_file_by_file(&file.target, &source);
+                    }
+                    file_operations.link(&file.target, source)
+                }
+                Strategy::Copy => file_operations.copy(&file.target, source),
+            }
         }
+        Ok(())
     }
 
-    pub fn unlink(&self, file_operations: &mut FileOperations) {
-        for path in self.config.shell_files(&self.name) {
-            file_operations.remove(&path)
+    /// Removes the links and copies of this shell's files, leaving
+    /// anything else that has taken their place alone.
+    pub fn unlink(&self, file_operations: &mut FileOperations) -> Result<()> {
+        let shell_root = self.root_path();
+        for file in self.config.shell_files(&self.name)? {
+            file_operations.unlink(&file.target, shell_root.join(&file.source))
         }
+        Ok(())
     }
 }
 
@@ -43,7 +126,10 @@ mod tests {
         rc::Rc,
     };
 
-    use crate::{config::mock::MockConfig, file_operations::Op, test_helpers::ops::*};
+    use crate::{
+        config::mock::MockConfig, file_operations::Op, test_helpers::filesystem::set_up,
+        test_helpers::ops::*,
+    };
 
     fn root_path(path_str: &str) -> PathBuf {
         PathBuf::from(path_str)
@@ -77,6 +163,20 @@ mod tests {
         assert_eq!(s.root_path(), expected_path);
     }
 
+    #[test]
+    fn keeps_its_local_directory_out_of_git() {
+        let test_root_dir = set_up();
+        let config = mock_config(test_root_dir.path());
+        let s = Shell::new("default", config);
+        fs::create_dir_all(s.root_path()).unwrap();
+
+        let local_dir = s.local_dir().expect("Local dir failed");
+
+        assert_eq!(local_dir, s.root_path().join(".hermit/local"));
+        assert!(local_dir.is_dir());
+        assert!(ignore_rules::IgnoreRules::new(s.root_path()).is_ignored(&local_dir, true));
+    }
+
     #[test]
     fn resolves_empty_string_to_root() {
         let root_path = root_path("/Users/geoff/.config/hermit");
@@ -97,6 +197,42 @@ mod tests {
         assert_eq!(s.path_for(".bashrc"), expected_path);
     }
 
+    #[test]
+    fn lists_its_files_with_their_targets() {
+        let mut config = MockConfig::with_root("/Users/geoff/.config/hermit");
+        let files = vec![
+            ShellFile::mirrored(".bashrc"),
+            ShellFile::mapped("kitty.conf", ".config/kitty/kitty.conf"),
+        ];
+        config.set_files(files.clone());
+        let s = Shell::new("default", Rc::new(config));
+
+        assert_eq!(s.files().expect("Listing failed"), files);
+    }
+
+    #[test]
+    fn links_only_the_files_its_tags_allow() {
+        let mut config = MockConfig::with_root("/Users/geoff/.config/hermit");
+        config.set_files(vec![
+            ShellFile::mirrored(".bashrc"),
+            ShellFile::mirrored(".config/sway/config").with_tags(["gui"]),
+        ]);
+        let s = Shell::new("default", Rc::new(config));
+        let op_root = PathBuf::from("op_root");
+        let mut file_ops = FileOperations::rooted_at(&op_root);
+        let tags = TagFilter {
+            skip: ["gui".to_owned()].into(),
+            ..TagFilter::default()
+        };
+
+        s.link_with_tags(&mut file_ops, &tags).expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![link_op_for(&s.root_path(), &op_root, ".bashrc")]
+        );
+    }
+
     #[test]
     fn can_link_all_paths() {
         let root_path = root_path("/Users/geoff/.config/hermit");
@@ -106,7 +242,7 @@ mod tests {
         let op_root = PathBuf::from("op_root");
         let mut file_ops = FileOperations::rooted_at(&op_root);
 
-        s.link(&mut file_ops);
+        s.link(&mut file_ops).expect("Link failed");
 
         let shell_root = s.root_path();
         assert_eq!(
@@ -118,6 +254,99 @@ mod tests {
         );
     }
 
+    #[test]
+    fn links_mapped_paths_to_their_targets() {
+        let root_path = root_path("/Users/geoff/.config/hermit");
+        let mut config = MockConfig::with_root(&root_path);
+        config.set_files(vec![ShellFile::mapped(
+            "kitty.conf",
+            ".config/kitty/kitty.conf",
+        )]);
+        let s = Shell::new("default", Rc::new(config));
+        let op_root = PathBuf::from("op_root");
+        let mut file_ops = FileOperations::rooted_at(&op_root);
+
+        s.link(&mut file_ops).expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::Link {
+                path: op_root.join(".config/kitty/kitty.conf"),
+                target: s.root_path().join("kitty.conf"),
+            }]
+        );
+    }
+
+    #[test]
+    fn copies_files_with_the_copy_strategy() {
+        let mut config = MockConfig::with_root("/Users/geoff/.config/hermit");
+        config.set_files(vec![
+            ShellFile::mirrored(".gnupg/gpg.conf").with_strategy(Strategy::Copy)
+        ]);
+        let s = Shell::new("default", Rc::new(config));
+        let op_root = PathBuf::from("op_root");
+        let mut file_ops = FileOperations::rooted_at(&op_root);
+
+        s.link(&mut file_ops).expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::Copy {
+                path: op_root.join(".gnupg/gpg.conf"),
+                source: s.root_path().join(".gnupg/gpg.conf"),
+            }]
+        );
+    }
+
+    fn kitty_shell() -> Shell<MockConfig> {
+        let mut config = MockConfig::with_root("/Users/geoff/.config/hermit");
+        config.set_files(vec![
+            ShellFile::mirrored(".bashrc"),
+            ShellFile::mapped("kitty.conf", ".config/kitty/kitty.conf"),
+        ]);
+        Shell::new("default", Rc::new(config))
+    }
+
+    #[test]
+    fn finds_files_by_their_path_in_the_shell() {
+        let s = kitty_shell();
+        assert_eq!(
+            s.find_file("kitty.conf", "/Users/geoff").unwrap(),
+            ShellFile::mapped("kitty.conf", ".config/kitty/kitty.conf")
+        );
+    }
+
+    #[test]
+    fn finds_files_by_where_they_are_linked() {
+        let s = kitty_shell();
+        let kitty = ShellFile::mapped("kitty.conf", ".config/kitty/kitty.conf");
+
+        assert_eq!(
+            s.find_file(".config/kitty/kitty.conf", "/Users/geoff")
+                .unwrap(),
+            kitty
+        );
+        assert_eq!(
+            s.find_file("~/.config/kitty/kitty.conf", "/Users/geoff")
+                .unwrap(),
+            kitty
+        );
+        assert_eq!(
+            s.find_file("/Users/geoff/.config/kitty/kitty.conf", "/Users/geoff")
+                .unwrap(),
+            kitty
+        );
+    }
+
+    #[test]
+    fn cannot_find_untracked_files() {
+        let s = kitty_shell();
+        assert_eq!(
+            s.find_file(".vimrc", "/Users/geoff"),
+            Err(Error::FileNotTracked(PathBuf::from(".vimrc")))
+        );
+    }
+
     #[test]
     fn can_unlink_all_paths() {
         let root_path = root_path("/Users/geoff/.config/hermit");
@@ -127,13 +356,19 @@ mod tests {
         let op_root = PathBuf::from("op_root");
         let mut file_ops = FileOperations::rooted_at(&op_root);
 
-        s.unlink(&mut file_ops);
+        s.unlink(&mut file_ops).expect("Unlink failed");
 
         assert_eq!(
             file_ops.operations(),
             &vec![
-                Op::Remove(op_root.join(".bashrc")),
-                Op::Remove(op_root.join(".boot/profile.boot"))
+                Op::Unlink {
+                    path: op_root.join(".bashrc"),
+                    source: s.root_path().join(".bashrc"),
+                },
+                Op::Unlink {
+                    path: op_root.join(".boot/profile.boot"),
+                    source: s.root_path().join(".boot/profile.boot"),
+                },
             ]
         );
     }
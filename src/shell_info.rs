@@ -0,0 +1,229 @@
+use crate::common::*;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::fs_backend::{FsBackend, RealFs};
+
+use crate::manifest::METADATA_DIR;
+
+/// The name of the file inside a shell's metadata directory that
+/// describes the shell.
+pub const INFO_FILE_NAME: &str = "shell.toml";
+
+/// What a shell is for and where it came from, to tell shells apart.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ShellInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The day the shell was created or cloned, as `YYYY-MM-DD`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// The URL the shell was cloned from or first pushed to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// Whether the shell stores files without their leading dot, like
+    /// `vimrc` for `~/.vimrc`, so that they aren't hidden in the
+    /// repository. The dot is added back when they are linked.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub add_dots: bool,
+    /// Whether the files in directories that symlinks in the shell lead
+    /// to, like a submodule shared between shells, are linked one by
+    /// one, instead of the symlinks themselves.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub follow_links: bool,
+    /// A folder, like one in Dropbox or iCloud, that the shell is kept
+    /// in step with instead of a git remote. It may start with `~`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync_dir: Option<PathBuf>,
+    /// Makes the shell a system shell, whose files are linked below a
+    /// root like `/` instead of the home directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<SystemTarget>,
+}
+
+/// Where a system shell's files go. A system shell manages files like
+/// `/etc/ssh/sshd_config`, which it keeps as `etc/ssh/sshd_config`,
+/// and can only be applied by root, with `hermit apply --system`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SystemTarget {
+    /// The directory the shell's files are linked below.
+    #[serde(default = "SystemTarget::default_root")]
+    pub root: PathBuf,
+    /// The directories below `root` that the shell may put files in,
+    /// so that a mistake in the shell can't replace something like
+    /// `/usr/bin/sudo`.
+    #[serde(default = "SystemTarget::default_prefixes")]
+    pub prefixes: Vec<PathBuf>,
+}
+
+impl Default for SystemTarget {
+    fn default() -> SystemTarget {
+        SystemTarget {
+            root: SystemTarget::default_root(),
+            prefixes: SystemTarget::default_prefixes(),
+        }
+    }
+}
+
+impl SystemTarget {
+    fn default_root() -> PathBuf {
+        PathBuf::from("/")
+    }
+
+    fn default_prefixes() -> Vec<PathBuf> {
+        vec![PathBuf::from("etc")]
+    }
+
+    /// Checks that every one of `targets`, relative to the root, is in
+    /// one of the prefixes.
+    pub fn check<'a>(&self, targets: impl IntoIterator<Item = &'a Path>) -> Result<()> {
+        let outside = targets
+            .into_iter()
+            .filter(|target| {
+                !self
+                    .prefixes
+                    .iter()
+                    .any(|prefix| target.starts_with(prefix) && *target != prefix)
+            })
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        if outside.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::OutsideSystemPrefixes(outside))
+        }
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl ShellInfo {
+    /// Information about a shell that is being created today.
+    pub fn new(description: Option<&str>, origin: Option<&str>) -> ShellInfo {
+        ShellInfo {
+            description: description.map(ToOwned::to_owned),
+            created: Some(today()),
+            origin: origin.map(ToOwned::to_owned),
+            add_dots: false,
+            follow_links: false,
+            sync_dir: None,
+            system: None,
+        }
+    }
+
+    /// Where the file is, relative to the root of the shell.
+    pub fn relative_path() -> PathBuf {
+        Path::new(METADATA_DIR).join(INFO_FILE_NAME)
+    }
+
+    /// Reads the information about a shell. A shell without any gets
+    /// an empty set.
+    pub fn load(shell_root: impl AsRef<Path>) -> Result<ShellInfo> {
+        ShellInfo::load_with(&RealFs, shell_root)
+    }
+
+    /// Like `load`, but reads the information from `fs`.
+    pub fn load_with(fs: &dyn FsBackend, shell_root: impl AsRef<Path>) -> Result<ShellInfo> {
+        let path = shell_root.as_ref().join(ShellInfo::relative_path());
+        match fs.read_to_string(&path) {
+            Ok(contents) => {
+                toml::from_str(&contents).map_err(|err| Error::InvalidMetadata(err.to_string()))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(ShellInfo::default()),
+            Err(err) => Err(Error::InvalidMetadata(err.to_string())),
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).map_err(|err| Error::InvalidMetadata(err.to_string()))
+    }
+}
+
+/// Today's UTC date, as `YYYY-MM-DD`.
+pub(crate) fn today() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    date_from_timestamp(now)
+}
+
+/// The UTC date of a Unix timestamp, as `YYYY-MM-DD`.
+pub(crate) fn date_from_timestamp(seconds: u64) -> String {
+    // Howard Hinnant's days-to-civil algorithm, for days since
+    // 1970-01-01 that are never negative.
+    let days = seconds / 86400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn formats_timestamps_as_dates() {
+        assert_eq!(date_from_timestamp(0), "1970-01-01");
+        assert_eq!(date_from_timestamp(951_782_400), "2000-02-29");
+        assert_eq!(date_from_timestamp(1_000_000_000), "2001-09-09");
+    }
+
+    #[test]
+    fn a_shell_without_info_has_none() {
+        let test_root_dir = set_up();
+
+        assert_eq!(
+            ShellInfo::load(test_root_dir.path()).unwrap(),
+            ShellInfo::default()
+        );
+    }
+
+    #[test]
+    fn info_survives_a_round_trip() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path();
+        let info = ShellInfo::new(Some("Work laptop"), Some("git@example.com:me/work.git"));
+        let path = shell_root.join(ShellInfo::relative_path());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, info.to_toml().unwrap()).unwrap();
+
+        assert_eq!(ShellInfo::load(shell_root).unwrap(), info);
+    }
+
+    #[test]
+    fn system_shells_only_reach_into_their_prefixes() {
+        let info: ShellInfo = toml::from_str("[system]\nprefixes = [\"etc/ssh\"]\n").unwrap();
+        let system = info.system.unwrap();
+        assert_eq!(system.root, Path::new("/"));
+
+        assert_eq!(system.check(vec![Path::new("etc/ssh/sshd_config")]), Ok(()));
+        assert_eq!(
+            system.check(vec![
+                Path::new("etc/ssh"),
+                Path::new("etc/sudoers"),
+                Path::new("etc/ssh/ssh_config"),
+            ]),
+            Err(Error::OutsideSystemPrefixes(vec![
+                PathBuf::from("etc/ssh"),
+                PathBuf::from("etc/sudoers"),
+            ]))
+        );
+    }
+}
@@ -0,0 +1,53 @@
+/// The interactive shells that `hermit shell-init` can generate
+/// integration functions for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Dialect {
+    pub const NAMES: [&'static str; 3] = ["bash", "zsh", "fish"];
+
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        match name {
+            "bash" => Some(Dialect::Bash),
+            "zsh" => Some(Dialect::Zsh),
+            "fish" => Some(Dialect::Fish),
+            _ => None,
+        }
+    }
+
+    /// The script that wraps `hermit` in a function, so that `hermit
+    /// cd` can change the directory of the calling shell, and sets up
+    /// completion for `hermit cd` and `hermit edit`.
+    pub fn script(self) -> &'static str {
+        match self {
+            Dialect::Bash => include_str!("shell_init/hermit.bash"),
+            Dialect::Zsh => include_str!("shell_init/hermit.zsh"),
+            Dialect::Fish => include_str!("shell_init/hermit.fish"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_dialect_can_be_named() {
+        for name in Dialect::NAMES.iter() {
+            assert!(Dialect::from_name(name).is_some());
+        }
+        assert_eq!(Dialect::from_name("csh"), None);
+    }
+
+    #[test]
+    fn every_script_wraps_hermit_cd() {
+        for name in Dialect::NAMES.iter() {
+            let script = Dialect::from_name(name).unwrap().script();
+            assert!(script.contains("command hermit cd"), "{} script", name);
+        }
+    }
+}
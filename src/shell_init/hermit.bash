@@ -0,0 +1,33 @@
+# Shell integration for hermit. Enable it by adding this line to your
+# ~/.bashrc:
+#
+#     eval "$(hermit shell-init bash)"
+
+hermit() {
+    if [ "$1" = cd ]; then
+        shift
+        local dir
+        dir="$(command hermit cd "$@")" && cd "$dir"
+    else
+        command hermit "$@"
+    fi
+}
+
+_hermit_shell_integration() {
+    local word="${COMP_WORDS[COMP_CWORD]}"
+    local shell_path
+    COMPREPLY=()
+    if [ "$COMP_CWORD" -eq 2 ]; then
+        shell_path="$(command hermit cd 2>/dev/null)" || return
+        case "${COMP_WORDS[1]}" in
+            cd)
+                COMPREPLY=( $(cd "$(dirname "$shell_path")" && compgen -d -- "$word") )
+                ;;
+            edit)
+                COMPREPLY=( $(cd "$shell_path" && compgen -f -- "$word") )
+                ;;
+        esac
+    fi
+}
+
+complete -o default -F _hermit_shell_integration hermit
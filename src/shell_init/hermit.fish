@@ -0,0 +1,27 @@
+# Shell integration for hermit. Enable it by adding this line to your
+# ~/.config/fish/config.fish:
+#
+#     hermit shell-init fish | source
+
+function hermit --description 'hermit, with support for hermit cd'
+    if test "$argv[1]" = cd
+        set -l dir (command hermit cd $argv[2..-1]); and cd $dir
+    else
+        command hermit $argv
+    end
+end
+
+function __hermit_shell_names
+    set -l shell_path (command hermit cd 2>/dev/null); or return
+    for shell in (dirname $shell_path)/*/
+        basename $shell
+    end
+end
+
+function __hermit_shell_files
+    set -l shell_path (command hermit cd 2>/dev/null); or return
+    find $shell_path -type f -not -path '*/.git/*' | string replace "$shell_path/" ''
+end
+
+complete -c hermit -n '__fish_seen_subcommand_from cd' -f -a '(__hermit_shell_names)'
+complete -c hermit -n '__fish_seen_subcommand_from edit' -f -a '(__hermit_shell_files)'
@@ -0,0 +1,110 @@
+//! What hermit keeps track of on this machine, like the journal of the
+//! changes it has made, the logs of bootstrap steps that have run and
+//! the indexes of the files of shells. It lives in the `state`
+//! directory of the hermit root, apart from the shells, since none of
+//! it belongs in a shell's repository.
+
+use crate::{common::*, fs_backend::FsBackend};
+
+use std::ffi::OsString;
+
+/// The directory in the hermit root that holds its state.
+pub const STATE_DIR_NAME: &str = "state";
+
+/// The state that older versions of hermit kept at the top of the
+/// hermit root.
+const MOVED_INTO_STATE: &[&str] = &["journal", "bootstrap", "index"];
+
+/// The state directory of the hermit root at `root_path`.
+pub fn dir(root_path: impl AsRef<Path>) -> PathBuf {
+    root_path.as_ref().join(STATE_DIR_NAME)
+}
+
+/// The contents of the file at `path`, or `None` if there isn't one
+/// yet.
+pub fn read(fs: &dyn FsBackend, path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs.read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replaces the file at `path` all at once, by writing the new contents
+/// next to it and renaming them over it, so that it is never left half
+/// written. The directory it goes in is created if it has to be.
+pub fn write_atomic(fs: &dyn FsBackend, path: &Path, contents: &[u8]) -> io::Result<()> {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a file", message::path(path)),
+            ))
+        }
+    };
+    fs.create_dir_all(dir)?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(".new");
+    let temp = dir.join(temp_name);
+    fs.write(&temp, contents)?;
+    fs.rename(&temp, path)
+}
+
+/// Moves the state that older versions of hermit kept at the top of the
+/// hermit root into the state directory, unless the state directory
+/// already has its own.
+pub fn migrate(fs: &dyn FsBackend, root_path: &Path) -> io::Result<()> {
+    for name in MOVED_INTO_STATE {
+        let old = root_path.join(name);
+        let new = dir(root_path).join(name);
+        if fs.exists(&old) && !fs.exists(&new) {
+            fs.create_dir_all(&dir(root_path))?;
+            fs.rename(&old, &new)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fs_backend::MemoryFs;
+
+    #[test]
+    fn files_are_replaced_in_one_go() {
+        let fs = MemoryFs::new();
+        let path = Path::new("/root/state/plan.json");
+
+        assert_eq!(read(&fs, path).unwrap(), None);
+        write_atomic(&fs, path, b"first").unwrap();
+        write_atomic(&fs, path, b"second").unwrap();
+
+        assert_eq!(read(&fs, path).unwrap(), Some(b"second".to_vec()));
+        assert_eq!(
+            fs.read_dir(Path::new("/root/state")).unwrap(),
+            vec![OsString::from("plan.json")]
+        );
+    }
+
+    #[test]
+    fn old_state_is_moved_into_the_state_directory() {
+        let fs = MemoryFs::new();
+        let root = Path::new("/root");
+        fs.add_file(root.join("bootstrap/default"), "install\n");
+        fs.add_file(root.join("journal"), "old");
+        fs.add_file(dir(root).join("journal"), "new");
+
+        migrate(&fs, root).unwrap();
+
+        assert_eq!(
+            fs.read(&dir(root).join("bootstrap/default")).unwrap(),
+            b"install\n"
+        );
+        assert!(!fs.exists(&root.join("bootstrap")));
+        assert_eq!(fs.read(&dir(root).join("journal")).unwrap(), b"new");
+        assert_eq!(fs.read(&root.join("journal")).unwrap(), b"old");
+    }
+}
@@ -0,0 +1,112 @@
+//! How much room each shell takes up, for deciding what to prune from
+//! one before cloning it onto a machine with little disk to spare.
+
+use crate::{common::*, shell_info::date_from_timestamp};
+
+/// How many files a shell has, and how big they are.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShellStats {
+    pub name: String,
+    /// The number of files the shell tracks, counting each file in a
+    /// directory that is linked as a whole.
+    pub files: usize,
+    /// The total size of those files, in bytes.
+    pub size: u64,
+    /// The size of the shell's git history, in bytes, if the shell is
+    /// a repository of its own rather than part of a shared one.
+    pub history: Option<u64>,
+    /// When the shell was last committed to, as a Unix timestamp.
+    pub last_commit: Option<i64>,
+    /// The biggest of the files, biggest first, by their path in the
+    /// shell.
+    pub largest: Vec<(PathBuf, u64)>,
+}
+
+/// The files below `path`, or `path` itself if it is a file, each with
+/// its size. Links aren't followed.
+fn sizes(path: &Path) -> Vec<(PathBuf, u64)> {
+    WalkDir::new(path)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(StdResult::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| Some((entry.path().to_path_buf(), entry.metadata().ok()?.len())))
+        .collect()
+}
+
+/// The number of bytes taken up by the files below `path`.
+pub fn disk_usage(path: &Path) -> u64 {
+    sizes(path).iter().map(|(_, size)| size).sum()
+}
+
+impl ShellStats {
+    /// Counts up the `files` of the shell named `name` at `shell_root`,
+    /// keeping the `top` largest of them.
+    pub fn of_files(name: &str, shell_root: &Path, files: &[ShellFile], top: usize) -> ShellStats {
+        let mut found = files
+            .iter()
+            .flat_map(|file| sizes(&shell_root.join(&file.source)))
+            .map(|(path, size)| {
+                let path = path
+                    .strip_prefix(shell_root)
+                    .map_or(path.clone(), Path::to_path_buf);
+                (path, size)
+            })
+            .collect::<Vec<_>>();
+        found.sort_by(|(a_path, a_size), (b_path, b_size)| {
+            b_size.cmp(a_size).then_with(|| a_path.cmp(b_path))
+        });
+        found.dedup_by(|a, b| a.0 == b.0);
+
+        let git_dir = shell_root.join(".git");
+        ShellStats {
+            name: name.to_owned(),
+            files: found.len(),
+            size: found.iter().map(|(_, size)| size).sum(),
+            history: git_dir.is_dir().then(|| disk_usage(&git_dir)),
+            last_commit: None,
+            largest: found.into_iter().take(top).collect(),
+        }
+    }
+
+    /// The UTC date of the last commit, as `YYYY-MM-DD`.
+    pub fn last_commit_date(&self) -> Option<String> {
+        self.last_commit
+            .map(|seconds| date_from_timestamp(seconds.max(0) as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{manifest::Granularity, test_helpers::filesystem::set_up};
+
+    #[test]
+    fn files_in_linked_directories_are_counted_one_by_one() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        fs::create_dir_all(root.join(".vim/plugin")).unwrap();
+        fs::write(root.join(".bashrc"), "12345").unwrap();
+        fs::write(root.join(".vim/plugin/a.vim"), "1234567890").unwrap();
+        fs::write(root.join(".vim/vimrc"), "1").unwrap();
+        fs::write(root.join("untracked"), "123456789012345").unwrap();
+        let files = vec![
+            ShellFile::mirrored(".bashrc"),
+            ShellFile::mirrored(".vim").with_granularity(Granularity::Directory),
+        ];
+
+        let stats = ShellStats::of_files("default", root, &files, 2);
+
+        assert_eq!(stats.files, 3);
+        assert_eq!(stats.size, 16);
+        assert_eq!(stats.history, None);
+        assert_eq!(
+            stats.largest,
+            vec![
+                (PathBuf::from(".vim/plugin/a.vim"), 10),
+                (PathBuf::from(".bashrc"), 5),
+            ]
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use crate::{
+    common::*,
+    diff,
+    fs_backend::{self, RealFs},
+    manifest::{Granularity, Strategy},
+    shell_info::ShellInfo,
+};
+
+use sha2::{Digest, Sha256};
+
+/// How the link for a single shell file looks on disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileState {
+    /// The target is a symlink to the file in the shell.
+    Linked,
+    /// Nothing exists at the target path.
+    Missing,
+    /// The target is a symlink, but it points somewhere else.
+    LinkedElsewhere,
+    /// A regular file or directory is in the way of the link.
+    Conflict,
+    /// The target is a copy of the file in the shell, and its contents
+    /// are the same.
+    Copied,
+    /// The target is a copy of the file in the shell, but one of them
+    /// has changed since the copy was made.
+    OutOfDate,
+}
+
+impl FileState {
+    /// Inspects `path` to see whether it links to `target`.
+    pub fn of(path: impl AsRef<Path>, target: impl AsRef<Path>) -> FileState {
+        let path = path.as_ref();
+        match fs::symlink_metadata(path) {
+            Err(_) => FileState::Missing,
+            Ok(metadata) if metadata.file_type().is_symlink() => match fs::read_link(path) {
+                Ok(ref link_target) if link_target == target.as_ref() => FileState::Linked,
+                _ => FileState::LinkedElsewhere,
+            },
+            Ok(_) => FileState::Conflict,
+        }
+    }
+
+    /// Inspects the target of `file` according to its strategy.
+    /// `target_root` is where the file gets put, and `shell_root` is
+    /// where it lives.
+    ///
+    /// A file that is only there because a directory it is in is linked
+    /// as a whole, or a directory whose files are linked one by one
+    /// when it should be linked as a whole, is missing: linking it
+    /// replaces that link or directory.
+    pub fn of_file(file: &ShellFile, target_root: &Path, shell_root: &Path) -> FileState {
+        let path = target_root.join(&file.target);
+        let source = shell_root.join(&file.source);
+        if fs_backend::is_reached_through_link(&RealFs, &path, &source) {
+            return FileState::Missing;
+        }
+        match file.strategy {
+            Strategy::Link
+                if file.granularity == Granularity::Directory
+                    && fs_backend::is_linked_file_by_file(&RealFs, &path, &source) =>
+            {
+                FileState::Missing
+            }
+            Strategy::Link => FileState::of(path, source),
+            Strategy::Copy => FileState::of_copy(path, source),
+        }
+    }
+
+    /// Inspects `path` to see whether it is an up to date copy of
+    /// `source`, by comparing the hashes of their contents.
+    pub fn of_copy(path: impl AsRef<Path>, source: impl AsRef<Path>) -> FileState {
+        let path = path.as_ref();
+        match fs::symlink_metadata(path) {
+            Err(_) => FileState::Missing,
+            Ok(metadata) if !metadata.is_file() => FileState::Conflict,
+            Ok(_) => match (content_hash(path), content_hash(source)) {
+                (Ok(copy), Ok(original)) if copy == original => FileState::Copied,
+                _ => FileState::OutOfDate,
+            },
+        }
+    }
+
+    /// Whether the file is where it should be, with the right contents.
+    pub fn is_in_place(self) -> bool {
+        matches!(self, FileState::Linked | FileState::Copied)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            FileState::Linked => "linked",
+            FileState::Missing => "missing",
+            FileState::LinkedElsewhere => "linked elsewhere",
+            FileState::Conflict => "conflict",
+            FileState::Copied => "copied",
+            FileState::OutOfDate => "out of date",
+        }
+    }
+}
+
+pub(crate) fn content_hash(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+/// The state of one file tracked by a shell.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileStatus {
+    pub file: ShellFile,
+    pub state: FileState,
+}
+
+/// How the contents of a file that isn't in place differ from the
+/// shell's version of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// The file is secret, so how it differs isn't shown.
+    Redacted,
+    /// One of the files isn't text.
+    Binary,
+    /// The files are too long to compare.
+    TooLong,
+    /// The lines that differ, from the shell's version to the file.
+    Lines(Vec<diff::Line>),
+}
+
+impl FileStatus {
+    /// The two character code that `status --short` shows the file
+    /// with. The first says how the file is put in place, `L` for a
+    /// link or `C` for a copy, and the second what state it is in:
+    /// `=` in place, `?` missing, `>` linked elsewhere, `!` in the way
+    /// or `~` out of date. The codes never change, so that scripts can
+    /// rely on them.
+    pub fn short_code(&self) -> String {
+        let strategy = match self.file.strategy {
+            Strategy::Link => 'L',
+            Strategy::Copy => 'C',
+        };
+        let state = match self.state {
+            FileState::Linked | FileState::Copied => '=',
+            FileState::Missing => '?',
+            FileState::LinkedElsewhere => '>',
+            FileState::Conflict => '!',
+            FileState::OutOfDate => '~',
+        };
+        format!("{}{}", strategy, state)
+    }
+
+    /// How the file in `target_root` differs from the one in the
+    /// shell, if it is a regular file that is out of date or in the
+    /// way. Secret files are only compared if `show_secrets` is set.
+    pub fn difference(
+        &self,
+        target_root: &Path,
+        shell_root: &Path,
+        show_secrets: bool,
+    ) -> Option<Difference> {
+        if !matches!(self.state, FileState::OutOfDate | FileState::Conflict) {
+            return None;
+        }
+        let path = target_root.join(&self.file.target);
+        let source = shell_root.join(&self.file.source);
+        if !fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_file()) {
+            return None;
+        }
+        if self.file.secret && !show_secrets {
+            return Some(Difference::Redacted);
+        }
+
+        let old = fs::read(source).ok()?;
+        let new = fs::read(path).ok()?;
+        match (String::from_utf8(old), String::from_utf8(new)) {
+            (Ok(old), Ok(new)) => {
+                Some(diff::lines(&old, &new).map_or(Difference::TooLong, Difference::Lines))
+            }
+            _ => Some(Difference::Binary),
+        }
+    }
+}
+
+/// The state of every file tracked by a shell.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub shell: String,
+    pub info: ShellInfo,
+    pub files: Vec<FileStatus>,
+}
+
+impl Status {
+    /// Whether every file of the shell is linked or copied into place.
+    pub fn is_clean(&self) -> bool {
+        self.files.iter().all(|status| status.state.is_in_place())
+    }
+}
+
+/// What it takes to bring the files of a shell up to date, after its
+/// files or its manifest have changed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Delta {
+    pub linked: Vec<ShellFile>,
+    pub copied: Vec<ShellFile>,
+    /// Links that hermit made to files the shell no longer tracks, or
+    /// no longer puts there.
+    pub unlinked: Vec<PathBuf>,
+}
+
+impl Delta {
+    pub fn is_empty(&self) -> bool {
+        self.linked.is_empty() && self.copied.is_empty() && self.unlinked.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn a_missing_path_is_missing() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+
+        let state = FileState::of(test_root.join("link"), test_root.join("target"));
+        assert_eq!(state, FileState::Missing);
+    }
+
+    #[test]
+    fn a_link_to_the_target_is_linked() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let target = test_root.join("target");
+        unix::fs::symlink(&target, test_root.join("link")).unwrap();
+
+        assert_eq!(
+            FileState::of(test_root.join("link"), &target),
+            FileState::Linked
+        );
+    }
+
+    #[test]
+    fn a_link_to_another_file_is_linked_elsewhere() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        unix::fs::symlink(test_root.join("other"), test_root.join("link")).unwrap();
+
+        assert_eq!(
+            FileState::of(test_root.join("link"), test_root.join("target")),
+            FileState::LinkedElsewhere
+        );
+    }
+
+    #[test]
+    fn a_regular_file_is_a_conflict() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        File::create(test_root.join("link")).unwrap();
+
+        assert_eq!(
+            FileState::of(test_root.join("link"), test_root.join("target")),
+            FileState::Conflict
+        );
+    }
+
+    #[test]
+    fn a_copy_with_the_same_contents_is_copied() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::write(test_root.join("source"), "contents").unwrap();
+        fs::write(test_root.join("copy"), "contents").unwrap();
+
+        assert_eq!(
+            FileState::of_copy(test_root.join("copy"), test_root.join("source")),
+            FileState::Copied
+        );
+    }
+
+    #[test]
+    fn a_copy_with_different_contents_is_out_of_date() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::write(test_root.join("source"), "new contents").unwrap();
+        fs::write(test_root.join("copy"), "contents").unwrap();
+
+        assert_eq!(
+            FileState::of_copy(test_root.join("copy"), test_root.join("source")),
+            FileState::OutOfDate
+        );
+        assert_eq!(
+            FileState::of_copy(test_root.join("missing"), test_root.join("source")),
+            FileState::Missing
+        );
+    }
+
+    #[test]
+    fn a_link_where_a_copy_belongs_is_a_conflict() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::write(test_root.join("source"), "contents").unwrap();
+        unix::fs::symlink(test_root.join("source"), test_root.join("copy")).unwrap();
+
+        assert_eq!(
+            FileState::of_copy(test_root.join("copy"), test_root.join("source")),
+            FileState::Conflict
+        );
+    }
+
+    #[test]
+    fn a_status_is_clean_when_everything_is_linked() {
+        let linked = FileStatus {
+            file: ShellFile::mirrored(".bashrc"),
+            state: FileState::Linked,
+        };
+        let missing = FileStatus {
+            file: ShellFile::mirrored(".vimrc"),
+            state: FileState::Missing,
+        };
+
+        let mut status = Status {
+            shell: "default".to_owned(),
+            info: ShellInfo::default(),
+            files: vec![linked],
+        };
+        assert!(status.is_clean());
+
+        status.files.push(FileStatus {
+            file: ShellFile::mirrored(".gnupg/gpg.conf").with_strategy(Strategy::Copy),
+            state: FileState::Copied,
+        });
+        assert!(status.is_clean());
+
+        status.files.push(missing);
+        assert!(!status.is_clean());
+    }
+
+    #[test]
+    fn secret_differences_are_redacted_unless_asked_for() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::write(test_root.join("shell_netrc"), "password one\n").unwrap();
+        fs::write(test_root.join(".netrc"), "password two\n").unwrap();
+        let status = FileStatus {
+            file: ShellFile::mapped("shell_netrc", ".netrc")
+                .with_strategy(Strategy::Copy)
+                .with_secret(true),
+            state: FileState::OutOfDate,
+        };
+
+        assert_eq!(
+            status.difference(test_root, test_root, false),
+            Some(Difference::Redacted)
+        );
+        assert_eq!(
+            status.difference(test_root, test_root, true),
+            Some(Difference::Lines(vec![
+                diff::Line::Removed("password one".to_owned()),
+                diff::Line::Added("password two".to_owned()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn files_in_place_have_no_difference() {
+        let status = FileStatus {
+            file: ShellFile::mirrored(".bashrc"),
+            state: FileState::Linked,
+        };
+
+        assert_eq!(
+            status.difference(Path::new("/home"), Path::new("/shell"), true),
+            None
+        );
+    }
+
+    #[test]
+    fn short_codes_show_the_strategy_and_state() {
+        let status = |strategy, state| FileStatus {
+            file: ShellFile {
+                strategy,
+                ..ShellFile::mirrored(".bashrc")
+            },
+            state,
+        };
+
+        assert_eq!(status(Strategy::Link, FileState::Linked).short_code(), "L=");
+        assert_eq!(
+            status(Strategy::Link, FileState::Conflict).short_code(),
+            "L!"
+        );
+        assert_eq!(
+            status(Strategy::Copy, FileState::OutOfDate).short_code(),
+            "C~"
+        );
+        assert_eq!(
+            status(Strategy::Copy, FileState::Missing).short_code(),
+            "C?"
+        );
+    }
+}
@@ -0,0 +1,95 @@
+/// The names among `candidates` that look like a misspelling of
+/// `name`, closest first.
+pub fn similar_names(name: &str, candidates: &[String]) -> Vec<String> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut similar = candidates
+        .iter()
+        .map(|candidate| (strsim::levenshtein(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect::<Vec<_>>();
+    similar.sort();
+    similar
+        .into_iter()
+        .map(|(_, candidate)| candidate.clone())
+        .collect()
+}
+
+/// The only name among `candidates` that starts with `prefix`, if
+/// there is exactly one.
+pub fn unique_prefix_match(prefix: &str, candidates: &[String]) -> Option<String> {
+    let mut matches = candidates
+        .iter()
+        .filter(|candidate| candidate.starts_with(prefix));
+    match (matches.next(), matches.next()) {
+        (Some(only), None) if !prefix.is_empty() => Some(only.clone()),
+        _ => None,
+    }
+}
+
+/// Formats suggestions as a sentence to append to an error message.
+pub fn did_you_mean(suggestions: &[String]) -> String {
+    match suggestions {
+        [] => String::new(),
+        [only] => format!(". Did you mean '{}'?", only),
+        _ => format!(
+            ". Did you mean one of {}?",
+            suggestions
+                .iter()
+                .map(|suggestion| format!("'{}'", suggestion))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn suggests_names_with_small_typos() {
+        let shells = names(&["default", "work", "server"]);
+        assert_eq!(similar_names("defualt", &shells), names(&["default"]));
+        assert_eq!(similar_names("wrok", &shells), Vec::<String>::new());
+        assert_eq!(similar_names("wok", &shells), names(&["work"]));
+    }
+
+    #[test]
+    fn suggests_nothing_for_unrelated_names() {
+        let shells = names(&["default", "work"]);
+        assert!(similar_names("laptop", &shells).is_empty());
+    }
+
+    #[test]
+    fn closest_suggestions_come_first() {
+        let shells = names(&["works", "work"]);
+        assert_eq!(similar_names("wor", &shells), names(&["work"]));
+        assert_eq!(similar_names("workz", &shells), names(&["work", "works"]));
+    }
+
+    #[test]
+    fn accepts_unambiguous_prefixes() {
+        let shells = names(&["default", "work", "workstation"]);
+        assert_eq!(unique_prefix_match("def", &shells), Some("default".into()));
+        assert_eq!(unique_prefix_match("wor", &shells), None);
+        assert_eq!(
+            unique_prefix_match("works", &shells),
+            Some("workstation".into())
+        );
+        assert_eq!(unique_prefix_match("", &names(&["default"])), None);
+    }
+
+    #[test]
+    fn formats_suggestions() {
+        assert_eq!(did_you_mean(&[]), "");
+        assert_eq!(did_you_mean(&names(&["work"])), ". Did you mean 'work'?");
+        assert_eq!(
+            did_you_mean(&names(&["work", "works"])),
+            ". Did you mean one of 'work', 'works'?"
+        );
+    }
+}
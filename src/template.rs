@@ -0,0 +1,225 @@
+use crate::common::*;
+
+use std::collections::BTreeMap;
+
+/// The built-in skeletons, by name. Each is a list of paths and
+/// their contents.
+const BUILTINS: [(&str, &[(&str, &str)]); 1] = [(
+    "basic",
+    &[
+        ("hermit.toml", include_str!("template/basic/hermit.toml")),
+        ("README.md", include_str!("template/basic/README.md")),
+        (
+            ".hermit/hooks/README.md",
+            include_str!("template/basic/hooks/README.md"),
+        ),
+    ],
+)];
+
+/// A file to create in a new shell.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateFile {
+    pub path: PathBuf,
+    pub contents: Vec<u8>,
+}
+
+/// Where the initial files of a new shell come from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Template {
+    Builtin(&'static str),
+    Repository(String),
+}
+
+impl Template {
+    pub fn builtin_names() -> Vec<&'static str> {
+        BUILTINS.iter().map(|(name, _)| *name).collect()
+    }
+
+    /// Built-in skeletons are referred to by name, anything else is
+    /// taken to be the URL of a git repository.
+    pub fn from_spec(spec: &str) -> Template {
+        match BUILTINS.iter().find(|(name, _)| *name == spec) {
+            Some((name, _)) => Template::Builtin(name),
+            None => Template::Repository(spec.to_owned()),
+        }
+    }
+
+    /// The files of the template with their placeholders expanded. A
+    /// template repository is cloned to get them, but its history is
+    /// left behind.
+    pub fn files(&self, placeholders: &Placeholders) -> Result<Vec<TemplateFile>> {
+        let files = match self {
+            Template::Builtin(name) => builtin_files(name),
+            Template::Repository(url) => repository_files(url)
+                .map_err(|err| Error::TemplateFailed(url.clone(), err.to_string()))?,
+        };
+
+        Ok(files
+            .into_iter()
+            .map(|file| TemplateFile {
+                contents: placeholders.expand(file.contents),
+                ..file
+            })
+            .collect())
+    }
+}
+
+fn builtin_files(name: &str) -> Vec<TemplateFile> {
+    BUILTINS
+        .iter()
+        .filter(|(builtin, _)| *builtin == name)
+        .flat_map(|(_, files)| files.iter())
+        .map(|(path, contents)| TemplateFile {
+            path: PathBuf::from(path),
+            contents: contents.as_bytes().to_vec(),
+        })
+        .collect()
+}
+
+fn repository_files(url: &str) -> anyhow::Result<Vec<TemplateFile>> {
+    let checkout = tempfile::tempdir()?;
+    git2::Repository::clone(url, checkout.path())?;
+
+    let mut files = vec![];
+    let entries = WalkDir::new(checkout.path())
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != ".git");
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files.push(TemplateFile {
+                path: entry.path().strip_prefix(checkout.path())?.to_path_buf(),
+                contents: fs::read(entry.path())?,
+            });
+        }
+    }
+    Ok(files)
+}
+
+/// The values substituted for `{{name}}` style placeholders in
+/// template files.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Placeholders {
+    values: BTreeMap<&'static str, String>,
+}
+
+impl Placeholders {
+    /// Placeholders for a new shell: the built-in `variables`, `shell`
+    /// as the new shell's name, plus the user's `name` and `email` from
+    /// their git configuration when it has them.
+    pub fn for_shell(shell_name: &str, variables: Vec<(&'static str, String)>) -> Placeholders {
+        let mut placeholders = Placeholders::default();
+        for (placeholder, value) in variables {
+            placeholders.set(placeholder, value);
+        }
+        placeholders.set("shell", shell_name);
+        if let Ok(config) = git2::Config::open_default() {
+            for (placeholder, key) in &[("name", "user.name"), ("email", "user.email")] {
+                if let Ok(value) = config.get_string(key) {
+                    placeholders.set(placeholder, value);
+                }
+            }
+        }
+        placeholders
+    }
+
+    pub fn set(&mut self, placeholder: &'static str, value: impl Into<String>) {
+        self.values.insert(placeholder, value.into());
+    }
+
+    /// Replaces the known placeholders in `contents`. Files that aren't
+    /// text are left alone, as are unknown placeholders.
+    pub fn expand(&self, contents: Vec<u8>) -> Vec<u8> {
+        match String::from_utf8(contents) {
+            Ok(mut text) => {
+                for (placeholder, value) in &self.values {
+                    text = text.replace(&format!("{{{{{}}}}}", placeholder), value);
+                }
+                text.into_bytes()
+            }
+            Err(err) => err.into_bytes(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        git::test_support::init_repo, manifest::MANIFEST_FILE_NAME,
+        test_helpers::filesystem::set_up,
+    };
+
+    fn placeholders() -> Placeholders {
+        let mut placeholders = Placeholders::default();
+        placeholders.set("shell", "work");
+        placeholders.set("name", "Geoff");
+        placeholders
+    }
+
+    #[test]
+    fn builtin_templates_are_found_by_name() {
+        assert_eq!(Template::from_spec("basic"), Template::Builtin("basic"));
+        assert_eq!(
+            Template::from_spec("https://example.com/skeleton.git"),
+            Template::Repository("https://example.com/skeleton.git".to_owned())
+        );
+    }
+
+    #[test]
+    fn expands_known_placeholders() {
+        let expanded = placeholders().expand(b"{{name}}'s {{shell}} {{unknown}}".to_vec());
+        assert_eq!(expanded, b"Geoff's work {{unknown}}".to_vec());
+    }
+
+    #[test]
+    fn new_shells_are_named_after_themselves() {
+        let variables = vec![("os", "linux".to_owned()), ("shell", "old".to_owned())];
+        let placeholders = Placeholders::for_shell("new", variables);
+
+        let expanded = placeholders.expand(b"{{shell}} on {{os}}".to_vec());
+        assert_eq!(expanded, b"new on linux".to_vec());
+    }
+
+    #[test]
+    fn leaves_binary_files_alone() {
+        let binary = vec![0xff, b'{', b'{', 0xfe];
+        assert_eq!(placeholders().expand(binary.clone()), binary);
+    }
+
+    #[test]
+    fn the_basic_template_has_a_manifest() {
+        let files = Template::from_spec("basic").files(&placeholders()).unwrap();
+        let manifest = files
+            .iter()
+            .find(|file| file.path == Path::new(MANIFEST_FILE_NAME))
+            .expect("No manifest in template");
+
+        let contents = String::from_utf8(manifest.contents.clone()).unwrap();
+        assert!(contents.contains("work shell of Geoff"));
+        assert!(Manifest::parse(&contents).is_ok());
+    }
+
+    #[test]
+    fn repository_templates_leave_their_history_behind() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        init_repo(test_root);
+        fs::create_dir(test_root.join("vim")).unwrap();
+        fs::write(test_root.join("vim/.vimrc"), "\" {{name}}").unwrap();
+        crate::git::commit_paths(test_root, &["vim/.vimrc"], "Add vimrc", false).unwrap();
+
+        let template = Template::from_spec(test_root.to_str().unwrap());
+        let files = template.files(&placeholders()).unwrap();
+
+        assert_eq!(
+            files,
+            vec![TemplateFile {
+                path: PathBuf::from("vim/.vimrc"),
+                contents: b"\" Geoff".to_vec(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,411 @@
+//! Replacing the running hermit with the latest release from GitHub,
+//! for machines where hermit wasn't installed by a package manager
+//! that could upgrade it instead.
+//!
+//! Every release publishes a binary for each platform, named like
+//! `hermit-x86_64-linux`, along with a `SHA256SUMS` file of their
+//! checksums and a `SHA256SUMS.asc` gpg signature of that file. The
+//! signature is checked against the release key that is built into
+//! hermit, never against the keys in the user's keyring.
+
+use crate::{common::*, journal::hex};
+
+use std::{cmp::Ordering, env, os::unix::fs::PermissionsExt};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// Where the latest release is described.
+pub const RELEASES_URL: &str = "https://api.github.com/repos/bike-barn/hermit/releases/latest";
+
+/// Overrides `RELEASES_URL`, for mirrors of the releases.
+pub const RELEASES_URL_VAR: &str = "HERMIT_RELEASES_URL";
+
+/// The file of a release that holds the checksums of its binaries.
+pub const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+
+/// The signature of `CHECKSUMS_ASSET`.
+pub const SIGNATURE_ASSET: &str = "SHA256SUMS.asc";
+
+/// The public key that hermit's releases are signed with, as a binary
+/// keyring for `gpgv`. It is exported into `keys/release.gpg` when
+/// releases are built; a build without it can't check signatures, and
+/// so doesn't install releases at all.
+#[cfg(release_key)]
+const RELEASE_KEYRING: Option<&[u8]> = Some(include_bytes!("../keys/release.gpg"));
+#[cfg(not(release_key))]
+const RELEASE_KEYRING: Option<&[u8]> = None;
+
+/// The version of hermit that is running.
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A published release, as the GitHub API describes it.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Release {
+    #[serde(rename = "tag_name")]
+    pub tag: String,
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+}
+
+/// A file attached to a release.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    #[serde(rename = "browser_download_url")]
+    pub url: String,
+}
+
+/// The name of the binary built for the machine hermit is running on.
+pub fn binary_name() -> String {
+    format!("hermit-{}-{}", env::consts::ARCH, env::consts::OS)
+}
+
+fn failed(reason: impl Into<String>) -> Error {
+    Error::UpgradeFailed(reason.into())
+}
+
+/// Downloads `url` with curl, which also reads `file://` URLs.
+pub fn download(url: &str) -> Result<Vec<u8>> {
+    let output = process::Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--header", "Accept: application/vnd.github+json"])
+        .arg(url)
+        .stdin(process::Stdio::null())
+        .output()
+        .map_err(|err| failed(format!("could not run curl: {}", err)))?;
+    if !output.status.success() {
+        return Err(failed(format!(
+            "could not download {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+impl Release {
+    /// The latest release, from `HERMIT_RELEASES_URL` if it is set.
+    pub fn latest() -> Result<Release> {
+        let url = env::var(RELEASES_URL_VAR).unwrap_or_else(|_| RELEASES_URL.to_owned());
+        Release::parse(&download(&url)?)
+    }
+
+    pub fn parse(json: &[u8]) -> Result<Release> {
+        serde_json::from_slice(json)
+            .map_err(|err| failed(format!("could not read the release: {}", err)))
+    }
+
+    /// The version of the release, without the `v` its tag starts with.
+    pub fn version(&self) -> &str {
+        self.tag.strip_prefix('v').unwrap_or(&self.tag)
+    }
+
+    /// Whether the release is newer than the hermit that is running.
+    pub fn is_newer(&self) -> bool {
+        compare_versions(self.version(), CURRENT_VERSION) == Ordering::Greater
+    }
+
+    pub fn asset(&self, name: &str) -> Result<&Asset> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| failed(format!("release {} has no {}", self.tag, name)))
+    }
+
+    /// Downloads the binary for this machine, and checks it against
+    /// the release's checksums, and those against their signature by
+    /// the release key. A release that isn't signed is refused unless
+    /// `allow_unsigned`, since the checksums alone only show that the
+    /// download is whole. A hermit without the release key refuses
+    /// every release. Returns the binary, and whether its checksums
+    /// were signed.
+    pub fn fetch_binary(&self, allow_unsigned: bool) -> Result<(Vec<u8>, bool)> {
+        self.fetch_binary_with(RELEASE_KEYRING, "gpgv", allow_unsigned)
+    }
+
+    fn fetch_binary_with(
+        &self,
+        keyring: Option<&[u8]>,
+        gpgv: &str,
+        allow_unsigned: bool,
+    ) -> Result<(Vec<u8>, bool)> {
+        let keyring = keyring.ok_or_else(|| {
+            failed(
+                "this hermit was built without the key that signs releases, so it can't \
+                 check them; upgrade it the way it was installed instead",
+            )
+        })?;
+        let name = binary_name();
+        let signature = self.asset(SIGNATURE_ASSET).ok();
+        if signature.is_none() && !allow_unsigned {
+            return Err(failed(format!(
+                "release {} isn't signed, so nothing shows who built it; pass \
+                 --allow-unsigned to install it on its checksum alone",
+                self.tag
+            )));
+        }
+        let binary = download(&self.asset(&name)?.url)?;
+        let sums = download(&self.asset(CHECKSUMS_ASSET)?.url)?;
+        let signed = match signature {
+            Some(signature) => {
+                let signature = download(&signature.url)?;
+                verify_signature(gpgv, keyring, &sums, &signature)?;
+                true
+            }
+            None => false,
+        };
+
+        let sums = String::from_utf8_lossy(&sums);
+        let expected = expected_checksum(&sums, &name)
+            .ok_or_else(|| failed(format!("{} has no checksum for {}", CHECKSUMS_ASSET, name)))?;
+        if hex(&Sha256::digest(&binary)) != expected.to_lowercase() {
+            return Err(failed(format!(
+                "the download of {} doesn't match its checksum",
+                name
+            )));
+        }
+        Ok((binary, signed))
+    }
+}
+
+/// Compares versions like `1.10.0` and `1.9.2` number by number.
+/// Anything after a `-` or `+`, like `-rc1`, is ignored.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let numbers = |version: &str| {
+        version
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse::<u64>().unwrap_or(0))
+            .collect::<Vec<_>>()
+    };
+    numbers(a).cmp(&numbers(b))
+}
+
+/// The checksum of the file `name` in the lines of a `SHA256SUMS`
+/// file, as `sha256sum` writes them.
+pub fn expected_checksum<'a>(sums: &'a str, name: &str) -> Option<&'a str> {
+    sums.lines().find_map(|line| {
+        let (sum, file) = line.split_once(char::is_whitespace)?;
+        (file.trim_start().trim_start_matches('*') == name).then_some(sum)
+    })
+}
+
+/// Checks the gpg signature of the checksums with `gpgv`, against
+/// `keyring` alone, so that no other key the user trusts can vouch for
+/// a release.
+fn verify_signature(gpgv: &str, keyring: &[u8], sums: &[u8], signature: &[u8]) -> Result<()> {
+    let dir = tempfile::tempdir().map_err(|err| failed(err.to_string()))?;
+    let keyring_path = dir.path().join("release.gpg");
+    let sums_path = dir.path().join(CHECKSUMS_ASSET);
+    let signature_path = dir.path().join(SIGNATURE_ASSET);
+    fs::write(&keyring_path, keyring)
+        .and_then(|()| fs::write(&sums_path, sums))
+        .and_then(|()| fs::write(&signature_path, signature))
+        .map_err(|err| failed(err.to_string()))?;
+    let output = process::Command::new(gpgv)
+        .arg("--keyring")
+        .arg(&keyring_path)
+        .args([&signature_path, &sums_path])
+        .stdin(process::Stdio::null())
+        .output()
+        .map_err(|err| failed(format!("could not run {}: {}", gpgv, err)))?;
+    if !output.status.success() {
+        return Err(failed(format!(
+            "{} isn't signed by hermit's release key:\n{}",
+            CHECKSUMS_ASSET,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Replaces the binary at `path` with `binary`, keeping its
+/// permissions. The new binary is written next to it and renamed over
+/// it, so that the old one keeps working until the new one is whole.
+pub fn install(path: &Path, binary: &[u8]) -> Result<()> {
+    let cannot_write = |err: io::Error| {
+        failed(format!(
+            "could not replace {}: {}",
+            message::path(path),
+            err
+        ))
+    };
+    let mode = fs::metadata(path).map_or(0o755, |metadata| metadata.permissions().mode());
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(".upgrade");
+    let temp = path.with_file_name(temp_name);
+
+    fs::write(&temp, binary).map_err(cannot_write)?;
+    fs::set_permissions(&temp, fs::Permissions::from_mode(mode))
+        .and_then(|()| fs::rename(&temp, path))
+        .map_err(|err| {
+            let _ = fs::remove_file(&temp);
+            cannot_write(err)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn versions_are_compared_number_by_number() {
+        assert_eq!(compare_versions("1.10.0", "1.9.2"), Ordering::Greater);
+        assert_eq!(compare_versions("0.0.1", "0.0.1-rc1"), Ordering::Equal);
+        assert_eq!(compare_versions("0.1", "0.1.1"), Ordering::Less);
+    }
+
+    /// A stand-in for `gpgv` that takes `RELEASE` for the release key,
+    /// and `signed:` followed by the checksums for their signature.
+    fn fake_gpgv(dir: &Path) -> String {
+        let gpgv = dir.join("fake-gpgv");
+        fs::write(
+            &gpgv,
+            "#!/bin/sh\n[ \"$1\" = --keyring ] && grep -q RELEASE \"$2\" \
+             && [ \"$(cat \"$3\")\" = \"signed:$(cat \"$4\")\" ]\n",
+        )
+        .unwrap();
+        fs::set_permissions(&gpgv, fs::Permissions::from_mode(0o755)).unwrap();
+        gpgv.to_str().unwrap().to_owned()
+    }
+
+    /// A release in `dir` of `binary`, with checksums, signed if
+    /// `signed`.
+    fn release_in(dir: &Path, binary: &[u8], signed: bool) -> Release {
+        let name = binary_name();
+        fs::write(dir.join(&name), binary).unwrap();
+        let sums = format!(
+            "{}  {}\n{}  other\n",
+            hex(&Sha256::digest(binary)),
+            name,
+            "0".repeat(64)
+        );
+        fs::write(dir.join(CHECKSUMS_ASSET), &sums).unwrap();
+        fs::write(
+            dir.join(SIGNATURE_ASSET),
+            format!("signed:{}", sums.trim_end()),
+        )
+        .unwrap();
+        let asset = |name: &str| {
+            format!(
+                r#"{{"name": "{}", "browser_download_url": "file://{}"}}"#,
+                name,
+                dir.join(name).display()
+            )
+        };
+        let mut assets = vec![asset(&name), asset(CHECKSUMS_ASSET)];
+        if signed {
+            assets.push(asset(SIGNATURE_ASSET));
+        }
+        Release::parse(
+            format!(
+                r#"{{"tag_name": "v99.0.0", "assets": [{}]}}"#,
+                assets.join(", ")
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn a_checked_binary_replaces_the_old_one() {
+        let test_root_dir = set_up();
+        let dir = test_root_dir.path();
+        let gpgv = fake_gpgv(dir);
+        let binary = b"#!/bin/sh\necho new\n";
+        let release = release_in(dir, binary, true);
+        assert!(release.is_newer());
+
+        let (fetched, signed) = release
+            .fetch_binary_with(Some(b"RELEASE"), &gpgv, false)
+            .unwrap();
+        assert!(signed);
+
+        let installed = dir.join("hermit");
+        fs::write(&installed, "old").unwrap();
+        fs::set_permissions(&installed, fs::Permissions::from_mode(0o750)).unwrap();
+        install(&installed, &fetched).unwrap();
+        assert_eq!(fs::read(&installed).unwrap(), binary);
+        assert_eq!(
+            fs::metadata(&installed).unwrap().permissions().mode() & 0o777,
+            0o750
+        );
+    }
+
+    #[test]
+    fn unsigned_releases_need_to_be_allowed() {
+        let test_root_dir = set_up();
+        let dir = test_root_dir.path();
+        let gpgv = fake_gpgv(dir);
+        let release = release_in(dir, b"new", false);
+
+        assert!(matches!(
+            release.fetch_binary_with(Some(b"RELEASE"), &gpgv, false),
+            Err(Error::UpgradeFailed(reason)) if reason.contains("isn't signed")
+        ));
+        let (fetched, signed) = release
+            .fetch_binary_with(Some(b"RELEASE"), &gpgv, true)
+            .unwrap();
+        assert_eq!((&fetched[..], signed), (&b"new"[..], false));
+    }
+
+    #[test]
+    fn nothing_is_installed_without_the_release_key() {
+        let test_root_dir = set_up();
+        let dir = test_root_dir.path();
+        let gpgv = fake_gpgv(dir);
+
+        for signed in [true, false] {
+            assert!(matches!(
+                release_in(dir, b"new", signed).fetch_binary_with(None, &gpgv, true),
+                Err(Error::UpgradeFailed(reason)) if reason.contains("without the key")
+            ));
+        }
+    }
+
+    #[test]
+    fn tampered_releases_are_refused() {
+        let test_root_dir = set_up();
+        let dir = test_root_dir.path();
+        let gpgv = fake_gpgv(dir);
+        let release = release_in(dir, b"new", true);
+        let name = binary_name();
+
+        // The binary was swapped, but not the checksums.
+        fs::write(dir.join(&name), "evil").unwrap();
+        assert!(matches!(
+            release.fetch_binary_with(Some(b"RELEASE"), &gpgv, true),
+            Err(Error::UpgradeFailed(reason)) if reason.contains("doesn't match its checksum")
+        ));
+
+        // The checksums were swapped too, but they can't be signed.
+        fs::write(
+            dir.join(CHECKSUMS_ASSET),
+            format!("{}  {}\n", hex(&Sha256::digest(b"evil")), name),
+        )
+        .unwrap();
+        assert!(matches!(
+            release.fetch_binary_with(Some(b"RELEASE"), &gpgv, true),
+            Err(Error::UpgradeFailed(reason)) if reason.contains("release key")
+        ));
+    }
+
+    #[test]
+    fn signatures_are_checked_against_the_release_key_alone() {
+        let test_root_dir = set_up();
+        let gpgv = fake_gpgv(test_root_dir.path());
+
+        assert!(verify_signature(&gpgv, b"RELEASE", b"sums", b"signed:sums").is_ok());
+        assert!(matches!(
+            verify_signature(&gpgv, b"RELEASE", b"tampered sums", b"signed:sums"),
+            Err(Error::UpgradeFailed(reason)) if reason.contains("release key")
+        ));
+        assert!(verify_signature(&gpgv, b"SOMEONE ELSE", b"sums", b"signed:sums").is_err());
+    }
+}
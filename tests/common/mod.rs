@@ -0,0 +1,90 @@
+//! Runs the real `hermit` binary against a hermit root and a home
+//! directory of its own, so that commands can be tested the way a user
+//! runs them.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use assert_cmd::Command;
+use tempfile::TempDir;
+
+/// A temporary hermit root and home directory, removed when dropped.
+pub struct Sandbox {
+    dir: TempDir,
+}
+
+impl Sandbox {
+    pub fn new() -> Sandbox {
+        let sandbox = Sandbox {
+            dir: tempfile::tempdir().expect("failed to create tempdir"),
+        };
+        fs::create_dir_all(sandbox.home()).unwrap();
+        sandbox
+    }
+
+    pub fn root(&self) -> PathBuf {
+        self.dir.path().join("hermit")
+    }
+
+    pub fn home(&self) -> PathBuf {
+        self.dir.path().join("home")
+    }
+
+    /// The directory of a shell.
+    pub fn shell(&self, name: &str) -> PathBuf {
+        self.root().join("shells").join(name)
+    }
+
+    /// A `hermit` command that only sees the sandbox. Git's global and
+    /// system configuration are left out too, so the user's own can't
+    /// change what the command does.
+    pub fn hermit(&self, args: &[&str]) -> Command {
+        let mut command = Command::cargo_bin("hermit").expect("hermit wasn't built");
+        command.args(args).current_dir(self.home());
+        self.sandboxed(command)
+    }
+
+    /// A `git` command run in `dir`, seeing as little as `hermit` does.
+    pub fn git(&self, dir: &Path, args: &[&str]) -> Command {
+        let mut command = Command::new("git");
+        command.arg("-C").arg(dir).args(args);
+        self.sandboxed(command)
+    }
+
+    fn sandboxed(&self, mut command: Command) -> Command {
+        command
+            .env_clear()
+            .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+            .env("HOME", self.home())
+            .env("HERMIT_ROOT", self.root())
+            .env("HERMIT_HOME", self.home())
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_AUTHOR_NAME", "Hermit Tests")
+            .env("GIT_AUTHOR_EMAIL", "tests@example.com")
+            .env("GIT_COMMITTER_NAME", "Hermit Tests")
+            .env("GIT_COMMITTER_EMAIL", "tests@example.com");
+        command
+    }
+
+    /// Writes a file in the home directory, creating the directories it
+    /// goes in.
+    pub fn write_home_file(&self, path: &str, contents: &str) -> PathBuf {
+        let path = self.home().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Where the file at `path` in the home directory links to, if it
+    /// is a link.
+    pub fn link_target(&self, path: &str) -> Option<PathBuf> {
+        fs::read_link(self.home().join(path)).ok()
+    }
+}
+
+/// Whether `path` exists, without following links.
+pub fn exists(path: impl AsRef<Path>) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
@@ -0,0 +1,477 @@
+mod common;
+
+use std::{fs, os::unix, path::Path};
+
+use predicates::prelude::*;
+
+use common::{exists, Sandbox};
+
+#[test]
+fn init_add_use_and_status() {
+    let sandbox = Sandbox::new();
+    sandbox.write_home_file(".bashrc", "alias ll='ls -l'\n");
+
+    sandbox.hermit(&["init"]).assert().success();
+    assert!(sandbox.shell("default").join(".git").is_dir());
+
+    sandbox
+        .hermit(&["add", "~/.bashrc"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Adding .bashrc"));
+    assert_eq!(
+        sandbox.link_target(".bashrc"),
+        Some(sandbox.shell("default").join(".bashrc"))
+    );
+    assert_eq!(
+        fs::read_to_string(sandbox.shell("default").join(".bashrc")).unwrap(),
+        "alias ll='ls -l'\n"
+    );
+
+    sandbox
+        .hermit(&["status"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("On shell default"))
+        .stdout(predicate::str::is_match(r"linked\s+\.bashrc").unwrap());
+
+    sandbox.hermit(&["init", "work"]).assert().success();
+    sandbox.hermit(&["use", "default"]).assert().success();
+    sandbox.hermit(&["use", "work"]).assert().success();
+    assert!(!exists(sandbox.home().join(".bashrc")));
+
+    sandbox.hermit(&["use", "-"]).assert().success();
+    assert_eq!(
+        sandbox.link_target(".bashrc"),
+        Some(sandbox.shell("default").join(".bashrc"))
+    );
+}
+
+#[test]
+fn commands_need_an_initialized_root() {
+    let sandbox = Sandbox::new();
+
+    sandbox
+        .hermit(&["status"])
+        .assert()
+        .failure()
+        .code(3)
+        .stderr(predicate::str::contains("run `hermit init` first"));
+}
+
+#[test]
+fn verbose_versions_describe_where_hermit_runs() {
+    let sandbox = Sandbox::new();
+
+    sandbox
+        .hermit(&["version", "--verbose"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("(not initialized)"))
+        .stdout(predicate::str::contains("shell:    none"));
+
+    sandbox.hermit(&["init"]).assert().success();
+    sandbox
+        .hermit(&["version", "--verbose"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("shell:    default"))
+        .stdout(predicate::str::is_match(r"libgit2:\s+\d+\.\d+").unwrap());
+}
+
+#[test]
+fn unknown_shells_are_an_error() {
+    let sandbox = Sandbox::new();
+    sandbox.hermit(&["init"]).assert().success();
+
+    sandbox
+        .hermit(&["use", "nosuch"])
+        .assert()
+        .failure()
+        .code(2)
+        .stderr(predicate::str::contains("There is no shell named 'nosuch'"));
+    sandbox
+        .hermit(&["use", "--no-such-flag"])
+        .assert()
+        .code(2)
+        .stdout(predicate::str::is_empty());
+    sandbox
+        .hermit(&["--help"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("USAGE"));
+}
+
+#[test]
+fn files_in_the_way_are_left_alone_unless_forced() {
+    let sandbox = Sandbox::new();
+    sandbox.hermit(&["init"]).assert().success();
+    fs::write(sandbox.shell("default").join(".vimrc"), "set nu\n").unwrap();
+    sandbox.write_home_file(".vimrc", "mine\n");
+
+    sandbox.hermit(&["apply"]).assert().success();
+    sandbox
+        .hermit(&["status"])
+        .assert()
+        .stdout(predicate::str::is_match(r"conflict\s+\.vimrc").unwrap());
+    assert_eq!(
+        fs::read_to_string(sandbox.home().join(".vimrc")).unwrap(),
+        "mine\n"
+    );
+
+    sandbox.hermit(&["apply", "--force"]).assert().success();
+    assert_eq!(
+        sandbox.link_target(".vimrc"),
+        Some(sandbox.shell("default").join(".vimrc"))
+    );
+}
+
+#[test]
+fn dry_runs_show_the_plan_without_changing_anything() {
+    let sandbox = Sandbox::new();
+    sandbox.hermit(&["init"]).assert().success();
+    sandbox.hermit(&["init", "work"]).assert().success();
+    fs::write(sandbox.shell("work").join(".vimrc"), "set nu\n").unwrap();
+    fs::write(sandbox.shell("work").join(".bashrc"), "work\n").unwrap();
+    sandbox.write_home_file(".vimrc", "mine\n");
+
+    sandbox
+        .hermit(&["use", "work", "--dry-run"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("  + link       .bashrc -> "))
+        .stdout(predicate::str::contains("  ! conflict   .vimrc -> "))
+        .stdout(predicate::str::contains(
+            "Plan: 1 link to create, 1 conflict",
+        ));
+    assert!(!exists(sandbox.home().join(".bashrc")));
+
+    sandbox
+        .hermit(&["nuke", "default", "--dry-run"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Plan: 1 removal"));
+    assert!(sandbox.shell("default").is_dir());
+}
+
+#[test]
+fn read_only_mode_turns_changes_into_dry_runs() {
+    let sandbox = Sandbox::new();
+    sandbox.hermit(&["init"]).assert().success();
+    sandbox.hermit(&["init", "work"]).assert().success();
+    fs::write(sandbox.shell("work").join(".bashrc"), "work\n").unwrap();
+
+    sandbox
+        .hermit(&["use", "work"])
+        .env("HERMIT_READONLY", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Plan: 1 link to create"));
+    assert!(!exists(sandbox.home().join(".bashrc")));
+
+    sandbox
+        .hermit(&["--read-only", "init", "home"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Plan:"));
+    assert!(!sandbox.shell("home").exists());
+
+    sandbox.write_home_file(".vimrc", "set nu\n");
+    sandbox
+        .hermit(&["--read-only", "add", "~/.vimrc"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Plan:"));
+    assert!(!exists(sandbox.shell("default").join(".vimrc")));
+    assert_eq!(sandbox.link_target(".vimrc"), None);
+
+    sandbox
+        .hermit(&["--read-only", "packages", "add", "brew", "git"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("packages/Brewfile"));
+    assert!(!exists(sandbox.shell("default").join("packages")));
+
+    sandbox
+        .hermit(&["--read-only", "undo"])
+        .assert()
+        .failure()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "can't show what it would change without changing it",
+        ));
+
+    sandbox
+        .hermit(&["status"])
+        .env("HERMIT_READONLY", "1")
+        .assert()
+        .success();
+}
+
+#[test]
+fn read_only_mode_can_be_set_up_in_the_settings() {
+    let sandbox = Sandbox::new();
+    sandbox.hermit(&["init"]).assert().success();
+    sandbox.hermit(&["init", "work"]).assert().success();
+    fs::write(sandbox.shell("work").join(".bashrc"), "work\n").unwrap();
+    fs::write(
+        sandbox.root().join("config.toml"),
+        "[operations]\nread_only = true\n",
+    )
+    .unwrap();
+
+    sandbox
+        .hermit(&["use", "work"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Plan: 1 link to create"));
+    assert!(!exists(sandbox.home().join(".bashrc")));
+
+    // A link into the shells of another hermit root, which doctor
+    // offers to point at this one instead.
+    let elsewhere = sandbox.home().join("old-hermit/shells/work/.bashrc");
+    unix::fs::symlink(&elsewhere, sandbox.home().join(".bashrc")).unwrap();
+    sandbox
+        .hermit(&["doctor", "--offline"])
+        .write_stdin("y\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "read-only mode is on, so nothing was changed",
+        ));
+    assert_eq!(sandbox.link_target(".bashrc"), Some(elsewhere));
+}
+
+#[test]
+fn saved_plans_are_carried_out_unless_things_changed() {
+    let sandbox = Sandbox::new();
+    sandbox.hermit(&["init"]).assert().success();
+    sandbox.hermit(&["init", "work"]).assert().success();
+    fs::write(sandbox.shell("work").join(".bashrc"), "work\n").unwrap();
+    let plan = sandbox.root().join("plan.json");
+    let plan = plan.to_str().unwrap();
+
+    sandbox
+        .hermit(&["plan", "work", "-o", plan])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Plan: 1 link to create"));
+    assert!(!exists(sandbox.home().join(".bashrc")));
+
+    sandbox.write_home_file(".bashrc", "mine\n");
+    sandbox
+        .hermit(&["apply", "--plan", plan])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(".bashrc has changed"));
+
+    fs::remove_file(sandbox.home().join(".bashrc")).unwrap();
+    sandbox
+        .hermit(&["apply", "--plan", plan])
+        .assert()
+        .success();
+    assert_eq!(
+        sandbox.link_target(".bashrc"),
+        Some(sandbox.shell("work").join(".bashrc"))
+    );
+    sandbox
+        .hermit(&["status"])
+        .assert()
+        .stdout(predicate::str::contains("work"));
+}
+
+#[test]
+fn failed_changes_make_hermit_exit_with_an_error() {
+    let sandbox = Sandbox::new();
+    sandbox.hermit(&["init"]).assert().success();
+    sandbox.hermit(&["init", "work"]).assert().success();
+    fs::write(sandbox.shell("work").join(".vimrc"), "set nu\n").unwrap();
+    sandbox.write_home_file(".vimrc", "mine\n");
+
+    sandbox
+        .hermit(&["use", "work"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("is in the way").not())
+        .stderr(predicate::str::contains(".vimrc is in the way"))
+        .stderr(predicate::str::contains(
+            "1 of the changes failed or were skipped",
+        ));
+}
+
+#[test]
+fn short_status_is_stable_for_scripts() {
+    let sandbox = Sandbox::new();
+    sandbox.hermit(&["init"]).assert().success();
+    fs::write(sandbox.shell("default").join(".bashrc"), "").unwrap();
+    fs::write(sandbox.shell("default").join(".vimrc"), "set nu\n").unwrap();
+    sandbox.hermit(&["apply"]).assert().success();
+    fs::write(sandbox.shell("default").join(".inputrc"), "").unwrap();
+
+    sandbox
+        .hermit(&["status", "--short"])
+        .assert()
+        .success()
+        .stdout("## default\nL= .bashrc\nL? .inputrc\nL= .vimrc\n");
+}
+
+#[test]
+fn provisioning_twice_changes_nothing_the_second_time() {
+    let sandbox = Sandbox::new();
+    let repo = sandbox.home().join("src/dotfiles");
+    fs::create_dir_all(&repo).unwrap();
+    fs::write(repo.join(".bashrc"), "alias ll='ls -l'\n").unwrap();
+    fs::write(
+        repo.join("hermit.toml"),
+        "[[bootstrap]]\nname = \"mark\"\nrun = \"echo ran >> \\\"$HERMIT_HOME/bootstrapped\\\"\"\n",
+    )
+    .unwrap();
+    for args in &[
+        &["init", "--quiet"][..],
+        &["add", "."],
+        &[
+            "-c",
+            "user.name=t",
+            "-c",
+            "user.email=t@t",
+            "commit",
+            "--quiet",
+            "-m",
+            "Add",
+        ],
+    ] {
+        let status = std::process::Command::new("git")
+            .args(*args)
+            .current_dir(&repo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    let url = repo.to_str().unwrap();
+
+    sandbox
+        .hermit(&["provision", "--shell", url])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            r#"{"step":"clone","shell":"dotfiles","outcome":"changed""#,
+        ))
+        .stdout(predicate::str::contains(
+            r#"{"step":"bootstrap","shell":"dotfiles","outcome":"changed","detail":"mark"}"#,
+        ));
+    assert_eq!(
+        sandbox.link_target(".bashrc"),
+        Some(sandbox.shell("dotfiles").join(".bashrc"))
+    );
+
+    sandbox
+        .hermit(&["provision", "--shell", url])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(r#""outcome":"changed""#).not());
+    assert_eq!(
+        fs::read_to_string(sandbox.home().join("bootstrapped")).unwrap(),
+        "ran\n"
+    );
+
+    sandbox
+        .hermit(&["provision", "--shell", "/elsewhere/dotfiles"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            r#"{"step":"clone","shell":"dotfiles","outcome":"failed""#,
+        ));
+}
+
+#[test]
+fn a_remote_that_cant_be_pushed_to_doesnt_stop_the_others() {
+    let sandbox = Sandbox::new();
+    sandbox.hermit(&["init"]).assert().success();
+    let shell = sandbox.shell("default");
+    let git = |repo: &Path, args: &[&str]| {
+        let output = sandbox.git(repo, args).output().unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    };
+    git(&shell, &["commit", "--allow-empty", "-m", "Start"]);
+    let branch = git(&shell, &["rev-parse", "--abbrev-ref", "HEAD"]);
+    let origin = sandbox.root().join("origin.git");
+    let backup = sandbox.root().join("backup.git");
+    for remote in &[&origin, &backup] {
+        git(
+            &sandbox.root(),
+            &["init", "--bare", remote.to_str().unwrap()],
+        );
+    }
+    let nowhere = sandbox.root().join("nowhere.git");
+    for args in &[
+        ["broken", nowhere.to_str().unwrap(), "--mirror"],
+        ["backup", backup.to_str().unwrap(), "--mirror"],
+    ] {
+        let mut remote_add = vec!["remote", "add"];
+        remote_add.extend(args);
+        sandbox.hermit(&remote_add).assert().success();
+    }
+    sandbox
+        .hermit(&["remote", "add", "origin", origin.to_str().unwrap()])
+        .assert()
+        .success();
+    let pushed_everywhere = || {
+        let head = git(&shell, &["rev-parse", "HEAD"]);
+        for remote in &[&origin, &backup] {
+            assert_eq!(git(remote, &["rev-parse", &branch]), head);
+        }
+    };
+
+    sandbox
+        .hermit(&["--read-only", "push"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "Would push {} to origin",
+            branch
+        )))
+        .stdout(predicate::str::contains(format!(
+            "Would push {} to backup",
+            branch
+        )));
+    let pushed = sandbox
+        .git(&origin, &["rev-parse", "--verify", &branch])
+        .output()
+        .unwrap();
+    assert!(!pushed.status.success());
+
+    sandbox
+        .hermit(&["push"])
+        .assert()
+        .failure()
+        .code(4)
+        .stdout(predicate::str::contains("Pushed to origin"))
+        .stdout(predicate::str::contains("Pushed to backup"))
+        .stderr(predicate::str::contains("Could not push to broken"));
+    pushed_everywhere();
+
+    git(&shell, &["commit", "--allow-empty", "-m", "More"]);
+    sandbox
+        .hermit(&["push", "--all"])
+        .assert()
+        .failure()
+        .code(4)
+        .stderr(predicate::str::contains("Could not push to broken"));
+    pushed_everywhere();
+    assert_eq!(
+        git(&shell, &["rev-parse", "--abbrev-ref", "@{upstream}"]),
+        format!("origin/{}", branch)
+    );
+
+    git(&shell, &["commit", "--allow-empty", "-m", "Later"]);
+    let head = git(&shell, &["rev-parse", "HEAD"]);
+    sandbox
+        .hermit(&["--read-only", "sync"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Would pull 0 commits and push 1 commit, as of the last fetch",
+        ));
+    assert_ne!(git(&origin, &["rev-parse", &branch]), head);
+}